@@ -48,6 +48,7 @@ fn node_config() -> NodeConfig {
         genesis,
         external_address: peer_address,
         network: Default::default(),
+        tls: None,
         connect_list: Default::default(),
         api: api_cfg,
         mempool: Default::default(),
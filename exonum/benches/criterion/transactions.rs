@@ -152,12 +152,12 @@ impl TransactionVerifier {
         let channel = NodeChannel::new(&EventsPoolCapacity::default());
         let handler = TransactionsHandlerRef::new();
 
-        let handler_part = HandlerPart {
-            handler: handler.clone(),
-            internal_rx: channel.internal_events.1,
-            network_rx: channel.network_events.1,
-            api_rx: channel.api_requests.1,
-        };
+        let handler_part = HandlerPart::new(
+            handler.clone(),
+            channel.internal_events.1,
+            channel.network_events.1,
+            channel.api_requests.1,
+        );
 
         let handler_thread = thread::spawn(move || {
             let mut core = Core::new().unwrap();
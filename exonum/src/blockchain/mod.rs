@@ -42,6 +42,8 @@ pub use self::{
     },
 };
 
+pub(crate) use self::transaction::panic_description;
+
 pub mod config;
 
 use byteorder::{ByteOrder, LittleEndian};
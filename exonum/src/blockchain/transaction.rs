@@ -634,7 +634,7 @@ macro_rules! transactions {
 }
 
 /// Tries to get a meaningful description from the given panic.
-fn panic_description(any: &Box<dyn Any + Send>) -> Option<String> {
+pub(crate) fn panic_description(any: &Box<dyn Any + Send>) -> Option<String> {
     if let Some(s) = any.downcast_ref::<&str>() {
         Some(s.to_string())
     } else if let Some(s) = any.downcast_ref::<String>() {
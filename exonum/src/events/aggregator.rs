@@ -0,0 +1,631 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Aggregation of several event sources into a single stream of events.
+
+use futures::{stream::{self, Empty}, Async, Poll, Stream};
+
+use std::{collections::HashMap, time::Duration};
+
+use events::{InternalEvent, NetworkEvent};
+use node::ExternalMessage;
+
+/// Defines an aggregator over the given event sources. Each source is a `Stream` whose items
+/// are convertible into `Event`; all the sources share the same error type. Sources are
+/// listed along with their positions, which define the order of polling.
+///
+/// The generated aggregator polls the sources in a round-robin fashion and completes only
+/// when all of them are exhausted. The number of events a source may yield in a row is set
+/// by its weight in the `SchedulePolicy`, see `with_policy`. Sources listed as `prioritized`
+/// are peeked before each round: if the peeked event is high-priority, it is yielded
+/// immediately; otherwise it is buffered until the turn of its source. After
+/// `InternalEvent::Shutdown` is yielded by one of the sources, the other sources are dropped;
+/// the events which are already queued in that source are yielded, and then the aggregator
+/// completes. If `drain_on_shutdown` is set, the queued events of all the sources are yielded
+/// instead.
+///
+/// The completion of a source is handled according to its `SourceClosure` in the
+/// `SourceClosurePolicy`, see `with_closure_policy`: by default the remaining sources keep
+/// being polled, but the aggregator may also be shut down as if the closed source has yielded
+/// `InternalEvent::Shutdown`.
+///
+/// An error of a source fails the aggregator, unless the errors of the source are isolated
+/// with `isolate_errors`: then each error is yielded as `NetworkEvent::StreamError`, and
+/// the source keeps being polled.
+///
+/// An event taken from a source is either yielded right away or buffered in the aggregator,
+/// so dropping a future driving the aggregator by reference, e.g., the losing branch of
+/// a `select`, loses no events. `peek` takes the next event without yielding it; the event
+/// is yielded by the next `poll`.
+///
+/// ```ignore
+/// events_aggregator! {
+///     /// Aggregator of internal and network events.
+///     pub struct Aggregator {
+///         internal: S1 = 0,
+///         network: S2 = 1,
+///     }
+///     prioritized = [1];
+/// }
+/// ```
+macro_rules! events_aggregator {
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident {
+            $($field:ident: $stream:ident = $index:tt),+ $(,)*
+        }
+    ) => {
+        events_aggregator! {
+            $(#[$attr])*
+            pub struct $name {
+                $($field: $stream = $index),+
+            }
+            prioritized = [];
+        }
+    };
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident {
+            $($field:ident: $stream:ident = $index:tt),+ $(,)*
+        }
+        prioritized = [$($prioritized:tt),*];
+    ) => {
+        $(#[$attr])*
+        #[derive(Debug)]
+        pub struct $name<$($stream),+>
+        where
+            $($stream: $crate::futures::Stream),+
+        {
+            done: bool,
+            // Position of the source which yielded `InternalEvent::Shutdown`.
+            shutting_down: Option<usize>,
+            start_index: usize,
+            // Time given to drain all the sources after the shutdown.
+            drain_timeout: Option<::std::time::Duration>,
+            drain_deadline: Option<::std::time::Instant>,
+            // Event peeked from a prioritized source along with the position of the source.
+            peeked: Option<(usize, $crate::events::Event)>,
+            // Event taken by `peek` and not yielded yet.
+            pending: Option<$crate::events::Event>,
+            policy: $crate::events::SchedulePolicy,
+            // Number of events the source at `start_index` may still yield in a row.
+            credit: usize,
+            // Positions of the sources whose errors are yielded as events.
+            isolated: Vec<usize>,
+            closure_policy: $crate::events::SourceClosurePolicy,
+            // Position of the closed source which shuts the aggregator down once the grace
+            // period elapses.
+            closure_deadline: Option<(usize, $crate::tokio::timer::Delay)>,
+            $($field: Option<$stream>),+
+        }
+
+        impl<$($stream),+> $name<$($stream),+>
+        where
+            $($stream: $crate::futures::Stream),+
+        {
+            pub fn new($($field: $stream),+) -> Self {
+                $name {
+                    done: false,
+                    shutting_down: None,
+                    start_index: 0,
+                    drain_timeout: None,
+                    drain_deadline: None,
+                    peeked: None,
+                    pending: None,
+                    policy: $crate::events::SchedulePolicy::default(),
+                    credit: 0,
+                    isolated: Vec::new(),
+                    closure_policy: $crate::events::SourceClosurePolicy::default(),
+                    closure_deadline: None,
+                    $($field: Some($field)),+
+                }
+            }
+
+            /// Makes the aggregator yield the events queued in all the sources after
+            /// `InternalEvent::Shutdown`, rather than in the source of the shutdown only.
+            /// Draining stops once none of the sources is ready, or after `timeout` elapses.
+            pub fn drain_on_shutdown(mut self, timeout: ::std::time::Duration) -> Self {
+                self.drain_timeout = Some(timeout);
+                self
+            }
+
+            /// Sets the weights of the sources; by default all the sources have the same weight.
+            pub fn with_policy(mut self, policy: $crate::events::SchedulePolicy) -> Self {
+                self.policy = policy;
+                self
+            }
+
+            /// Sets the reactions to the completion of the sources; by default the remaining
+            /// sources keep being polled.
+            pub fn with_closure_policy(
+                mut self,
+                policy: $crate::events::SourceClosurePolicy,
+            ) -> Self {
+                self.closure_policy = policy;
+                self
+            }
+
+            /// Makes the errors of the source at the given position recoverable: instead of
+            /// failing the aggregator, each error is yielded as `NetworkEvent::StreamError`,
+            /// and the source keeps being polled.
+            pub fn isolate_errors(mut self, index: usize) -> Self {
+                assert!(
+                    index < Self::sources_count(),
+                    "There is no event source with index {}",
+                    index
+                );
+                self.isolated.push(index);
+                self
+            }
+
+            fn sources_count() -> usize {
+                [$($index),+].len()
+            }
+
+            fn prioritized() -> &'static [usize] {
+                &[$($prioritized),*]
+            }
+
+            fn is_exhausted(&self) -> bool {
+                self.peeked.is_none()
+                    && self.pending.is_none()
+                    && self.closure_deadline.is_none()
+                    $(&& self.$field.is_none())+
+            }
+        }
+
+        impl<E, $($stream),+> $name<$($stream),+>
+        where
+            $(
+                $stream: $crate::futures::Stream<Error = E>,
+                $stream::Item: Into<$crate::events::Event>,
+            )+
+            E: ::std::fmt::Debug,
+        {
+            fn poll_source(
+                &mut self,
+                index: usize,
+            ) -> $crate::futures::Poll<Option<$crate::events::Event>, E> {
+                use $crate::futures::Async;
+
+                match self.peeked.take() {
+                    Some((peeked_index, event)) if peeked_index == index => {
+                        return Ok(Async::Ready(Some(event)));
+                    }
+                    peeked => self.peeked = peeked,
+                }
+
+                let polled = match index {
+                    $(
+                        $index => $crate::events::aggregator::poll_alive(
+                            &mut self.$field,
+                            Into::into,
+                        ),
+                    )+
+                    _ => unreachable!("There is no event source with index {}", index),
+                };
+                match polled {
+                    Err(ref error) if self.isolated.contains(&index) => {
+                        let event = $crate::events::NetworkEvent::StreamError {
+                            source: index,
+                            error: format!("{:?}", error),
+                        };
+                        Ok(Async::Ready(Some(event.into())))
+                    }
+                    Ok(Async::Ready(None)) if self.shutting_down.is_none() => {
+                        self.source_closed(index)
+                    }
+                    Ok(Async::NotReady)
+                        if self.closure_deadline.as_ref().map(|d| d.0) == Some(index) =>
+                    {
+                        Ok(self.poll_closure_deadline())
+                    }
+                    polled => polled,
+                }
+            }
+
+            // Reacts to the completion of the source according to the closure policy.
+            // The source yields `InternalEvent::Shutdown` if the aggregator should be shut
+            // down right away.
+            fn source_closed(
+                &mut self,
+                index: usize,
+            ) -> $crate::futures::Poll<Option<$crate::events::Event>, E> {
+                use $crate::events::SourceClosure;
+                use $crate::futures::Async;
+
+                match self.closure_policy.action(index) {
+                    SourceClosure::Ignore => Ok(Async::Ready(None)),
+                    SourceClosure::Degrade => {
+                        error!("Event source {} has been closed, its events are lost", index);
+                        Ok(Async::Ready(None))
+                    }
+                    SourceClosure::Shutdown(grace_period) => {
+                        error!(
+                            "Event source {} has been closed, shutting down in {:?}",
+                            index, grace_period
+                        );
+                        if grace_period == ::std::time::Duration::from_secs(0) {
+                            return Ok(Async::Ready(Some(Self::shutdown_event())));
+                        }
+                        let deadline = ::std::time::Instant::now() + grace_period;
+                        let delay = $crate::tokio::timer::Delay::new(deadline);
+                        self.closure_deadline = Some((index, delay));
+                        Ok(self.poll_closure_deadline())
+                    }
+                }
+            }
+
+            fn poll_closure_deadline(
+                &mut self,
+            ) -> $crate::futures::Async<Option<$crate::events::Event>> {
+                use $crate::futures::{Async, Future};
+
+                let elapsed = match self.closure_deadline {
+                    Some((_, ref mut delay)) => match delay.poll() {
+                        Ok(Async::NotReady) => false,
+                        Ok(Async::Ready(())) => true,
+                        Err(e) => {
+                            error!("Grace period of the closed event source failed: {}", e);
+                            true
+                        }
+                    },
+                    None => false,
+                };
+                if elapsed {
+                    self.closure_deadline = None;
+                    Async::Ready(Some(Self::shutdown_event()))
+                } else {
+                    Async::NotReady
+                }
+            }
+
+            /// Takes the next event without yielding it. The event is buffered and yielded
+            /// by the next `poll`, so it is not lost if the future which has peeked it
+            /// is dropped.
+            pub fn peek(
+                &mut self,
+            ) -> $crate::futures::Poll<Option<&$crate::events::Event>, E> {
+                use $crate::futures::Async;
+
+                if self.pending.is_none() {
+                    match self.poll_event()? {
+                        Async::Ready(Some(event)) => self.pending = Some(event),
+                        Async::Ready(None) => return Ok(Async::Ready(None)),
+                        Async::NotReady => return Ok(Async::NotReady),
+                    }
+                }
+                Ok(Async::Ready(self.pending.as_ref()))
+            }
+
+            fn shutdown_event() -> $crate::events::Event {
+                $crate::events::InternalEvent::Shutdown.into()
+            }
+
+            // Completes the aggregator; it yields nothing after that.
+            fn complete(&mut self) -> $crate::futures::Poll<Option<$crate::events::Event>, E> {
+                self.done = true;
+                Ok($crate::futures::Async::Ready(None))
+            }
+
+            fn begin_shutdown(
+                &mut self,
+                index: usize,
+            ) -> $crate::futures::Poll<Option<$crate::events::Event>, E> {
+                self.shutting_down = Some(index);
+                self.closure_deadline = None;
+                if let Some(timeout) = self.drain_timeout {
+                    let deadline = ::std::time::Instant::now() + timeout;
+                    self.drain_deadline = Some(deadline);
+                    return self.poll_drain(deadline);
+                }
+
+                if self.peeked.as_ref().map_or(false, |&(peeked, _)| peeked != index) {
+                    self.peeked = None;
+                }
+                $(
+                    if $index != index {
+                        self.$field = None;
+                    }
+                )+
+                self.poll_shutdown(index)
+            }
+
+            // Yields ready events of all the sources until the deadline, and completes as soon
+            // as none of the sources has anything to offer.
+            fn poll_drain(
+                &mut self,
+                deadline: ::std::time::Instant,
+            ) -> $crate::futures::Poll<Option<$crate::events::Event>, E> {
+                use $crate::events::{Event, InternalEvent};
+                use $crate::futures::Async;
+
+                let count = Self::sources_count();
+                'drain: while ::std::time::Instant::now() < deadline {
+                    for offset in 0..count {
+                        let index = (self.start_index + offset) % count;
+                        if let Async::Ready(Some(event)) = self.poll_source(index)? {
+                            self.start_index = (index + 1) % count;
+                            match event {
+                                Event::Internal(InternalEvent::Shutdown) => continue 'drain,
+                                event => return Ok(Async::Ready(Some(event))),
+                            }
+                        }
+                    }
+                    break;
+                }
+                self.complete()
+            }
+
+            // Yields events of the source which has initiated the shutdown while they are
+            // ready, and completes as soon as the source has nothing to offer.
+            fn poll_shutdown(
+                &mut self,
+                index: usize,
+            ) -> $crate::futures::Poll<Option<$crate::events::Event>, E> {
+                use $crate::events::{Event, InternalEvent};
+                use $crate::futures::Async;
+
+                loop {
+                    match self.poll_source(index)? {
+                        Async::Ready(Some(Event::Internal(InternalEvent::Shutdown))) => continue,
+                        Async::Ready(Some(event)) => return Ok(Async::Ready(Some(event))),
+                        Async::Ready(None) | Async::NotReady => return self.complete(),
+                    }
+                }
+            }
+
+            // Takes the next event from the sources; the event must be either yielded or
+            // buffered by the caller.
+            fn poll_event(&mut self) -> $crate::futures::Poll<Option<$crate::events::Event>, E> {
+                use $crate::events::{Event, InternalEvent};
+                use $crate::futures::Async;
+
+                if self.done {
+                    return Ok(Async::Ready(None));
+                }
+                if let Some(index) = self.shutting_down {
+                    return match self.drain_deadline {
+                        Some(deadline) => self.poll_drain(deadline),
+                        None => self.poll_shutdown(index),
+                    };
+                }
+
+                for &index in Self::prioritized() {
+                    if self.peeked.is_some() {
+                        break;
+                    }
+                    if let Async::Ready(Some(event)) = self.poll_source(index)? {
+                        if event.is_high_priority() {
+                            return Ok(Async::Ready(Some(event)));
+                        }
+                        self.peeked = Some((index, event));
+                    }
+                }
+
+                let count = Self::sources_count();
+                for offset in 0..count {
+                    let index = (self.start_index + offset) % count;
+                    // A source which is not ready forfeits the rest of its credit.
+                    if offset > 0 || self.credit == 0 {
+                        self.credit = self.policy.weight(index);
+                    }
+                    if let Async::Ready(Some(event)) = self.poll_source(index)? {
+                        // The first ready source short-circuits the poll; once the source
+                        // has used up its credit, the next poll will start from
+                        // the following source.
+                        self.credit -= 1;
+                        if self.credit == 0 {
+                            self.start_index = (index + 1) % count;
+                        } else {
+                            self.start_index = index;
+                        }
+                        if let Event::Internal(InternalEvent::Shutdown) = event {
+                            return self.begin_shutdown(index);
+                        }
+                        return Ok(Async::Ready(Some(event)));
+                    }
+                }
+                self.credit = 0;
+
+                if self.is_exhausted() {
+                    return self.complete();
+                }
+                Ok(Async::NotReady)
+            }
+        }
+
+        impl<E, $($stream),+> $crate::futures::Stream for $name<$($stream),+>
+        where
+            $(
+                $stream: $crate::futures::Stream<Error = E>,
+                $stream::Item: Into<$crate::events::Event>,
+            )+
+            E: ::std::fmt::Debug,
+        {
+            type Item = $crate::events::Event;
+            type Error = E;
+
+            fn poll(&mut self) -> $crate::futures::Poll<Option<Self::Item>, E> {
+                match self.pending.take() {
+                    Some(event) => Ok($crate::futures::Async::Ready(Some(event))),
+                    None => self.poll_event(),
+                }
+            }
+        }
+    };
+}
+
+events_aggregator! {
+    /// Receives timeout, network and api events and invokes `handle_event` method of handler.
+    /// If one of these streams closes, the aggregator keeps polling the remaining ones and
+    /// completes only when all of them are exhausted.
+    ///
+    /// After `InternalEvent::Shutdown` is received, network and api events are no longer
+    /// accepted; the internal events which are already queued are yielded, and then
+    /// the aggregator completes.
+    ///
+    /// Sources are polled in a round-robin fashion: each poll starts from the source following
+    /// the one which yielded the previous event, so a busy source cannot starve the others.
+    /// Sources with a greater weight in the `SchedulePolicy` may yield several events in a row.
+    ///
+    /// Administrative api messages preempt the other events, see
+    /// `ExternalMessage::is_high_priority`.
+    ///
+    /// The aggregator is an ordinary `Stream` of `Event`s, so it can be consumed without
+    /// `HandlerPart`. Sources may be any streams sharing the error type; `from_internal`,
+    /// `from_network` and `from_api` create the aggregator over a single source.
+    ///
+    /// ```
+    /// # extern crate exonum;
+    /// # extern crate futures;
+    /// use exonum::events::{Event, EventsAggregator, NetworkEvent};
+    /// use futures::{stream, Future, Stream};
+    ///
+    /// # fn main() {
+    /// let addresses = vec!["127.0.0.1:2000".parse().unwrap(), "127.0.0.1:2001".parse().unwrap()];
+    /// let network = stream::iter_ok::<_, ()>(
+    ///     addresses.into_iter().map(NetworkEvent::PeerDisconnected),
+    /// );
+    ///
+    /// let mut disconnected = Vec::new();
+    /// EventsAggregator::from_network(network)
+    ///     .for_each(|event| {
+    ///         if let Event::Network(NetworkEvent::PeerDisconnected(address)) = event {
+    ///             disconnected.push(address.port());
+    ///         }
+    ///         Ok(())
+    ///     })
+    ///     .wait()
+    ///     .unwrap();
+    /// assert_eq!(disconnected, vec![2000, 2001]);
+    /// # }
+    /// ```
+    pub struct EventsAggregator {
+        internal: S1 = 0,
+        network: S2 = 1,
+        api: S3 = 2,
+    }
+    prioritized = [2];
+}
+
+impl<S: Stream>
+    EventsAggregator<S, Empty<NetworkEvent, S::Error>, Empty<ExternalMessage, S::Error>>
+{
+    /// Creates the aggregator over the internal events only.
+    pub fn from_internal(internal: S) -> Self {
+        Self::new(internal, stream::empty(), stream::empty())
+    }
+}
+
+impl<S: Stream>
+    EventsAggregator<Empty<InternalEvent, S::Error>, S, Empty<ExternalMessage, S::Error>>
+{
+    /// Creates the aggregator over the network events only.
+    pub fn from_network(network: S) -> Self {
+        Self::new(stream::empty(), network, stream::empty())
+    }
+}
+
+impl<S: Stream> EventsAggregator<Empty<InternalEvent, S::Error>, Empty<NetworkEvent, S::Error>, S> {
+    /// Creates the aggregator over the api messages only.
+    pub fn from_api(api: S) -> Self {
+        Self::new(stream::empty(), stream::empty(), api)
+    }
+}
+
+/// Weights of the event sources of an aggregator, indexed by the positions of the sources.
+///
+/// A source with weight `n` may yield up to `n` events in a row before the turn passes
+/// to the next source, so under load the source receives `n / total_weight` of
+/// the dispatch slots. A source which is not ready loses its turn.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SchedulePolicy {
+    weights: Vec<usize>,
+}
+
+impl SchedulePolicy {
+    /// Creates a policy giving all the sources the same weight.
+    pub fn round_robin() -> Self {
+        Self::default()
+    }
+
+    /// Creates a policy with the given weights. Sources missing in `weights` have weight `1`;
+    /// zero weights are treated as `1`.
+    pub fn weighted(weights: Vec<usize>) -> Self {
+        SchedulePolicy { weights }
+    }
+
+    /// Returns the weight of the source at the given position.
+    pub fn weight(&self, index: usize) -> usize {
+        self.weights.get(index).cloned().unwrap_or(1).max(1)
+    }
+}
+
+/// Reaction of an aggregator to the completion of one of its event sources.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceClosure {
+    /// The remaining sources keep being polled.
+    Ignore,
+    /// The closure is logged as an error, and the remaining sources keep being polled.
+    Degrade,
+    /// The closure is logged as an error, and once the grace period elapses the aggregator
+    /// shuts down as if the closed source has yielded `InternalEvent::Shutdown`. The events
+    /// of the remaining sources are yielded during the grace period.
+    Shutdown(Duration),
+}
+
+/// Reactions of an aggregator to the completion of its event sources, indexed by
+/// the positions of the sources. The closure of a source missing in the policy is ignored.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SourceClosurePolicy {
+    actions: HashMap<usize, SourceClosure>,
+}
+
+impl SourceClosurePolicy {
+    /// Sets the reaction to the completion of the source at the given position.
+    pub fn on_closed(mut self, index: usize, action: SourceClosure) -> Self {
+        self.actions.insert(index, action);
+        self
+    }
+
+    /// Returns the reaction to the completion of the source at the given position.
+    pub fn action(&self, index: usize) -> SourceClosure {
+        self.actions
+            .get(&index)
+            .cloned()
+            .unwrap_or(SourceClosure::Ignore)
+    }
+}
+
+/// Polls the source if it is not exhausted yet and maps its item into the event.
+/// A source which has completed is dropped and never polled again.
+pub(crate) fn poll_alive<S, F, T>(source: &mut Option<S>, map_event: F) -> Poll<Option<T>, S::Error>
+where
+    S: Stream,
+    F: FnOnce(S::Item) -> T,
+{
+    let polled = match *source {
+        Some(ref mut stream) => stream.poll()?,
+        None => return Ok(Async::NotReady),
+    };
+    match polled {
+        Async::Ready(Some(item)) => Ok(Async::Ready(Some(map_event(item)))),
+        Async::Ready(None) => {
+            *source = None;
+            Ok(Async::Ready(None))
+        }
+        Async::NotReady => Ok(Async::NotReady),
+    }
+}
@@ -12,14 +12,26 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use bytes::BytesMut;
+use futures::{sync::mpsc, Future};
 use test::Bencher;
+use tokio_io::codec::{Decoder, Encoder};
 
-use std::{net::SocketAddr, thread};
+use std::{
+    collections::BTreeSet, net::SocketAddr, thread, time::{Duration, SystemTime},
+};
 
 use events::{
-    network::NetworkConfiguration, tests::{raw_message, ConnectionParams, TestEvents},
+    codec::{test::create_encrypted_codecs_with_max_len, Frame}, error::HandlerError,
+    network::NetworkConfiguration,
+    tests::{raw_message, ConnectionParams, TestEvents}, timer_wheel::TimerWheel, Event,
+    EventHandler, HandlerPart, InternalEvent, NetworkEvent, TimeoutRequest,
+};
+use helpers::Height;
+use messages::RawMessage;
+use node::{
+    state::SharedConnectList, ConnectList, EventsPoolCapacity, ExternalMessage, NodeTimeout,
 };
-use node::{state::SharedConnectList, ConnectList, EventsPoolCapacity};
 
 struct BenchConfig {
     times: usize,
@@ -34,8 +46,11 @@ fn test_events(cfg: &BenchConfig, listen_address: SocketAddr) -> TestEvents {
     };
     TestEvents {
         listen_address,
+        additional_listen_addresses: Vec::new(),
         network_config,
         events_config: EventsPoolCapacity::default(),
+        memory_transport: None,
+        network_metrics: Default::default(),
     }
 }
 
@@ -238,3 +253,168 @@ fn bench_msg_long_100_nodelay(b: &mut Bencher) {
     ];
     bench_network(b, addrs, &cfg);
 }
+
+struct CountingHandler(usize);
+
+impl EventHandler for CountingHandler {
+    fn handle_event(&mut self, _: Event) {
+        self.0 += 1;
+    }
+}
+
+fn bench_dispatch(b: &mut Bencher, max_batch: usize, times: usize) {
+    bench_dispatch_loop(b, max_batch, times, |handler_part| handler_part.run().wait().unwrap());
+}
+
+// Dispatches events through the boxed event loop to measure the overhead of dynamic dispatch.
+fn bench_dispatch_boxed(b: &mut Bencher, max_batch: usize, times: usize) {
+    bench_dispatch_loop(b, max_batch, times, |handler_part| {
+        let event_loop: Box<dyn Future<Item = (), Error = HandlerError>> =
+            Box::new(handler_part.run());
+        event_loop.wait().unwrap()
+    });
+}
+
+fn bench_dispatch_loop<F>(b: &mut Bencher, max_batch: usize, times: usize, run: F)
+where
+    F: Fn(HandlerPart<CountingHandler>),
+{
+    let address: SocketAddr = "127.0.0.1:9300".parse().unwrap();
+    b.iter(|| {
+        let (_, internal_rx) = mpsc::channel::<InternalEvent>(1);
+        let (_, api_rx) = mpsc::channel::<ExternalMessage>(1);
+        let (mut network_tx, network_rx) = mpsc::channel(times);
+        for _ in 0..times {
+            network_tx
+                .try_send(NetworkEvent::PeerDisconnected(address))
+                .unwrap();
+        }
+        drop(network_tx);
+
+        let mut handler_part =
+            HandlerPart::new(CountingHandler(0), internal_rx, network_rx, api_rx);
+        handler_part.max_batch = max_batch;
+        run(handler_part);
+    })
+}
+
+#[bench]
+fn bench_dispatch_single_10_000(b: &mut Bencher) {
+    bench_dispatch(b, 1, 10_000);
+}
+
+#[bench]
+fn bench_dispatch_single_boxed_10_000(b: &mut Bencher) {
+    bench_dispatch_boxed(b, 1, 10_000);
+}
+
+#[bench]
+fn bench_dispatch_batch_64_10_000(b: &mut Bencher) {
+    bench_dispatch(b, 64, 10_000);
+}
+
+#[bench]
+fn bench_dispatch_batch_64_boxed_10_000(b: &mut Bencher) {
+    bench_dispatch_boxed(b, 64, 10_000);
+}
+
+#[bench]
+fn bench_dispatch_batch_1024_10_000(b: &mut Bencher) {
+    bench_dispatch(b, 1024, 10_000);
+}
+
+// Encodes and decodes a message per iteration. The copying variant additionally copies
+// the decoded message into an owned buffer, like the decoder did before yielding messages
+// which share the memory of the received frame.
+fn bench_decode(b: &mut Bencher, len: usize, copy: bool) {
+    let (mut responder, mut initiator) = create_encrypted_codecs_with_max_len(2 * len as u32);
+    let message = raw_message(0, len);
+    let mut bytes = BytesMut::new();
+    b.iter(|| {
+        initiator
+            .encode(Frame::Message(message.clone()), &mut bytes)
+            .unwrap();
+        match responder.decode(&mut bytes).unwrap() {
+            Some(Frame::Message(ref decoded)) if copy => {
+                RawMessage::from_vec(decoded.to_vec());
+            }
+            Some(Frame::Message(_)) => {}
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    })
+}
+
+#[bench]
+fn bench_decode_shared_1_000_000(b: &mut Bencher) {
+    bench_decode(b, 1_000_000, false);
+}
+
+#[bench]
+fn bench_decode_copying_1_000_000(b: &mut Bencher) {
+    bench_decode(b, 1_000_000, true);
+}
+
+const TIMEOUTS_SPAN_MS: u64 = 10_000;
+const TIMEOUTS_STEP_MS: u64 = 10;
+
+fn timeout_requests(origin: SystemTime, count: u64) -> Vec<TimeoutRequest> {
+    (0..count)
+        .map(|i| {
+            let delay = Duration::from_millis(i * 7_919 % TIMEOUTS_SPAN_MS);
+            TimeoutRequest(origin + delay, NodeTimeout::Status(Height(i)))
+        })
+        .collect()
+}
+
+// Schedules the timeouts and expires them while the time passes in steps of the wheel
+// resolution, the way the internal part keeps the exact timeouts.
+fn bench_timeouts_exact(b: &mut Bencher, count: u64) {
+    let origin = SystemTime::now();
+    let requests = timeout_requests(origin, count);
+    b.iter(|| {
+        let mut pending = BTreeSet::new();
+        for request in requests.iter().cloned() {
+            pending.insert(request);
+        }
+        let mut fired = 0;
+        for step in 0..=TIMEOUTS_SPAN_MS / TIMEOUTS_STEP_MS {
+            let now = origin + Duration::from_millis(step * TIMEOUTS_STEP_MS);
+            while let Some(first) = pending.iter().next().cloned() {
+                if first.0 > now {
+                    break;
+                }
+                pending.remove(&first);
+                fired += 1;
+            }
+        }
+        assert_eq!(fired, count);
+    })
+}
+
+fn bench_timeouts_wheel(b: &mut Bencher, count: u64) {
+    let origin = SystemTime::now();
+    let requests = timeout_requests(origin, count);
+    let resolution = Duration::from_millis(TIMEOUTS_STEP_MS);
+    b.iter(|| {
+        let mut wheel = TimerWheel::new(origin, resolution);
+        for request in requests.iter().cloned() {
+            wheel.insert(request.0, request);
+        }
+        let mut fired = 0;
+        for step in 0..=TIMEOUTS_SPAN_MS / TIMEOUTS_STEP_MS {
+            let now = origin + Duration::from_millis(step * TIMEOUTS_STEP_MS);
+            fired += wheel.advance(now).len() as u64;
+        }
+        assert_eq!(fired, count);
+    })
+}
+
+#[bench]
+fn bench_timeouts_exact_100_000(b: &mut Bencher) {
+    bench_timeouts_exact(b, 100_000);
+}
+
+#[bench]
+fn bench_timeouts_wheel_100_000(b: &mut Bencher) {
+    bench_timeouts_wheel(b, 100_000);
+}
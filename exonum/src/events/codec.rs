@@ -14,11 +14,400 @@
 
 use byteorder::{ByteOrder, LittleEndian};
 use bytes::BytesMut;
+use crc::crc32;
 use failure;
+use hex;
+use serde_json::{self, Value};
 use tokio_io::codec::{Decoder, Encoder};
 
-use events::noise::{NoiseWrapper, HEADER_LENGTH as NOISE_HEADER_LENGTH};
-use messages::{MessageBuffer, RawMessage, HEADER_LENGTH};
+use std::{borrow::Cow, cmp, fmt, sync::Arc};
+
+use crypto::{Signature, SIGNATURE_LENGTH};
+use events::{
+    error::DecodeError,
+    noise::{encrypted_msg_len, NoiseWrapper, HEADER_LENGTH as NOISE_HEADER_LENGTH},
+};
+use messages::{RawMessage, HEADER_LENGTH};
+
+/// Version of the wire protocol, exchanged with the peer right after the connection
+/// is established.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Length of the nonce which the peer signs to prove the ownership of its key.
+pub const AUTH_NONCE_LENGTH: usize = 32;
+
+/// Messages shorter than this are sent uncompressed, since compression would not pay off.
+const MIN_COMPRESSED_LEN: usize = 256;
+/// Length of the header of a compressed frame: the type byte and the uncompressed length.
+const COMPRESSED_HEADER_LENGTH: usize = 5;
+/// Length of the CRC32 checksum appended to a checksummed frame.
+const CHECKSUM_LENGTH: usize = 4;
+/// Capacity of the read buffer restored after a frame longer than
+/// `READ_BUFFER_HIGH_WATER_MARK` is decoded, so that the memory taken by a large frame
+/// is released rather than kept for the lifetime of the connection.
+const READ_BUFFER_CAPACITY: usize = 8 * 1024;
+const READ_BUFFER_HIGH_WATER_MARK: usize = 64 * 1024;
+
+/// Compression of the messages sent to peers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompressionKind {
+    None,
+    Lz4,
+}
+
+impl CompressionKind {
+    /// Returns the byte announcing the compression during the handshake.
+    pub fn as_byte(self) -> u8 {
+        match self {
+            CompressionKind::None => 0,
+            CompressionKind::Lz4 => 1,
+        }
+    }
+
+    /// Parses the handshake payload. Peers of older versions send an empty payload,
+    /// which means they do not support compression.
+    pub fn from_handshake(payload: &[u8]) -> Self {
+        match payload.first() {
+            Some(&1) => CompressionKind::Lz4,
+            _ => CompressionKind::None,
+        }
+    }
+}
+
+/// Returns `true` if the handshake payload announces the support of frame checksums.
+/// Peers of older versions send at most the compression byte, see `CompressionKind`.
+pub fn checksums_from_handshake(payload: &[u8]) -> bool {
+    payload.get(1) == Some(&1)
+}
+
+impl Default for CompressionKind {
+    fn default() -> Self {
+        CompressionKind::None
+    }
+}
+
+/// Type of the frame transferred over the wire, encoded in its first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    /// Exonum message; its first byte is a part of the message header.
+    Message,
+    /// Keep-alive request, which should be answered with `Pong`.
+    Ping,
+    /// Keep-alive response.
+    Pong,
+    /// Exonum message compressed with LZ4.
+    Compressed,
+    /// Protocol version of the peer, followed by 4 bytes of the version in little-endian.
+    Version,
+    /// Random nonce which the peer should sign to prove the ownership of its key.
+    Challenge,
+    /// Signature of the nonce received in `Challenge`.
+    ChallengeResponse,
+    /// Frame of any other type followed by 4 bytes of the CRC32 of that frame in little-endian.
+    Checksummed,
+    /// Number of additional messages the peer may send, followed by 4 bytes of the number
+    /// in little-endian.
+    Credit,
+}
+
+impl MessageType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(MessageType::Message),
+            1 => Some(MessageType::Ping),
+            2 => Some(MessageType::Pong),
+            3 => Some(MessageType::Compressed),
+            4 => Some(MessageType::Version),
+            5 => Some(MessageType::Challenge),
+            6 => Some(MessageType::ChallengeResponse),
+            7 => Some(MessageType::Checksummed),
+            8 => Some(MessageType::Credit),
+            _ => None,
+        }
+    }
+
+    fn as_byte(self) -> u8 {
+        match self {
+            MessageType::Message => 0,
+            MessageType::Ping => 1,
+            MessageType::Pong => 2,
+            MessageType::Compressed => 3,
+            MessageType::Version => 4,
+            MessageType::Challenge => 5,
+            MessageType::ChallengeResponse => 6,
+            MessageType::Checksummed => 7,
+            MessageType::Credit => 8,
+        }
+    }
+}
+
+/// Frame transferred over the wire. Control frames consist of a single type byte
+/// and are never passed to the consensus.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Message(RawMessage),
+    Ping,
+    Pong,
+    Version(u32),
+    Challenge([u8; AUTH_NONCE_LENGTH]),
+    ChallengeResponse(Signature),
+    Credit(u32),
+}
+
+impl Frame {
+    pub fn message_type(&self) -> MessageType {
+        match *self {
+            Frame::Message(_) => MessageType::Message,
+            Frame::Ping => MessageType::Ping,
+            Frame::Pong => MessageType::Pong,
+            Frame::Version(_) => MessageType::Version,
+            Frame::Challenge(_) => MessageType::Challenge,
+            Frame::ChallengeResponse(_) => MessageType::ChallengeResponse,
+            Frame::Credit(_) => MessageType::Credit,
+        }
+    }
+}
+
+impl From<RawMessage> for Frame {
+    fn from(message: RawMessage) -> Self {
+        Frame::Message(message)
+    }
+}
+
+/// Format of the frames transferred over the wire, i.e., of the contents of the encrypted
+/// frames. Compression and checksums are applied on top of the format, so the encoded frames
+/// must not start with the bytes of `MessageType::Compressed` and `MessageType::Checksummed`.
+/// Peers should use the same format; the frames in any other format are rejected as malformed.
+pub trait FrameCodec: fmt::Debug + Send + Sync + 'static {
+    /// Serializes the frame.
+    fn encode_frame<'a>(&self, frame: &'a Frame) -> Result<Cow<'a, [u8]>, failure::Error>;
+
+    /// Parses the frame, rejecting the messages longer than `max_message_len`.
+    fn decode_frame(&self, buf: BytesMut, max_message_len: u32) -> Result<Frame, failure::Error>;
+}
+
+/// Binary format of the frames used by default: messages are sent as is, and control
+/// frames start with the byte of their `MessageType`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryFrameCodec;
+
+impl FrameCodec for BinaryFrameCodec {
+    fn encode_frame<'a>(&self, frame: &'a Frame) -> Result<Cow<'a, [u8]>, failure::Error> {
+        let encoded = match *frame {
+            Frame::Message(ref msg) => Cow::Borrowed(msg.as_ref()),
+            Frame::Version(version) => {
+                let mut frame = vec![MessageType::Version.as_byte(), 0, 0, 0, 0];
+                LittleEndian::write_u32(&mut frame[1..], version);
+                Cow::Owned(frame)
+            }
+            Frame::Credit(credit) => {
+                let mut frame = vec![MessageType::Credit.as_byte(), 0, 0, 0, 0];
+                LittleEndian::write_u32(&mut frame[1..], credit);
+                Cow::Owned(frame)
+            }
+            Frame::Challenge(nonce) => {
+                let mut frame = vec![MessageType::Challenge.as_byte()];
+                frame.extend_from_slice(&nonce);
+                Cow::Owned(frame)
+            }
+            Frame::ChallengeResponse(signature) => {
+                let mut frame = vec![MessageType::ChallengeResponse.as_byte()];
+                frame.extend_from_slice(signature.as_ref());
+                Cow::Owned(frame)
+            }
+            ref control => Cow::Owned(vec![control.message_type().as_byte()]),
+        };
+        Ok(encoded)
+    }
+
+    fn decode_frame(&self, buf: BytesMut, max_message_len: u32) -> Result<Frame, failure::Error> {
+        match MessageType::from_byte(buf[0]) {
+            Some(MessageType::Message) => {}
+            Some(MessageType::Compressed) => {
+                bail!("Received compressed frame nested into another one");
+            }
+            Some(MessageType::Version) => {
+                if buf.len() != 5 {
+                    bail!("Received malformed Version frame of length {}", buf.len());
+                }
+                return Ok(Frame::Version(LittleEndian::read_u32(&buf[1..])));
+            }
+            Some(MessageType::Credit) => {
+                if buf.len() != 5 {
+                    bail!("Received malformed Credit frame of length {}", buf.len());
+                }
+                return Ok(Frame::Credit(LittleEndian::read_u32(&buf[1..])));
+            }
+            Some(MessageType::Challenge) => {
+                if buf.len() != 1 + AUTH_NONCE_LENGTH {
+                    bail!("Received malformed Challenge frame of length {}", buf.len());
+                }
+                let mut nonce = [0; AUTH_NONCE_LENGTH];
+                nonce.copy_from_slice(&buf[1..]);
+                return Ok(Frame::Challenge(nonce));
+            }
+            Some(MessageType::ChallengeResponse) => {
+                if buf.len() != 1 + SIGNATURE_LENGTH {
+                    bail!(
+                        "Received malformed ChallengeResponse frame of length {}",
+                        buf.len()
+                    );
+                }
+                let signature = Signature::from_slice(&buf[1..]).unwrap();
+                return Ok(Frame::ChallengeResponse(signature));
+            }
+            Some(message_type) => {
+                if buf.len() != 1 {
+                    bail!(
+                        "Received malformed {:?} frame of length {}",
+                        message_type,
+                        buf.len()
+                    );
+                }
+                return Ok(match message_type {
+                    MessageType::Ping => Frame::Ping,
+                    _ => Frame::Pong,
+                });
+            }
+            None => bail!("A first byte of the message must be set to 0"),
+        }
+
+        check_message(&buf, max_message_len)?;
+
+        // The message shares the memory of the decrypted frame instead of copying it.
+        let raw = RawMessage::from_bytes(buf.freeze());
+        Ok(Frame::Message(raw))
+    }
+}
+
+/// Checks that the message has a complete header, and that the length in the header
+/// matches the length of the message and does not exceed `max_message_len`. The messages
+/// decoded by any `FrameCodec` should pass this check, since `RawMessage` reads its header
+/// without checking the bounds.
+fn check_message(buf: &[u8], max_message_len: u32) -> Result<(), failure::Error> {
+    if buf.len() < HEADER_LENGTH {
+        bail!(
+            "Received malicious message with insufficient size: {}, \
+             expected header size {}",
+            buf.len(),
+            HEADER_LENGTH
+        );
+    }
+
+    // Check payload len
+    let total_len = LittleEndian::read_u32(&buf[6..10]) as usize;
+
+    if total_len as u32 > max_message_len {
+        bail!(
+            "Received message is too long: {}, maximum allowed length is {} bytes",
+            total_len,
+            max_message_len,
+        );
+    }
+
+    if total_len < HEADER_LENGTH {
+        bail!(
+            "Received malicious message with insufficient \
+             size in header: {}, expected header size {}",
+            total_len,
+            HEADER_LENGTH
+        );
+    }
+
+    if total_len != buf.len() {
+        bail!(
+            "Received malicious message with wrong \
+             total_len: {}, expected message length {}",
+            total_len,
+            buf.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Human-readable format of the frames for debugging, in which each frame is a JSON object
+/// with the `type` of the frame, e.g., `{"type":"ping"}`, and its fields; messages, nonces
+/// and signatures are hex-encoded. As messages take twice as much space, only the messages
+/// up to about half of `max_message_len` fit into a frame.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFrameCodec;
+
+impl JsonFrameCodec {
+    fn field<'a>(frame: &'a Value, name: &str) -> Result<&'a Value, failure::Error> {
+        frame
+            .get(name)
+            .ok_or_else(|| format_err!("JSON frame has no field {}", name))
+    }
+
+    fn hex_field(frame: &Value, name: &str) -> Result<Vec<u8>, failure::Error> {
+        let field = Self::field(frame, name)?
+            .as_str()
+            .ok_or_else(|| format_err!("Field {} of JSON frame is not a string", name))?;
+        hex::decode(field).map_err(|e| format_err!("Field {} of JSON frame: {}", name, e))
+    }
+
+    fn u32_field(frame: &Value, name: &str) -> Result<u32, failure::Error> {
+        let field = Self::field(frame, name)?.as_u64();
+        match field {
+            Some(value) if value <= u64::from(u32::max_value()) => Ok(value as u32),
+            _ => bail!("Field {} of JSON frame is not a 32-bit number", name),
+        }
+    }
+}
+
+impl FrameCodec for JsonFrameCodec {
+    fn encode_frame<'a>(&self, frame: &'a Frame) -> Result<Cow<'a, [u8]>, failure::Error> {
+        let value = match *frame {
+            Frame::Message(ref msg) => json!({ "type": "message", "data": hex::encode(msg) }),
+            Frame::Ping => json!({ "type": "ping" }),
+            Frame::Pong => json!({ "type": "pong" }),
+            Frame::Version(version) => json!({ "type": "version", "version": version }),
+            Frame::Challenge(ref nonce) => {
+                json!({ "type": "challenge", "nonce": hex::encode(&nonce[..]) })
+            }
+            Frame::ChallengeResponse(ref signature) => json!({
+                "type": "challenge_response",
+                "signature": hex::encode(signature)
+            }),
+            Frame::Credit(credit) => json!({ "type": "credit", "credit": credit }),
+        };
+        Ok(Cow::Owned(serde_json::to_vec(&value)?))
+    }
+
+    fn decode_frame(&self, buf: BytesMut, max_message_len: u32) -> Result<Frame, failure::Error> {
+        let value: Value = serde_json::from_slice(&buf)?;
+        let frame = match value.get("type").and_then(Value::as_str) {
+            Some("message") => {
+                let data = Self::hex_field(&value, "data")?;
+                check_message(&data, max_message_len)?;
+                Frame::Message(RawMessage::from_vec(data))
+            }
+            Some("ping") => Frame::Ping,
+            Some("pong") => Frame::Pong,
+            Some("version") => Frame::Version(Self::u32_field(&value, "version")?),
+            Some("challenge") => {
+                let data = Self::hex_field(&value, "nonce")?;
+                if data.len() != AUTH_NONCE_LENGTH {
+                    bail!("Received malformed challenge of length {}", data.len());
+                }
+                let mut nonce = [0; AUTH_NONCE_LENGTH];
+                nonce.copy_from_slice(&data);
+                Frame::Challenge(nonce)
+            }
+            Some("challenge_response") => {
+                let data = Self::hex_field(&value, "signature")?;
+                let signature = Signature::from_slice(&data)
+                    .ok_or_else(|| format_err!("Received malformed signature"))?;
+                Frame::ChallengeResponse(signature)
+            }
+            Some("credit") => Frame::Credit(Self::u32_field(&value, "credit")?),
+            other => bail!("Received JSON frame of unknown type {:?}", other),
+        };
+        Ok(frame)
+    }
+}
 
 #[derive(Debug)]
 pub struct MessagesCodec {
@@ -26,6 +415,16 @@ pub struct MessagesCodec {
     max_message_len: u32,
     /// Noise session to encrypt/decrypt messages.
     session: NoiseWrapper,
+    /// Format of the frames.
+    format: Arc<dyn FrameCodec>,
+    /// Compression enabled in our configuration.
+    compression: CompressionKind,
+    /// Whether sent messages are compressed, i.e., compression is enabled on both sides.
+    compress_sent: bool,
+    /// Frame checksums enabled in our configuration.
+    checksums: bool,
+    /// Whether checksums are appended to sent frames, i.e., they are enabled on both sides.
+    checksum_sent: bool,
 }
 
 impl MessagesCodec {
@@ -33,64 +432,169 @@ impl MessagesCodec {
         Self {
             max_message_len,
             session,
+            format: Arc::new(BinaryFrameCodec),
+            compression: CompressionKind::None,
+            compress_sent: false,
+            checksums: false,
+            checksum_sent: false,
         }
     }
-}
 
-impl Decoder for MessagesCodec {
-    type Item = RawMessage;
-    type Error = failure::Error;
+    /// Makes the codec serialize the frames in the given format instead of the binary one.
+    pub fn with_frame_codec(mut self, format: Arc<dyn FrameCodec>) -> Self {
+        self.format = format;
+        self
+    }
 
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // Read header
-        if buf.len() < HEADER_LENGTH {
-            return Ok(None);
+    /// Enables checksums of the sent frames if the peer supports them as well. Similarly
+    /// to compression, if the support of the peer is not known from the handshake, checksums
+    /// are sent after the first checksummed frame is received.
+    pub fn with_checksums(mut self, checksums: bool, remote: bool) -> Self {
+        self.checksums = checksums;
+        self.checksum_sent = checksums && remote;
+        self
+    }
+
+    fn append_checksum(frame: &[u8]) -> Vec<u8> {
+        let mut checksummed = Vec::with_capacity(1 + frame.len() + CHECKSUM_LENGTH);
+        checksummed.push(MessageType::Checksummed.as_byte());
+        checksummed.extend_from_slice(frame);
+        let mut checksum = [0_u8; CHECKSUM_LENGTH];
+        LittleEndian::write_u32(&mut checksum, crc32::checksum_ieee(frame));
+        checksummed.extend_from_slice(&checksum);
+        checksummed
+    }
+
+    /// Verifies and strips the checksum of a checksummed frame; other frames are returned
+    /// as is.
+    fn strip_checksum(&mut self, mut buf: BytesMut) -> Result<BytesMut, failure::Error> {
+        if MessageType::from_byte(buf[0]) != Some(MessageType::Checksummed) {
+            return Ok(buf);
+        }
+        if buf.len() < 2 + CHECKSUM_LENGTH {
+            bail!("Received malformed checksummed frame of length {}", buf.len());
         }
 
-        let len = LittleEndian::read_u32(buf) as usize;
+        let checksum_start = buf.len() - CHECKSUM_LENGTH;
+        let expected = LittleEndian::read_u32(&buf[checksum_start..]);
+        buf.truncate(checksum_start);
+        buf.split_to(1);
+        let actual = crc32::checksum_ieee(&buf);
+        if actual != expected {
+            return Err(DecodeError::ChecksumMismatch { expected, actual }.into());
+        }
+        if MessageType::from_byte(buf[0]) == Some(MessageType::Checksummed) {
+            bail!("Received checksummed frame nested into another one");
+        }
 
-        if buf.len() < len + NOISE_HEADER_LENGTH {
+        // The peer supports checksums, so we can send them to it.
+        if self.checksums {
+            self.checksum_sent = true;
+        }
+        Ok(buf)
+    }
+
+    /// Enables compression of the sent messages if the peer supports it as well. If the
+    /// compression used by the peer is not known from the handshake, messages are compressed
+    /// after the first compressed frame is received.
+    pub fn with_compression(
+        mut self,
+        compression: CompressionKind,
+        remote: CompressionKind,
+    ) -> Self {
+        self.compression = compression;
+        self.compress_sent = compression == CompressionKind::Lz4 && remote == CompressionKind::Lz4;
+        self
+    }
+
+    fn compress(raw: &[u8]) -> Result<Option<Vec<u8>>, failure::Error> {
+        let compressed = lz4::block::compress(raw, None, false)?;
+        if compressed.len() + COMPRESSED_HEADER_LENGTH >= raw.len() {
             return Ok(None);
         }
 
-        let mut buf = self.session.decrypt_msg(len, buf)?;
+        let mut frame = vec![0_u8; COMPRESSED_HEADER_LENGTH];
+        frame[0] = MessageType::Compressed.as_byte();
+        LittleEndian::write_u32(&mut frame[1..COMPRESSED_HEADER_LENGTH], raw.len() as u32);
+        frame.extend_from_slice(&compressed);
+        Ok(Some(frame))
+    }
 
-        if buf[0] != 0 {
-            bail!("A first byte of the message must be set to 0");
+    fn decompress(&self, frame: &[u8]) -> Result<BytesMut, failure::Error> {
+        if frame.len() < COMPRESSED_HEADER_LENGTH {
+            bail!("Received malformed compressed frame of length {}", frame.len());
         }
 
-        // Check payload len
-        let total_len = LittleEndian::read_u32(&buf[6..10]) as usize;
+        // Check the length before allocating the buffer for the decompressed message.
+        let len = LittleEndian::read_u32(&frame[1..COMPRESSED_HEADER_LENGTH]) as usize;
+        if len > self.max_message_len as usize {
+            return Err(DecodeError::FrameTooLong(len, self.max_message_len as usize).into());
+        }
 
-        if total_len as u32 > self.max_message_len {
-            bail!(
-                "Received message is too long: {}, maximum allowed length is {} bytes",
-                total_len,
-                self.max_message_len,
-            );
+        let data = lz4::block::decompress(&frame[COMPRESSED_HEADER_LENGTH..], Some(len as i32))?;
+        Ok(BytesMut::from(data))
+    }
+
+    /// Parses the decrypted contents of a frame, decompressing them if necessary.
+    fn parse_frame(&mut self, buf: BytesMut) -> Result<Frame, failure::Error> {
+        if MessageType::from_byte(buf[0]) != Some(MessageType::Compressed) {
+            return self.format.decode_frame(buf, self.max_message_len);
         }
 
-        if total_len < HEADER_LENGTH {
-            bail!(
-                "Received malicious message with insufficient \
-                 size in header: {}, expected header size {}",
-                total_len,
-                HEADER_LENGTH
-            );
+        let buf = self.decompress(&buf)?;
+        let frame = self.format.decode_frame(buf, self.max_message_len)?;
+        if frame.message_type() != MessageType::Message {
+            bail!("Received compressed {:?} frame", frame.message_type());
+        }
+        // The peer supports compression, so we can compress messages sent to it.
+        if self.compression == CompressionKind::Lz4 {
+            self.compress_sent = true;
         }
+        Ok(frame)
+    }
+}
 
-        if total_len != buf.len() {
-            bail!(
-                "Received malicious message with wrong \
-                 total_len: {}, expected message length {}",
-                total_len,
-                buf.len()
-            );
+impl Decoder for MessagesCodec {
+    type Item = Frame;
+    type Error = failure::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read header
+        if buf.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        let len = LittleEndian::read_u32(buf) as usize;
+
+        // Reject the frame before its contents are buffered.
+        let max_len = encrypted_msg_len(self.max_message_len as usize);
+        if len > max_len {
+            return Err(DecodeError::FrameTooLong(len, max_len).into());
         }
 
-        let data = buf.split_to(total_len).to_vec();
-        let raw = RawMessage::new(MessageBuffer::from_vec(data));
-        Ok(Some(raw))
+        let frame_len = len + NOISE_HEADER_LENGTH;
+        if buf.len() < frame_len {
+            // The partial frame stays in the buffer; the room for the rest of it is reserved
+            // at once, so that the buffer does not grow with each read.
+            let missing = frame_len - buf.len();
+            buf.reserve(missing);
+            return Ok(None);
+        }
+
+        let decrypted = self.session.decrypt_msg(len, buf);
+        if frame_len > READ_BUFFER_HIGH_WATER_MARK {
+            shrink_read_buffer(buf);
+        }
+        let buf = decrypted?;
+        // The frame has been consumed, so the next one can be decoded regardless of the result.
+        self.strip_checksum(buf)
+            .and_then(|buf| self.parse_frame(buf))
+            .map(Some)
+            .map_err(|e| match e.downcast::<DecodeError>() {
+                Ok(error @ DecodeError::ChecksumMismatch { .. }) => error.into(),
+                Ok(error) => DecodeError::MalformedFrame(error.to_string()).into(),
+                Err(e) => DecodeError::MalformedFrame(e.to_string()).into(),
+            })
     }
 
     fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
@@ -105,36 +609,346 @@ impl Decoder for MessagesCodec {
     }
 }
 
+/// Moves the bytes following a large frame into a new buffer, releasing the memory
+/// taken by the frame.
+fn shrink_read_buffer(buf: &mut BytesMut) {
+    let mut shrunk = BytesMut::with_capacity(cmp::max(buf.len(), READ_BUFFER_CAPACITY));
+    shrunk.extend_from_slice(buf);
+    *buf = shrunk;
+}
+
 impl Encoder for MessagesCodec {
-    type Item = RawMessage;
+    type Item = Frame;
     type Error = failure::Error;
 
-    fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        self.session.encrypt_msg(msg.as_ref(), buf)?;
+    fn encode(&mut self, frame: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        let is_message = frame.message_type() == MessageType::Message;
+        let encoded = self.format.encode_frame(&frame)?;
+        let encoded = if is_message && self.compress_sent && encoded.len() >= MIN_COMPRESSED_LEN {
+            match Self::compress(&encoded)? {
+                Some(compressed) => Cow::Owned(compressed),
+                None => encoded,
+            }
+        } else {
+            encoded
+        };
+        if self.checksum_sent {
+            self.session
+                .encrypt_msg(&Self::append_checksum(&encoded), buf)?
+        } else {
+            self.session.encrypt_msg(&encoded, buf)?
+        }
         Ok(())
     }
 }
 
 #[cfg(test)]
-mod test {
+pub(super) mod test {
     use bytes::BytesMut;
     use failure;
     use tokio_io::codec::{Decoder, Encoder};
 
-    use super::MessagesCodec;
-    use events::noise::{HandshakeParams, NoiseWrapper};
-    use messages::{MessageBuffer, RawMessage};
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use std::sync::Arc;
+
+    use super::{
+        CompressionKind, Frame, FrameCodec, JsonFrameCodec, MessagesCodec, AUTH_NONCE_LENGTH,
+        READ_BUFFER_CAPACITY,
+    };
+    use crypto::{Signature, SIGNATURE_LENGTH};
+    use events::{
+        error::DecodeError, noise::{HandshakeParams, NoiseWrapper},
+    };
+    use messages::{MessageBuffer, RawMessage, HEADER_LENGTH};
 
     #[test]
     fn decode_message_valid_header_size() {
         let data = vec![0_u8, 0, 0, 0, 0, 0, 10, 0, 0, 0];
 
         match get_decoded_message(&data) {
-            Ok(Some(ref message)) if *message.as_ref() == data[..] => {}
+            Ok(Some(Frame::Message(ref message))) if *message.as_ref() == data[..] => {}
             _ => panic!("Wrong input"),
         };
     }
 
+    #[test]
+    fn decode_control_frames() {
+        let (ref mut responder, ref mut initiator) = create_encrypted_codecs();
+
+        let mut bytes: BytesMut = BytesMut::new();
+        initiator.encode(Frame::Ping, &mut bytes).unwrap();
+        initiator.encode(Frame::Pong, &mut bytes).unwrap();
+        initiator.encode(Frame::Version(0x0102), &mut bytes).unwrap();
+        initiator.encode(Frame::Credit(64), &mut bytes).unwrap();
+
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Ping));
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Pong));
+        assert_eq!(
+            responder.decode(&mut bytes).unwrap(),
+            Some(Frame::Version(0x0102))
+        );
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Credit(64)));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Received malformed Ping frame")]
+    fn decode_control_frame_with_payload() {
+        let data = vec![1_u8, 0, 0, 0, 0];
+
+        get_decoded_message(&data).unwrap();
+    }
+
+    #[test]
+    fn decode_frame_too_long() {
+        let (ref mut responder, _) = create_encrypted_codecs();
+
+        // Only the length prefix is sent, the frame itself is never received.
+        let mut bytes = BytesMut::from(vec![0xff_u8, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0]);
+        let error = responder.decode(&mut bytes).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::FrameTooLong(0xffff_ffff, 10_016))
+        );
+    }
+
+    fn compressible_message(len: usize) -> RawMessage {
+        let mut data = vec![0_u8; len];
+        LittleEndian::write_u32(&mut data[6..10], len as u32);
+        RawMessage::new(MessageBuffer::from_vec(data))
+    }
+
+    #[test]
+    fn frame_read_byte_by_byte_is_reassembled() {
+        let (ref mut responder, ref mut initiator) = create_encrypted_codecs();
+        let message = compressible_message(1_000);
+        let mut encoded = BytesMut::new();
+        initiator.encode(message.clone().into(), &mut encoded).unwrap();
+
+        let mut bytes = BytesMut::new();
+        let mut capacity = None;
+        for &byte in &encoded[..encoded.len() - 1] {
+            bytes.extend_from_slice(&[byte]);
+            assert_eq!(responder.decode(&mut bytes).unwrap(), None);
+            // Once the length of the frame is known, the buffer is not grown anymore.
+            if bytes.len() >= HEADER_LENGTH {
+                let reserved = *capacity.get_or_insert(bytes.capacity());
+                assert!(reserved >= encoded.len());
+                assert_eq!(bytes.capacity(), reserved);
+            }
+        }
+        assert_eq!(bytes.len(), encoded.len() - 1);
+
+        bytes.extend_from_slice(&encoded[encoded.len() - 1..]);
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Message(message)));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn read_buffer_is_shrunk_after_large_frame() {
+        let (ref mut responder, ref mut initiator) = create_encrypted_codecs_with_max_len(200_000);
+        let large = compressible_message(100_000);
+        let small = compressible_message(100);
+        let mut bytes = BytesMut::new();
+        initiator.encode(large.clone().into(), &mut bytes).unwrap();
+        initiator.encode(small.clone().into(), &mut bytes).unwrap();
+
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Message(large)));
+        assert_eq!(bytes.capacity(), READ_BUFFER_CAPACITY);
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Message(small)));
+    }
+
+    #[test]
+    fn compressed_frames_round_trip() {
+        let (responder, initiator) = create_encrypted_codecs();
+        let mut responder = responder.with_compression(CompressionKind::Lz4, CompressionKind::Lz4);
+        let mut initiator = initiator.with_compression(CompressionKind::Lz4, CompressionKind::Lz4);
+
+        let large = compressible_message(5_000);
+        let small = compressible_message(100);
+
+        let mut bytes = BytesMut::new();
+        initiator.encode(large.clone().into(), &mut bytes).unwrap();
+        assert!(bytes.len() < large.len());
+        initiator.encode(small.clone().into(), &mut bytes).unwrap();
+
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Message(large)));
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Message(small)));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn frames_round_trip_through_json_codec() {
+        let (responder, initiator) = create_encrypted_codecs();
+        let mut responder = responder.with_frame_codec(Arc::new(JsonFrameCodec));
+        let mut initiator = initiator.with_frame_codec(Arc::new(JsonFrameCodec));
+
+        let signature = Signature::from_slice(&[7; SIGNATURE_LENGTH]).unwrap();
+        let frames = vec![
+            Frame::Message(compressible_message(100)),
+            Frame::Ping,
+            Frame::Pong,
+            Frame::Version(0x0102),
+            Frame::Challenge([5; AUTH_NONCE_LENGTH]),
+            Frame::ChallengeResponse(signature),
+            Frame::Credit(64),
+        ];
+        let mut bytes = BytesMut::new();
+        for frame in frames.clone() {
+            initiator.encode(frame, &mut bytes).unwrap();
+        }
+        for frame in frames {
+            assert_eq!(responder.decode(&mut bytes).unwrap(), Some(frame));
+        }
+        assert!(bytes.is_empty());
+
+        let ping = JsonFrameCodec.encode_frame(&Frame::Ping).unwrap();
+        assert_eq!(&ping[..], &br#"{"type":"ping"}"#[..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Received malicious message with insufficient size")]
+    fn decode_json_message_shorter_than_header() {
+        let data = vec![0_u8, 0, 0, 0, 0, 0, 5, 0];
+
+        get_decoded_json_message(&data).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Received malicious message with wrong total_len")]
+    fn decode_json_message_wrong_length() {
+        let data = vec![0_u8, 0, 0, 0, 0, 0, 11, 0, 0, 0];
+
+        get_decoded_json_message(&data).unwrap();
+    }
+
+    #[test]
+    fn compressed_json_frames_round_trip() {
+        let (responder, initiator) = create_encrypted_codecs();
+        let mut responder = responder
+            .with_frame_codec(Arc::new(JsonFrameCodec))
+            .with_compression(CompressionKind::Lz4, CompressionKind::Lz4);
+        let mut initiator = initiator
+            .with_frame_codec(Arc::new(JsonFrameCodec))
+            .with_compression(CompressionKind::Lz4, CompressionKind::Lz4);
+
+        let large = compressible_message(5_000);
+        let mut bytes = BytesMut::new();
+        initiator.encode(large.clone().into(), &mut bytes).unwrap();
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Message(large)));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn compression_is_enabled_by_compressed_frame() {
+        let (responder, initiator) = create_encrypted_codecs();
+        // The responder knows from the handshake that the initiator supports compression,
+        // but not vice versa.
+        let mut responder = responder.with_compression(CompressionKind::Lz4, CompressionKind::None);
+        let mut initiator = initiator.with_compression(CompressionKind::Lz4, CompressionKind::Lz4);
+        let message = compressible_message(5_000);
+
+        let mut bytes = BytesMut::new();
+        responder.encode(message.clone().into(), &mut bytes).unwrap();
+        assert!(bytes.len() > message.len());
+        assert_eq!(
+            initiator.decode(&mut bytes).unwrap(),
+            Some(Frame::Message(message.clone()))
+        );
+
+        initiator.encode(message.clone().into(), &mut bytes).unwrap();
+        responder.decode(&mut bytes).unwrap();
+        responder.encode(message.clone().into(), &mut bytes).unwrap();
+        assert!(bytes.len() < message.len());
+    }
+
+    #[test]
+    fn compression_disabled_on_one_side() {
+        let (responder, initiator) = create_encrypted_codecs();
+        let mut responder = responder.with_compression(CompressionKind::None, CompressionKind::Lz4);
+        let mut initiator = initiator.with_compression(CompressionKind::Lz4, CompressionKind::Lz4);
+        let message = compressible_message(5_000);
+
+        // The responder still decodes compressed frames, but never compresses its own.
+        let mut bytes = BytesMut::new();
+        initiator.encode(message.clone().into(), &mut bytes).unwrap();
+        assert_eq!(
+            responder.decode(&mut bytes).unwrap(),
+            Some(Frame::Message(message.clone()))
+        );
+        responder.encode(message.clone().into(), &mut bytes).unwrap();
+        assert!(bytes.len() > message.len());
+    }
+
+    #[test]
+    fn checksummed_frames_round_trip() {
+        let (responder, initiator) = create_encrypted_codecs();
+        let mut responder = responder.with_checksums(true, true);
+        let mut initiator = initiator.with_checksums(true, true);
+        let message = compressible_message(100);
+
+        let mut bytes = BytesMut::new();
+        initiator.encode(message.clone().into(), &mut bytes).unwrap();
+        initiator.encode(Frame::Ping, &mut bytes).unwrap();
+        assert_eq!(
+            responder.decode(&mut bytes).unwrap(),
+            Some(Frame::Message(message))
+        );
+        assert_eq!(responder.decode(&mut bytes).unwrap(), Some(Frame::Ping));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn checksum_detects_corrupted_frame() {
+        let (mut responder, mut initiator) = create_encrypted_codecs();
+        let message = compressible_message(100);
+
+        // The frame is corrupted before encryption, so the decryption succeeds.
+        let mut frame = MessagesCodec::append_checksum(message.as_ref());
+        frame[20] ^= 0x01;
+        let mut bytes = BytesMut::new();
+        initiator.session.encrypt_msg(&frame, &mut bytes).unwrap();
+        initiator.encode(message.clone().into(), &mut bytes).unwrap();
+
+        let error = responder.decode(&mut bytes).unwrap_err();
+        match error.downcast_ref::<DecodeError>() {
+            Some(&DecodeError::ChecksumMismatch { expected, actual }) => {
+                assert_ne!(expected, actual)
+            }
+            other => panic!("Unexpected error: {:?}", other),
+        }
+        // The stream of frames is not broken.
+        assert_eq!(
+            responder.decode(&mut bytes).unwrap(),
+            Some(Frame::Message(message))
+        );
+    }
+
+    #[test]
+    fn checksums_are_enabled_by_checksummed_frame() {
+        let (responder, initiator) = create_encrypted_codecs();
+        // The responder knows from the handshake that the initiator supports checksums,
+        // but not vice versa; the older peers support neither.
+        let mut responder = responder.with_checksums(true, true);
+        let mut initiator = initiator.with_checksums(true, false);
+        let mut older = create_encrypted_codecs().1;
+        let message = compressible_message(100);
+
+        let mut bytes = BytesMut::new();
+        older.encode(message.clone().into(), &mut bytes).unwrap();
+        let plain_len = bytes.len();
+        bytes.clear();
+        initiator.encode(message.clone().into(), &mut bytes).unwrap();
+        assert_eq!(bytes.len(), plain_len);
+        responder.decode(&mut bytes).unwrap();
+
+        responder.encode(message.clone().into(), &mut bytes).unwrap();
+        initiator.decode(&mut bytes).unwrap();
+        initiator.encode(message.clone().into(), &mut bytes).unwrap();
+        assert_eq!(bytes.len(), plain_len + 5);
+    }
+
     #[test]
     fn decode_message_small_size_in_header() {
         let data = vec![0_u8, 0, 0, 0, 0, 0, 0, 0, 0, 0];
@@ -173,11 +987,11 @@ mod test {
         let raw = RawMessage::new(MessageBuffer::from_vec(data.clone()));
 
         let mut bytes: BytesMut = BytesMut::new();
-        initiator.encode(raw.clone(), &mut bytes).unwrap();
-        initiator.encode(raw, &mut bytes).unwrap();
+        initiator.encode(raw.clone().into(), &mut bytes).unwrap();
+        initiator.encode(raw.into(), &mut bytes).unwrap();
 
         match responder.decode_eof(&mut bytes.clone()) {
-            Ok(Some(ref message)) if *message.as_ref() == data[..] => {}
+            Ok(Some(Frame::Message(ref message))) if *message.as_ref() == data[..] => {}
             _ => panic!("Wrong input"),
         };
 
@@ -189,17 +1003,35 @@ mod test {
         assert!(responder.decode_eof(&mut bytes).unwrap().is_none());
     }
 
-    fn get_decoded_message(data: &[u8]) -> Result<Option<RawMessage>, failure::Error> {
+    fn get_decoded_message(data: &[u8]) -> Result<Option<Frame>, failure::Error> {
         let (ref mut responder, ref mut initiator) = create_encrypted_codecs();
         let raw = RawMessage::new(MessageBuffer::from_vec(data.to_vec()));
 
         let mut bytes: BytesMut = BytesMut::new();
-        initiator.encode(raw, &mut bytes).unwrap();
+        initiator.encode(raw.into(), &mut bytes).unwrap();
 
         responder.decode(&mut bytes)
     }
 
-    fn create_encrypted_codecs() -> (MessagesCodec, MessagesCodec) {
+    fn get_decoded_json_message(data: &[u8]) -> Result<Option<Frame>, failure::Error> {
+        let (responder, initiator) = create_encrypted_codecs();
+        let mut responder = responder.with_frame_codec(Arc::new(JsonFrameCodec));
+        let mut initiator = initiator.with_frame_codec(Arc::new(JsonFrameCodec));
+        let raw = RawMessage::new(MessageBuffer::from_vec(data.to_vec()));
+
+        let mut bytes: BytesMut = BytesMut::new();
+        initiator.encode(raw.into(), &mut bytes).unwrap();
+
+        responder.decode(&mut bytes)
+    }
+
+    pub fn create_encrypted_codecs() -> (MessagesCodec, MessagesCodec) {
+        create_encrypted_codecs_with_max_len(10000)
+    }
+
+    pub fn create_encrypted_codecs_with_max_len(
+        max_message_len: u32,
+    ) -> (MessagesCodec, MessagesCodec) {
         let params = HandshakeParams::with_default_params();
 
         let mut initiator = NoiseWrapper::initiator(&params).session;
@@ -235,15 +1067,8 @@ mod test {
             session: initiator.into_transport_mode().unwrap(),
         };
 
-        let responder_codec = MessagesCodec {
-            max_message_len: 10000,
-            session: initiator,
-        };
-
-        let initiator_codec = MessagesCodec {
-            max_message_len: 10000,
-            session: responder,
-        };
+        let responder_codec = MessagesCodec::new(max_message_len, initiator);
+        let initiator_codec = MessagesCodec::new(max_message_len, responder);
 
         (responder_codec, initiator_codec)
     }
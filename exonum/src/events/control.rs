@@ -0,0 +1,151 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local control channel of the node over a UNIX domain socket, see
+//! `NetworkConfiguration::control_socket`.
+//!
+//! Each line written to the socket is a JSON-encoded `ControlMessage`, e.g., `"shutdown"`
+//! or `{"enable":false}`, which is delivered to the handler as an `ExternalMessage`.
+//! Malformed lines are logged and skipped. Access to the channel is controlled by
+//! the permissions of the socket file.
+
+use futures::{sync::mpsc, Future, Sink, Stream};
+use serde_json;
+use tokio::net::UnixListener;
+use tokio_codec::{FramedRead, LinesCodec};
+use tokio_core::reactor::Handle;
+
+use std::{fs, io, path::PathBuf};
+
+use node::{ConnectInfo, ExternalMessage};
+
+/// Administrative message accepted by the control socket.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// Adds a new connection, see `ExternalMessage::PeerAdd`.
+    PeerAdd(ConnectInfo),
+    /// Enables or disables the node, see `ExternalMessage::Enable`.
+    Enable(bool),
+    /// Shuts down the node, see `ExternalMessage::Shutdown`.
+    Shutdown,
+    /// Rebroadcasts transactions from the pool, see `ExternalMessage::Rebroadcast`.
+    Rebroadcast,
+}
+
+impl From<ControlMessage> for ExternalMessage {
+    fn from(message: ControlMessage) -> Self {
+        match message {
+            ControlMessage::PeerAdd(info) => ExternalMessage::PeerAdd(info),
+            ControlMessage::Enable(enabled) => ExternalMessage::Enable(enabled),
+            ControlMessage::Shutdown => ExternalMessage::Shutdown,
+            ControlMessage::Rebroadcast => ExternalMessage::Rebroadcast,
+        }
+    }
+}
+
+/// Listener of the control socket feeding the api messages of the handler.
+#[derive(Debug)]
+pub struct ControlPart {
+    pub path: PathBuf,
+    pub api_tx: mpsc::Sender<ExternalMessage>,
+}
+
+impl ControlPart {
+    pub fn new<P: Into<PathBuf>>(path: P, api_tx: mpsc::Sender<ExternalMessage>) -> Self {
+        ControlPart {
+            path: path.into(),
+            api_tx,
+        }
+    }
+
+    /// Binds the socket, replacing a stale one left at the path by a previous run, and returns
+    /// the future accepting connections to it. Each connection is served by a separate task
+    /// spawned on the given handle.
+    pub fn run(self, handle: &Handle) -> io::Result<impl Future<Item = (), Error = ()>> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        let listener = UnixListener::bind(&self.path)?;
+        info!("Listening for control messages on {}", self.path.display());
+
+        let handle = handle.clone();
+        let api_tx = self.api_tx;
+        let server = listener
+            .incoming()
+            .map_err(|e| error!("Control socket failed: {}", e))
+            .for_each(move |stream| {
+                let connection = FramedRead::new(stream, LinesCodec::new())
+                    .map_err(|e| warn!("Control connection failed: {}", e))
+                    .filter_map(|line| parse_line(&line))
+                    .map(ExternalMessage::from)
+                    .forward(api_tx.clone().sink_map_err(drop))
+                    .map(drop);
+                handle.spawn(connection);
+                Ok(())
+            });
+        Ok(server)
+    }
+}
+
+fn parse_line(line: &str) -> Option<ControlMessage> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    match serde_json::from_str(line) {
+        Ok(message) => Some(message),
+        Err(e) => {
+            warn!("Malformed control message {:?}: {}", line, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    use std::{io::Write, os::unix::net::UnixStream, thread};
+
+    use super::*;
+
+    #[test]
+    fn control_messages_are_delivered() {
+        let dir = TempDir::new("exonum_control").unwrap();
+        let path = dir.path().join("control.sock");
+        let (api_tx, api_rx) = mpsc::channel(4);
+
+        let mut core = Core::new().unwrap();
+        let server = ControlPart::new(path.clone(), api_tx)
+            .run(&core.handle())
+            .unwrap();
+        core.handle().spawn(server);
+
+        let client = thread::spawn(move || {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream
+                .write_all(b"\"rebroadcast\"\nnot a message\n\n{\"enable\": false}\n")
+                .unwrap();
+        });
+        let messages = core.run(api_rx.take(2).collect()).unwrap();
+        client.join().unwrap();
+
+        match messages[..] {
+            [ExternalMessage::Rebroadcast, ExternalMessage::Enable(false)] => {}
+            ref other => panic!("Unexpected messages: {:?}", other),
+        }
+    }
+}
@@ -14,10 +14,172 @@
 
 // These functions transform source error types into other.
 #![cfg_attr(feature="cargo-clippy", allow(needless_pass_by_value))]
+// Workaround for `failure` see https://github.com/rust-lang-nursery/failure/issues/223 and
+// ECR-1771 for the details.
+#![allow(bare_trait_objects)]
 
 use failure::Error;
+use futures::sync::mpsc::TrySendError;
 
-use std::{error::Error as StdError, fmt::Display};
+use std::{error::Error as StdError, fmt::Display, io, time::Duration};
+
+use crypto::PublicKey;
+
+/// Error caused by a malformed frame received from a peer.
+#[derive(Fail, Debug, PartialEq)]
+pub enum DecodeError {
+    /// Length prefix of the frame exceeds the limit. The frame is rejected
+    /// before its contents are buffered.
+    #[fail(
+        display = "Received frame is too long: {}, maximum allowed length is {} bytes",
+        _0,
+        _1
+    )]
+    FrameTooLong(usize, usize),
+    /// Frame has been received in full, but its contents are malformed. Unlike other errors,
+    /// this one does not break the stream of frames, so the connection may proceed.
+    #[fail(display = "{}", _0)]
+    MalformedFrame(String),
+    /// Checksum of the received frame does not match its contents, i.e., the frame has been
+    /// corrupted. The stream of frames is not broken either.
+    #[fail(
+        display = "Checksum of the received frame is {:08x}, expected {:08x}",
+        actual,
+        expected
+    )]
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+/// Error which terminates a connection with a peer using an incompatible protocol version.
+#[derive(Fail, Debug, PartialEq)]
+#[fail(
+    display = "Protocol version {} of the peer is not in the supported range {}..={}",
+    version,
+    min,
+    max
+)]
+pub struct IncompatibleVersion {
+    pub version: u32,
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Error which terminates a connection with a peer which has not proven the ownership
+/// of the key announced in its `Connect` message.
+#[derive(Fail, Debug, PartialEq)]
+#[fail(display = "Peer has not proven the ownership of the key {:?}", _0)]
+pub struct AuthenticationFailed(pub PublicKey);
+
+/// Error which terminates a connection whose peer keeps sending messages above the rate limit.
+#[derive(Fail, Debug, PartialEq)]
+#[fail(display = "Peer has been exceeding the messages rate limit for {:?}", _0)]
+pub struct RateLimitExceeded(pub Duration);
+
+/// Error which terminates a connection whose peer sends more messages than it has been granted
+/// credits for, see `NetworkConfiguration::flow_control_window`.
+#[derive(Fail, Debug, PartialEq)]
+#[fail(display = "Peer has exceeded the flow control window of {} messages", _0)]
+pub struct FlowControlViolation(pub u32);
+
+/// Error which terminates a connection whose peer does not answer pings.
+#[derive(Fail, Debug)]
+#[fail(display = "Peer has not answered a ping in {:?}", _0)]
+pub struct PingTimeout(pub Duration);
+
+/// Error which terminates a connection whose peer does not accept written frames.
+#[derive(Fail, Debug)]
+#[fail(display = "Peer has not accepted written frames in {:?}", _0)]
+pub struct WriteTimeout(pub Duration);
+
+/// Category of the failure to connect to a peer, with which a connection attempt fails.
+/// Dials failing with any category but `HandshakeRejected` are repeated according to
+/// the reconnection policy; the handshake is not repeated, as the peer has refused it.
+#[derive(Fail, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectError {
+    /// Endpoint of the peer cannot be resolved into addresses.
+    #[fail(display = "Endpoint of the peer cannot be resolved")]
+    Resolve,
+    /// Peer does not accept connections at the address.
+    #[fail(display = "Connection has been refused by the peer")]
+    Refused,
+    /// Connection has been reset or closed by the peer before the handshake completed.
+    #[fail(display = "Connection has been reset by the peer")]
+    Reset,
+    /// Peer has failed the handshake, e.g., because it is not in the `ConnectList`
+    /// or uses an incompatible protocol version.
+    #[fail(display = "Handshake with the peer has been rejected")]
+    HandshakeRejected,
+    /// Peer has not answered in `connect_timeout`.
+    #[fail(display = "Connection attempt has timed out")]
+    Timeout,
+    /// Any other error, e.g., the network is unreachable.
+    #[fail(display = "Connection attempt has failed")]
+    Other,
+}
+
+impl ConnectError {
+    /// Classifies the OS error of dialing a peer.
+    pub fn from_io(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => ConnectError::Refused,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => ConnectError::Reset,
+            io::ErrorKind::TimedOut => ConnectError::Timeout,
+            _ => ConnectError::Other,
+        }
+    }
+
+    /// Classifies the error of the handshake with a peer: OS errors are classified
+    /// by `from_io`, and the others mean that the peer has been rejected.
+    pub fn from_handshake(error: &Error) -> Self {
+        match error.downcast_ref::<io::Error>() {
+            Some(error) => Self::from_io(error),
+            None => ConnectError::HandshakeRejected,
+        }
+    }
+
+    /// Returns `true` if connecting to the peer should be attempted again.
+    pub fn is_retriable(self) -> bool {
+        self != ConnectError::HandshakeRejected
+    }
+}
+
+/// Unrecoverable error of the event handler, which stops the event loop.
+#[derive(Fail, Debug, PartialEq)]
+#[fail(display = "Event handler failed: {}", _0)]
+pub struct HandlerError(pub String);
+
+impl HandlerError {
+    /// Creates an error with the given description.
+    pub fn new<T: Display>(reason: T) -> Self {
+        HandlerError(reason.to_string())
+    }
+}
+
+/// Error of delivering a network event to the event handler.
+#[derive(Fail, Debug, PartialEq)]
+pub enum EventsChannelError {
+    /// Events channel is full; the reading from the peer should be paused until
+    /// the handler catches up.
+    #[fail(display = "Network events channel is full")]
+    Full,
+    /// Event handler is gone; the network part should be shut down.
+    #[fail(display = "Network events receiver is gone")]
+    Closed,
+}
+
+impl EventsChannelError {
+    /// Classifies the error of sending an event into the channel.
+    pub fn from_try_send<T>(error: &TrySendError<T>) -> Self {
+        if error.is_full() {
+            EventsChannelError::Full
+        } else {
+            EventsChannelError::Closed
+        }
+    }
+}
 
 pub fn result_ok<T>(_: T) -> Result<(), Error> {
     Ok(())
@@ -0,0 +1,196 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Credit-based flow control of the messages exchanged with a peer, see
+//! `NetworkConfiguration::flow_control_window`.
+//!
+//! The receiving side grants the peer a window of credits with `Frame::Credit` right after
+//! the connection is established; each message sent by the peer uses up a credit. Credits are
+//! granted back in batches as the received messages are handed over to the handler. The
+//! sending side writes messages only while it has credits; until the peer grants any,
+//! sending is not limited, since the peer may not use flow control at all.
+
+use futures::{
+    task::{self, Task}, Async, Poll, Stream,
+};
+
+use std::{cell::RefCell, rc::Rc};
+
+use events::error::FlowControlViolation;
+
+/// Credits granted to the peer by the receiving side of a connection.
+#[derive(Debug)]
+pub struct ReceiveWindow {
+    window: u32,
+    // Credits granted to the peer and not used up yet.
+    granted: u32,
+    // Messages handed over to the handler since the credits were last granted back.
+    consumed: u32,
+}
+
+impl ReceiveWindow {
+    /// Creates a window, whose credits should be granted to the peer with `Frame::Credit`.
+    pub fn new(window: u32) -> Self {
+        ReceiveWindow {
+            window: window.max(1),
+            granted: window.max(1),
+            consumed: 0,
+        }
+    }
+
+    /// Returns the number of credits initially granted to the peer.
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// Uses up a credit for a message received from the peer.
+    pub fn message_received(&mut self) -> Result<(), FlowControlViolation> {
+        if self.granted == 0 {
+            return Err(FlowControlViolation(self.window));
+        }
+        self.granted -= 1;
+        Ok(())
+    }
+
+    /// Accounts a message handed over to the handler. Returns the number of credits
+    /// to grant back to the peer once a half of the window is consumed.
+    pub fn message_consumed(&mut self) -> Option<u32> {
+        self.consumed += 1;
+        if self.consumed < (self.window / 2).max(1) {
+            return None;
+        }
+        let credit = self.consumed;
+        self.granted += credit;
+        self.consumed = 0;
+        Some(credit)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // `None` until the peer grants any credits.
+    available: Option<u64>,
+    task: Option<Task>,
+}
+
+/// Credits granted by the peer to the sending side of a connection.
+#[derive(Debug, Clone, Default)]
+pub struct SendCredits {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl SendCredits {
+    /// Adds the credits received from the peer with `Frame::Credit`, resuming the sending.
+    pub fn grant(&self, credit: u32) {
+        let mut inner = self.inner.borrow_mut();
+        let available = inner.available.unwrap_or(0) + u64::from(credit);
+        inner.available = Some(available);
+        if let Some(task) = inner.task.take() {
+            task.notify();
+        }
+    }
+
+    /// Returns the number of the available credits, or `None` if the sending is not limited.
+    pub fn available(&self) -> Option<u64> {
+        self.inner.borrow().available
+    }
+}
+
+/// Stream of the messages to send, which is paused while there are no credits.
+#[derive(Debug)]
+pub struct Credited<S> {
+    stream: S,
+    credits: SendCredits,
+}
+
+impl<S> Credited<S> {
+    pub fn new(stream: S, credits: SendCredits) -> Self {
+        Credited { stream, credits }
+    }
+}
+
+impl<S: Stream> Stream for Credited<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let mut inner = self.credits.inner.borrow_mut();
+        if inner.available == Some(0) {
+            inner.task = Some(task::current());
+            return Ok(Async::NotReady);
+        }
+        match self.stream.poll()? {
+            Async::Ready(Some(item)) => {
+                if let Some(ref mut available) = inner.available {
+                    *available -= 1;
+                }
+                Ok(Async::Ready(Some(item)))
+            }
+            polled => Ok(polled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Async, Future, Stream};
+
+    use super::{Credited, ReceiveWindow, SendCredits};
+    use events::{error::FlowControlViolation, outgoing::queue, tests::raw_message};
+    use messages::BLOCK_RESPONSE_MESSAGE_ID;
+
+    #[test]
+    fn sending_stops_without_credits() {
+        let (sender, receiver) = queue(8);
+        let messages: Vec<_> = (0..4)
+            .map(|i| raw_message(BLOCK_RESPONSE_MESSAGE_ID + i, 10))
+            .collect();
+        for message in &messages {
+            sender.push(message.clone());
+        }
+
+        let credits = SendCredits::default();
+        let mut stream = Credited::new(receiver, credits.clone());
+        let mut poll = move || future::lazy(|| stream.poll()).wait().unwrap();
+
+        // The peer has not granted any credits yet.
+        assert_eq!(poll(), Async::Ready(Some(messages[0].clone())));
+        credits.grant(1);
+        assert_eq!(poll(), Async::Ready(Some(messages[1].clone())));
+        assert_eq!(credits.available(), Some(0));
+        assert_eq!(poll(), Async::NotReady);
+        assert_eq!(poll(), Async::NotReady);
+
+        credits.grant(2);
+        assert_eq!(poll(), Async::Ready(Some(messages[2].clone())));
+        assert_eq!(poll(), Async::Ready(Some(messages[3].clone())));
+        assert_eq!(credits.available(), Some(1));
+    }
+
+    #[test]
+    fn credits_are_granted_back_as_messages_are_consumed() {
+        let mut window = ReceiveWindow::new(4);
+        assert_eq!(window.window(), 4);
+        for _ in 0..4 {
+            window.message_received().unwrap();
+        }
+        assert_eq!(window.message_received(), Err(FlowControlViolation(4)));
+
+        assert_eq!(window.message_consumed(), None);
+        assert_eq!(window.message_consumed(), Some(2));
+        window.message_received().unwrap();
+        window.message_received().unwrap();
+        assert_eq!(window.message_received(), Err(FlowControlViolation(4)));
+    }
+}
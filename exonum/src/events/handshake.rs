@@ -0,0 +1,197 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Negotiation of the protocol version and authentication of the peer, performed right
+//! after the secure connection with the peer is established.
+
+use failure;
+use futures::{Future, Sink, Stream};
+use rand::{self, RngCore};
+use tokio_codec::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use crypto::{self, PublicKey, SecretKey};
+use events::{
+    codec::{Frame, MessagesCodec, AUTH_NONCE_LENGTH, PROTOCOL_VERSION},
+    error::{AuthenticationFailed, IncompatibleVersion}, network::NetworkConfiguration,
+};
+
+/// Prefix of the signed challenge, which keeps the signature from being valid
+/// for anything else, e.g., for an Exonum message.
+const CHALLENGE_CONTEXT: &[u8] = b"exonum-peer-auth";
+
+/// Checks that the protocol version of the peer lies in the range allowed by the configuration.
+pub fn check_version(
+    version: u32,
+    network_config: &NetworkConfiguration,
+) -> Result<(), IncompatibleVersion> {
+    let min = network_config.min_protocol_version;
+    let max = network_config.max_protocol_version;
+    if version < min || version > max {
+        Err(IncompatibleVersion { version, min, max })
+    } else {
+        Ok(())
+    }
+}
+
+/// Sends our protocol version to the peer and waits for the version of the peer.
+/// Fails if the versions are incompatible or the peer sends another frame first.
+pub fn exchange_versions<S>(
+    socket: Framed<S, MessagesCodec>,
+    network_config: &NetworkConfiguration,
+) -> impl Future<Item = Framed<S, MessagesCodec>, Error = failure::Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let network_config = *network_config;
+    socket
+        .send(Frame::Version(PROTOCOL_VERSION))
+        .and_then(|socket| socket.into_future().map_err(|(e, _)| e))
+        .and_then(move |(frame, socket)| match frame {
+            Some(Frame::Version(version)) => {
+                check_version(version, &network_config)?;
+                Ok(socket)
+            }
+            Some(frame) => bail!(
+                "Expected the protocol version from the peer, got {:?} frame",
+                frame.message_type()
+            ),
+            None => bail!("Connection closed before the protocol version is received"),
+        })
+}
+
+/// Proves to the peer that we own the key announced in our `Connect` message and checks
+/// that the peer owns `peer_key`: each side signs a random nonce sent by the other one.
+/// Fails with `AuthenticationFailed` if the signature of the peer is invalid.
+pub fn authenticate<S>(
+    socket: Framed<S, MessagesCodec>,
+    peer_key: PublicKey,
+    signing_key: &SecretKey,
+) -> impl Future<Item = Framed<S, MessagesCodec>, Error = failure::Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let signing_key = signing_key.clone();
+    let mut nonce = [0; AUTH_NONCE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    socket
+        .send(Frame::Challenge(nonce))
+        .and_then(|socket| socket.into_future().map_err(|(e, _)| e))
+        .and_then(move |(frame, socket)| match frame {
+            Some(Frame::Challenge(peer_nonce)) => {
+                let signature = crypto::sign(&challenge_data(&peer_nonce), &signing_key);
+                Ok(socket.send(Frame::ChallengeResponse(signature)))
+            }
+            frame => Err(unexpected_frame(frame, "challenge")),
+        })
+        .flatten()
+        .and_then(|socket| socket.into_future().map_err(|(e, _)| e))
+        .and_then(move |(frame, socket)| match frame {
+            Some(Frame::ChallengeResponse(signature)) => {
+                if crypto::verify(&signature, &challenge_data(&nonce), &peer_key) {
+                    Ok(socket)
+                } else {
+                    Err(AuthenticationFailed(peer_key).into())
+                }
+            }
+            frame => Err(unexpected_frame(frame, "challenge response")),
+        })
+}
+
+fn challenge_data(nonce: &[u8]) -> Vec<u8> {
+    let mut data = CHALLENGE_CONTEXT.to_vec();
+    data.extend_from_slice(nonce);
+    data
+}
+
+fn unexpected_frame(frame: Option<Frame>, expected: &str) -> failure::Error {
+    match frame {
+        Some(frame) => format_err!(
+            "Expected the {} from the peer, got {:?} frame",
+            expected,
+            frame.message_type()
+        ),
+        None => format_err!("Connection closed before the {} is received", expected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_codec::Decoder;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use crypto::gen_keypair;
+    use events::{codec::test::create_encrypted_codecs, transport::MemoryStream};
+
+    fn authenticate_pair(claimed_key: PublicKey, signing_key: &SecretKey) -> bool {
+        let (verifier_key, verifier_secret) = gen_keypair();
+        let (verifier_codec, peer_codec) = create_encrypted_codecs();
+        let (verifier_stream, peer_stream) = MemoryStream::pair();
+
+        let mut core = Core::new().unwrap();
+        let peer = authenticate(peer_codec.framed(peer_stream), verifier_key, signing_key);
+        core.handle().spawn(peer.map(drop).map_err(drop));
+        let verifier = authenticate(
+            verifier_codec.framed(verifier_stream),
+            claimed_key,
+            &verifier_secret,
+        );
+        match core.run(verifier) {
+            Ok(_) => true,
+            Err(e) => {
+                assert_eq!(
+                    e.downcast_ref::<AuthenticationFailed>(),
+                    Some(&AuthenticationFailed(claimed_key))
+                );
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn authenticate_accepts_valid_signature() {
+        let (public_key, secret_key) = gen_keypair();
+        assert!(authenticate_pair(public_key, &secret_key));
+    }
+
+    #[test]
+    fn authenticate_rejects_forged_signature() {
+        let (public_key, _) = gen_keypair();
+        let (_, forged_key) = gen_keypair();
+        assert!(!authenticate_pair(public_key, &forged_key));
+    }
+
+    #[test]
+    fn check_version_range() {
+        let config = NetworkConfiguration {
+            min_protocol_version: 2,
+            max_protocol_version: 3,
+            ..NetworkConfiguration::default()
+        };
+
+        assert!(check_version(2, &config).is_ok());
+        assert!(check_version(3, &config).is_ok());
+        assert_eq!(
+            check_version(1, &config),
+            Err(IncompatibleVersion {
+                version: 1,
+                min: 2,
+                max: 3,
+            })
+        );
+        assert!(check_version(4, &config).is_err());
+    }
+}
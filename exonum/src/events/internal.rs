@@ -13,27 +13,406 @@
 // limitations under the License.
 
 use futures::{
-    future::{self, Either, Executor}, sync::mpsc, Future, Sink, Stream,
+    future::{self, Either, Executor, Loop}, sync::{mpsc, oneshot}, task::{self, Task}, Async,
+    Future, Poll, Sink, Stream,
 };
 use tokio_core::reactor::{Handle, Timeout};
 
-use std::time::{Duration, SystemTime};
+use std::{
+    cell::RefCell, collections::{BTreeMap, BTreeSet, VecDeque}, fmt, rc::Rc, sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
-use super::{InternalEvent, InternalRequest, TimeoutRequest};
+use super::{
+    metrics::InternalMetrics, timer_wheel::TimerWheel,
+    watermark::{CountingSender, QueueDepth}, InternalEvent, InternalRequest, TimeoutRequest,
+};
 use blockchain::Transaction;
+use helpers::{Height, Round};
+use node::{EventsPoolCapacity, NodeTimeout};
+
+/// Timeouts which are scheduled and have neither fired nor been cancelled yet.
+type PendingTimeouts = Rc<RefCell<BTreeSet<TimeoutRequest>>>;
+
+/// Repeating timeouts, by their first requests, which have not been cancelled yet, along with
+/// the ids of their registrations. A repeating timeout keeps firing only while the id of its
+/// registration is in the map, so that it stops once cancelled even if an identical timeout
+/// is registered again afterwards.
+type RepeatingTimeouts = Rc<RefCell<BTreeMap<TimeoutRequest, u64>>>;
+
+/// Default maximum number of pending timeouts, see `InternalPart::timeouts_capacity`.
+const DEFAULT_TIMEOUTS_CAPACITY: usize = 4_096;
+
+/// Data structure keeping the pending timeouts until they fire.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutsTimer {
+    /// Each timeout is scheduled at its exact deadline.
+    Exact,
+    /// Timeouts are kept in a hierarchical timer wheel ticking with the given resolution.
+    /// Scheduling and firing a timeout take constant amortized time, which pays off
+    /// for thousands of pending timeouts, but the timeouts fire up to a tick late.
+    Wheel(Duration),
+}
+
+impl Default for TimeoutsTimer {
+    fn default() -> Self {
+        TimeoutsTimer::Exact
+    }
+}
+
+#[derive(Debug)]
+struct WheelTimeouts {
+    wheel: TimerWheel<TimeoutRequest>,
+    // Whether the wheel is being turned; it stops once there are no timeouts left.
+    turning: bool,
+}
+
+type SharedWheel = Rc<RefCell<WheelTimeouts>>;
+
+/// Snapshot of the wall-clock and monotonic time taken at the same moment, which converts
+/// deadlines between them.
+///
+/// Deadlines of `TimeoutRequest`s are wall-clock, since they are derived from e.g. the start
+/// of a round. Timeouts are scheduled at the corresponding `Instant`, so that adjusting
+/// the system clock after a timeout has been requested does not make it fire early or late.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSnapshot {
+    system: SystemTime,
+    instant: Instant,
+}
+
+impl ClockSnapshot {
+    /// Takes the snapshot of the current time.
+    pub fn now() -> Self {
+        Self::new(SystemTime::now(), Instant::now())
+    }
+
+    /// Creates the snapshot in which `system` and `instant` denote the same moment.
+    pub fn new(system: SystemTime, instant: Instant) -> Self {
+        ClockSnapshot { system, instant }
+    }
+
+    /// Converts the wall-clock deadline into the monotonic one. Deadlines in the past
+    /// are mapped to the moment of the snapshot.
+    pub fn to_instant(&self, time: SystemTime) -> Instant {
+        match time.duration_since(self.system) {
+            Ok(duration) => self.instant + duration,
+            Err(_) => self.instant,
+        }
+    }
+
+    /// Converts the monotonic deadline into the wall-clock one.
+    pub fn to_system_time(&self, instant: Instant) -> SystemTime {
+        if instant >= self.instant {
+            self.system + (instant - self.instant)
+        } else {
+            self.system - (self.instant - instant)
+        }
+    }
+}
+
+/// Source of the time used to schedule timeouts.
+pub trait Clock: fmt::Debug + Send {
+    /// Returns the current wall-clock time.
+    fn now(&self) -> SystemTime;
+
+    /// Returns a future which completes once the clock reaches `deadline`.
+    fn sleep_until(
+        &self,
+        deadline: SystemTime,
+        handle: &Handle,
+    ) -> Box<dyn Future<Item = (), Error = ()>>;
+}
+
+/// Clock backed by the system time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(
+        &self,
+        deadline: SystemTime,
+        handle: &Handle,
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        let deadline = ClockSnapshot::now().to_instant(deadline);
+        let timeout = Timeout::new_at(deadline, handle)
+            .expect("Unable to create timeout")
+            .map_err(|e| panic!("Cannot execute timeout: {:?}", e));
+        Box::new(timeout)
+    }
+}
+
+#[derive(Debug)]
+struct MockClockState {
+    now: SystemTime,
+    sleepers: Vec<(SystemTime, oneshot::Sender<()>)>,
+}
+
+/// Clock which only moves when advanced manually, so that tests can fire timeouts without
+/// waiting for them. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    state: Arc<Mutex<MockClockState>>,
+}
+
+impl MockClock {
+    /// Creates the clock showing the given time.
+    pub fn new(now: SystemTime) -> Self {
+        let state = MockClockState {
+            now,
+            sleepers: Vec::new(),
+        };
+        MockClock {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Moves the clock forward, waking up the sleepers whose deadlines have been reached.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+        let now = state.now;
+        let (woken, sleeping): (Vec<_>, Vec<_>) = state
+            .sleepers
+            .drain(..)
+            .partition(|&(deadline, _)| deadline <= now);
+        state.sleepers = sleeping;
+        for (_, sleeper) in woken {
+            // The timeout may have been dropped already.
+            let _ = sleeper.send(());
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep_until(
+        &self,
+        deadline: SystemTime,
+        _handle: &Handle,
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        let mut state = self.state.lock().unwrap();
+        if deadline <= state.now {
+            return Box::new(future::ok(()));
+        }
+        let (tx, rx) = oneshot::channel();
+        state.sleepers.push((deadline, tx));
+        Box::new(rx.map_err(drop))
+    }
+}
+
+/// Action taken when an internal event is produced while the queue of internal events is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InternalEventsOverflow {
+    /// Wait until the handler takes the queued events, so that no event is lost.
+    Block,
+    /// Drop the oldest queued event to make room for the new one.
+    DropOldest,
+    /// Panic; suitable for catching runaway producers of internal events.
+    Panic,
+}
+
+impl Default for InternalEventsOverflow {
+    fn default() -> Self {
+        InternalEventsOverflow::Block
+    }
+}
+
+#[derive(Debug)]
+struct EventsQueueState {
+    events: VecDeque<InternalEvent>,
+    capacity: usize,
+    overflow: InternalEventsOverflow,
+    // Task forwarding the queued events to the handler.
+    receiver: Option<Task>,
+    // Tasks waiting for room in the queue.
+    blocked: Vec<Task>,
+}
+
+/// Producing end of the bounded queue of internal events.
+#[derive(Debug)]
+struct EventsQueue(Rc<RefCell<EventsQueueState>>);
+
+/// Consuming end of the queue; completes once all the producing ends are dropped.
+#[derive(Debug)]
+struct EventsQueueReceiver(Rc<RefCell<EventsQueueState>>);
+
+impl EventsQueue {
+    fn bounded(capacity: usize, overflow: InternalEventsOverflow) -> (Self, EventsQueueReceiver) {
+        let state = EventsQueueState {
+            events: VecDeque::new(),
+            capacity: capacity.max(1),
+            overflow,
+            receiver: None,
+            blocked: Vec::new(),
+        };
+        let state = Rc::new(RefCell::new(state));
+        (EventsQueue(Rc::clone(&state)), EventsQueueReceiver(state))
+    }
+
+    // Returns the event back if it should wait for room in the queue.
+    fn try_push(&self, event: InternalEvent) -> Result<(), InternalEvent> {
+        let mut state = self.0.borrow_mut();
+        if state.events.len() >= state.capacity {
+            match state.overflow {
+                InternalEventsOverflow::Block => {
+                    state.blocked.push(task::current());
+                    return Err(event);
+                }
+                InternalEventsOverflow::DropOldest => {
+                    let dropped = state.events.pop_front();
+                    warn!("Internal events queue is full, dropping {:?}", dropped);
+                }
+                InternalEventsOverflow::Panic => {
+                    panic!("Internal events queue is full ({} events)", state.capacity)
+                }
+            }
+        }
+        state.events.push_back(event);
+        if let Some(receiver) = state.receiver.take() {
+            receiver.notify();
+        }
+        Ok(())
+    }
+
+    fn push(&self, event: InternalEvent) -> impl Future<Item = (), Error = ()> {
+        let queue = self.clone();
+        let mut event = Some(event);
+        future::poll_fn(move || {
+            let pushed = queue.try_push(event.take().expect("polled after completion"));
+            match pushed {
+                Ok(()) => Ok(Async::Ready(())),
+                Err(returned) => {
+                    event = Some(returned);
+                    Ok(Async::NotReady)
+                }
+            }
+        })
+    }
+}
+
+impl Clone for EventsQueue {
+    fn clone(&self) -> Self {
+        EventsQueue(Rc::clone(&self.0))
+    }
+}
+
+impl Drop for EventsQueue {
+    fn drop(&mut self) {
+        // The receiver completes once the last producer is gone.
+        if let Some(receiver) = self.0.borrow_mut().receiver.take() {
+            receiver.notify();
+        }
+    }
+}
+
+impl Stream for EventsQueueReceiver {
+    type Item = InternalEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<InternalEvent>, ()> {
+        let mut state = self.0.borrow_mut();
+        if let Some(event) = state.events.pop_front() {
+            for blocked in state.blocked.drain(..) {
+                blocked.notify();
+            }
+            return Ok(Async::Ready(Some(event)));
+        }
+        if Rc::strong_count(&self.0) == 1 {
+            return Ok(Async::Ready(None));
+        }
+        state.receiver = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
 
 #[derive(Debug)]
 pub struct InternalPart {
     pub internal_tx: mpsc::Sender<InternalEvent>,
     pub internal_requests_rx: mpsc::Receiver<InternalRequest>,
+    /// Clock which schedules timeouts.
+    pub clock: Box<dyn Clock>,
+    /// Maximum number of internal events queued before `events_overflow` comes into play.
+    pub events_capacity: usize,
+    /// Action taken when an event is produced while the queue of internal events is full.
+    pub events_overflow: InternalEventsOverflow,
+    /// Maximum number of pending timeouts. Once it is exceeded, the timeouts due
+    /// the latest are dropped.
+    pub timeouts_capacity: usize,
+    /// Counters of the evicted timeouts.
+    pub metrics: Arc<InternalMetrics>,
+    /// Data structure keeping the pending timeouts.
+    pub timer: TimeoutsTimer,
+    /// Number of the events sent into `internal_tx` and not handled yet; should be shared
+    /// with the `HandlerPart` receiving the events.
+    pub events_depth: QueueDepth,
 }
 
 impl InternalPart {
+    /// Creates the internal part scheduling timeouts by the system clock.
+    pub fn new(
+        internal_tx: mpsc::Sender<InternalEvent>,
+        internal_requests_rx: mpsc::Receiver<InternalRequest>,
+    ) -> Self {
+        InternalPart {
+            internal_tx,
+            internal_requests_rx,
+            clock: Box::new(SystemClock),
+            events_capacity: EventsPoolCapacity::default().internal_events_capacity,
+            events_overflow: InternalEventsOverflow::default(),
+            timeouts_capacity: DEFAULT_TIMEOUTS_CAPACITY,
+            metrics: Arc::default(),
+            timer: TimeoutsTimer::default(),
+            events_depth: QueueDepth::default(),
+        }
+    }
+
+    /// Makes the internal part schedule timeouts by the given clock.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Bounds the queue of internal events, except for verified transactions, by `capacity`
+    /// and sets the action taken once the queue is full.
+    pub fn with_overflow(mut self, capacity: usize, overflow: InternalEventsOverflow) -> Self {
+        self.events_capacity = capacity;
+        self.events_overflow = overflow;
+        self
+    }
+
+    /// Bounds the number of pending timeouts by `capacity`; the timeouts exceeding it
+    /// are reported to `metrics`.
+    pub fn with_timeouts_capacity(
+        mut self,
+        capacity: usize,
+        metrics: Arc<InternalMetrics>,
+    ) -> Self {
+        self.timeouts_capacity = capacity;
+        self.metrics = metrics;
+        self
+    }
+
+    /// Makes the internal part keep the timeouts in a timer wheel with the given resolution,
+    /// see `TimeoutsTimer::Wheel`.
+    pub fn with_timer_wheel(mut self, resolution: Duration) -> Self {
+        self.timer = TimeoutsTimer::Wheel(resolution);
+        self
+    }
+
     // If the receiver for internal events is gone, we panic, as we cannot
     // continue our work (e.g., timely responding to timeouts).
     fn send_event(
         event: impl Future<Item = InternalEvent, Error = ()>,
-        sender: mpsc::Sender<InternalEvent>,
+        sender: CountingSender<InternalEvent>,
     ) -> impl Future<Item = (), Error = ()> {
         event.and_then(|evt| {
             sender
@@ -45,7 +424,7 @@ impl InternalPart {
 
     fn verify_transaction(
         tx: Box<dyn Transaction>,
-        internal_tx: mpsc::Sender<InternalEvent>,
+        internal_tx: CountingSender<InternalEvent>,
     ) -> impl Future<Item = (), Error = ()> {
         future::lazy(move || {
             if tx.verify() {
@@ -57,6 +436,183 @@ impl InternalPart {
         })
     }
 
+    // The returned future fails if the timeout has been cancelled before it fired,
+    // so that no event is sent.
+    fn schedule_timeout(
+        request: TimeoutRequest,
+        pending_timeouts: &PendingTimeouts,
+        clock: &dyn Clock,
+        handle: &Handle,
+    ) -> impl Future<Item = InternalEvent, Error = ()> {
+        let pending_timeouts = Rc::clone(pending_timeouts);
+
+        clock
+            .sleep_until(request.0, handle)
+            .and_then(move |()| {
+                if pending_timeouts.borrow_mut().remove(&request) {
+                    Ok(InternalEvent::Timeout(request.1))
+                } else {
+                    Err(())
+                }
+            })
+    }
+
+    fn schedule_on_wheel(
+        request: TimeoutRequest,
+        wheel: &SharedWheel,
+        pending_timeouts: &PendingTimeouts,
+        clock: &Rc<dyn Clock>,
+        handle: &Handle,
+        queue: &EventsQueue,
+    ) {
+        let mut timeouts = wheel.borrow_mut();
+        timeouts.wheel.insert(request.0, request);
+        if !timeouts.turning {
+            timeouts.turning = true;
+            let turn = Self::turn_wheel(
+                Rc::clone(wheel),
+                Rc::clone(pending_timeouts),
+                Rc::clone(clock),
+                handle.clone(),
+                queue.clone(),
+            );
+            handle.spawn(turn);
+        }
+    }
+
+    // Turns the wheel at every tick while it has timeouts, sending the fired ones which
+    // have not been cancelled in the order of their deadlines. Ticks are scheduled
+    // at the absolute moments, so that delays of the event loop do not accumulate.
+    fn turn_wheel(
+        wheel: SharedWheel,
+        pending_timeouts: PendingTimeouts,
+        clock: Rc<dyn Clock>,
+        handle: Handle,
+        queue: EventsQueue,
+    ) -> impl Future<Item = (), Error = ()> {
+        future::loop_fn((), move |()| {
+            let next_tick = wheel.borrow().wheel.next_tick();
+            let wheel = Rc::clone(&wheel);
+            let pending_timeouts = Rc::clone(&pending_timeouts);
+            let now = Rc::clone(&clock);
+            let spawn_handle = handle.clone();
+            let queue = queue.clone();
+            clock.sleep_until(next_tick, &handle).map(move |()| {
+                let mut timeouts = wheel.borrow_mut();
+                let mut fired = timeouts.wheel.advance(now.now());
+                fired.sort();
+                for request in fired {
+                    if pending_timeouts.borrow_mut().remove(&request) {
+                        spawn_handle.spawn(queue.push(InternalEvent::Timeout(request.1)));
+                    }
+                }
+                if timeouts.wheel.is_empty() {
+                    timeouts.turning = false;
+                    Loop::Break(())
+                } else {
+                    Loop::Continue(())
+                }
+            })
+        })
+    }
+
+    // Fires the repeating timeout at its deadline and then after each interval while it is
+    // in `repeating_timeouts` with the id of the registration. Deadlines are advanced from
+    // the previous ones, so that delays of the event loop do not accumulate.
+    fn schedule_repeating(
+        request: TimeoutRequest,
+        interval: Duration,
+        id: u64,
+        repeating_timeouts: &RepeatingTimeouts,
+        clock: &Rc<dyn Clock>,
+        handle: &Handle,
+        queue: &EventsQueue,
+    ) -> impl Future<Item = (), Error = ()> {
+        let repeating_timeouts = Rc::clone(repeating_timeouts);
+        let clock = Rc::clone(clock);
+        let handle = handle.clone();
+        let queue = queue.clone();
+        future::loop_fn(request.0, move |deadline| {
+            let repeating_timeouts = Rc::clone(&repeating_timeouts);
+            let request = request.clone();
+            let queue = queue.clone();
+            clock.sleep_until(deadline, &handle).and_then(move |()| {
+                if repeating_timeouts.borrow().get(&request) != Some(&id) {
+                    return Either::A(future::ok(Loop::Break(())));
+                }
+                let event = InternalEvent::Timeout(request.1);
+                Either::B(
+                    queue
+                        .push(event)
+                        .map(move |()| Loop::Continue(deadline + interval)),
+                )
+            })
+        })
+    }
+
+    // Round timeouts of the previous rounds at the same height are superseded
+    // by the jump to a new round.
+    fn is_superseded_by_round(request: &TimeoutRequest, height: Height, round: Round) -> bool {
+        match request.1 {
+            NodeTimeout::Round(h, r) => h == height && r < round,
+            _ => false,
+        }
+    }
+
+    // Timeouts of the previous heights are superseded by the commit of a block.
+    fn is_superseded_by_height(request: &TimeoutRequest, height: Height) -> bool {
+        request.1.context().0.map_or(false, |h| h < height)
+    }
+
+    fn cancel_timeouts<F>(pending_timeouts: &PendingTimeouts, superseded: F)
+    where
+        F: Fn(&TimeoutRequest) -> bool,
+    {
+        let mut pending_timeouts = pending_timeouts.borrow_mut();
+        let superseded: Vec<_> = pending_timeouts
+            .iter()
+            .filter(|request| superseded(request))
+            .cloned()
+            .collect();
+        for request in superseded {
+            pending_timeouts.remove(&request);
+        }
+    }
+
+    fn cancel_repeating_timeouts<F>(repeating_timeouts: &RepeatingTimeouts, superseded: F)
+    where
+        F: Fn(&TimeoutRequest) -> bool,
+    {
+        let mut repeating_timeouts = repeating_timeouts.borrow_mut();
+        let superseded: Vec<_> = repeating_timeouts
+            .keys()
+            .filter(|request| superseded(request))
+            .cloned()
+            .collect();
+        for request in superseded {
+            repeating_timeouts.remove(&request);
+        }
+    }
+
+    // Drops the timeouts due the latest, i.e., the least urgent ones, while there are more
+    // than `capacity` of them.
+    fn evict_timeouts(
+        pending_timeouts: &PendingTimeouts,
+        capacity: usize,
+        metrics: &InternalMetrics,
+    ) {
+        let mut pending_timeouts = pending_timeouts.borrow_mut();
+        while pending_timeouts.len() > capacity.max(1) {
+            let latest = match pending_timeouts.iter().next_back() {
+                Some(latest) => latest.clone(),
+                None => break,
+            };
+            pending_timeouts.remove(&latest);
+            metrics.record_evicted_timeout();
+            warn!("Too many pending timeouts, dropping {:?}", latest);
+        }
+    }
+
     /// Represents a task that processes Internal Requests and produces Internal Events.
     /// `handle` is used to schedule additional tasks within this task.
     /// `verify_executor` is where transaction verification task is executed.
@@ -64,7 +620,31 @@ impl InternalPart {
     where
         E: Executor<Box<dyn Future<Item = (), Error = ()> + Send>>,
     {
-        let internal_tx = self.internal_tx;
+        let internal_tx = CountingSender::new(self.internal_tx, self.events_depth);
+        let clock: Rc<dyn Clock> = Rc::from(self.clock);
+        let wheel = match self.timer {
+            TimeoutsTimer::Exact => None,
+            TimeoutsTimer::Wheel(resolution) => {
+                let timeouts = WheelTimeouts {
+                    wheel: TimerWheel::new(clock.now(), resolution),
+                    turning: false,
+                };
+                Some(Rc::new(RefCell::new(timeouts)))
+            }
+        };
+        let pending_timeouts = PendingTimeouts::default();
+        let repeating_timeouts = RepeatingTimeouts::default();
+        let mut last_repeating_id = 0;
+        let timeouts_capacity = self.timeouts_capacity;
+        let metrics = self.metrics;
+        let (queue, queued_events) =
+            EventsQueue::bounded(self.events_capacity, self.events_overflow);
+        // Events are forwarded by a single sender, so that the channel stays bounded.
+        let forward = queued_events
+            .forward(internal_tx.clone().sink_map_err(drop))
+            .map(drop)
+            .map_err(|()| panic!("cannot send internal event"));
+        handle.spawn(forward);
 
         self.internal_requests_rx
             .map(move |request| {
@@ -77,31 +657,106 @@ impl InternalPart {
                         return;
                     }
 
-                    InternalRequest::Timeout(TimeoutRequest(time, timeout)) => {
-                        let duration = time.duration_since(SystemTime::now())
-                            .unwrap_or_else(|_| Duration::from_millis(0));
+                    InternalRequest::Timeout(request) => {
+                        // An identical timeout is already pending, so it would fire twice.
+                        if !pending_timeouts.borrow_mut().insert(request.clone()) {
+                            return;
+                        }
+                        Self::evict_timeouts(&pending_timeouts, timeouts_capacity, &metrics);
+                        // The request has been evicted right away, as it is due the latest.
+                        if !pending_timeouts.borrow().contains(&request) {
+                            return;
+                        }
+                        if let Some(ref wheel) = wheel {
+                            Self::schedule_on_wheel(
+                                request,
+                                wheel,
+                                &pending_timeouts,
+                                &clock,
+                                &handle,
+                                &queue,
+                            );
+                            return;
+                        }
+                        let fut = Self::schedule_timeout(
+                            request,
+                            &pending_timeouts,
+                            clock.as_ref(),
+                            &handle,
+                        );
+                        Either::A(fut)
+                    }
+
+                    InternalRequest::RepeatingTimeout(request, interval) => {
+                        if interval == Duration::from_secs(0) {
+                            warn!("Ignoring repeating timeout {:?} with zero interval", request);
+                            return;
+                        }
+                        // An identical timeout is already repeating.
+                        if repeating_timeouts.borrow().contains_key(&request) {
+                            return;
+                        }
+                        last_repeating_id += 1;
+                        repeating_timeouts
+                            .borrow_mut()
+                            .insert(request.clone(), last_repeating_id);
+                        let fut = Self::schedule_repeating(
+                            request,
+                            interval,
+                            last_repeating_id,
+                            &repeating_timeouts,
+                            &clock,
+                            &handle,
+                            &queue,
+                        );
+                        handle.spawn(fut);
+                        return;
+                    }
 
-                        let fut = Timeout::new(duration, &handle)
-                            .expect("Unable to create timeout")
-                            .map(|()| InternalEvent::Timeout(timeout))
-                            .map_err(|e| panic!("Cannot execute timeout: {:?}", e));
+                    InternalRequest::CancelTimeout(timeout_handle) => {
+                        pending_timeouts
+                            .borrow_mut()
+                            .remove(timeout_handle.request());
+                        repeating_timeouts
+                            .borrow_mut()
+                            .remove(timeout_handle.request());
+                        return;
+                    }
 
-                        Either::A(fut)
+                    InternalRequest::CancelTimeoutsBefore(height) => {
+                        let superseded = |request: &TimeoutRequest| {
+                            Self::is_superseded_by_height(request, height)
+                        };
+                        Self::cancel_timeouts(&pending_timeouts, superseded);
+                        Self::cancel_repeating_timeouts(&repeating_timeouts, superseded);
+                        return;
                     }
 
                     InternalRequest::JumpToRound(height, round) => {
+                        let superseded = |request: &TimeoutRequest| {
+                            Self::is_superseded_by_round(request, height, round)
+                        };
+                        Self::cancel_timeouts(&pending_timeouts, superseded);
+                        Self::cancel_repeating_timeouts(&repeating_timeouts, superseded);
                         let event = InternalEvent::JumpToRound(height, round);
                         Either::B(future::ok(event))
                     }
 
+                    InternalRequest::RetryPropose(height, round, hash) => {
+                        let event = InternalEvent::RetryPropose(height, round, hash);
+                        Either::B(future::ok(event))
+                    }
+
+                    InternalRequest::Flush => Either::B(future::ok(InternalEvent::Flush)),
+
                     InternalRequest::Shutdown => {
                         let event = InternalEvent::Shutdown;
                         Either::B(future::ok(event))
                     }
                 };
 
-                let send_event = Self::send_event(event, internal_tx.clone());
-                handle.spawn(send_event);
+                let queue = queue.clone();
+                handle.spawn(event.and_then(move |event| queue.push(event)));
             })
             .for_each(Ok)
     }
@@ -109,7 +764,7 @@ impl InternalPart {
 
 #[cfg(test)]
 mod tests {
-    use tokio_core::reactor::Core;
+    use tokio_core::reactor::{Core, Timeout};
 
     use std::thread;
 
@@ -117,6 +772,7 @@ mod tests {
     use blockchain::ExecutionResult;
     use crypto::{gen_keypair, PublicKey, Signature};
     use messages::Message;
+    use node::state::RequestData;
     use storage::Fork;
 
     transactions! {
@@ -144,10 +800,7 @@ mod tests {
         let (internal_tx, internal_rx) = mpsc::channel(16);
         let (internal_requests_tx, internal_requests_rx) = mpsc::channel(16);
 
-        let internal_part = InternalPart {
-            internal_tx,
-            internal_requests_rx,
-        };
+        let internal_part = InternalPart::new(internal_tx, internal_requests_rx);
 
         let thread = thread::spawn(|| {
             let mut core = Core::new().unwrap();
@@ -167,6 +820,443 @@ mod tests {
         thread.join().unwrap()
     }
 
+    fn process_requests(requests: Vec<InternalRequest>) -> Vec<InternalEvent> {
+        let (internal_tx, internal_rx) = mpsc::channel(16);
+        let (internal_requests_tx, internal_requests_rx) = mpsc::channel(16);
+
+        let internal_part = InternalPart::new(internal_tx, internal_requests_rx);
+
+        let thread = thread::spawn(|| {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let verifier = core.handle();
+
+            let task = internal_part
+                .run(handle, verifier)
+                .and_then(|()| internal_rx.collect());
+            core.run(task).unwrap()
+        });
+
+        let mut internal_requests_tx = internal_requests_tx.wait();
+        for request in requests {
+            internal_requests_tx.send(request).unwrap();
+        }
+        drop(internal_requests_tx);
+        thread.join().unwrap()
+    }
+
+    // Jumps to `count` rounds with the queue of internal events bounded by 2 events;
+    // the events are read only after all the requests are processed.
+    fn overflow_events(
+        count: u32,
+        overflow: InternalEventsOverflow,
+    ) -> thread::Result<Vec<InternalEvent>> {
+        let (internal_tx, internal_rx) = mpsc::channel(0);
+        let (mut internal_requests_tx, internal_requests_rx) = mpsc::channel(count as usize);
+        for round in 1..=count {
+            let request = InternalRequest::JumpToRound(Height(1), Round(round));
+            internal_requests_tx.try_send(request).unwrap();
+        }
+        drop(internal_requests_tx);
+
+        let internal_part =
+            InternalPart::new(internal_tx, internal_requests_rx).with_overflow(2, overflow);
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            handle.spawn(internal_part.run(core.handle(), core.handle()));
+            let settle = Timeout::new(Duration::from_millis(50), &handle).unwrap();
+            core.run(settle).unwrap();
+            core.run(internal_rx.collect()).unwrap()
+        }).join()
+    }
+
+    fn rounds(events: &[InternalEvent]) -> Vec<u32> {
+        events
+            .iter()
+            .map(|event| match *event {
+                InternalEvent::JumpToRound(_, round) => round.0,
+                ref other => panic!("Unexpected event {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blocked_events_are_delivered() {
+        let events = overflow_events(6, InternalEventsOverflow::Block).unwrap();
+        assert_eq!(rounds(&events), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn overflow_drops_oldest_events() {
+        let events = overflow_events(6, InternalEventsOverflow::DropOldest).unwrap();
+        let rounds = rounds(&events);
+        // Besides the queued events, one event may be already taken by the channel,
+        // and another one may be in flight.
+        assert!(rounds.len() >= 2 && rounds.len() <= 4, "{:?}", rounds);
+        assert_eq!(&rounds[rounds.len() - 2..], &[5, 6]);
+        assert!(rounds.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn overflow_panics() {
+        assert!(overflow_events(6, InternalEventsOverflow::Panic).is_err());
+    }
+
+    #[test]
+    fn cancel_timeout() {
+        let now = SystemTime::now();
+        let first = TimeoutRequest(now + Duration::from_millis(50), NodeTimeout::PeerExchange);
+        let second = TimeoutRequest(now + Duration::from_millis(100), NodeTimeout::UpdateApiState);
+        let first_handle = first.handle();
+
+        let events = process_requests(vec![
+            first.into(),
+            second.into(),
+            InternalRequest::CancelTimeout(first_handle),
+        ]);
+        assert_eq!(
+            events,
+            vec![InternalEvent::Timeout(NodeTimeout::UpdateApiState)]
+        );
+    }
+
+    #[test]
+    fn deduplicate_timeouts() {
+        let time = SystemTime::now() + Duration::from_millis(50);
+        let request = TimeoutRequest(time, NodeTimeout::Status(Height(3)));
+
+        let events = process_requests(vec![
+            request.clone().into(),
+            request.clone().into(),
+            request.into(),
+        ]);
+        assert_eq!(
+            events,
+            vec![InternalEvent::Timeout(NodeTimeout::Status(Height(3)))]
+        );
+    }
+
+    #[test]
+    fn flush() {
+        let events = process_requests(vec![InternalRequest::Flush]);
+        assert_eq!(events, vec![InternalEvent::Flush]);
+    }
+
+    #[test]
+    fn jump_to_round_cancels_round_timeouts() {
+        let time = SystemTime::now() + Duration::from_millis(50);
+        let stale = TimeoutRequest(time, NodeTimeout::Round(Height(1), Round(1)));
+        let actual = TimeoutRequest(time, NodeTimeout::Round(Height(1), Round(2)));
+
+        let events = process_requests(vec![
+            stale.into(),
+            actual.into(),
+            InternalRequest::JumpToRound(Height(1), Round(2)),
+        ]);
+        assert_eq!(
+            events,
+            vec![
+                InternalEvent::JumpToRound(Height(1), Round(2)),
+                InternalEvent::Timeout(NodeTimeout::Round(Height(1), Round(2))),
+            ]
+        );
+    }
+
+    #[test]
+    fn cancel_timeouts_before_height() {
+        let time = SystemTime::now() + Duration::from_millis(50);
+        let timeouts = vec![
+            NodeTimeout::Status(Height(1)),
+            NodeTimeout::Round(Height(1), Round(1)),
+            NodeTimeout::Request(RequestData::Block(Height(1)), None),
+            NodeTimeout::Status(Height(2)),
+            NodeTimeout::Round(Height(2), Round(1)),
+            NodeTimeout::Propose(Height(3), Round(1)),
+            NodeTimeout::PeerExchange,
+        ];
+
+        let mut requests: Vec<InternalRequest> = timeouts
+            .into_iter()
+            .map(|timeout| TimeoutRequest(time, timeout).into())
+            .collect();
+        requests.push(InternalRequest::CancelTimeoutsBefore(Height(2)));
+        let mut fired: Vec<_> = process_requests(requests)
+            .into_iter()
+            .map(|event| match event {
+                InternalEvent::Timeout(timeout) => timeout,
+                other => panic!("Unexpected event {:?}", other),
+            })
+            .collect();
+        fired.sort();
+        assert_eq!(
+            fired,
+            vec![
+                NodeTimeout::Status(Height(2)),
+                NodeTimeout::Round(Height(2), Round(1)),
+                NodeTimeout::Propose(Height(3), Round(1)),
+                NodeTimeout::PeerExchange,
+            ]
+        );
+    }
+
+    #[test]
+    fn mock_clock_fires_timeouts() {
+        let clock = MockClock::new(SystemTime::now());
+        let now = clock.now();
+        let (internal_tx, internal_rx) = mpsc::channel(16);
+        let (internal_requests_tx, internal_requests_rx) = mpsc::channel(16);
+        let internal_part =
+            InternalPart::new(internal_tx, internal_requests_rx).with_clock(clock.clone());
+
+        let thread = thread::spawn(|| {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let verifier = core.handle();
+            core.run(internal_part.run(handle, verifier)).unwrap();
+        });
+
+        let mut internal_requests_tx = internal_requests_tx.wait();
+        let first = TimeoutRequest(now + Duration::from_secs(60), NodeTimeout::PeerExchange);
+        let second = TimeoutRequest(now + Duration::from_secs(120), NodeTimeout::UpdateApiState);
+        internal_requests_tx.send(first.into()).unwrap();
+        internal_requests_tx.send(second.into()).unwrap();
+
+        // Real time does not matter: the timeouts fire only once the clock is advanced.
+        let mut internal_rx = internal_rx.wait();
+        clock.advance(Duration::from_secs(90));
+        let event = internal_rx.next().unwrap().unwrap();
+        assert_eq!(event, InternalEvent::Timeout(NodeTimeout::PeerExchange));
+        clock.advance(Duration::from_secs(30));
+        let event = internal_rx.next().unwrap().unwrap();
+        assert_eq!(event, InternalEvent::Timeout(NodeTimeout::UpdateApiState));
+
+        drop(internal_requests_tx);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn repeating_timeout_fires_until_cancelled() {
+        let clock = MockClock::new(SystemTime::now());
+        let now = clock.now();
+        let (internal_tx, internal_rx) = mpsc::channel(16);
+        let (internal_requests_tx, internal_requests_rx) = mpsc::channel(16);
+        let internal_part =
+            InternalPart::new(internal_tx, internal_requests_rx).with_clock(clock.clone());
+
+        let thread = thread::spawn(|| {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let verifier = core.handle();
+            core.run(internal_part.run(handle, verifier)).unwrap();
+        });
+
+        let mut internal_requests_tx = internal_requests_tx.wait();
+        let interval = Duration::from_secs(60);
+        let request = TimeoutRequest(now + interval, NodeTimeout::PeerExchange);
+        let timeout_handle = request.handle();
+        let repeating = InternalRequest::RepeatingTimeout(request, interval);
+        internal_requests_tx.send(repeating).unwrap();
+        internal_requests_tx.send(InternalRequest::Flush).unwrap();
+        let mut internal_rx = internal_rx.wait();
+        assert_eq!(internal_rx.next().unwrap(), Ok(InternalEvent::Flush));
+
+        // The timeout fires once per interval.
+        for _ in 0..3 {
+            clock.advance(interval);
+            let event = internal_rx.next().unwrap().unwrap();
+            assert_eq!(event, InternalEvent::Timeout(NodeTimeout::PeerExchange));
+        }
+
+        // Once the flush is received, the timeout has been cancelled.
+        let cancel = InternalRequest::CancelTimeout(timeout_handle);
+        internal_requests_tx.send(cancel).unwrap();
+        internal_requests_tx.send(InternalRequest::Flush).unwrap();
+        assert_eq!(internal_rx.next().unwrap(), Ok(InternalEvent::Flush));
+        clock.advance(interval * 3);
+        internal_requests_tx.send(InternalRequest::Flush).unwrap();
+        assert_eq!(internal_rx.next().unwrap(), Ok(InternalEvent::Flush));
+
+        drop(internal_requests_tx);
+        thread.join().unwrap();
+        assert!(internal_rx.next().is_none());
+    }
+
+    #[test]
+    fn repeating_timeout_re_added_after_cancel_fires_once() {
+        let clock = MockClock::new(SystemTime::now());
+        let now = clock.now();
+        let (internal_tx, internal_rx) = mpsc::channel(16);
+        let (internal_requests_tx, internal_requests_rx) = mpsc::channel(16);
+        let internal_part =
+            InternalPart::new(internal_tx, internal_requests_rx).with_clock(clock.clone());
+
+        let thread = thread::spawn(|| {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let verifier = core.handle();
+            core.run(internal_part.run(handle, verifier)).unwrap();
+        });
+
+        let mut internal_requests_tx = internal_requests_tx.wait();
+        let interval = Duration::from_secs(60);
+        let request = TimeoutRequest(now + interval, NodeTimeout::PeerExchange);
+        let timeout_handle = request.handle();
+        let repeating = InternalRequest::RepeatingTimeout(request.clone(), interval);
+        internal_requests_tx.send(repeating).unwrap();
+        internal_requests_tx.send(InternalRequest::Flush).unwrap();
+        let mut internal_rx = internal_rx.wait();
+        assert_eq!(internal_rx.next().unwrap(), Ok(InternalEvent::Flush));
+
+        // The identical timeout is registered again while the cancelled one is asleep.
+        let cancel = InternalRequest::CancelTimeout(timeout_handle);
+        internal_requests_tx.send(cancel).unwrap();
+        let repeating = InternalRequest::RepeatingTimeout(request, interval);
+        internal_requests_tx.send(repeating).unwrap();
+        internal_requests_tx.send(InternalRequest::Flush).unwrap();
+        assert_eq!(internal_rx.next().unwrap(), Ok(InternalEvent::Flush));
+
+        // Only the timeout of the new registration fires.
+        for _ in 0..2 {
+            clock.advance(interval);
+            let event = internal_rx.next().unwrap().unwrap();
+            assert_eq!(event, InternalEvent::Timeout(NodeTimeout::PeerExchange));
+            internal_requests_tx.send(InternalRequest::Flush).unwrap();
+            assert_eq!(internal_rx.next().unwrap(), Ok(InternalEvent::Flush));
+        }
+
+        drop(internal_requests_tx);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn farthest_timeouts_are_evicted() {
+        let clock = MockClock::new(SystemTime::now());
+        let now = clock.now();
+        let (internal_tx, internal_rx) = mpsc::channel(16);
+        let (internal_requests_tx, internal_requests_rx) = mpsc::channel(16);
+        let metrics = Arc::new(InternalMetrics::new());
+        let internal_part = InternalPart::new(internal_tx, internal_requests_rx)
+            .with_clock(clock.clone())
+            .with_timeouts_capacity(2, Arc::clone(&metrics));
+
+        let thread = thread::spawn(|| {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let verifier = core.handle();
+            core.run(internal_part.run(handle, verifier)).unwrap();
+        });
+
+        let mut internal_requests_tx = internal_requests_tx.wait();
+        let timeouts = vec![
+            (60, NodeTimeout::PeerExchange),
+            (120, NodeTimeout::UpdateApiState),
+            (180, NodeTimeout::Status(Height(1))),
+            (30, NodeTimeout::Status(Height(2))),
+        ];
+        for (delay, timeout) in timeouts {
+            let request = TimeoutRequest(now + Duration::from_secs(delay), timeout);
+            internal_requests_tx.send(request.into()).unwrap();
+        }
+        // Once the flush is received, all the timeouts have been scheduled.
+        internal_requests_tx.send(InternalRequest::Flush).unwrap();
+        let mut internal_rx = internal_rx.wait();
+        assert_eq!(internal_rx.next().unwrap(), Ok(InternalEvent::Flush));
+
+        // Only the two nearest timeouts fire.
+        clock.advance(Duration::from_secs(45));
+        let event = internal_rx.next().unwrap().unwrap();
+        assert_eq!(
+            event,
+            InternalEvent::Timeout(NodeTimeout::Status(Height(2)))
+        );
+        clock.advance(Duration::from_secs(155));
+        let event = internal_rx.next().unwrap().unwrap();
+        assert_eq!(event, InternalEvent::Timeout(NodeTimeout::PeerExchange));
+
+        drop(internal_requests_tx);
+        thread.join().unwrap();
+        assert!(internal_rx.next().is_none());
+        assert_eq!(metrics.evicted_timeouts(), 2);
+    }
+
+    #[test]
+    fn timer_wheel_fires_timeouts_in_order() {
+        let clock = MockClock::new(SystemTime::now());
+        let now = clock.now();
+        let (internal_tx, internal_rx) = mpsc::channel(16);
+        let (internal_requests_tx, internal_requests_rx) = mpsc::channel(16);
+        let internal_part = InternalPart::new(internal_tx, internal_requests_rx)
+            .with_clock(clock.clone())
+            .with_timer_wheel(Duration::from_secs(1));
+
+        let thread = thread::spawn(|| {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let verifier = core.handle();
+            core.run(internal_part.run(handle, verifier)).unwrap();
+        });
+
+        let mut internal_requests_tx = internal_requests_tx.wait();
+        let timeouts = vec![
+            (60, NodeTimeout::PeerExchange),
+            (30, NodeTimeout::Status(Height(2))),
+            (31, NodeTimeout::Status(Height(1))),
+        ];
+        for (delay, timeout) in timeouts {
+            let request = TimeoutRequest(now + Duration::from_secs(delay), timeout);
+            internal_requests_tx.send(request.into()).unwrap();
+        }
+        internal_requests_tx.send(InternalRequest::Flush).unwrap();
+        let mut internal_rx = internal_rx.wait();
+        assert_eq!(internal_rx.next().unwrap(), Ok(InternalEvent::Flush));
+
+        // The clock jumps over several ticks, which fire at once in the order of deadlines.
+        clock.advance(Duration::from_secs(45));
+        let event = internal_rx.next().unwrap().unwrap();
+        assert_eq!(
+            event,
+            InternalEvent::Timeout(NodeTimeout::Status(Height(2)))
+        );
+        let event = internal_rx.next().unwrap().unwrap();
+        assert_eq!(
+            event,
+            InternalEvent::Timeout(NodeTimeout::Status(Height(1)))
+        );
+        clock.advance(Duration::from_secs(15));
+        let event = internal_rx.next().unwrap().unwrap();
+        assert_eq!(event, InternalEvent::Timeout(NodeTimeout::PeerExchange));
+
+        drop(internal_requests_tx);
+        thread.join().unwrap();
+        assert!(internal_rx.next().is_none());
+    }
+
+    #[test]
+    fn clock_step_back_preserves_timeouts_order() {
+        let start = ClockSnapshot::now();
+        let first = start.system + Duration::from_millis(100);
+
+        // The system clock is stepped back by an hour shortly after the first request.
+        let instant = start.instant + Duration::from_millis(10);
+        let system = start.to_system_time(instant) - Duration::from_secs(3_600);
+        let stepped = ClockSnapshot::new(system, instant);
+        let second = stepped.system + Duration::from_millis(200);
+
+        // By wall-clock the second timeout is due an hour earlier, but it has been requested
+        // to fire later.
+        assert!(second < first);
+        let first = start.to_instant(first);
+        let second = stepped.to_instant(second);
+        assert_eq!(first, start.instant + Duration::from_millis(100));
+        assert_eq!(second, start.instant + Duration::from_millis(210));
+        assert!(first < second);
+
+        // Deadlines in the past fire immediately.
+        let past = stepped.system - Duration::from_secs(1);
+        assert_eq!(stepped.to_instant(past), instant);
+    }
+
     #[test]
     fn verify_tx() {
         let (pk, sk) = gen_keypair();
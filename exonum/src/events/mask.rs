@@ -0,0 +1,135 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Subscription of the handler to the kinds of events it is interested in.
+//!
+//! Events of the kinds missing in the `EventMask` of `HandlerPart` never reach the handler.
+//! A source all of whose kinds are masked out is not polled at all; its channel is kept
+//! open, so the producers of such events should be disabled, otherwise they stall once
+//! the channel is full.
+
+use futures::{Async, Poll, Stream};
+
+use std::ops::BitOr;
+
+use events::{Event, InternalEvent};
+
+/// Set of the kinds of events delivered to the handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventMask(u8);
+
+impl EventMask {
+    /// Events of the network part.
+    pub const NETWORK: EventMask = EventMask(0b0001);
+    /// Timeouts, i.e., `InternalEvent::Timeout`.
+    pub const TIMEOUT: EventMask = EventMask(0b0010);
+    /// Internal events other than timeouts. `InternalEvent::Shutdown` is delivered regardless
+    /// of the mask as long as either of the internal kinds is selected.
+    pub const INTERNAL: EventMask = EventMask(0b0100);
+    /// Messages of the api.
+    pub const API: EventMask = EventMask(0b1000);
+
+    /// Returns the mask selecting all the kinds of events.
+    pub fn all() -> Self {
+        Self::NETWORK | Self::TIMEOUT | Self::INTERNAL | Self::API
+    }
+
+    /// Returns the mask selecting no events.
+    pub fn empty() -> Self {
+        EventMask(0)
+    }
+
+    /// Returns `true` if all the kinds of `other` are selected by this mask.
+    pub fn contains(self, other: EventMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns `true` if any kind of `other` is selected by this mask.
+    pub fn intersects(self, other: EventMask) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Returns `true` if the event should be delivered to the handler.
+    pub fn matches(self, event: &Event) -> bool {
+        let kind = match *event {
+            Event::Network(_) => Self::NETWORK,
+            Event::Api(_) => Self::API,
+            Event::Internal(InternalEvent::Timeout(_)) => Self::TIMEOUT,
+            Event::Internal(InternalEvent::Shutdown) => Self::TIMEOUT | Self::INTERNAL,
+            Event::Internal(_) => Self::INTERNAL,
+        };
+        self.intersects(kind)
+    }
+}
+
+impl Default for EventMask {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl BitOr for EventMask {
+    type Output = EventMask;
+
+    fn bitor(self, rhs: EventMask) -> EventMask {
+        EventMask(self.0 | rhs.0)
+    }
+}
+
+/// Event source yielding only the events matching the mask. The source is not polled
+/// at all if it cannot yield any of the selected kinds.
+#[derive(Debug)]
+pub struct MaskedStream<S> {
+    stream: S,
+    mask: EventMask,
+    subscribed: bool,
+}
+
+impl<S> MaskedStream<S> {
+    /// Wraps the source of the events of the given `kinds`.
+    pub fn new(stream: S, mask: EventMask, kinds: EventMask) -> Self {
+        MaskedStream {
+            stream,
+            mask,
+            subscribed: mask.intersects(kinds),
+        }
+    }
+}
+
+impl<S> Stream for MaskedStream<S>
+where
+    S: Stream,
+    S::Item: Into<Event>,
+{
+    type Item = Event;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, S::Error> {
+        if !self.subscribed {
+            return Ok(Async::NotReady);
+        }
+        loop {
+            match self.stream.poll()? {
+                Async::Ready(Some(item)) => {
+                    let event = item.into();
+                    if self.mask.matches(&event) {
+                        return Ok(Async::Ready(Some(event)));
+                    }
+                }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Filtering of the messages received from peers by their types, see
+//! `NetworkPart::message_filter`.
+//!
+//! Messages of the types which are not allowed for a connection are dropped before they reach
+//! the handler and counted in `NetworkMetrics::filtered_messages`. The allow-list of
+//! a connection is chosen by the public key of the peer once the connection is established.
+
+use std::collections::{HashMap, HashSet};
+
+use crypto::PublicKey;
+use messages::RawMessage;
+
+/// Allow-list of the messages accepted over a single connection. Messages are identified
+/// by their service id and message type.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AllowedMessages {
+    types: HashSet<(u16, u16)>,
+}
+
+impl AllowedMessages {
+    /// Creates the allow-list of the given `(service_id, message_type)` pairs.
+    pub fn new<I: IntoIterator<Item = (u16, u16)>>(types: I) -> Self {
+        AllowedMessages {
+            types: types.into_iter().collect(),
+        }
+    }
+
+    /// Returns `true` if the message should be passed to the handler.
+    pub fn allows(&self, message: &RawMessage) -> bool {
+        self.types
+            .contains(&(message.service_id(), message.message_type()))
+    }
+}
+
+/// Allow-lists of the messages accepted from the peers. By default, all the messages
+/// are accepted from all the peers.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    default: Option<AllowedMessages>,
+    peers: HashMap<PublicKey, AllowedMessages>,
+}
+
+impl MessageFilter {
+    /// Creates the filter accepting all the messages.
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Restricts the messages of the peers which have no allow-list of their own,
+    /// e.g., of the peers which are not validators.
+    pub fn by_default(mut self, allowed: AllowedMessages) -> Self {
+        self.default = Some(allowed);
+        self
+    }
+
+    /// Sets the allow-list of the peer with the given key.
+    pub fn for_peer(mut self, peer: PublicKey, allowed: AllowedMessages) -> Self {
+        self.peers.insert(peer, allowed);
+        self
+    }
+
+    /// Returns the allow-list of a connection with the peer, or `None` if all the messages
+    /// of the peer are accepted.
+    pub fn allowed(&self, peer: &PublicKey) -> Option<AllowedMessages> {
+        self.peers
+            .get(peer)
+            .or_else(|| self.default.as_ref())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllowedMessages, MessageFilter};
+    use crypto::gen_keypair;
+    use events::tests::raw_message;
+
+    #[test]
+    fn allow_lists_of_peers() {
+        let (validator, _) = gen_keypair();
+        let (observer, _) = gen_keypair();
+        assert_eq!(MessageFilter::allow_all().allowed(&observer), None);
+
+        let filter = MessageFilter::allow_all()
+            .by_default(AllowedMessages::new(vec![(0, 1)]))
+            .for_peer(validator, AllowedMessages::new(vec![(0, 1), (0, 2)]));
+        let observer_allowed = filter.allowed(&observer).unwrap();
+        assert!(observer_allowed.allows(&raw_message(1, 100)));
+        assert!(!observer_allowed.allows(&raw_message(2, 100)));
+        let validator_allowed = filter.allowed(&validator).unwrap();
+        assert!(validator_allowed.allows(&raw_message(2, 100)));
+        assert!(!validator_allowed.allows(&raw_message(3, 100)));
+    }
+}
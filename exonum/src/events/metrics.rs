@@ -0,0 +1,631 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering}, time::Duration,
+};
+
+use super::{error::ConnectError, Event, InternalEvent, TimedEvent};
+use helpers::{Height, Round};
+
+/// Event sources which have produced events during the last poll of the event loop.
+///
+/// If all the flags are unset, either there were no events to handle, or the handler
+/// was busy with the previous event at the moment of the poll.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AggregatorStatus {
+    pub timeout_ready: bool,
+    pub network_ready: bool,
+    pub api_ready: bool,
+    pub internal_ready: bool,
+}
+
+impl AggregatorStatus {
+    const TIMEOUT: usize = 1;
+    const NETWORK: usize = 1 << 1;
+    const API: usize = 1 << 2;
+    const INTERNAL: usize = 1 << 3;
+
+    /// Sets the flag corresponding to the event kind.
+    pub fn record(&mut self, event: &Event) {
+        let flag = match *event {
+            Event::Network(_) => &mut self.network_ready,
+            Event::Api(_) => &mut self.api_ready,
+            Event::Internal(InternalEvent::Timeout(_)) => &mut self.timeout_ready,
+            Event::Internal(_) => &mut self.internal_ready,
+        };
+        *flag = true;
+    }
+
+    fn to_bits(self) -> usize {
+        let flags = [
+            (self.timeout_ready, Self::TIMEOUT),
+            (self.network_ready, Self::NETWORK),
+            (self.api_ready, Self::API),
+            (self.internal_ready, Self::INTERNAL),
+        ];
+        flags
+            .iter()
+            .filter(|&&(ready, _)| ready)
+            .fold(0, |bits, &(_, bit)| bits | bit)
+    }
+
+    fn from_bits(bits: usize) -> Self {
+        AggregatorStatus {
+            timeout_ready: bits & Self::TIMEOUT != 0,
+            network_ready: bits & Self::NETWORK != 0,
+            api_ready: bits & Self::API != 0,
+            internal_ready: bits & Self::INTERNAL != 0,
+        }
+    }
+}
+
+/// Counters of the events dispatched by the `HandlerPart`.
+///
+/// Counters only grow, so the rate of a certain kind of events can be obtained
+/// by taking the difference between two snapshots.
+#[derive(Debug, Default)]
+pub struct EventsMetrics {
+    network: AtomicUsize,
+    timeout: AtomicUsize,
+    api: AtomicUsize,
+    internal: AtomicUsize,
+    // Total delay between receiving and dispatching of events, in microseconds.
+    dispatch_delay: AtomicUsize,
+    // `AggregatorStatus` of the last poll encoded as bit flags.
+    aggregator_status: AtomicUsize,
+    // Total time the handler has been waiting for events, in microseconds.
+    idle: AtomicUsize,
+    // Height and round of the last dispatched timeout, incremented by one; zero stands
+    // for a timeout without the height or round.
+    timeout_height: AtomicUsize,
+    timeout_round: AtomicUsize,
+    handler_panics: AtomicUsize,
+    expired_api: AtomicUsize,
+    handling_latency: HandlingLatency,
+}
+
+impl EventsMetrics {
+    /// Creates metrics with all counters set to zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bumps the counter corresponding to the event kind.
+    pub fn record(&self, event: &Event) {
+        let counter = match *event {
+            Event::Network(_) => &self.network,
+            Event::Api(_) => &self.api,
+            Event::Internal(InternalEvent::Timeout(_)) => &self.timeout,
+            Event::Internal(_) => &self.internal,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        if let Event::Internal(InternalEvent::Timeout(ref timeout)) = *event {
+            let (height, round) = timeout.context();
+            let height = height.map_or(0, |height| height.0 as usize + 1);
+            let round = round.map_or(0, |round| round.0 as usize + 1);
+            self.timeout_height.store(height, Ordering::Relaxed);
+            self.timeout_round.store(round, Ordering::Relaxed);
+        }
+    }
+
+    /// Bumps the counter corresponding to the event kind and accounts the time passed
+    /// since the event has been received.
+    pub fn record_timed(&self, event: &TimedEvent) {
+        self.record(&event.event);
+        let delay = event.received_at.elapsed();
+        let micros = delay.as_secs() * 1_000_000 + u64::from(delay.subsec_micros());
+        self.dispatch_delay.fetch_add(micros as usize, Ordering::Relaxed);
+    }
+
+    /// Returns the number of dispatched network events.
+    pub fn network_events(&self) -> usize {
+        self.network.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of dispatched timeouts.
+    pub fn timeout_events(&self) -> usize {
+        self.timeout.load(Ordering::Relaxed)
+    }
+
+    /// Returns the height and round of the last dispatched timeout, see `NodeTimeout::context`.
+    /// Compare them with the state of consensus to see which timeouts it is progressing by.
+    pub fn last_timeout_context(&self) -> (Option<Height>, Option<Round>) {
+        let height = self.timeout_height.load(Ordering::Relaxed);
+        let round = self.timeout_round.load(Ordering::Relaxed);
+        let height = height.checked_sub(1).map(|height| Height(height as u64));
+        let round = round.checked_sub(1).map(|round| Round(round as u32));
+        (height, round)
+    }
+
+    /// Counts the panic of the handler which has been recovered from.
+    pub fn record_handler_panic(&self) {
+        self.handler_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of the handler panics caught with `HandlerPart::recover_panics`.
+    pub fn handler_panics(&self) -> usize {
+        self.handler_panics.load(Ordering::Relaxed)
+    }
+
+    /// Accounts the time the handler has spent on an event (or a batch of events),
+    /// including the completion of the returned future.
+    pub fn record_handling(&self, duration: Duration) {
+        self.handling_latency.0.record(duration);
+    }
+
+    /// Returns the histogram of the times the handler has spent on events (or batches),
+    /// with the buckets `HANDLING_BUCKETS_US`. Its percentiles reveal the slow events
+    /// hidden by the average, which may cause the node to miss rounds.
+    pub fn handling_latency(&self) -> &LatencyHistogram {
+        &self.handling_latency.0
+    }
+
+    /// Counts the api message skipped because its deadline has passed.
+    pub fn record_expired_api_event(&self) {
+        self.expired_api.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of the api messages skipped because their deadlines have passed,
+    /// see `ExternalMessage::Expiring`. These messages are not counted in `api_events`.
+    pub fn expired_api_events(&self) -> usize {
+        self.expired_api.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of dispatched api events.
+    pub fn api_events(&self) -> usize {
+        self.api.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of dispatched internal events, excluding timeouts.
+    pub fn internal_events(&self) -> usize {
+        self.internal.load(Ordering::Relaxed)
+    }
+
+    /// Stores the status of the last poll of the event loop.
+    pub fn record_aggregator_status(&self, status: AggregatorStatus) {
+        self.aggregator_status
+            .store(status.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the event sources which have produced events during the last poll
+    /// of the event loop.
+    pub fn aggregator_status(&self) -> AggregatorStatus {
+        AggregatorStatus::from_bits(self.aggregator_status.load(Ordering::Relaxed))
+    }
+
+    /// Accounts the time the handler has been waiting for events.
+    pub fn record_idle(&self, duration: Duration) {
+        let micros = duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros());
+        self.idle.fetch_add(micros as usize, Ordering::Relaxed);
+    }
+
+    /// Returns the total time the handler has been free while none of the event sources
+    /// was ready. Compare its growth with the wall-clock time to see how busy the event
+    /// loop is.
+    pub fn idle_time(&self) -> Duration {
+        Duration::from_micros(self.idle.load(Ordering::Relaxed) as u64)
+    }
+
+    /// Returns the total delay between receiving and dispatching of all timed events.
+    /// Divide it by the number of dispatched events to get the average delay.
+    pub fn dispatch_delay(&self) -> Duration {
+        Duration::from_micros(self.dispatch_delay.load(Ordering::Relaxed) as u64)
+    }
+}
+
+/// Upper bounds of the buckets of `LatencyHistogram`, in milliseconds. Durations exceeding
+/// the last bound fall into an additional unbounded bucket.
+pub const LATENCY_BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000];
+
+/// Upper bounds of the buckets of the event handling times, in microseconds. The bounds
+/// grow in `1-2-5` steps, so the relative error of the percentiles is bounded
+/// in the whole range.
+pub const HANDLING_BUCKETS_US: [u64; 16] = [
+    10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000, 200_000,
+    500_000, 1_000_000,
+];
+
+/// Percentiles of the durations recorded by a `LatencyHistogram`. Each percentile is
+/// the upper bound of the bucket it falls into; the percentiles in the unbounded bucket
+/// are the longest recorded duration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyPercentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// Histogram of durations with the fixed buckets, `LATENCY_BUCKETS_MS` by default.
+/// Recording takes a few atomic operations and the memory does not grow with the number
+/// of the recorded durations.
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    // Upper bounds of the buckets except for the last one, which is unbounded.
+    bounds: Vec<Duration>,
+    // Counts of the durations in each bucket.
+    buckets: Vec<AtomicUsize>,
+    // Total of the recorded durations, in microseconds.
+    total: AtomicUsize,
+    // Longest of the recorded durations, in microseconds.
+    max: AtomicUsize,
+}
+
+impl LatencyHistogram {
+    /// Creates a histogram with buckets bounded by the given durations in ascending order,
+    /// and an additional unbounded bucket.
+    pub fn with_bounds(bounds: Vec<Duration>) -> Self {
+        assert!(
+            bounds.windows(2).all(|pair| pair[0] < pair[1]),
+            "Bounds of the buckets should be in ascending order"
+        );
+        let buckets = (0..=bounds.len()).map(|_| AtomicUsize::new(0)).collect();
+        LatencyHistogram {
+            bounds,
+            buckets,
+            total: AtomicUsize::new(0),
+            max: AtomicUsize::new(0),
+        }
+    }
+
+    /// Accounts the duration in the bucket with the least bound not shorter than it.
+    pub fn record(&self, duration: Duration) {
+        let index = self
+            .bounds
+            .iter()
+            .position(|&bound| duration <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+
+        let micros = duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros());
+        let micros = micros as usize;
+        self.total.fetch_add(micros, Ordering::Relaxed);
+        let mut max = self.max.load(Ordering::Relaxed);
+        while micros > max {
+            let swapped =
+                self.max
+                    .compare_exchange_weak(max, micros, Ordering::Relaxed, Ordering::Relaxed);
+            match swapped {
+                Ok(_) => break,
+                Err(current) => max = current,
+            }
+        }
+    }
+
+    /// Returns the counts of the durations in the buckets along with their upper bounds;
+    /// the bound of the last bucket is `None`.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, usize)> {
+        let bounds = self.bounds.iter().cloned().map(Some).chain(Some(None));
+        bounds
+            .zip(&self.buckets)
+            .map(|(bound, count)| (bound, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Returns the upper bound of the bucket the given percentile of the recorded durations
+    /// falls into, or the longest duration for the unbounded bucket. Returns `None`
+    /// if nothing has been recorded.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let buckets = self.buckets();
+        let count: usize = buckets.iter().map(|&(_, count)| count).sum();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0 * count as f64).ceil() as usize).max(1);
+        let mut seen = 0;
+        for (bound, bucket_count) in buckets {
+            seen += bucket_count;
+            if seen >= rank {
+                return Some(bound.unwrap_or_else(|| self.max()));
+            }
+        }
+        Some(self.max())
+    }
+
+    /// Returns the median, 95th and 99th percentiles of the recorded durations,
+    /// see `percentile`.
+    pub fn percentiles(&self) -> Option<LatencyPercentiles> {
+        Some(LatencyPercentiles {
+            p50: self.percentile(50.0)?,
+            p95: self.percentile(95.0)?,
+            p99: self.percentile(99.0)?,
+        })
+    }
+
+    /// Returns the number of the recorded durations.
+    pub fn count(&self) -> usize {
+        self.buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns the total of the recorded durations.
+    pub fn total(&self) -> Duration {
+        Duration::from_micros(self.total.load(Ordering::Relaxed) as u64)
+    }
+
+    /// Returns the longest of the recorded durations.
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max.load(Ordering::Relaxed) as u64)
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        let bounds = LATENCY_BUCKETS_MS.iter().map(|&ms| Duration::from_millis(ms));
+        Self::with_bounds(bounds.collect())
+    }
+}
+
+// Histogram of the event handling times with the buckets `HANDLING_BUCKETS_US`.
+#[derive(Debug)]
+struct HandlingLatency(LatencyHistogram);
+
+impl Default for HandlingLatency {
+    fn default() -> Self {
+        let bounds = HANDLING_BUCKETS_US.iter().map(|&us| Duration::from_micros(us));
+        HandlingLatency(LatencyHistogram::with_bounds(bounds.collect()))
+    }
+}
+
+/// Counters of the events produced by the `NetworkPart`.
+#[derive(Debug, Default)]
+pub struct NetworkMetrics {
+    paused_reads: AtomicUsize,
+    failed_dials: AtomicUsize,
+    // Failed connection attempts indexed by the discriminants of `ConnectError`.
+    connect_errors: [AtomicUsize; 6],
+    outgoing_overflows: AtomicUsize,
+    throttled_messages: AtomicUsize,
+    filtered_messages: AtomicUsize,
+    messages_received: AtomicUsize,
+    bytes_received: AtomicUsize,
+    messages_sent: AtomicUsize,
+    bytes_sent: AtomicUsize,
+    connect_latency: LatencyHistogram,
+}
+
+impl NetworkMetrics {
+    /// Creates metrics with all counters set to zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a message sent to a peer whose outgoing queue was full.
+    pub fn record_outgoing_overflow(&self) {
+        self.outgoing_overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of messages sent to peers whose outgoing queues were full.
+    pub fn outgoing_overflows(&self) -> usize {
+        self.outgoing_overflows.load(Ordering::Relaxed)
+    }
+
+    /// Registers a message which has been delayed because its peer exceeded the rate limit.
+    pub fn record_throttled_message(&self) {
+        self.throttled_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of messages delayed because their peers exceeded the rate limit.
+    pub fn throttled_messages(&self) -> usize {
+        self.throttled_messages.load(Ordering::Relaxed)
+    }
+
+    /// Registers a message dropped because its type is not allowed for the connection.
+    pub fn record_filtered_message(&self) {
+        self.filtered_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of messages dropped because their types are not allowed for
+    /// the connections, see `MessageFilter`.
+    pub fn filtered_messages(&self) -> usize {
+        self.filtered_messages.load(Ordering::Relaxed)
+    }
+
+    /// Accounts a message of the given length received from a peer.
+    pub fn record_received_message(&self, len: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Accounts a message of the given length written to a peer.
+    pub fn record_sent_message(&self, len: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Returns the number of messages received from all the peers.
+    pub fn messages_received(&self) -> usize {
+        self.messages_received.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total length of the messages received from all the peers.
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of messages written to all the peers.
+    pub fn messages_sent(&self) -> usize {
+        self.messages_sent.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total length of the messages written to all the peers.
+    pub fn bytes_sent(&self) -> usize {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Registers a read from a peer paused because the events channel was full.
+    pub fn record_paused_read(&self) {
+        self.paused_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of times reading from a peer has been paused because
+    /// the events channel was full.
+    pub fn paused_reads(&self) -> usize {
+        self.paused_reads.load(Ordering::Relaxed)
+    }
+
+    /// Registers an attempt to dial a peer which has failed or timed out.
+    pub fn record_failed_dial(&self) {
+        self.failed_dials.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of failed or timed out attempts to dial peers.
+    pub fn failed_dials(&self) -> usize {
+        self.failed_dials.load(Ordering::Relaxed)
+    }
+
+    /// Registers an attempt to connect to a peer which has failed with the given error.
+    pub fn record_connect_error(&self, error: ConnectError) {
+        self.connect_errors[error as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of attempts to connect to peers which have failed with
+    /// the given error.
+    pub fn connect_errors(&self, error: ConnectError) -> usize {
+        self.connect_errors[error as usize].load(Ordering::Relaxed)
+    }
+
+    /// Accounts the time from dialing a peer to the completion of the handshake with it.
+    pub fn record_connect_latency(&self, duration: Duration) {
+        self.connect_latency.record(duration);
+    }
+
+    /// Returns the histogram of the times from dialing peers to the completion
+    /// of the handshakes with them, including the protocol version exchange and
+    /// the authentication. Peers which are reachable, but slow to handshake, e.g., because
+    /// they are overloaded, are found in the upper buckets.
+    pub fn connect_latency(&self) -> &LatencyHistogram {
+        &self.connect_latency
+    }
+}
+
+/// Counters of the events produced by the `InternalPart`.
+#[derive(Debug, Default)]
+pub struct InternalMetrics {
+    evicted_timeouts: AtomicUsize,
+}
+
+impl InternalMetrics {
+    /// Creates metrics with all counters set to zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a pending timeout dropped because too many timeouts were scheduled.
+    pub fn record_evicted_timeout(&self) {
+        self.evicted_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of pending timeouts dropped because too many timeouts
+    /// were scheduled.
+    pub fn evicted_timeouts(&self) -> usize {
+        self.evicted_timeouts.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helpers::{Height, Round};
+    use node::{ExternalMessage, NodeTimeout};
+
+    #[test]
+    fn record_events() {
+        let metrics = EventsMetrics::new();
+
+        metrics.record(&InternalEvent::Timeout(NodeTimeout::PeerExchange).into());
+        metrics.record(&InternalEvent::Timeout(NodeTimeout::UpdateApiState).into());
+        metrics.record(&InternalEvent::JumpToRound(Height(1), Round(2)).into());
+        metrics.record(&ExternalMessage::Rebroadcast.into());
+        assert_eq!(metrics.last_timeout_context(), (None, None));
+        let timeout = NodeTimeout::Propose(Height(3), Round(1));
+        metrics.record(&InternalEvent::Timeout(timeout).into());
+        assert_eq!(
+            metrics.last_timeout_context(),
+            (Some(Height(3)), Some(Round(1)))
+        );
+
+        assert_eq!(metrics.network_events(), 0);
+        assert_eq!(metrics.timeout_events(), 3);
+        assert_eq!(metrics.internal_events(), 1);
+        assert_eq!(metrics.api_events(), 1);
+    }
+
+    #[test]
+    fn aggregator_status_roundtrip() {
+        let metrics = EventsMetrics::new();
+        assert_eq!(metrics.aggregator_status(), AggregatorStatus::default());
+
+        let mut status = AggregatorStatus::default();
+        status.record(&InternalEvent::Timeout(NodeTimeout::PeerExchange).into());
+        status.record(&ExternalMessage::Rebroadcast.into());
+        metrics.record_aggregator_status(status);
+
+        let expected = AggregatorStatus {
+            timeout_ready: true,
+            api_ready: true,
+            ..AggregatorStatus::default()
+        };
+        assert_eq!(metrics.aggregator_status(), expected);
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentiles(), None);
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(1_500));
+        }
+        for _ in 0..7 {
+            histogram.record(Duration::from_millis(15));
+        }
+        for _ in 0..3 {
+            histogram.record(Duration::from_millis(7_000));
+        }
+        let percentiles = histogram.percentiles().unwrap();
+        assert_eq!(percentiles.p50, Duration::from_millis(2));
+        assert_eq!(percentiles.p95, Duration::from_millis(20));
+        // The tail falls into the unbounded bucket.
+        assert_eq!(percentiles.p99, Duration::from_millis(7_000));
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_millis(2)));
+    }
+
+    #[test]
+    fn record_handling_times() {
+        let metrics = EventsMetrics::new();
+        metrics.record_handling(Duration::from_micros(30));
+        metrics.record_handling(Duration::from_micros(400));
+
+        let latency = metrics.handling_latency();
+        assert_eq!(latency.count(), 2);
+        assert_eq!(latency.percentile(50.0), Some(Duration::from_micros(50)));
+        assert_eq!(latency.percentile(100.0), Some(Duration::from_micros(500)));
+    }
+
+    #[test]
+    fn record_timed_events() {
+        let metrics = EventsMetrics::new();
+
+        let mut event = TimedEvent::new(ExternalMessage::Rebroadcast.into());
+        event.received_at -= Duration::from_millis(10);
+        metrics.record_timed(&event);
+
+        assert_eq!(metrics.api_events(), 1);
+        assert!(metrics.dispatch_delay() >= Duration::from_millis(10));
+    }
+}
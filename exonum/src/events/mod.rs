@@ -14,25 +14,77 @@
 
 #![allow(missing_debug_implementations, missing_docs)]
 
-pub use self::internal::InternalPart;
-pub use self::network::{NetworkConfiguration, NetworkEvent, NetworkPart, NetworkRequest};
+pub use self::aggregator::{EventsAggregator, SchedulePolicy, SourceClosure, SourceClosurePolicy};
+pub use self::codec::{BinaryFrameCodec, CompressionKind, FrameCodec, JsonFrameCodec};
+pub use self::internal::{
+    Clock, InternalEventsOverflow, InternalPart, MockClock, SystemClock, TimeoutsTimer,
+};
+pub use self::mask::EventMask;
+pub use self::message_filter::{AllowedMessages, MessageFilter};
+pub use self::metrics::{
+    AggregatorStatus, EventsMetrics, InternalMetrics, LatencyHistogram, LatencyPercentiles,
+    NetworkMetrics,
+};
+pub use self::network::{
+    merge_network, ConnectionActivity, ConnectionStats, DecodeErrorPolicy, Direction,
+    NetworkConfiguration, NetworkEvent, NetworkPart, NetworkRequest, OutgoingQueueOverflow,
+    PeerInfo, PeerTraffic, ResponseSender, SendFailure, SendFailureReason, SendResult,
+};
+pub use self::pipeline::{ChainedHandler, EventMiddleware, Filter};
+pub use self::replay::{EventLog, EventRecorder, ReplayHandlerPart};
+pub use self::resolver::{ResolveFuture, Resolver, SystemResolver};
+pub use self::tls::{TlsConfig, TlsTransport};
+pub use self::transport::{MemoryTransport, TcpTransport, Transport};
+pub use self::watermark::{CountingSender, QueueDepth, QueueDepths, Watermarks};
 
+#[macro_use]
+mod aggregator;
 pub mod codec;
+#[cfg(unix)]
+pub mod control;
 pub mod error;
+pub mod handshake;
 pub mod internal;
+pub mod mask;
+pub mod metrics;
 pub mod network;
 pub mod noise;
+pub mod pipeline;
+pub mod replay;
+pub mod tls;
+pub mod transport;
+pub mod watermark;
+
+mod flow_control;
+mod message_filter;
+mod outgoing;
+mod rate_limit;
+mod resolver;
+mod spans;
+mod timer_wheel;
 
+use failure;
 use futures::{
-    sink::Wait, sync::mpsc::{self, Sender}, Async, Future, Poll, Stream,
+    future::{self, Executor}, sink::Wait, sync::{mpsc::{self, Sender, TrySendError}, oneshot},
+    Async, Future, Poll, Sink, Stream,
 };
+use tokio::timer::Interval;
+use tokio_core::reactor::Handle;
 
-use std::{cmp::Ordering, time::SystemTime};
+use std::{
+    cmp::Ordering, panic::{self, AssertUnwindSafe}, sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use blockchain::Transaction;
+use self::{
+    error::{into_failure, HandlerError}, mask::MaskedStream, noise::HandshakeParams,
+    spans::EventSpan, watermark::{CountingReceiver, WatermarkStream},
+};
+use blockchain::{panic_description, Transaction};
+use crypto::Hash;
 use helpers::{Height, Round};
 use messages::RawTransaction;
-use node::{ExternalMessage, NodeTimeout};
+use node::{EventsPoolCapacity, ExternalMessage, NodeTimeout};
 
 #[cfg(all(test, feature = "long_benchmarks"))]
 mod benches;
@@ -53,21 +105,89 @@ pub enum InternalEvent {
     Shutdown,
     /// Transaction has been successfully verified.
     TxVerified(RawTransaction),
+    /// Retry handling of the propose with the given hash at the given height and round,
+    /// e.g., once its missing transactions have arrived.
+    RetryPropose(Height, Round, Hash),
+    /// Perform the deferred work, e.g., large storage writes, in a dedicated tick
+    /// of the event loop. Dispatched to `EventHandler::handle_flush`.
+    Flush,
+    /// Periodic maintenance tick scheduled at the given moment, see
+    /// `HandlerPart::tick_interval`. Dispatched to `EventHandler::handle_tick`.
+    Tick(Instant),
 }
 
 #[derive(Debug)]
 /// Asynchronous requests for internal actions.
 pub enum InternalRequest {
     Timeout(TimeoutRequest),
+    /// Fires the timeout at the given time and then after each interval, until the timeout
+    /// is cancelled with the handle of the request. Repeating timeouts are always scheduled
+    /// at their exact deadlines and do not count towards `InternalPart::timeouts_capacity`.
+    RepeatingTimeout(TimeoutRequest, Duration),
+    /// Cancels the scheduled timeout if it has not fired yet; a repeating timeout
+    /// is not re-armed anymore.
+    CancelTimeout(TimeoutHandle),
+    /// Cancels the scheduled timeouts referring to the heights below the given one,
+    /// which become obsolete once the node commits a block.
+    CancelTimeoutsBefore(Height),
     JumpToRound(Height, Round),
     Shutdown,
     /// Async request to verify a transaction in the thread pool.
     VerifyTx(Box<dyn Transaction>),
+    /// Schedules handling of the propose in the next tick of the event loop.
+    RetryPropose(Height, Round, Hash),
+    /// Schedules `InternalEvent::Flush`.
+    Flush,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Request to fire the timeout at the given time.
+///
+/// Requests are ordered by time in ascending order. Requests with the same time are ordered
+/// by the kind of the timeout, so that the consensus timeouts fire first: `Round`, `Propose`,
+/// `Status`, `Request`, `UpdateApiState` and `PeerExchange`. Timeouts of the same kind are
+/// ordered by their contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TimeoutRequest(pub SystemTime, pub NodeTimeout);
 
+/// Wrapper reversing the order of timeout requests, so that `BinaryHeap<EarliestFirst>`,
+/// which is a max-heap, pops the request with the earliest time first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EarliestFirst(pub TimeoutRequest);
+
+/// Opaque identifier of a scheduled timeout which can be used to cancel it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutHandle(TimeoutRequest);
+
+impl TimeoutRequest {
+    /// Returns the handle identifying this timeout.
+    pub fn handle(&self) -> TimeoutHandle {
+        TimeoutHandle(self.clone())
+    }
+
+    // Rank of the timeout among the ones with the same time; lower ranks fire first.
+    fn priority(&self) -> u8 {
+        match self.1 {
+            NodeTimeout::Round(..) => 0,
+            NodeTimeout::Propose(..) => 1,
+            NodeTimeout::Status(..) => 2,
+            NodeTimeout::Request(..) => 3,
+            NodeTimeout::UpdateApiState => 4,
+            NodeTimeout::PeerExchange => 5,
+        }
+    }
+}
+
+impl TimeoutHandle {
+    /// Returns `true` if the handle identifies the given timeout request.
+    pub fn matches(&self, request: &TimeoutRequest) -> bool {
+        self.0 == *request
+    }
+
+    pub(crate) fn request(&self) -> &TimeoutRequest {
+        &self.0
+    }
+}
+
 #[derive(Debug)]
 pub enum Event {
     Network(NetworkEvent),
@@ -75,30 +195,708 @@ pub enum Event {
     Internal(InternalEvent),
 }
 
+impl Event {
+    /// Returns `true` if the event should be handled ahead of the other ready events.
+    pub fn is_high_priority(&self) -> bool {
+        match *self {
+            Event::Api(ref message) => message.is_high_priority(),
+            Event::Network(_) | Event::Internal(_) => false,
+        }
+    }
+
+    /// Returns a compact description of the event suitable for logs: the kind and variant
+    /// of the event, the height and round it refers to, and, for network messages,
+    /// the message type and sender. Payloads, e.g., transactions, are omitted.
+    pub fn summary(&self) -> String {
+        let mut summary = format!("{} {}", self.kind(), self.variant());
+        match *self {
+            Event::Network(NetworkEvent::MessageReceived(address, ref key, ref message)) => {
+                summary += &format!(
+                    " service={} type={} from={} key={}",
+                    message.service_id(),
+                    message.message_type(),
+                    address,
+                    key
+                );
+            }
+            Event::Network(NetworkEvent::PeerConnected(address, _))
+            | Event::Network(NetworkEvent::PeerDisconnected(address))
+            | Event::Network(NetworkEvent::UnableConnectToPeer(address)) => {
+                summary += &format!(" peer={}", address);
+            }
+            Event::Network(NetworkEvent::DecodeError { peer, ref error }) => {
+                summary += &format!(" peer={} error=\"{}\"", peer, error);
+            }
+            Event::Network(NetworkEvent::StreamError { source, ref error }) => {
+                summary += &format!(" source={} error=\"{}\"", source, error);
+            }
+            Event::Network(NetworkEvent::HighWatermark(depth))
+            | Event::Network(NetworkEvent::LowWatermark(depth)) => {
+                summary += &format!(" depth={}", depth);
+            }
+            _ => {}
+        }
+        let (height, round) = self.position();
+        if let Some(height) = height {
+            summary += &format!(" height={}", height);
+        }
+        if let Some(round) = round {
+            summary += &format!(" round={}", round);
+        }
+        summary
+    }
+
+    /// Returns the kind of the event: `network`, `api`, `timeout` or `internal`.
+    fn kind(&self) -> &'static str {
+        match *self {
+            Event::Network(_) => "network",
+            Event::Api(_) => "api",
+            Event::Internal(InternalEvent::Timeout(_)) => "timeout",
+            Event::Internal(_) => "internal",
+        }
+    }
+
+    fn variant(&self) -> &'static str {
+        match *self {
+            Event::Network(ref event) => match *event {
+                NetworkEvent::MessageReceived(..) => "MessageReceived",
+                NetworkEvent::PeerConnected(..) => "PeerConnected",
+                NetworkEvent::PeerDisconnected(..) => "PeerDisconnected",
+                NetworkEvent::UnableConnectToPeer(..) => "UnableConnectToPeer",
+                NetworkEvent::DecodeError { .. } => "DecodeError",
+                NetworkEvent::StreamError { .. } => "StreamError",
+                NetworkEvent::HighWatermark(..) => "HighWatermark",
+                NetworkEvent::LowWatermark(..) => "LowWatermark",
+            },
+            Event::Api(ref message) => match *message {
+                ExternalMessage::PeerAdd(..) => "PeerAdd",
+                ExternalMessage::Transaction(..) => "Transaction",
+                ExternalMessage::Enable(..) => "Enable",
+                ExternalMessage::Shutdown => "Shutdown",
+                ExternalMessage::Rebroadcast => "Rebroadcast",
+                ExternalMessage::ConnectionsActivity(..) => "ConnectionsActivity",
+                ExternalMessage::PeerInfoRequest(..) => "PeerInfoRequest",
+                ExternalMessage::Expiring { .. } => "Expiring",
+            },
+            Event::Internal(ref event) => match *event {
+                InternalEvent::Timeout(ref timeout) => match *timeout {
+                    NodeTimeout::Status(..) => "Status",
+                    NodeTimeout::Round(..) => "Round",
+                    NodeTimeout::Request(..) => "Request",
+                    NodeTimeout::Propose(..) => "Propose",
+                    NodeTimeout::UpdateApiState => "UpdateApiState",
+                    NodeTimeout::PeerExchange => "PeerExchange",
+                },
+                InternalEvent::JumpToRound(..) => "JumpToRound",
+                InternalEvent::Shutdown => "Shutdown",
+                InternalEvent::TxVerified(..) => "TxVerified",
+                InternalEvent::RetryPropose(..) => "RetryPropose",
+                InternalEvent::Flush => "Flush",
+                InternalEvent::Tick(..) => "Tick",
+            },
+        }
+    }
+
+    /// Returns the height and round the event refers to, if any.
+    fn position(&self) -> (Option<Height>, Option<Round>) {
+        match *self {
+            Event::Internal(InternalEvent::Timeout(ref timeout)) => timeout.context(),
+            Event::Internal(InternalEvent::JumpToRound(height, round))
+            | Event::Internal(InternalEvent::RetryPropose(height, round, _)) => {
+                (Some(height), Some(round))
+            }
+            _ => (None, None),
+        }
+    }
+}
+
+/// Event accompanied by the moment it has been yielded by the `EventsAggregator`.
+#[derive(Debug)]
+pub struct TimedEvent {
+    pub received_at: Instant,
+    pub event: Event,
+}
+
+impl TimedEvent {
+    /// Wraps the event received at the current moment.
+    pub fn new(event: Event) -> Self {
+        Self {
+            received_at: Instant::now(),
+            event,
+        }
+    }
+}
+
 pub trait EventHandler {
     fn handle_event(&mut self, event: Event);
+
+    /// Handles the event and reports an unrecoverable error, which stops the event loop.
+    /// By default the event is passed to `handle_event` (or `handle_flush`), which never fails.
+    fn try_handle_event(&mut self, event: Event) -> Result<(), HandlerError> {
+        dispatch_event(self, event);
+        Ok(())
+    }
+
+    /// Handles a batch of events which were ready at the same time. This method is used
+    /// instead of `handle_event` if `HandlerPart::max_batch` is greater than one.
+    fn handle_events(&mut self, events: Vec<Event>) {
+        for event in events {
+            dispatch_event(self, event);
+        }
+    }
+
+    /// Handles `InternalEvent::Flush`, which the handler may schedule to itself with
+    /// `InternalRequest::Flush` to perform heavy work between the other events.
+    fn handle_flush(&mut self) {}
+
+    /// Handles `InternalEvent::Tick`, which is delivered periodically if
+    /// `HandlerPart::tick_interval` is set, e.g., to request unknown transactions.
+    fn handle_tick(&mut self, _scheduled_at: Instant) {}
+
+    /// Fallible counterpart of `handle_events`. By default the events are passed to
+    /// `handle_events`, so handlers overriding `try_handle_event` should override this method
+    /// as well if batching is enabled.
+    fn try_handle_events(&mut self, events: Vec<Event>) -> Result<(), HandlerError> {
+        self.handle_events(events);
+        Ok(())
+    }
+
+    /// Invoked once the event loop has stopped, e.g. after `InternalEvent::Shutdown`
+    /// has been received and the queued internal events have been handled.
+    /// It is not invoked if the loop has been stopped by a `HandlerError`.
+    fn handle_shutdown(&mut self) {}
+}
+
+/// Passes `InternalEvent::Flush` to `handle_flush`, `InternalEvent::Tick` to `handle_tick`
+/// and the other events to `handle_event`.
+fn dispatch_event<H: EventHandler + ?Sized>(handler: &mut H, event: Event) {
+    match event {
+        Event::Internal(InternalEvent::Flush) => handler.handle_flush(),
+        Event::Internal(InternalEvent::Tick(scheduled_at)) => handler.handle_tick(scheduled_at),
+        event => handler.handle_event(event),
+    }
+}
+
+/// Future returned by the `AsyncEventHandler`.
+pub type HandlerFuture = Box<dyn Future<Item = (), Error = HandlerError>>;
+
+/// Event handler which processes events asynchronously, e.g., commits blocks on a thread pool
+/// without blocking the event loop. Every `EventHandler` is an `AsyncEventHandler` whose
+/// futures are already completed.
+pub trait AsyncEventHandler {
+    /// Starts handling of the event. The next event is not dispatched until the returned
+    /// future completes; if the future fails, the event loop is stopped with the error.
+    fn handle_event(&mut self, event: Event) -> HandlerFuture;
+
+    /// Starts handling of a batch of events. By default the events are passed to
+    /// `handle_event` in order, and the returned futures are awaited together.
+    fn handle_events(&mut self, events: Vec<Event>) -> HandlerFuture {
+        let futures: Vec<_> = events
+            .into_iter()
+            .map(|event| self.handle_event(event))
+            .collect();
+        Box::new(future::join_all(futures).map(drop))
+    }
+
+    /// Invoked once the event loop has stopped.
+    fn handle_shutdown(&mut self) {}
+}
+
+impl<H: EventHandler> AsyncEventHandler for H {
+    fn handle_event(&mut self, event: Event) -> HandlerFuture {
+        Box::new(future::result(self.try_handle_event(event)))
+    }
+
+    fn handle_events(&mut self, events: Vec<Event>) -> HandlerFuture {
+        Box::new(future::result(self.try_handle_events(events)))
+    }
+
+    fn handle_shutdown(&mut self) {
+        EventHandler::handle_shutdown(self);
+    }
 }
 
 #[derive(Debug)]
-pub struct HandlerPart<H: EventHandler> {
+pub struct HandlerPart<H: AsyncEventHandler> {
     pub handler: H,
     pub internal_rx: mpsc::Receiver<InternalEvent>,
     pub network_rx: mpsc::Receiver<NetworkEvent>,
     pub api_rx: mpsc::Receiver<ExternalMessage>,
+    pub metrics: Arc<EventsMetrics>,
+    /// Maximum number of ready events passed to `AsyncEventHandler::handle_events` at once.
+    /// Events are dispatched one by one via `AsyncEventHandler::handle_event` if it is set
+    /// to `1`.
+    pub max_batch: usize,
+    /// Weights of internal, network and api events in the aggregator.
+    pub schedule_policy: SchedulePolicy,
+    /// Reactions to the closure of the internal, network and api channels. By default
+    /// the closure is ignored, and the remaining channels keep being polled.
+    pub closure_policy: SourceClosurePolicy,
+    /// Interval of `InternalEvent::Tick`; `None` disables ticks. If the event loop is busy,
+    /// the missed ticks are skipped, and only the latest one is delivered.
+    pub tick_interval: Option<Duration>,
+    /// Depths of the network events queue at which `NetworkEvent::HighWatermark` and
+    /// `NetworkEvent::LowWatermark` are delivered; `None` disables them.
+    pub network_watermarks: Option<Watermarks>,
+    /// Gauges of the numbers of the queued events of each source. The depths should be
+    /// shared with the senders of the events, e.g., `NetworkPart::events_depth`
+    /// and `InternalPart::events_depth`.
+    pub queue_depths: QueueDepths,
+    /// Kinds of events delivered to the handler; the others are skipped, and the sources
+    /// which cannot yield any of the selected kinds are not polled.
+    pub event_mask: EventMask,
+    /// Catches panics of the handler while it is given an event (or a batch), so that
+    /// the event is skipped instead of bringing the node down; the panics are logged
+    /// along with the event summaries and counted in `EventsMetrics::handler_panics`.
+    ///
+    /// The handler may be left in an inconsistent state by the interrupted call, e.g.,
+    /// with a half-applied update of the consensus state, so the node may misbehave
+    /// afterwards. Panics of the futures returned by an `AsyncEventHandler` are not caught.
+    pub recover_panics: bool,
+    /// Log to which the dispatched events are written in order, so that they can be replayed
+    /// by `ReplayHandlerPart`; `None` disables recording. Recording stops if the log fails.
+    pub recorder: Option<EventRecorder>,
 }
 
-impl<H: EventHandler + 'static> HandlerPart<H> {
-    pub fn run(self) -> Box<dyn Future<Item = (), Error = ()>> {
-        let mut handler = self.handler;
+impl<H: AsyncEventHandler> HandlerPart<H> {
+    pub fn new(
+        handler: H,
+        internal_rx: mpsc::Receiver<InternalEvent>,
+        network_rx: mpsc::Receiver<NetworkEvent>,
+        api_rx: mpsc::Receiver<ExternalMessage>,
+    ) -> Self {
+        Self::with_metrics(handler, internal_rx, network_rx, api_rx, Arc::default())
+    }
 
-        let fut = EventsAggregator::new(self.internal_rx, self.network_rx, self.api_rx).for_each(
-            move |event| {
-                handler.handle_event(event);
-                Ok(())
-            },
-        );
+    /// Creates a handler part which reports dispatched events to the given `metrics`.
+    pub fn with_metrics(
+        handler: H,
+        internal_rx: mpsc::Receiver<InternalEvent>,
+        network_rx: mpsc::Receiver<NetworkEvent>,
+        api_rx: mpsc::Receiver<ExternalMessage>,
+        metrics: Arc<EventsMetrics>,
+    ) -> Self {
+        HandlerPart {
+            handler,
+            internal_rx,
+            network_rx,
+            api_rx,
+            metrics,
+            max_batch: 1,
+            // Internal events, including timeouts, get half of the dispatch slots under load,
+            // so that consensus keeps going under a network flood.
+            schedule_policy: SchedulePolicy::weighted(vec![2, 1, 1]),
+            closure_policy: SourceClosurePolicy::default(),
+            tick_interval: None,
+            network_watermarks: None,
+            queue_depths: QueueDepths::default(),
+            event_mask: EventMask::default(),
+            recover_panics: false,
+            recorder: None,
+        }
+    }
+
+    /// Creates a handler part along with the sender which injects events into its event loop.
+    pub fn with_sender(handler: H, capacity: &EventsPoolCapacity) -> (Self, EventSender) {
+        let (internal_tx, internal_rx) = mpsc::channel(capacity.internal_events_capacity);
+        let (network_tx, network_rx) = mpsc::channel(capacity.network_events_capacity);
+        let (api_tx, api_rx) = mpsc::channel(capacity.api_requests_capacity);
+        let queue_depths = QueueDepths::default();
+        let sender = EventSender {
+            internal: CountingSender::new(internal_tx, queue_depths.internal.clone()),
+            network: CountingSender::new(network_tx, queue_depths.network.clone()),
+            api: CountingSender::new(api_tx, queue_depths.api.clone()),
+        };
+        let mut handler_part = Self::new(handler, internal_rx, network_rx, api_rx);
+        handler_part.queue_depths = queue_depths;
+        (handler_part, sender)
+    }
+
+    /// Runs the event loop. The returned future fails if the handler reports
+    /// an unrecoverable error.
+    pub fn run(self) -> EventLoop<H> {
+        let mask = self.event_mask;
+        let depths = self.queue_depths;
+        let internal = CountingReceiver::new(self.internal_rx, depths.internal);
+        let internal = Ticks::new(Coalesced::new(internal), self.tick_interval);
+        let internal = MaskedStream::new(internal, mask, EventMask::TIMEOUT | EventMask::INTERNAL);
+        let network =
+            WatermarkStream::new(self.network_rx, depths.network, self.network_watermarks);
+        let network = MaskedStream::new(network, mask, EventMask::NETWORK);
+        let api = CountingReceiver::new(self.api_rx, depths.api);
+        let api = SkipExpired::new(api, Arc::clone(&self.metrics));
+        let api = MaskedStream::new(api, mask, EventMask::API);
+        EventLoop {
+            handler: self.handler,
+            events: EventsAggregator::new(internal, network, api)
+                .with_policy(self.schedule_policy)
+                .with_closure_policy(self.closure_policy),
+            metrics: self.metrics,
+            max_batch: self.max_batch,
+            recover_panics: self.recover_panics,
+            recorder: self.recorder,
+            pending: None,
+            status: AggregatorStatus::default(),
+            idle_since: None,
+            started: None,
+        }
+    }
+
+    /// Runs the event loop which fires `started` once it has been polled for the first time
+    /// and has subscribed to all event sources. Events sent after the signal are guaranteed
+    /// to wake up the event loop.
+    pub fn run_with_started_signal(self, started: oneshot::Sender<()>) -> EventLoop<H> {
+        let mut event_loop = self.run();
+        event_loop.started = Some(started);
+        event_loop
+    }
+
+    /// Runs the event loop which keeps dispatching the already queued network, api and
+    /// internal events after `InternalEvent::Shutdown` until none of them is ready, but no
+    /// longer than `timeout`.
+    pub fn run_until_drained(self, timeout: Duration) -> EventLoop<H> {
+        let mut event_loop = self.run();
+        event_loop.events = event_loop.events.drain_on_shutdown(timeout);
+        event_loop
+    }
+}
+
+/// Sender of the events into the event loop of a `HandlerPart`, e.g., to push synthetic
+/// events in tests or admin tooling. The event loop completes once the sender is dropped
+/// and the queued events are handled.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    internal: CountingSender<InternalEvent>,
+    network: CountingSender<NetworkEvent>,
+    api: CountingSender<ExternalMessage>,
+}
+
+impl EventSender {
+    /// Sends a network event, waiting for the space in the channel.
+    pub fn send_network(&self, event: NetworkEvent) -> Result<(), failure::Error> {
+        send_event(&self.network, event)
+    }
+
+    /// Sends a timeout, waiting for the space in the channel.
+    pub fn send_timeout(&self, timeout: NodeTimeout) -> Result<(), failure::Error> {
+        self.send_internal(InternalEvent::Timeout(timeout))
+    }
+
+    /// Sends an api message, waiting for the space in the channel.
+    pub fn send_api(&self, message: ExternalMessage) -> Result<(), failure::Error> {
+        send_event(&self.api, message)
+    }
+
+    /// Sends an api message if there is space in the channel, without blocking. The error
+    /// tells whether the channel is full or the event loop is gone, see
+    /// `TrySendError::is_full`, and gives the message back.
+    ///
+    /// The space is accounted per sender, so a clone of the sender may send a message
+    /// even if the channel is full for the original one.
+    pub fn try_send_api(
+        &mut self,
+        message: ExternalMessage,
+    ) -> Result<(), TrySendError<ExternalMessage>> {
+        self.api.try_send(message)
+    }
+
+    /// Sends an internal event, waiting for the space in the channel.
+    pub fn send_internal(&self, event: InternalEvent) -> Result<(), failure::Error> {
+        send_event(&self.internal, event)
+    }
+}
+
+/// Forwarder of the api messages from the channel of `ApiSender` to the handler part, which
+/// counts them in `QueueDepths::api` on the way. The senders of the api messages are not
+/// aware of the gauge, so the node receives the messages from them and resends them into
+/// the channel of the handler through `CountingSender`.
+#[derive(Debug)]
+pub struct ApiPart {
+    pub api_rx: mpsc::Receiver<ExternalMessage>,
+    pub api_tx: CountingSender<ExternalMessage>,
+}
+
+impl ApiPart {
+    /// Runs the forwarder until the senders of the api messages or the handler part
+    /// are gone.
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        self.api_rx
+            .forward(self.api_tx.sink_map_err(drop))
+            .map(drop)
+    }
+}
+
+/// Handler, network and internal parts of a node, which are run together on a single reactor.
+#[derive(Debug)]
+pub struct NodeParts<H: AsyncEventHandler, T = TcpTransport> {
+    pub handler_part: HandlerPart<H>,
+    pub network_part: NetworkPart<T>,
+    pub internal_part: InternalPart,
+    /// Forwarder of the api messages to the handler part; the handler part receives them
+    /// directly if it is `None`.
+    pub api_part: Option<ApiPart>,
+}
 
-        to_box(fut)
+impl<H, T> NodeParts<H, T>
+where
+    H: AsyncEventHandler + 'static,
+    T: Transport + 'static,
+{
+    /// Runs all the parts on the reactor of `handle`. The returned future resolves only after
+    /// all of them have stopped, and fails as soon as any of them fails.
+    ///
+    /// Once the event loop of the handler stops, e.g., after `InternalEvent::Shutdown`,
+    /// the network part is shut down with `NetworkRequest::Shutdown`, so that its sockets
+    /// are closed. The internal part stops once all the senders of internal requests,
+    /// e.g., the one of the handler, are dropped.
+    pub fn join<E>(
+        self,
+        handle: &Handle,
+        handshake_params: &HandshakeParams,
+        verify_executor: E,
+    ) -> impl Future<Item = (), Error = failure::Error>
+    where
+        E: Executor<Box<dyn Future<Item = (), Error = ()> + Send>>,
+    {
+        let network_requests = self.network_part.network_requests.0.clone();
+        let handler = self
+            .handler_part
+            .run()
+            .map_err(failure::Error::from)
+            .and_then(move |()| {
+                // The network part may have been shut down already.
+                network_requests
+                    .send(NetworkRequest::Shutdown)
+                    .then(|_| Ok::<_, failure::Error>(()))
+            });
+        let network = self.network_part.run(handle, handshake_params);
+        let internal = self
+            .internal_part
+            .run(handle.clone(), verify_executor)
+            .map_err(|()| format_err!("Internal part has failed"));
+        if let Some(api_part) = self.api_part {
+            handle.spawn(api_part.run());
+        }
+        handler.join3(network, internal).map(drop)
+    }
+}
+
+fn send_event<T>(sender: &CountingSender<T>, event: T) -> Result<(), failure::Error>
+where
+    T: Send + Sync + 'static,
+{
+    sender
+        .clone()
+        .send(event)
+        .wait()
+        .map(drop)
+        .map_err(into_failure)
+}
+
+type HandlerEvents = EventsAggregator<
+    MaskedStream<Ticks<Coalesced<CountingReceiver<mpsc::Receiver<InternalEvent>>>>>,
+    MaskedStream<WatermarkStream<mpsc::Receiver<NetworkEvent>>>,
+    MaskedStream<SkipExpired<CountingReceiver<mpsc::Receiver<ExternalMessage>>>>,
+>;
+
+/// Future dispatching events to the handler, which is returned by `HandlerPart::run`.
+/// It completes once the event sources are exhausted, or fails if the handler reports
+/// an unrecoverable error.
+pub struct EventLoop<H: AsyncEventHandler> {
+    handler: H,
+    events: HandlerEvents,
+    metrics: Arc<EventsMetrics>,
+    max_batch: usize,
+    recover_panics: bool,
+    recorder: Option<EventRecorder>,
+    // Future of the event (or batch) being handled at the moment along with its span
+    // and the moment the handler has been given the event.
+    pending: Option<(HandlerFuture, EventSpan, Instant)>,
+    // Sources of the events received during the current poll.
+    status: AggregatorStatus,
+    // Moment since which the handler has been waiting for events, if it is waiting.
+    idle_since: Option<Instant>,
+    // Signal fired after the first poll.
+    started: Option<oneshot::Sender<()>>,
+}
+
+impl<H: AsyncEventHandler> EventLoop<H> {
+    fn poll_event(&mut self) -> Poll<Option<Event>, HandlerError> {
+        let polled = self
+            .events
+            .poll()
+            .map_err(|()| HandlerError::new("Event sources failed"))?;
+        if let Async::Ready(Some(ref event)) = polled {
+            self.status.record(event);
+            self.record_to_log(event);
+            if let Some(idle_since) = self.idle_since.take() {
+                self.metrics.record_idle(idle_since.elapsed());
+            }
+        }
+        Ok(polled)
+    }
+
+    fn record_to_log(&mut self, event: &Event) {
+        let failed = match self.recorder {
+            Some(ref mut recorder) => recorder.record(event).err(),
+            None => return,
+        };
+        if let Some(e) = failed {
+            error!(
+                "Cannot record event {}, recording is stopped: {}",
+                event.summary(),
+                e
+            );
+            self.recorder = None;
+        }
+    }
+
+    // Called once none of the sources is ready while the handler is free.
+    fn begin_idle(&mut self) {
+        if self.idle_since.is_none() {
+            self.idle_since = Some(Instant::now());
+        }
+    }
+
+    // Calls the handler within the span. If panics are recovered, a panicked call is logged
+    // with the summary of the events and considered completed.
+    fn dispatch<F>(&mut self, span: &EventSpan, summary: Option<String>, call: F) -> HandlerFuture
+    where
+        F: FnOnce(&mut H) -> HandlerFuture,
+    {
+        let handler = &mut self.handler;
+        let summary = match summary {
+            Some(summary) => summary,
+            None => return span.in_scope(|| call(handler)),
+        };
+        match panic::catch_unwind(AssertUnwindSafe(|| span.in_scope(|| call(handler)))) {
+            Ok(handled) => handled,
+            Err(payload) => {
+                self.metrics.record_handler_panic();
+                let description = panic_description(&payload);
+                error!(
+                    "Event handler panicked on {}: {}",
+                    summary,
+                    description.as_ref().map_or("unknown panic", String::as_str)
+                );
+                Box::new(future::ok(()))
+            }
+        }
+    }
+
+    fn record_event(&mut self, event: Event) -> Event {
+        let timed = TimedEvent::new(event);
+        self.metrics.record_timed(&timed);
+        timed.into()
+    }
+
+    /// Collects up to `max_batch` events which are ready at the moment. Unlike `Stream::chunks`,
+    /// a batch is yielded as soon as the events are not ready, even if the batch is not full.
+    fn poll_batch(&mut self) -> Poll<Option<Vec<Event>>, HandlerError> {
+        let mut batch = Vec::new();
+        while batch.len() < self.max_batch {
+            match self.poll_event()? {
+                Async::Ready(Some(event)) => batch.push(TimedEvent::new(event)),
+                Async::Ready(None) if batch.is_empty() => return Ok(Async::Ready(None)),
+                Async::NotReady if batch.is_empty() => return Ok(Async::NotReady),
+                Async::Ready(None) | Async::NotReady => break,
+            }
+        }
+
+        let metrics = &self.metrics;
+        let events = batch
+            .into_iter()
+            .map(|timed| {
+                metrics.record_timed(&timed);
+                timed.into()
+            })
+            .collect();
+        Ok(Async::Ready(Some(events)))
+    }
+}
+
+impl<H: AsyncEventHandler> Future for EventLoop<H> {
+    type Item = ();
+    type Error = HandlerError;
+
+    fn poll(&mut self) -> Poll<(), HandlerError> {
+        self.status = AggregatorStatus::default();
+        let polled = self.poll_events();
+        self.metrics.record_aggregator_status(self.status);
+        if polled.is_ok() {
+            if let Some(started) = self.started.take() {
+                // The receiver may be not interested in the signal anymore.
+                let _ = started.send(());
+            }
+        }
+        polled
+    }
+}
+
+impl<H: AsyncEventHandler> EventLoop<H> {
+    /// Dispatches events until the handler or the event sources are not ready.
+    fn poll_events(&mut self) -> Poll<(), HandlerError> {
+        loop {
+            let handled = match self.pending {
+                Some((ref mut pending, ref span, _)) => span.in_scope(|| pending.poll())?,
+                None => Async::Ready(()),
+            };
+            if handled.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            if let Some((_, span, started_at)) = self.pending.take() {
+                span.finish();
+                self.metrics.record_handling(started_at.elapsed());
+            }
+
+            let pending = if self.max_batch > 1 {
+                match self.poll_batch()? {
+                    Async::Ready(Some(events)) => {
+                        let span = EventSpan::batch(&events);
+                        let summary = if self.recover_panics {
+                            let summaries: Vec<_> = events.iter().map(Event::summary).collect();
+                            Some(format!("batch [{}]", summaries.join(", ")))
+                        } else {
+                            None
+                        };
+                        let started_at = Instant::now();
+                        let handled =
+                            self.dispatch(&span, summary, |handler| handler.handle_events(events));
+                        (handled, span, started_at)
+                    }
+                    Async::Ready(None) => break,
+                    Async::NotReady => {
+                        self.begin_idle();
+                        return Ok(Async::NotReady);
+                    }
+                }
+            } else {
+                match self.poll_event()? {
+                    Async::Ready(Some(event)) => {
+                        let event = self.record_event(event);
+                        let span = EventSpan::new(&event);
+                        let summary = if self.recover_panics {
+                            Some(event.summary())
+                        } else {
+                            None
+                        };
+                        let started_at = Instant::now();
+                        let handled =
+                            self.dispatch(&span, summary, |handler| handler.handle_event(event));
+                        (handled, span, started_at)
+                    }
+                    Async::Ready(None) => break,
+                    Async::NotReady => {
+                        self.begin_idle();
+                        return Ok(Async::NotReady);
+                    }
+                }
+            };
+            self.pending = Some(pending);
+        }
+
+        self.handler.handle_shutdown();
+        Ok(Async::Ready(()))
     }
 }
 
@@ -116,7 +914,28 @@ impl PartialOrd for TimeoutRequest {
 
 impl Ord for TimeoutRequest {
     fn cmp(&self, other: &Self) -> Ordering {
-        (&self.0, &self.1).cmp(&(&other.0, &other.1)).reverse()
+        self.0
+            .cmp(&other.0)
+            .then_with(|| self.priority().cmp(&other.priority()))
+            .then_with(|| self.1.cmp(&other.1))
+    }
+}
+
+impl PartialOrd for EarliestFirst {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for EarliestFirst {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0).reverse()
+    }
+}
+
+impl Into<Event> for TimedEvent {
+    fn into(self) -> Event {
+        self.event
     }
 }
 
@@ -144,83 +963,183 @@ impl Into<Event> for InternalEvent {
     }
 }
 
-/// Receives timeout, network and api events and invokes `handle_event` method of handler.
-/// If one of these streams closes, the aggregator stream completes immediately.
+/// Merging of the consecutive ready events superseding each other, so that a burst of such
+/// events is handled at once.
+pub trait Coalesce: Sized {
+    /// Merges the event ready right after this one into it, e.g., keeping the latest or
+    /// the highest of the two, or dropping the next one. Returns the next event back
+    /// if the events cannot be merged.
+    fn coalesce(&mut self, next: Self) -> Option<Self>;
+}
+
+/// Of the consecutive `JumpToRound` events for the same height, only the one with
+/// the highest round is meaningful.
+impl Coalesce for InternalEvent {
+    fn coalesce(&mut self, next: Self) -> Option<Self> {
+        if let InternalEvent::JumpToRound(height, ref mut round) = *self {
+            if let InternalEvent::JumpToRound(next_height, next_round) = next {
+                if next_height == height {
+                    *round = (*round).max(next_round);
+                    return None;
+                }
+            }
+        }
+        Some(next)
+    }
+}
+
+/// Merges the consecutive ready events of the stream, see `Coalesce`.
 #[derive(Debug)]
-pub struct EventsAggregator<S1, S2, S3>
-where
-    S1: Stream,
-    S2: Stream,
-    S3: Stream,
-{
+struct Coalesced<S: Stream> {
+    stream: S,
+    // Event which has been polled while coalescing and is yielded next.
+    peeked: Option<S::Item>,
     done: bool,
-    internal: S1,
-    network: S2,
-    api: S3,
 }
 
-impl<S1, S2, S3> EventsAggregator<S1, S2, S3>
+impl<S: Stream> Coalesced<S>
 where
-    S1: Stream,
-    S2: Stream,
-    S3: Stream,
+    S::Item: Coalesce,
 {
-    pub fn new(internal: S1, network: S2, api: S3) -> Self {
+    fn new(stream: S) -> Self {
         Self {
+            stream,
+            peeked: None,
             done: false,
-            network,
-            internal,
-            api,
         }
     }
+
+    fn poll_next(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if let Some(event) = self.peeked.take() {
+            return Ok(Async::Ready(Some(event)));
+        }
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        let polled = self.stream.poll()?;
+        if let Async::Ready(None) = polled {
+            self.done = true;
+        }
+        Ok(polled)
+    }
 }
 
-impl<S1, S2, S3> Stream for EventsAggregator<S1, S2, S3>
+impl<S: Stream> Stream for Coalesced<S>
 where
-    S1: Stream<Item = InternalEvent>,
-    S2: Stream<Item = NetworkEvent, Error = S1::Error>,
-    S3: Stream<Item = ExternalMessage, Error = S1::Error>,
+    S::Item: Coalesce,
 {
-    type Item = Event;
-    type Error = S1::Error;
+    type Item = S::Item;
+    type Error = S::Error;
 
-    fn poll(&mut self) -> Poll<Option<Event>, Self::Error> {
-        if self.done {
-            Ok(Async::Ready(None))
-        } else {
-            match self.internal.poll()? {
-                Async::Ready(None) | Async::Ready(Some(InternalEvent::Shutdown)) => {
-                    self.done = true;
-                    return Ok(Async::Ready(None));
-                }
-                Async::Ready(Some(item)) => {
-                    return Ok(Async::Ready(Some(Event::Internal(item))));
-                }
-                Async::NotReady => {}
-            };
-            match self.network.poll()? {
-                Async::Ready(Some(item)) => {
-                    return Ok(Async::Ready(Some(Event::Network(item))));
-                }
-                Async::Ready(None) => {
-                    self.done = true;
-                    return Ok(Async::Ready(None));
-                }
-                Async::NotReady => {}
-            };
-            match self.api.poll()? {
-                Async::Ready(None) => {
-                    self.done = true;
-                    return Ok(Async::Ready(None));
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let mut event = match self.poll_next()? {
+            Async::Ready(Some(event)) => event,
+            other => return Ok(other),
+        };
+
+        while let Async::Ready(Some(next)) = self.poll_next()? {
+            if let Some(next) = event.coalesce(next) {
+                self.peeked = Some(next);
+                break;
+            }
+        }
+        Ok(Async::Ready(Some(event)))
+    }
+}
+
+/// Skips the api messages whose deadline has passed, see `ExternalMessage::Expiring`,
+/// counting them in `EventsMetrics::expired_api_events`, and unwraps the other expiring
+/// messages.
+#[derive(Debug)]
+struct SkipExpired<S> {
+    stream: S,
+    metrics: Arc<EventsMetrics>,
+}
+
+impl<S: Stream<Item = ExternalMessage>> SkipExpired<S> {
+    fn new(stream: S, metrics: Arc<EventsMetrics>) -> Self {
+        Self { stream, metrics }
+    }
+}
+
+impl<S: Stream<Item = ExternalMessage>> Stream for SkipExpired<S> {
+    type Item = ExternalMessage;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<ExternalMessage>, S::Error> {
+        loop {
+            match self.stream.poll()? {
+                Async::Ready(Some(ExternalMessage::Expiring {
+                    message,
+                    expires_at,
+                })) => {
+                    if expires_at > Instant::now() {
+                        return Ok(Async::Ready(Some(*message)));
+                    }
+                    self.metrics.record_expired_api_event();
+                    debug!("Skipping expired api message {:?}", message);
                 }
-                Async::Ready(Some(item)) => {
-                    return Ok(Async::Ready(Some(Event::Api(item))));
+                polled => return Ok(polled),
+            }
+        }
+    }
+}
+
+/// Yields `InternalEvent::Tick` along with the events of the inner stream. Ticks missed
+/// while the stream has not been polled are collapsed into the latest one. The stream ends
+/// along with the inner one.
+#[derive(Debug)]
+struct Ticks<S> {
+    stream: S,
+    interval: Option<Interval>,
+    exhausted: bool,
+}
+
+impl<S: Stream<Item = InternalEvent>> Ticks<S> {
+    fn new(stream: S, period: Option<Duration>) -> Self {
+        let interval = period.map(|period| Interval::new(Instant::now() + period, period));
+        Self {
+            stream,
+            interval,
+            exhausted: false,
+        }
+    }
+
+    fn poll_tick(&mut self) -> Option<Instant> {
+        let mut latest = None;
+        loop {
+            let polled = self.interval.as_mut()?.poll();
+            match polled {
+                Ok(Async::Ready(Some(scheduled_at))) => latest = Some(scheduled_at),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => return latest,
+                Err(e) => {
+                    // Timer errors are not recoverable, e.g., the timer has been shut down.
+                    error!("Ticks are disabled because of the timer error: {}", e);
+                    self.interval = None;
+                    return latest;
                 }
-                Async::NotReady => {}
-            };
+            }
+        }
+    }
+}
 
-            Ok(Async::NotReady)
+impl<S: Stream<Item = InternalEvent>> Stream for Ticks<S> {
+    type Item = InternalEvent;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<InternalEvent>, S::Error> {
+        if self.exhausted {
+            return Ok(Async::Ready(None));
+        }
+        if let Some(scheduled_at) = self.poll_tick() {
+            return Ok(Async::Ready(Some(InternalEvent::Tick(scheduled_at))));
+        }
+        let polled = self.stream.poll()?;
+        if let Async::Ready(None) = polled {
+            self.exhausted = true;
+            self.interval = None;
         }
+        Ok(polled)
     }
 }
 
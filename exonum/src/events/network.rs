@@ -14,51 +14,334 @@
 
 use failure;
 use futures::{
-    future, future::{err, Either}, sync::mpsc, unsync, Future, IntoFuture, Sink, Stream,
+    future, future::{err, Either}, stream, sync::{mpsc, oneshot}, task::{self, Task}, unsync,
+    Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
 };
-use tokio::net::{TcpListener, TcpStream};
 use tokio_codec::Framed;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Interval, Timeout};
 
-use tokio_retry::{
-    strategy::{jitter, FixedInterval}, Retry,
-};
+use tokio_retry::{strategy::jitter, Retry};
 
-use std::{cell::RefCell, collections::HashMap, net::SocketAddr, rc::Rc, time::Duration};
+use std::{
+    cell::{Cell, RefCell}, cmp, collections::{HashMap, HashSet, VecDeque}, fmt, io, mem,
+    net::SocketAddr, rc::Rc, sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use super::{error::log_error, to_box};
+use crypto::{PublicKey, SecretKey};
 use events::{
-    codec::MessagesCodec, error::into_failure, noise::{Handshake, HandshakeParams, NoiseHandshake},
+    codec::{BinaryFrameCodec, CompressionKind, Frame, FrameCodec, MessagesCodec, PROTOCOL_VERSION},
+    error::{
+        into_failure, ConnectError, DecodeError, EventsChannelError, FlowControlViolation,
+        IncompatibleVersion, PingTimeout, RateLimitExceeded, WriteTimeout,
+    },
+    flow_control::{Credited, ReceiveWindow, SendCredits}, handshake,
+    message_filter::{AllowedMessages, MessageFilter}, metrics::NetworkMetrics,
+    noise::{Handshake, HandshakeParams, NoiseHandshake},
+    outgoing::{self, OutgoingReceiver, OutgoingSender}, rate_limit::{PeerRateLimiter, RateLimiter},
+    resolver::{Resolver, SystemResolver}, transport::{TcpTransport, Transport},
+    watermark::{CountingSender, QueueDepth, Watermarks},
 };
 use helpers::Milliseconds;
 use messages::{Any, Connect, Message, RawMessage};
 
-const OUTGOING_CHANNEL_SIZE: usize = 10;
-
 #[derive(Debug)]
 pub enum NetworkEvent {
-    MessageReceived(SocketAddr, RawMessage),
+    /// Message has been received from the peer; the address is the one announced
+    /// in the `Connect` message of the peer, along with its public key.
+    MessageReceived(SocketAddr, PublicKey, RawMessage),
+    /// Connection with the peer has been established; the `Connect` message contains
+    /// the public key of the peer.
     PeerConnected(SocketAddr, Connect),
+    /// Connection with the peer has been closed at our request, because the peer
+    /// has not answered a keep-alive ping in time, has been sending messages above the rate
+    /// limit or the flow control window or malformed frames, or uses an incompatible protocol
+    /// version.
     PeerDisconnected(SocketAddr),
     UnableConnectToPeer(SocketAddr),
+    /// Malformed frame has been received from the peer and skipped, see `DecodeErrorPolicy`.
+    DecodeError { peer: SocketAddr, error: DecodeError },
+    /// Event source at the given position of the aggregator has failed with an error,
+    /// which is recoverable according to `EventsAggregator::isolate_errors`.
+    StreamError { source: usize, error: String },
+    /// Number of the queued network events has reached the high watermark, see
+    /// `NetworkConfiguration::events_watermarks`.
+    HighWatermark(usize),
+    /// Number of the queued network events has dropped to the low watermark after reaching
+    /// the high one.
+    LowWatermark(usize),
 }
 
 #[derive(Debug, Clone)]
 pub enum NetworkRequest {
+    /// Sends the message to the peer, connecting to it if necessary.
+    ///
+    /// Messages sent to the same peer are received by it in the order they have been sent,
+    /// except for consensus messages jumping ahead of the others, see `outgoing::Priority`.
+    /// The order is kept while the connection is being established as well as through
+    /// a reconnection: messages which have not been written before the connection is lost
+    /// are sent first once the peer is reconnected within `reconnect_buffer_timeout`.
+    /// Messages to the peers disconnected with `DisconnectWithPeer` are dropped.
     SendMessage(SocketAddr, RawMessage),
+    /// Sends the message as `SendMessage` does and reports the outcome into the sender
+    /// once known.
+    SendMessageWithAck(SocketAddr, RawMessage, ResponseSender<SendResult>),
     DisconnectWithPeer(SocketAddr),
+    /// Closes the connection with the peer having the given public key, if any, and emits
+    /// `PeerDisconnected`. If the duration is given, connections with the peer are refused
+    /// until it elapses.
+    DisconnectPeer(PublicKey, Option<Duration>),
+    /// Stops reading from the peers while keeping the connections open, so that the peers
+    /// are slowed down by the TCP flow control. Sending messages to the peers is not affected.
+    Pause,
+    /// Resumes reading from the peers after `Pause`.
+    Resume,
+    /// Sends the message to all the connected peers except for the ones with the given
+    /// public keys, e.g., the peer the message has been received from. Peers whose
+    /// outgoing queues are full are handled according to `OutgoingQueueOverflow`.
+    Broadcast(RawMessage, HashSet<PublicKey>),
     Shutdown,
+    /// Requests the activity of the established connections indexed by the peer addresses.
+    ConnectionsActivity(ResponseSender<HashMap<SocketAddr, ConnectionActivity>>),
+    /// Requests the traffic of the established connections indexed by the public keys
+    /// of the peers.
+    PeerTraffic(ResponseSender<HashMap<PublicKey, PeerTraffic>>),
+    /// Requests the list of the connected peers.
+    PeerInfo(ResponseSender<Vec<PeerInfo>>),
+    /// Requests the number of the established connections along with the configured limits,
+    /// e.g., to throttle gossip when the node is close to its capacity.
+    ConnectionStats(ResponseSender<ConnectionStats>),
+    /// Replaces the set of peers the node should be connected to, e.g., after the validators
+    /// have changed. Connections with the peers removed from the previous set are closed
+    /// once their queued messages are flushed, and `PeerDisconnected` is emitted for them;
+    /// the added peers are connected to at the addresses from the `ConnectList`.
+    /// Connections with the peers which have never been in the set are not affected.
+    ReconcilePeers(HashSet<PublicKey>),
+    /// Replaces the network configuration without dropping the established connections.
+    ///
+    /// Rate limits and the overflow policy of the outgoing queues apply to the established
+    /// connections at once; connection limits are checked against the new values when
+    /// the next connection is accepted or dialed, and the connections above a lowered limit
+    /// are kept. The other options, e.g., keep-alive and socket options, the reconnection
+    /// policy and the capacity of the outgoing queues, apply to the connections established
+    /// after the update. `compression`, `frame_checksums` and `events_watermarks` are used
+    /// only on start, so changing them has no effect.
+    UpdateConfig(NetworkConfiguration),
+}
+
+/// Sender of the response to a `NetworkRequest`, created from a `oneshot::Sender`.
+/// The clones of a request share the sender, so the response of the one handled first
+/// is delivered.
+pub struct ResponseSender<T>(Arc<Mutex<Option<oneshot::Sender<T>>>>);
+
+impl<T> ResponseSender<T> {
+    /// Sends the response. Returns it back if it has been sent already or the receiver
+    /// has gone away.
+    pub fn send(&self, response: T) -> Result<(), T> {
+        let sender = self.0.lock().expect("Response sender is poisoned").take();
+        match sender {
+            Some(sender) => sender.send(response),
+            None => Err(response),
+        }
+    }
+}
+
+impl<T> From<oneshot::Sender<T>> for ResponseSender<T> {
+    fn from(sender: oneshot::Sender<T>) -> Self {
+        ResponseSender(Arc::new(Mutex::new(Some(sender))))
+    }
+}
+
+impl<T> Clone for ResponseSender<T> {
+    fn clone(&self) -> Self {
+        ResponseSender(Arc::clone(&self.0))
+    }
+}
+
+impl<T> fmt::Debug for ResponseSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseSender").finish()
+    }
 }
 
+/// Outcome of `NetworkRequest::SendMessageWithAck`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendResult {
+    /// The message has been queued for sending to the peer.
+    Queued,
+    /// The message has been dropped, e.g., because the outgoing queue of the peer is full
+    /// or the connection with the peer cannot be established.
+    Dropped,
+    /// There is no connection with the peer, and the peer is not in the `ConnectList`.
+    PeerUnknown,
+}
+
+/// Message which has not been sent to a peer, see `NetworkPart::subscribe_send_failures`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendFailure {
+    pub peer: SocketAddr,
+    pub service_id: u16,
+    pub message_type: u16,
+    pub reason: SendFailureReason,
+}
+
+/// Reason of a `SendFailure`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendFailureReason {
+    /// The outgoing queue of the peer is full, see `OutgoingQueueOverflow`. With
+    /// `OutgoingQueueOverflow::DropOldest`, the failure is reported for the evicted message.
+    QueueOverflow,
+    /// The peer is not in the `ConnectList`.
+    PeerUnknown,
+    /// The connection with the peer cannot be established, e.g., because the peer
+    /// is banned or does not answer.
+    ConnectFailed,
+    /// The connection with the peer has been closed.
+    ConnectionClosed,
+}
+
+/// Moments at which frames have been received from and sent to a peer for the last time.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConnectionActivity {
+    pub last_received: Option<Instant>,
+    pub last_sent: Option<Instant>,
+}
+
+/// Connected peer reported in response to `NetworkRequest::PeerInfo`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerInfo {
+    pub public_key: PublicKey,
+    /// Address of the peer from its `Connect` message.
+    pub address: SocketAddr,
+    pub direction: Direction,
+    /// Moment a frame has been received from or sent to the peer for the last time.
+    pub last_activity: Option<Instant>,
+}
+
+/// Connections reported in response to `NetworkRequest::ConnectionStats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Number of the established connections accepted from the peers.
+    pub incoming: usize,
+    /// Number of the established connections dialed by this node.
+    pub outgoing: usize,
+    /// Number of the connections being dialed at the moment.
+    pub pending_outgoing: usize,
+    pub max_incoming_connections: usize,
+    pub max_outgoing_connections: usize,
+}
+
+/// Number of messages and their total length in bytes received from and sent to a peer
+/// over a connection. Control frames, e.g., pings, are not accounted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerTraffic {
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+}
+
+/// Action performed when a message is sent to a peer whose outgoing queue is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutgoingQueueOverflow {
+    /// Drop the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Reject the message and disconnect from the peer.
+    Disconnect,
+}
+
+/// Action performed when a malformed frame is received from a peer.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DecodeErrorPolicy {
+    /// Disconnect from the peer.
+    Disconnect,
+    /// Skip the frame and report it as `NetworkEvent::DecodeError`. Frames which cannot be
+    /// skipped, e.g., the ones failing decryption, still cause a disconnection.
+    Report,
+}
+
+/// Network configuration of the node. The parameters missing in the config file take
+/// their default values, so that the configs written by older versions keep loading.
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
 pub struct NetworkConfiguration {
     // TODO: Think more about config parameters. (ECR-162)
+    /// Maximum number of incoming connections, including the ones which have not completed
+    /// the handshake yet. Once the limit is reached, the oldest connection which has not
+    /// completed the handshake is closed to make room for a new one; if there are no such
+    /// connections, new connections are closed right after being accepted.
     pub max_incoming_connections: usize,
     pub max_outgoing_connections: usize,
     pub tcp_nodelay: bool,
     pub tcp_keep_alive: Option<u64>,
+    /// Size of the socket send buffer; `None` keeps the system default.
+    pub tcp_send_buffer: Option<usize>,
+    /// Size of the socket receive buffer; `None` keeps the system default.
+    pub tcp_recv_buffer: Option<usize>,
+    /// Delay before the first reconnection attempt. Outgoing connections which are lost
+    /// are re-established after a random delay below this one.
     pub tcp_connect_retry_timeout: Milliseconds,
     pub tcp_connect_max_retries: u64,
+    /// Factor by which the delay grows after each unsuccessful reconnection attempt;
+    /// the default of `1.0` keeps the delay constant.
+    pub tcp_connect_retry_multiplier: f64,
+    /// Upper bound of the delay between reconnection attempts.
+    pub tcp_connect_retry_max_timeout: Milliseconds,
+    /// Maximum number of outgoing connections being established at the same time.
+    pub max_concurrent_connects: usize,
+    /// Maximum number of messages waiting to be sent to a single peer.
+    pub max_outgoing_queue_len: usize,
+    /// Action performed when the outgoing queue of a peer is full.
+    pub outgoing_queue_overflow: OutgoingQueueOverflow,
+    /// Idle time after which a ping is sent to the peer; `None` disables pings.
+    pub keep_alive_interval: Option<Milliseconds>,
+    /// Time to wait for a pong before the connection is closed.
+    pub keep_alive_timeout: Milliseconds,
+    /// Compression of the sent messages, used only if the peer supports it as well.
+    pub compression: CompressionKind,
+    /// Minimal protocol version of the peers we accept connections with.
+    pub min_protocol_version: u32,
+    /// Maximal protocol version of the peers we accept connections with.
+    pub max_protocol_version: u32,
+    /// Maximum number of messages per second received from a single peer; `None` disables
+    /// the limit. Reading from the peers exceeding the limit is paused.
+    pub max_incoming_rate: Option<u32>,
+    /// Peers exceeding the rate limit longer than this are disconnected.
+    pub max_throttle_duration: Milliseconds,
+    /// Time after which an attempt to dial a peer is aborted. The attempt is then
+    /// repeated according to the reconnection policy.
+    pub connect_timeout: Milliseconds,
+    /// Action performed when a malformed frame is received from a peer.
+    pub decode_error_policy: DecodeErrorPolicy,
+    /// Time after which a connection whose peer does not accept written frames is closed;
+    /// `None` disables the timeout.
+    pub write_timeout: Option<Milliseconds>,
+    /// Time given to flush the queued messages after the peer has closed its writing half
+    /// of the connection; the messages which are not sent by then are dropped.
+    pub half_close_timeout: Milliseconds,
+    /// Requires peers to prove the ownership of the public keys announced in their `Connect`
+    /// messages by signing a random challenge; peers which fail to do so are refused.
+    pub authenticate_peers: bool,
+    /// Appends a CRC32 checksum to the frames sent to the peers supporting it, so that
+    /// corrupted frames are detected, see `DecodeError::ChecksumMismatch`. Peers announce
+    /// the support during the handshake, so the older ones keep receiving plain frames.
+    pub frame_checksums: bool,
+    /// Depths of the network events queue at which the handler is notified with
+    /// `NetworkEvent::HighWatermark` and `NetworkEvent::LowWatermark`; `None` disables
+    /// the notifications.
+    pub events_watermarks: Option<Watermarks>,
+    /// Number of messages a peer may send before they are handed over to the handler;
+    /// `None` disables the flow control. The credits are granted back to the peer as
+    /// the handler catches up; peers exceeding the window are disconnected. Messages to
+    /// the peers granting credits are sent only while there are credits left, regardless
+    /// of this option. Should be enabled only once all the peers support `Frame::Credit`.
+    pub flow_control_window: Option<u32>,
+    /// Time for which the messages not written over a lost connection are kept, to be
+    /// sent first once the peer is reconnected; see `NetworkRequest::SendMessage`.
+    pub reconnect_buffer_timeout: Milliseconds,
 }
 
 impl Default for NetworkConfiguration {
@@ -68,46 +351,144 @@ impl Default for NetworkConfiguration {
             max_outgoing_connections: 128,
             tcp_keep_alive: None,
             tcp_nodelay: true,
+            tcp_send_buffer: None,
+            tcp_recv_buffer: None,
             tcp_connect_retry_timeout: 15_000,
             tcp_connect_max_retries: 10,
+            tcp_connect_retry_multiplier: 1.0,
+            tcp_connect_retry_max_timeout: 120_000,
+            max_concurrent_connects: 16,
+            max_outgoing_queue_len: 512,
+            outgoing_queue_overflow: OutgoingQueueOverflow::DropOldest,
+            keep_alive_interval: Some(30_000),
+            keep_alive_timeout: 10_000,
+            compression: CompressionKind::None,
+            min_protocol_version: PROTOCOL_VERSION,
+            max_protocol_version: PROTOCOL_VERSION,
+            max_incoming_rate: None,
+            max_throttle_duration: 10_000,
+            connect_timeout: 10_000,
+            decode_error_policy: DecodeErrorPolicy::Disconnect,
+            write_timeout: Some(30_000),
+            half_close_timeout: 5_000,
+            authenticate_peers: true,
+            frame_checksums: false,
+            events_watermarks: None,
+            flow_control_window: None,
+            reconnect_buffer_timeout: 5_000,
         }
     }
 }
 
 #[derive(Debug)]
-pub struct NetworkPart {
+pub struct NetworkPart<T = TcpTransport> {
     pub our_connect_message: Connect,
-    pub listen_address: SocketAddr,
+    /// Addresses to accept incoming connections on. Connections accepted on any of them
+    /// are reported into the same stream of network events.
+    pub listen_addresses: Vec<SocketAddr>,
     pub network_config: NetworkConfiguration,
     pub max_message_len: u32,
     pub network_requests: (mpsc::Sender<NetworkRequest>, mpsc::Receiver<NetworkRequest>),
     pub network_tx: mpsc::Sender<NetworkEvent>,
+    /// Number of the events sent into `network_tx` and not handled yet; should be shared
+    /// with the `HandlerPart` receiving the events.
+    pub events_depth: QueueDepth,
+    pub metrics: Arc<NetworkMetrics>,
+    /// Transport used to accept and establish connections.
+    pub transport: T,
+    /// Types of the messages accepted from the peers; by default all the messages
+    /// are accepted.
+    pub message_filter: MessageFilter,
+    /// Endpoints (`host:port`) of the peers, by the addresses the peers are known by.
+    /// The endpoint of a peer is resolved each time the peer is dialed, and the resolved
+    /// addresses are dialed in order until one of them succeeds.
+    pub endpoints: HashMap<SocketAddr, String>,
+    /// Resolver of the `endpoints`.
+    pub resolver: Arc<dyn Resolver>,
+    /// Sender of the failures to send messages, see `subscribe_send_failures`.
+    pub send_failures: Option<mpsc::Sender<SendFailure>>,
+    /// Format of the frames exchanged with the peers, which replaces the one
+    /// of the `HandshakeParams` the network part is run with.
+    pub frame_codec: Arc<dyn FrameCodec>,
+}
+
+/// Publisher of the failures to send messages into the bounded stream; the failures
+/// are dropped while the stream is full.
+#[derive(Debug, Clone, Default)]
+struct SendFailures {
+    tx: Rc<RefCell<Option<mpsc::Sender<SendFailure>>>>,
+}
+
+impl SendFailures {
+    fn new(tx: Option<mpsc::Sender<SendFailure>>) -> Self {
+        SendFailures {
+            tx: Rc::new(RefCell::new(tx)),
+        }
+    }
+
+    fn publish(&self, peer: &SocketAddr, message: &RawMessage, reason: SendFailureReason) {
+        let mut tx = self.tx.borrow_mut();
+        let closed = match *tx {
+            Some(ref mut tx) => {
+                let failure = SendFailure {
+                    peer: *peer,
+                    service_id: message.service_id(),
+                    message_type: message.message_type(),
+                    reason,
+                };
+                tx.try_send(failure)
+                    .err()
+                    .map_or(false, |e| e.is_disconnected())
+            }
+            None => false,
+        };
+        // Nobody is interested in the failures anymore.
+        if closed {
+            *tx = None;
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 struct ConnectionPool {
-    peers: Rc<RefCell<HashMap<SocketAddr, mpsc::Sender<RawMessage>>>>,
+    peers: Rc<RefCell<HashMap<SocketAddr, OutgoingSender>>>,
+    queue_len: Rc<Cell<usize>>,
+    overflow: Rc<Cell<OutgoingQueueOverflow>>,
+    buffer_timeout: Rc<Cell<Duration>>,
+    metrics: Arc<NetworkMetrics>,
+    failures: SendFailures,
 }
 
 impl ConnectionPool {
-    fn new() -> Self {
+    fn new(network_config: &NetworkConfiguration, metrics: Arc<NetworkMetrics>) -> Self {
         ConnectionPool {
             peers: Rc::new(RefCell::new(HashMap::new())),
+            queue_len: Rc::new(Cell::new(network_config.max_outgoing_queue_len)),
+            overflow: Rc::new(Cell::new(network_config.outgoing_queue_overflow)),
+            buffer_timeout: Rc::new(Cell::new(Duration::from_millis(
+                network_config.reconnect_buffer_timeout,
+            ))),
+            metrics,
+            failures: SendFailures::default(),
         }
     }
 
-    fn len(&self) -> usize {
-        self.peers.borrow().len()
+    /// Applies the queue options to the queues created from now on, and the overflow policy
+    /// and the reconnection buffer timeout to the existing queues as well.
+    fn update(&self, network_config: &NetworkConfiguration) {
+        self.queue_len.set(network_config.max_outgoing_queue_len);
+        self.overflow.set(network_config.outgoing_queue_overflow);
+        let buffer_timeout = Duration::from_millis(network_config.reconnect_buffer_timeout);
+        self.buffer_timeout.set(buffer_timeout);
     }
 
-    fn add(&self, address: &SocketAddr, sender: mpsc::Sender<RawMessage>) {
-        let mut peers = self.peers.borrow_mut();
-        peers.insert(*address, sender);
+    fn len(&self) -> usize {
+        self.peers.borrow().len()
     }
 
     fn contains(&self, address: &SocketAddr) -> bool {
         let peers = self.peers.borrow();
-        peers.get(address).is_some()
+        peers.get(address).map_or(false, |sender| !sender.is_closed())
     }
 
     fn remove(&self, address: &SocketAddr) {
@@ -115,140 +496,854 @@ impl ConnectionPool {
         peers.remove(address);
     }
 
-    fn add_incoming_address(&self, remote_address: &SocketAddr) -> mpsc::Receiver<RawMessage> {
-        let (sender_tx, receiver_rx) = mpsc::channel::<RawMessage>(OUTGOING_CHANNEL_SIZE);
-        self.add(&remote_address, sender_tx);
-        receiver_rx
+    fn add_address(&self, address: &SocketAddr) -> OutgoingReceiver {
+        let (sender, receiver) = outgoing::queue(self.queue_len.get());
+        let mut peers = self.peers.borrow_mut();
+        // Messages not written over the lost connection go first to keep their order.
+        if let Some(lost) = peers.get(address) {
+            if self.keeps_queued(lost) {
+                for message in lost.take_queued() {
+                    sender.push(message);
+                }
+            }
+        }
+        peers.insert(*address, sender);
+        receiver
     }
 
-    fn send_message(
-        &self,
-        address: &SocketAddr,
-        message: &RawMessage,
-    ) -> impl Future<Item = (), Error = failure::Error> {
-        let address = *address;
-        let sender_tx = self.peers.borrow();
-        let write_pool = self.clone();
-
-        if let Some(sender) = sender_tx.get(&address) {
-            Either::A(
-                sender
-                    .clone()
-                    .send(message.clone())
-                    .map(drop)
-                    .or_else(move |e| {
-                        log_error(e);
-                        write_pool.remove(&address);
-                        Ok(())
-                    })
-                    .map(drop),
-            )
-        } else {
-            Either::B(future::ok(()))
+    /// Returns `true` if the queue of a closed connection should be kept until the peer
+    /// is reconnected.
+    fn keeps_queued(&self, sender: &OutgoingSender) -> bool {
+        sender.closed_at().map_or(false, |closed_at| {
+            closed_at.elapsed() <= self.buffer_timeout.get()
+        })
+    }
+
+    /// Enqueues the message for the peer. Returns `Err` if the message has been rejected
+    /// and the peer should be disconnected according to the overflow policy.
+    fn send_message(&self, address: &SocketAddr, message: &RawMessage) -> Result<SendResult, ()> {
+        let mut peers = self.peers.borrow_mut();
+        let closed = match peers.get(address) {
+            Some(sender) if sender.is_closed() => !self.keeps_queued(sender),
+            Some(sender) => {
+                if sender.is_full() {
+                    self.metrics.record_outgoing_overflow();
+                    if self.overflow.get() == OutgoingQueueOverflow::Disconnect {
+                        warn!("Outgoing queue is full, disconnecting peer={}", address);
+                        self.failures
+                            .publish(address, message, SendFailureReason::QueueOverflow);
+                        return Err(());
+                    }
+                    warn!("Outgoing queue is full, dropped message to peer={}", address);
+                }
+                if let Some(evicted) = sender.push(message.clone()) {
+                    self.failures
+                        .publish(address, &evicted, SendFailureReason::QueueOverflow);
+                }
+                return Ok(SendResult::Queued);
+            }
+            None => false,
+        };
+
+        if closed {
+            trace!("Connection with peer={} is closed", address);
+            peers.remove(address);
         }
+        self.failures
+            .publish(address, message, SendFailureReason::ConnectionClosed);
+        Ok(SendResult::Dropped)
     }
 }
 
-struct Connection {
+/// Item of the stream processed by the reading half of a connection.
+enum Incoming {
+    Frame(Frame),
+    Malformed(DecodeError),
+    Tick,
+    Closed,
+}
+
+/// State of the keep-alive protocol of a single connection.
+#[derive(Debug)]
+struct KeepAlive {
+    interval: Duration,
+    timeout: Duration,
+    last_received: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl KeepAlive {
+    fn new(network_config: &NetworkConfiguration) -> Option<Self> {
+        network_config.keep_alive_interval.map(|interval| KeepAlive {
+            interval: Duration::from_millis(interval),
+            timeout: Duration::from_millis(network_config.keep_alive_timeout),
+            last_received: Instant::now(),
+            ping_sent: None,
+        })
+    }
+
+    /// Period with which `tick` should be called.
+    fn period(&self) -> Duration {
+        self.interval.min(self.timeout)
+    }
+
+    /// Restarts the idle period, e.g., after reading from the peer has been resumed.
+    fn reset(&mut self) {
+        self.last_received = Instant::now();
+        self.ping_sent = None;
+    }
+
+    fn frame_received(&mut self, frame: &Frame) {
+        self.last_received = Instant::now();
+        if *frame == Frame::Pong {
+            self.ping_sent = None;
+        }
+    }
+
+    /// Returns `true` if a ping should be sent to the peer.
+    fn tick(&mut self) -> Result<bool, PingTimeout> {
+        match self.ping_sent {
+            Some(sent) if sent.elapsed() >= self.timeout => Err(PingTimeout(self.timeout)),
+            Some(_) => Ok(false),
+            None if self.last_received.elapsed() >= self.interval => {
+                self.ping_sent = Some(Instant::now());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Stream ending as soon as the inner stream has no ready items, once draining is requested.
+struct Drain<S> {
+    stream: S,
+    draining: Rc<Cell<bool>>,
+}
+
+impl<S: Stream> Stream for Drain<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        match self.stream.poll()? {
+            Async::NotReady if self.draining.get() => Ok(Async::Ready(None)),
+            polled => Ok(polled),
+        }
+    }
+}
+
+/// Sink failing with `WriteTimeout` once the inner sink has not made progress
+/// for the given time.
+struct WriteDeadline<S> {
+    sink: S,
+    timeout: Option<Duration>,
+    handle: Handle,
+    // Fires if the sink is stuck since the moment the timer has been started.
+    stalled: Option<Timeout>,
+}
+
+impl<S> WriteDeadline<S> {
+    fn new(sink: S, timeout: Option<Duration>, handle: Handle) -> Self {
+        WriteDeadline {
+            sink,
+            timeout,
+            handle,
+            stalled: None,
+        }
+    }
+
+    fn check_stalled(&mut self) -> Result<(), failure::Error> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+        if self.stalled.is_none() {
+            self.stalled = Some(Timeout::new(timeout, &self.handle)?);
+        }
+        match self.stalled.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => Err(WriteTimeout(timeout).into()),
+            Some(Err(e)) => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S> Sink for WriteDeadline<S>
+where
+    S: Sink<SinkError = failure::Error>,
+{
+    type SinkItem = S::SinkItem;
+    type SinkError = failure::Error;
+
+    fn start_send(&mut self, item: S::SinkItem) -> StartSend<S::SinkItem, failure::Error> {
+        match self.sink.start_send(item)? {
+            AsyncSink::Ready => {
+                self.stalled = None;
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady(item) => {
+                self.check_stalled()?;
+                Ok(AsyncSink::NotReady(item))
+            }
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), failure::Error> {
+        match self.sink.poll_complete()? {
+            Async::Ready(()) => {
+                self.stalled = None;
+                Ok(Async::Ready(()))
+            }
+            Async::NotReady => {
+                self.check_stalled()?;
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+struct Connection<S> {
     handle: Handle,
     address: SocketAddr,
-    socket: Framed<TcpStream, MessagesCodec>,
-    receiver_rx: mpsc::Receiver<RawMessage>,
+    socket: Framed<S, MessagesCodec>,
+    receiver_rx: OutgoingReceiver,
+    ticket: ConnectionTicket,
 }
 
-impl Connection {
+impl<S> Connection<S> {
     fn new(
         handle: Handle,
         address: SocketAddr,
-        socket: Framed<TcpStream, MessagesCodec>,
-        receiver_rx: mpsc::Receiver<RawMessage>,
+        socket: Framed<S, MessagesCodec>,
+        receiver_rx: OutgoingReceiver,
+        ticket: ConnectionTicket,
     ) -> Self {
         Connection {
             handle,
             address,
             socket,
             receiver_rx,
+            ticket,
+        }
+    }
+}
+
+/// Direction of a connection relative to this node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The connection has been accepted from the peer.
+    Incoming,
+    /// The connection has been dialed by this node.
+    Outgoing,
+}
+
+#[derive(Debug)]
+struct RegisteredConnection {
+    id: u64,
+    address: SocketAddr,
+    direction: Direction,
+    // Closes the reading half of the connection.
+    close_tx: unsync::oneshot::Sender<()>,
+    activity: ActivityTracker,
+}
+
+/// Activity and traffic of a connection updated from its reading and writing halves.
+#[derive(Debug, Clone, Default)]
+struct ActivityTracker {
+    activity: Rc<Cell<ConnectionActivity>>,
+    traffic: Rc<Cell<PeerTraffic>>,
+}
+
+impl ActivityTracker {
+    fn get(&self) -> ConnectionActivity {
+        self.activity.get()
+    }
+
+    fn traffic(&self) -> PeerTraffic {
+        self.traffic.get()
+    }
+
+    fn received(&self) {
+        let mut activity = self.activity.get();
+        activity.last_received = Some(Instant::now());
+        self.activity.set(activity);
+    }
+
+    fn sent(&self) {
+        let mut activity = self.activity.get();
+        activity.last_sent = Some(Instant::now());
+        self.activity.set(activity);
+    }
+
+    fn message_received(&self, len: usize) {
+        let mut traffic = self.traffic.get();
+        traffic.messages_received += 1;
+        traffic.bytes_received += len as u64;
+        self.traffic.set(traffic);
+    }
+
+    fn message_sent(&self, len: usize) {
+        let mut traffic = self.traffic.get();
+        traffic.messages_sent += 1;
+        traffic.bytes_sent += len as u64;
+        self.traffic.set(traffic);
+    }
+}
+
+/// Established connections indexed by the public keys of the peers.
+///
+/// If two peers dial each other simultaneously, two connections between them are established.
+/// Both peers keep the canonical one, i.e., the connection dialed by the peer with the lower
+/// public key, and close the other one. A connection replacing another one with the same
+/// direction is assumed to be a reconnection, so the stale connection is closed.
+#[derive(Debug, Clone)]
+struct PeerRegistry {
+    our_key: PublicKey,
+    next_id: Rc<Cell<u64>>,
+    peers: Rc<RefCell<HashMap<PublicKey, RegisteredConnection>>>,
+    // Pauses reading from all the connections.
+    read_pause: ReadPause,
+}
+
+impl PeerRegistry {
+    fn new(our_key: PublicKey) -> Self {
+        PeerRegistry {
+            our_key,
+            next_id: Rc::default(),
+            peers: Rc::default(),
+            read_pause: ReadPause::default(),
+        }
+    }
+
+    fn canonical_direction(&self, peer: &PublicKey) -> Direction {
+        if self.our_key < *peer {
+            Direction::Outgoing
+        } else {
+            Direction::Incoming
+        }
+    }
+
+    /// Registers an established connection with the peer. Returns `None` if the connection
+    /// duplicates the canonical connection and should be closed.
+    fn register(
+        &self,
+        peer: PublicKey,
+        address: SocketAddr,
+        direction: Direction,
+    ) -> Option<ConnectionTicket> {
+        let mut peers = self.peers.borrow_mut();
+        let announce = match peers.get(&peer) {
+            None => true,
+            Some(existing) if existing.direction == direction => true,
+            // `PeerConnected` has already been emitted for the replaced connection.
+            Some(_) if direction == self.canonical_direction(&peer) => false,
+            Some(_) => return None,
+        };
+
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let (close_tx, close_rx) = unsync::oneshot::channel();
+        let activity = ActivityTracker::default();
+        let connection = RegisteredConnection {
+            id,
+            address,
+            direction,
+            close_tx,
+            activity: activity.clone(),
+        };
+        if let Some(replaced) = peers.insert(peer, connection) {
+            let _ = replaced.close_tx.send(());
         }
+        Some(ConnectionTicket {
+            peer,
+            id,
+            announce,
+            close_rx: Some(close_rx),
+            lost_tx: None,
+            activity,
+            registry: self.clone(),
+        })
+    }
+
+    /// Returns the activity of the connections indexed by the peer addresses.
+    fn activity(&self) -> HashMap<SocketAddr, ConnectionActivity> {
+        self.peers
+            .borrow()
+            .values()
+            .map(|connection| (connection.address, connection.activity.get()))
+            .collect()
+    }
+
+    /// Returns the traffic of the connections indexed by the public keys of the peers.
+    fn traffic(&self) -> HashMap<PublicKey, PeerTraffic> {
+        self.peers
+            .borrow()
+            .iter()
+            .map(|(peer, connection)| (*peer, connection.activity.traffic()))
+            .collect()
+    }
+
+    /// Returns the description of the connected peers.
+    fn peer_info(&self) -> Vec<PeerInfo> {
+        self.peers
+            .borrow()
+            .iter()
+            .map(|(peer, connection)| {
+                let activity = connection.activity.get();
+                PeerInfo {
+                    public_key: *peer,
+                    address: connection.address,
+                    direction: connection.direction,
+                    last_activity: cmp::max(activity.last_received, activity.last_sent),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the number of the incoming and outgoing connections.
+    fn counts(&self) -> (usize, usize) {
+        let peers = self.peers.borrow();
+        let incoming = peers
+            .values()
+            .filter(|connection| connection.direction == Direction::Incoming)
+            .count();
+        (incoming, peers.len() - incoming)
+    }
+
+    /// Returns the public keys and addresses of the connected peers.
+    fn connected_peers(&self) -> Vec<(PublicKey, SocketAddr)> {
+        self.peers
+            .borrow()
+            .iter()
+            .map(|(peer, connection)| (*peer, connection.address))
+            .collect()
+    }
+
+    /// Returns the address of the connected peer with the given public key.
+    fn address_of(&self, peer: &PublicKey) -> Option<SocketAddr> {
+        self.peers
+            .borrow()
+            .get(peer)
+            .map(|connection| connection.address)
     }
+
+    /// Removes the connection with the given id of the peer. Returns `false` if the connection
+    /// has been replaced or closed already.
+    fn unregister(&self, peer: &PublicKey, id: u64) -> bool {
+        let mut peers = self.peers.borrow_mut();
+        if peers.get(peer).map_or(false, |c| c.id == id) {
+            peers.remove(peer);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Closes the connection with the peer at the given address, if any.
+    fn close(&self, address: &SocketAddr) {
+        let mut peers = self.peers.borrow_mut();
+        let peer = peers
+            .iter()
+            .find(|&(_, connection)| connection.address == *address)
+            .map(|(peer, _)| *peer);
+        if let Some(connection) = peer.and_then(|peer| peers.remove(&peer)) {
+            let _ = connection.close_tx.send(());
+        }
+    }
+}
+
+/// Registration of a connection in the `PeerRegistry`, revoked once the ticket is dropped.
+#[derive(Debug)]
+struct ConnectionTicket {
+    peer: PublicKey,
+    id: u64,
+    // `PeerConnected` should be emitted for the connection.
+    announce: bool,
+    // Completes once the connection is replaced or closed.
+    close_rx: Option<unsync::oneshot::Receiver<()>>,
+    // Notified if the connection is lost, see `lost`.
+    lost_tx: Option<unsync::oneshot::Sender<()>>,
+    activity: ActivityTracker,
+    registry: PeerRegistry,
 }
 
+impl ConnectionTicket {
+    /// Returns the future completing if the connection is lost, i.e., if it ends while it is
+    /// still registered. The future fails if the connection is replaced or closed on purpose.
+    fn lost(&mut self) -> unsync::oneshot::Receiver<()> {
+        let (lost_tx, lost_rx) = unsync::oneshot::channel();
+        self.lost_tx = Some(lost_tx);
+        lost_rx
+    }
+}
+
+impl Drop for ConnectionTicket {
+    fn drop(&mut self) {
+        if self.registry.unregister(&self.peer, self.id) {
+            if let Some(lost_tx) = self.lost_tx.take() {
+                let _ = lost_tx.send(());
+            }
+        }
+    }
+}
+
+/// Switch pausing reading from all the connections, see `NetworkRequest::Pause`.
+#[derive(Debug, Clone, Default)]
+struct ReadPause(Rc<RefCell<ReadPauseState>>);
+
+#[derive(Debug, Default)]
+struct ReadPauseState {
+    paused: bool,
+    // Tasks of the connections waiting for reading to be resumed.
+    blocked: Vec<Task>,
+}
+
+impl ReadPause {
+    fn pause(&self) {
+        self.0.borrow_mut().paused = true;
+    }
+
+    fn resume(&self) {
+        let mut state = self.0.borrow_mut();
+        state.paused = false;
+        for blocked in state.blocked.drain(..) {
+            blocked.notify();
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.0.borrow().paused
+    }
+
+    /// Returns `NotReady` and schedules the current task to be notified on resumption
+    /// if reading is paused.
+    fn poll_resumed(&self) -> Async<()> {
+        let mut state = self.0.borrow_mut();
+        if !state.paused {
+            return Async::Ready(());
+        }
+        if !state.blocked.iter().any(Task::will_notify_current) {
+            state.blocked.push(task::current());
+        }
+        Async::NotReady
+    }
+}
+
+/// Stream which is not polled while reading is paused.
+struct Pausable<S> {
+    stream: S,
+    pause: ReadPause,
+}
+
+impl<S: Stream> Stream for Pausable<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        match self.pause.poll_resumed() {
+            Async::Ready(()) => self.stream.poll(),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Signal stopping the network part. The signal is triggered either by
+/// `NetworkRequest::Shutdown` or if the network events receiver is gone.
+#[derive(Clone, Default)]
+struct ShutdownSignal(Rc<RefCell<Option<unsync::oneshot::Sender<()>>>>);
+
+impl ShutdownSignal {
+    /// Returns the future which completes once the signal is triggered.
+    fn listen(&self) -> unsync::oneshot::Receiver<()> {
+        let (sender, receiver) = unsync::oneshot::channel();
+        *self.0.borrow_mut() = Some(sender);
+        receiver
+    }
+
+    /// Triggers the signal. Returns `false` if the signal has already been triggered.
+    fn trigger(&self) -> bool {
+        self.0.borrow_mut().take().is_some()
+    }
+
+    /// Returns `true` if the signal has been triggered or is not listened to yet.
+    fn is_triggered(&self) -> bool {
+        self.0.borrow().is_none()
+    }
+}
+
+/// Peers connections with which are refused until the expiration of their bans.
+#[derive(Debug, Clone, Default)]
+struct BanList(Rc<RefCell<HashMap<PublicKey, Instant>>>);
+
+impl BanList {
+    fn ban(&self, peer: PublicKey, duration: Duration) {
+        let expires_at = Instant::now() + duration;
+        let mut bans = self.0.borrow_mut();
+        let entry = bans.entry(peer).or_insert(expires_at);
+        *entry = (*entry).max(expires_at);
+    }
+
+    fn is_banned(&self, peer: &PublicKey) -> bool {
+        let mut bans = self.0.borrow_mut();
+        match bans.get(peer).cloned() {
+            Some(expires_at) if expires_at > Instant::now() => true,
+            Some(_) => {
+                bans.remove(peer);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct IncomingSlotsState {
+    next_id: u64,
+    // Connections which have not completed the handshake, from the oldest to the newest.
+    pending: VecDeque<(u64, unsync::oneshot::Sender<()>)>,
+    established: HashSet<u64>,
+}
+
+/// Slots of the incoming connections limited by `max_incoming_connections`.
+///
+/// A peer becomes known once it completes the handshake, i.e., proves it is in
+/// the `ConnectList`. Connections of unknown peers are evicted first, so that a flood of
+/// connections which never complete the handshake cannot lock out the known peers.
+#[derive(Debug, Clone, Default)]
+struct IncomingSlots(Rc<RefCell<IncomingSlotsState>>);
+
+impl IncomingSlots {
+    /// Takes a slot for a new connection, evicting the oldest unknown connection
+    /// if there are no free slots. Returns `None` if all the slots are taken by known peers.
+    fn acquire(&self, limit: usize) -> Option<IncomingSlot> {
+        let mut state = self.0.borrow_mut();
+        if state.pending.len() + state.established.len() >= limit {
+            let (_, evict_tx) = state.pending.pop_front()?;
+            let _ = evict_tx.send(());
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        let (evict_tx, evict_rx) = unsync::oneshot::channel();
+        state.pending.push_back((id, evict_tx));
+        Some(IncomingSlot {
+            id,
+            evict_rx: Some(evict_rx),
+            slots: self.clone(),
+        })
+    }
+
+    /// Marks the connection as the one of a known peer, unless it has been evicted already.
+    fn establish(&self, id: u64) {
+        let mut state = self.0.borrow_mut();
+        if let Some(position) = state.pending.iter().position(|&(pending, _)| pending == id) {
+            state.pending.remove(position);
+            state.established.insert(id);
+        }
+    }
+
+    fn release(&self, id: u64) {
+        let mut state = self.0.borrow_mut();
+        state.pending.retain(|&(pending, _)| pending != id);
+        state.established.remove(&id);
+    }
+}
+
+/// Slot of an incoming connection, released once dropped.
+#[derive(Debug)]
+struct IncomingSlot {
+    id: u64,
+    // Completes once the connection is evicted.
+    evict_rx: Option<unsync::oneshot::Receiver<()>>,
+    slots: IncomingSlots,
+}
+
+impl IncomingSlot {
+    /// Returns the future which completes once the connection is evicted.
+    fn evicted(&mut self) -> unsync::oneshot::Receiver<()> {
+        self.evict_rx.take().expect("evicted() called twice")
+    }
+
+    /// Marks the connection as the one of a known peer.
+    fn establish(&self) {
+        self.slots.establish(self.id);
+    }
+}
+
+impl Drop for IncomingSlot {
+    fn drop(&mut self) {
+        self.slots.release(self.id);
+    }
+}
+
+type EventsSender = CountingSender<NetworkEvent>;
+
 #[derive(Clone)]
-struct NetworkHandler {
-    listen_address: SocketAddr,
+struct NetworkHandler<T> {
+    transport: T,
     pool: ConnectionPool,
     handle: Handle,
-    network_config: NetworkConfiguration,
-    network_tx: mpsc::Sender<NetworkEvent>,
+    // Shared by the clones of the handler, so that `NetworkRequest::UpdateConfig`
+    // is seen by the listeners as well.
+    network_config: Rc<Cell<NetworkConfiguration>>,
+    network_tx: EventsSender,
     handshake_params: HandshakeParams,
+    metrics: Arc<NetworkMetrics>,
+    rate_limiter: RateLimiter,
+    message_filter: Rc<MessageFilter>,
+    endpoints: Rc<HashMap<SocketAddr, String>>,
+    resolver: Arc<dyn Resolver>,
+    // Number of outgoing connections which are being established.
+    pending_connects: Rc<Cell<usize>>,
+    shutdown: ShutdownSignal,
+    registry: PeerRegistry,
+    banned: BanList,
+    // Peers set by the latest `NetworkRequest::ReconcilePeers`.
+    peer_set: Rc<RefCell<HashSet<PublicKey>>>,
 }
 
-impl NetworkHandler {
+impl<T: Transport> NetworkHandler<T> {
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
     fn new(
+        transport: T,
         handle: Handle,
-        address: SocketAddr,
         connection_pool: ConnectionPool,
         network_config: NetworkConfiguration,
-        network_tx: mpsc::Sender<NetworkEvent>,
+        network_tx: EventsSender,
         handshake_params: HandshakeParams,
+        metrics: Arc<NetworkMetrics>,
+        message_filter: MessageFilter,
+        endpoints: HashMap<SocketAddr, String>,
+        resolver: Arc<dyn Resolver>,
     ) -> Self {
         NetworkHandler {
+            transport,
             handle,
-            listen_address: address,
             pool: connection_pool,
-            network_config,
+            network_config: Rc::new(Cell::new(network_config)),
             network_tx,
             handshake_params,
+            metrics,
+            rate_limiter: RateLimiter::new(&network_config),
+            message_filter: Rc::new(message_filter),
+            endpoints: Rc::new(endpoints),
+            resolver,
+            pending_connects: Rc::default(),
+            shutdown: ShutdownSignal::default(),
+            registry: PeerRegistry::new(*handshake_params.connect.pub_key()),
+            banned: BanList::default(),
+            peer_set: Rc::default(),
         }
     }
 
-    fn listener(self) -> impl Future<Item = (), Error = failure::Error> {
-        let listen_address = self.listen_address;
-        let server = TcpListener::bind(&listen_address).unwrap().incoming();
+    fn listener(
+        self,
+        listen_address: SocketAddr,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        let server = self.transport.listen(&listen_address).unwrap();
+        let transport = self.transport.clone();
         let pool = self.pool.clone();
 
         let handshake_params = self.handshake_params.clone();
         let network_tx = self.network_tx.clone();
         let handle = self.handle.clone();
+        let metrics = self.metrics.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let message_filter = Rc::clone(&self.message_filter);
+        let shutdown = self.shutdown.clone();
+        let registry = self.registry.clone();
+        let banned = self.banned.clone();
 
-        // Incoming connections limiter
-        let incoming_connections_limit = self.network_config.max_incoming_connections;
-        // The reference counter is used to automatically count the number of the open connections.
-        let incoming_connections_counter: Rc<()> = Rc::default();
+        let incoming_slots = IncomingSlots::default();
 
         server
             .map_err(into_failure)
-            .for_each(move |incoming_connection| {
-                let address = incoming_connection
-                    .peer_addr()
-                    .expect("Remote peer address resolve failed");
+            .for_each(move |(incoming_connection, address)| {
+                let network_config = self.network_config.get();
+                if let Err(e) = transport.configure(&incoming_connection, &network_config) {
+                    warn!("Failed to configure socket of peer={}: {}", address, e);
+                    return Ok(());
+                }
                 let pool = pool.clone();
                 let network_tx = network_tx.clone();
+                let disconnect_tx = network_tx.clone();
                 let handle = handle.clone();
+                let metrics = metrics.clone();
+                let rate_limiter = rate_limiter.clone();
+                let message_filter = Rc::clone(&message_filter);
+                let shutdown = shutdown.clone();
+                let registry = registry.clone();
+                let banned = banned.clone();
+                let signing_key = handshake_params.signing_key.clone();
 
                 let handshake = NoiseHandshake::responder(&handshake_params, &listen_address);
-                let holder = incoming_connections_counter.clone();
-                // Check incoming connections count
-                let connections_count = Rc::strong_count(&incoming_connections_counter) - 1;
-                if connections_count >= incoming_connections_limit {
-                    warn!(
-                        "Rejected incoming connection with peer={}, \
-                         connections limit reached.",
-                        address
-                    );
-                    return Ok(());
-                }
+                let limit = network_config.max_incoming_connections;
+                let mut slot = match incoming_slots.acquire(limit) {
+                    Some(slot) => slot,
+                    None => {
+                        warn!(
+                            "Rejected incoming connection with peer={}, \
+                             connections limit reached.",
+                            address
+                        );
+                        return Ok(());
+                    }
+                };
+                let evicted = slot.evicted();
+                let slot = Rc::new(slot);
+                let holder = Rc::clone(&slot);
 
                 let listener = handshake
                     .listen(incoming_connection)
-                    .and_then(move |(socket, raw)| (Ok(socket), Self::parse_connect_msg(Some(raw))))
+                    .select2(evicted)
+                    .then(move |result| match result {
+                        Ok(Either::A((output, _))) => Ok(output),
+                        Err(Either::A((e, _))) => Err(e),
+                        Ok(Either::B(_)) | Err(Either::B(_)) => Err(format_err!(
+                            "Closed incoming connection with peer={} which has not completed \
+                             the handshake, connections limit reached.",
+                            address
+                        )),
+                    })
+                    .and_then(move |(socket, raw)| {
+                        slot.establish();
+                        (Ok(socket), Self::parse_connect_msg(Some(raw)))
+                    })
+                    .and_then(move |(socket, message)| {
+                        let address = message.addr();
+                        Self::check_protocol_version(socket, address, network_config, disconnect_tx)
+                            .map(move |socket| (socket, message))
+                    })
                     .and_then(move |(socket, message)| {
-                        let receiver_rx = pool.add_incoming_address(&message.addr());
-                        Ok((socket, message, receiver_rx))
+                        Self::authenticate_peer(socket, message, network_config, &signing_key)
                     })
-                    .and_then(move |(socket, message, receiver_rx)| {
+                    .and_then(move |(socket, message)| {
+                        let address = message.addr();
+                        let peer = *message.pub_key();
+                        if banned.is_banned(&peer) {
+                            warn!("Refused connection with banned peer={}", address);
+                            return Either::A(future::ok(()));
+                        }
+                        let ticket = match registry.register(peer, address, Direction::Incoming) {
+                            Some(ticket) => ticket,
+                            None => {
+                                trace!("Closing duplicate connection with peer={}", address);
+                                return Either::A(future::ok(()));
+                            }
+                        };
+                        let receiver_rx = pool.add_address(&address);
                         let connection =
-                            Connection::new(handle, message.addr(), socket, receiver_rx);
-                        Self::handle_connection(connection, message, &network_tx)
+                            Connection::new(handle, address, socket, receiver_rx, ticket);
+                        Either::B(Self::handle_connection(
+                            connection,
+                            message,
+                            &network_tx,
+                            network_config,
+                            &rate_limiter,
+                            &message_filter,
+                            metrics,
+                            shutdown,
+                        ))
                     })
                     .map(|_| {
                         drop(holder);
@@ -268,54 +1363,581 @@ impl NetworkHandler {
         let handshake_params = handshake_params.clone();
         let handle = self.handle.clone();
         let network_tx = self.network_tx.clone();
-        let network_config = self.network_config;
-        let timeout = self.network_config.tcp_connect_retry_timeout;
-        let max_tries = self.network_config.tcp_connect_max_retries as usize;
-        let strategy = FixedInterval::from_millis(timeout)
-            .map(jitter)
-            .take(max_tries);
+        let disconnect_tx = self.network_tx.clone();
+        let metrics = self.metrics.clone();
+        let network_config = self.network_config.get();
+        let rate_limiter = self.rate_limiter.clone();
+        let message_filter = Rc::clone(&self.message_filter);
+        let shutdown = self.shutdown.clone();
+        let registry = self.registry.clone();
+        let banned = self.banned.clone();
+        let pool = self.pool.clone();
+        let reconnecting = self.clone();
+        let signing_key = handshake_params.signing_key.clone();
+        let strategy = connect_retry_delays(&network_config).map(jitter);
 
-        let action = move || TcpStream::connect(&address);
+        let transport = self.transport.clone();
+        let dial_transport = self.transport.clone();
+        let dial_handle = handle.clone();
+        let dial_metrics = Arc::clone(&metrics);
+        let resolver = Arc::clone(&self.resolver);
+        let endpoint = self.endpoints.get(&address).cloned();
+        // Start of the latest attempt to dial the peer.
+        let dial_started = Rc::new(Cell::new(Instant::now()));
+        let attempt_started = Rc::clone(&dial_started);
+        // Category of the latest failed attempt, with which connecting fails once
+        // the attempts are over.
+        let dial_failed = Rc::new(Cell::new(ConnectError::Other));
+        let attempt_failed = Rc::clone(&dial_failed);
+        let action = move || {
+            attempt_started.set(Instant::now());
+            let metrics = Arc::clone(&dial_metrics);
+            let attempt_failed = Rc::clone(&attempt_failed);
+            let dial = match endpoint {
+                Some(ref endpoint) => Either::A(Self::dial_endpoint(
+                    &dial_transport,
+                    &*resolver,
+                    endpoint,
+                    &network_config,
+                    &dial_handle,
+                    metrics,
+                )),
+                None => Either::B(Self::dial(
+                    &dial_transport,
+                    address,
+                    &network_config,
+                    &dial_handle,
+                    metrics,
+                )),
+            };
+            dial.map_err(move |e| {
+                attempt_failed.set(e);
+                e
+            })
+        };
 
-        let (sender_tx, receiver_rx) = mpsc::channel::<RawMessage>(OUTGOING_CHANNEL_SIZE);
-        self.pool.add(&address, sender_tx);
+        let receiver_rx = self.pool.add_address(&address);
+
+        let pending_connects = Rc::clone(&self.pending_connects);
+        pending_connects.set(pending_connects.get() + 1);
+        let handshake_metrics = Arc::clone(&metrics);
 
         Retry::spawn(strategy, action)
-            .map_err(into_failure)
-            .and_then(move |socket| Self::configure_socket(socket, network_config))
+            .then(move |result| {
+                pending_connects.set(pending_connects.get() - 1);
+                result
+            })
+            .map_err(move |_| failure::Error::from(dial_failed.get()))
+            .and_then(move |socket| {
+                transport
+                    .configure(&socket, &network_config)
+                    .map(|_| socket)
+                    .map_err(failure::Error::from)
+            })
             .and_then(move |outgoing_connection| {
                 Self::build_handshake_initiator(outgoing_connection, &address, &handshake_params)
             })
             .and_then(move |(socket, raw)| (Ok(socket), Self::parse_connect_msg(Some(raw))))
             .and_then(move |(socket, message)| {
-                let connection = Connection::new(handle.clone(), address, socket, receiver_rx);
-                Self::handle_connection(connection, message, &network_tx)
+                Self::check_protocol_version(socket, address, network_config, disconnect_tx)
+                    .map(move |socket| (socket, message))
+            })
+            .and_then(move |(socket, message)| {
+                Self::authenticate_peer(socket, message, network_config, &signing_key)
+            })
+            .map_err(move |e| {
+                if e.downcast_ref::<ConnectError>().is_some() {
+                    return e;
+                }
+                let error = ConnectError::from_handshake(&e);
+                handshake_metrics.record_connect_error(error);
+                warn!("Failed to connect to peer={}: {}", address, e);
+                error.into()
+            })
+            .and_then(move |(socket, message)| {
+                metrics.record_connect_latency(dial_started.get().elapsed());
+                let peer = *message.pub_key();
+                if banned.is_banned(&peer) {
+                    warn!("Refused connection with banned peer={}", address);
+                    return Either::A(future::ok(()));
+                }
+                let mut ticket = match registry.register(peer, address, Direction::Outgoing) {
+                    Some(ticket) => ticket,
+                    None => {
+                        trace!("Closing duplicate connection with peer={}", address);
+                        return Either::A(future::ok(()));
+                    }
+                };
+                let lost = ticket.lost();
+                handle.spawn(reconnecting.reconnect_on_loss(address, lost));
+                // The queue has been reset by the replaced incoming connection.
+                let receiver_rx = if ticket.announce {
+                    receiver_rx
+                } else {
+                    pool.add_address(&address)
+                };
+                let connection =
+                    Connection::new(handle.clone(), address, socket, receiver_rx, ticket);
+                Either::B(Self::handle_connection(
+                    connection,
+                    message,
+                    &network_tx,
+                    network_config,
+                    &rate_limiter,
+                    &message_filter,
+                    metrics,
+                    shutdown,
+                ))
+            })
+            .map(drop)
+    }
+
+    /// Re-establishes the outgoing connection with the peer once it is lost, unless
+    /// the network is shutting down or the peer is no longer in the `ConnectList`.
+    /// The first attempt is made after a random delay below `tcp_connect_retry_timeout`,
+    /// so that the peers of a restarted node do not dial it at once; the following ones
+    /// back off as for any other connection.
+    fn reconnect_on_loss(
+        &self,
+        address: SocketAddr,
+        lost: unsync::oneshot::Receiver<()>,
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        let handler = self.clone();
+        let delay = Duration::from_millis(self.network_config.get().tcp_connect_retry_timeout);
+        let timer_handle = self.handle.clone();
+        let reconnecting = lost
+            // The connection has been replaced or closed on purpose.
+            .map_err(drop)
+            .and_then(move |()| {
+                future::result(Timeout::new(jitter(delay), &timer_handle))
+                    .flatten()
+                    .map_err(log_error)
+            })
+            .and_then(move |()| {
+                let known = handler
+                    .handshake_params
+                    .connect_list
+                    .find_key_by_address(&address)
+                    .is_some();
+                // The connection may have been re-established by a sent message already.
+                if handler.shutdown.is_triggered()
+                    || !known
+                    || handler.pool.contains(&address)
+                    || handler.is_banned_address(&address)
+                {
+                    return Either::A(future::ok(()));
+                }
+                if !handler.can_create_connections() {
+                    warn!(
+                        "Cannot reconnect to peer={}, connections limit reached",
+                        address
+                    );
+                    return Either::A(future::ok(()));
+                }
+                info!("Reconnecting to peer={}", address);
+                let connected = handler
+                    .connect(address, &handler.handshake_params)
+                    .map_err(log_error);
+                Either::B(connected)
+            });
+        Box::new(reconnecting)
+    }
+
+    /// Dials the peer, aborting the attempt after `connect_timeout`.
+    fn dial(
+        transport: &T,
+        address: SocketAddr,
+        network_config: &NetworkConfiguration,
+        handle: &Handle,
+        metrics: Arc<NetworkMetrics>,
+    ) -> impl Future<Item = T::Stream, Error = ConnectError> {
+        let timeout = Duration::from_millis(network_config.connect_timeout);
+        let expired = future::result(Timeout::new(timeout, handle))
+            .flatten()
+            .and_then(move |_| {
+                let message = format!("Dialing peer={} timed out after {:?}", address, timeout);
+                Err::<T::Stream, _>(io::Error::new(io::ErrorKind::TimedOut, message))
+            });
+
+        transport
+            .dial(&address)
+            .select(expired)
+            .map(|(socket, _)| socket)
+            .map_err(move |(e, _)| {
+                let error = ConnectError::from_io(&e);
+                metrics.record_failed_dial();
+                metrics.record_connect_error(error);
+                trace!("Failed to dial peer={}: {}", address, e);
+                error
+            })
+    }
+
+    /// Resolves the endpoint of the peer and dials the resolved addresses in order,
+    /// until one of them succeeds.
+    fn dial_endpoint(
+        transport: &T,
+        resolver: &dyn Resolver,
+        endpoint: &str,
+        network_config: &NetworkConfiguration,
+        handle: &Handle,
+        metrics: Arc<NetworkMetrics>,
+    ) -> Box<dyn Future<Item = T::Stream, Error = ConnectError>> {
+        let transport = transport.clone();
+        let network_config = *network_config;
+        let handle = handle.clone();
+        let endpoint = endpoint.to_owned();
+        let resolved_endpoint = endpoint.clone();
+        let resolve_metrics = Arc::clone(&metrics);
+
+        let dial = resolver
+            .resolve(&endpoint)
+            .map_err(move |e| {
+                resolve_metrics.record_failed_dial();
+                resolve_metrics.record_connect_error(ConnectError::Resolve);
+                trace!("Failed to resolve endpoint {}: {}", resolved_endpoint, e);
+                ConnectError::Resolve
             })
-            .map(drop)
+            .and_then(move |addresses| {
+                if addresses.is_empty() {
+                    metrics.record_connect_error(ConnectError::Resolve);
+                    trace!("Endpoint {} is resolved into no addresses", endpoint);
+                    return Either::A(err(ConnectError::Resolve));
+                }
+                let attempts = (addresses.into_iter(), None);
+                let dial = future::loop_fn(attempts, move |(mut addresses, last_error)| {
+                    let address = match addresses.next() {
+                        Some(address) => address,
+                        None => return Either::A(err(last_error.expect("No addresses dialed"))),
+                    };
+                    let metrics = Arc::clone(&metrics);
+                    let config = &network_config;
+                    let attempt = Self::dial(&transport, address, config, &handle, metrics);
+                    Either::B(attempt.then(move |result| match result {
+                        Ok(socket) => Ok(future::Loop::Break(socket)),
+                        Err(e) => Ok(future::Loop::Continue((addresses, Some(e)))),
+                    }))
+                });
+                Either::B(dial)
+            });
+        Box::new(dial)
     }
 
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
     fn process_messages(
         handle: &Handle,
-        connection: Connection,
-        network_tx: mpsc::Sender<NetworkEvent>,
+        connection: Connection<T::Stream>,
+        network_tx: EventsSender,
+        network_config: NetworkConfiguration,
+        rate_limiter: PeerRateLimiter,
+        allowed: Option<AllowedMessages>,
+        metrics: Arc<NetworkMetrics>,
+        shutdown: ShutdownSignal,
     ) -> Result<(), failure::Error> {
         let address = connection.address;
+        let mut ticket = connection.ticket;
+        let peer_key = ticket.peer;
+        let (registry, connection_id) = (ticket.registry.clone(), ticket.id);
+        let received = ticket.activity.clone();
+        let sent = ticket.activity.clone();
+        let received_metrics = Arc::clone(&metrics);
+        let filtered_metrics = Arc::clone(&metrics);
+        let sent_metrics = Arc::clone(&metrics);
         let (sink, stream) = connection.socket.split();
+        let read_pause = ticket.registry.read_pause.clone();
+        let stream = Pausable {
+            stream,
+            pause: read_pause.clone(),
+        };
+        // Stops reading from the socket once the connection is replaced or closed.
+        let closed = ticket
+            .close_rx
+            .take()
+            .expect("Connection is processed twice")
+            .then(|_| Ok::<_, failure::Error>(Incoming::Closed))
+            .into_stream();
+        // Control frames are written to the socket along with the queued messages.
+        let (control_tx, control_rx) = unsync::mpsc::unbounded();
+        // The peer is granted the window of credits right away; our messages are sent only
+        // while the peer grants us credits, if it does.
+        let receive_window = network_config.flow_control_window.map(|window| {
+            let window = ReceiveWindow::new(window);
+            let _ = control_tx.unbounded_send(Frame::Credit(window.window()));
+            Rc::new(RefCell::new(window))
+        });
+        let consumed_window = receive_window.clone();
+        let credit_tx = control_tx.clone();
+        let send_credits = SendCredits::default();
+        let granted_credits = send_credits.clone();
+        // Closes the writing half if the peer does not answer pings.
+        let (close_tx, close_rx) = unsync::oneshot::channel::<()>();
+        // Fails the reading half if the peer does not accept written frames, so that
+        // the peer is disconnected.
+        let (stalled_tx, stalled_rx) = unsync::oneshot::channel::<Duration>();
+        let stalled = stalled_rx
+            .then(|result| match result {
+                Ok(timeout) => Either::A(future::err(WriteTimeout(timeout).into())),
+                Err(_) => Either::B(future::empty::<Incoming, failure::Error>()),
+            })
+            .into_stream();
+
+        let mut keep_alive = KeepAlive::new(&network_config);
+        let ticks: Box<dyn Stream<Item = Incoming, Error = failure::Error>> = match keep_alive {
+            Some(ref keep_alive) => Box::new(
+                Interval::new(keep_alive.period(), handle)?
+                    .map(|_| Incoming::Tick)
+                    .map_err(into_failure),
+            ),
+            None => Box::new(stream::empty()),
+        };
+        let skip_malformed = network_config.decode_error_policy == DecodeErrorPolicy::Report;
+        let frames = stream
+            .then(move |result| match result {
+                Ok(frame) => Ok(Incoming::Frame(frame)),
+                Err(e) => match e.downcast::<DecodeError>() {
+                    Ok(error @ DecodeError::MalformedFrame(_))
+                    | Ok(error @ DecodeError::ChecksumMismatch { .. })
+                        if skip_malformed =>
+                    {
+                        Ok(Incoming::Malformed(error))
+                    }
+                    Ok(error) => Err(error.into()),
+                    Err(e) => Err(e),
+                },
+            })
+            .chain(stream::once(Ok(Incoming::Closed)))
+            .select(ticks)
+            .select(closed)
+            .select(stalled)
+            .take_while(|item| {
+                Ok(match *item {
+                    Incoming::Closed => false,
+                    _ => true,
+                })
+            });
+
+        let events = frames
+            .and_then(move |item| {
+                let frame = match item {
+                    Incoming::Frame(frame) => frame,
+                    Incoming::Malformed(error) => {
+                        received.received();
+                        trace!("Skipped malformed frame from peer={}: {}", address, error);
+                        let event = NetworkEvent::DecodeError {
+                            peer: address,
+                            error,
+                        };
+                        return Ok(Some(event));
+                    }
+                    Incoming::Tick => {
+                        if let Some(ref mut keep_alive) = keep_alive {
+                            // The peer cannot be heard while reading is paused.
+                            if read_pause.is_paused() {
+                                keep_alive.reset();
+                            } else if keep_alive.tick()? {
+                                trace!("Sending ping to peer={}", address);
+                                let _ = control_tx.unbounded_send(Frame::Ping);
+                            }
+                        }
+                        return Ok(None);
+                    }
+                    Incoming::Closed => return Ok(None),
+                };
+                received.received();
+                if let Some(ref mut keep_alive) = keep_alive {
+                    keep_alive.frame_received(&frame);
+                }
+                if let Frame::Message(ref message) = frame {
+                    if let Some(ref window) = receive_window {
+                        window.borrow_mut().message_received()?;
+                    }
+                    received.message_received(message.len());
+                    received_metrics.record_received_message(message.len());
+                }
+
+                match frame {
+                    Frame::Message(ref message)
+                        if allowed
+                            .as_ref()
+                            .map_or(false, |allowed| !allowed.allows(message)) =>
+                    {
+                        filtered_metrics.record_filtered_message();
+                        trace!(
+                            "Dropped message of type {} of service {} from peer={}",
+                            message.message_type(),
+                            message.service_id(),
+                            address
+                        );
+                        // The credit of the dropped message is granted back right away.
+                        let consumed = receive_window
+                            .as_ref()
+                            .map(|window| (Rc::clone(window), control_tx.clone()));
+                        grant_back(consumed);
+                        Ok(None)
+                    }
+                    Frame::Message(message) => {
+                        Ok(Some(NetworkEvent::MessageReceived(address, peer_key, message)))
+                    }
+                    Frame::Ping => {
+                        let _ = control_tx.unbounded_send(Frame::Pong);
+                        Ok(None)
+                    }
+                    Frame::Credit(credit) => {
+                        granted_credits.grant(credit);
+                        Ok(None)
+                    }
+                    Frame::Pong
+                    | Frame::Version(_)
+                    | Frame::Challenge(_)
+                    | Frame::ChallengeResponse(_) => Ok(None),
+                }
+            })
+            .filter_map(|event| event);
 
-        let incoming_connection = network_tx
-            .sink_map_err(into_failure)
-            .send_all(stream.map(move |message| NetworkEvent::MessageReceived(address, message)))
+        // Reading from the socket is paused while the peer exceeds the rate limit.
+        let timer_handle = handle.clone();
+        let throttle_metrics = Arc::clone(&metrics);
+        let events = events.and_then(move |event| {
+            let delay = match rate_limiter.throttle(Instant::now()) {
+                Ok(delay) => delay,
+                Err(e) => return Either::A(future::err(e.into())),
+            };
+            if delay == Duration::default() {
+                return Either::A(future::ok(event));
+            }
+
+            throttle_metrics.record_throttled_message();
+            trace!("Throttling peer={} for {:?}", address, delay);
+            let pause = future::result(Timeout::new(delay, &timer_handle))
+                .flatten()
+                .map(move |_| event)
+                .map_err(into_failure);
+            Either::B(pause)
+        });
+
+        // If the handler does not keep up with the incoming traffic, reading from the socket
+        // is paused until the events channel has room, so that the peer is slowed down
+        // by the TCP flow control.
+        let mut events_tx = network_tx.clone();
+        let incoming_connection = events
+            .for_each(move |event| {
+                // Credits are granted back only for the messages, which have used them up.
+                let consumed = match (&event, &consumed_window) {
+                    (&NetworkEvent::MessageReceived(..), &Some(ref window)) => {
+                        Some((Rc::clone(window), credit_tx.clone()))
+                    }
+                    _ => None,
+                };
+                let error = match events_tx.try_send(event) {
+                    Ok(()) => {
+                        grant_back(consumed);
+                        return Either::A(future::ok(()));
+                    }
+                    Err(e) => e,
+                };
+                match EventsChannelError::from_try_send(&error) {
+                    EventsChannelError::Full => {
+                        metrics.record_paused_read();
+                        trace!(
+                            "Network events channel is full, paused reading from peer={}",
+                            address
+                        );
+                        let send = events_tx
+                            .clone()
+                            .send(error.into_inner())
+                            .map(move |_| grant_back(consumed))
+                            .map_err(|_| failure::Error::from(EventsChannelError::Closed));
+                        Either::B(send)
+                    }
+                    EventsChannelError::Closed => {
+                        Either::A(future::err(EventsChannelError::Closed.into()))
+                    }
+                }
+            })
+            .or_else(move |e| {
+                if let Some(&EventsChannelError::Closed) = e.downcast_ref() {
+                    // The handler is gone, so there is no one to deliver the events to.
+                    if shutdown.trigger() {
+                        warn!("Network events receiver is gone, shutting down the network");
+                    }
+                    return Either::A(future::err(e));
+                }
+                let misbehaving = e.downcast_ref::<PingTimeout>().is_some()
+                    || e.downcast_ref::<RateLimitExceeded>().is_some()
+                    || e.downcast_ref::<FlowControlViolation>().is_some()
+                    || e.downcast_ref::<DecodeError>().is_some()
+                    || e.downcast_ref::<WriteTimeout>().is_some();
+                if !misbehaving {
+                    return Either::A(future::err(e));
+                }
+                warn!("Closing connection with peer={}: {}", address, e);
+                // The connection is closed on purpose, so it is not re-established.
+                registry.unregister(&peer_key, connection_id);
+                let _ = close_tx.send(());
+                let disconnected = network_tx
+                    .send(NetworkEvent::PeerDisconnected(address))
+                    .map(drop)
+                    .map_err(into_failure);
+                Either::B(disconnected)
+            })
             .map_err(|e| {
                 error!("Connection terminated: {}: {}", e, e.find_root_cause());
             })
-            .map(drop);
+            .then(move |result| {
+                drop(ticket);
+                result
+            });
 
-        let outgoing_connection = connection
-            .receiver_rx
-            .map_err(|_| format_err!("Receiver is gone."))
+        let write_timeout = network_config.write_timeout.map(Duration::from_millis);
+        let sink = WriteDeadline::new(sink, write_timeout, handle.clone());
+        let half_close_timeout = Duration::from_millis(network_config.half_close_timeout);
+        let drain_handle = handle.clone();
+        let draining = Rc::new(Cell::new(false));
+        let queued = Credited::new(connection.receiver_rx, send_credits)
+            .map(Frame::Message)
+            .select(control_rx)
+            .inspect(move |frame| {
+                sent.sent();
+                if let Frame::Message(ref message) = *frame {
+                    sent.message_sent(message.len());
+                    sent_metrics.record_sent_message(message.len());
+                }
+            })
+            .map_err(|_| format_err!("Receiver is gone."));
+        let frames = Drain {
+            stream: queued,
+            draining: Rc::clone(&draining),
+        };
+        let outgoing_connection = frames
             .forward(sink)
             .map(drop)
-            .map_err(|e| {
+            .select2(close_rx)
+            .then(move |result| match result {
+                Ok(_) => Either::A(future::ok(())),
+                Err(Either::A((e, _))) => Either::A(future::err(e)),
+                // The reading half has terminated for another reason, e.g. the peer has
+                // closed its writing half. The queued messages are flushed before
+                // the connection is closed.
+                Err(Either::B((_, outgoing))) => {
+                    draining.set(true);
+                    let expired = future::result(Timeout::new(half_close_timeout, &drain_handle))
+                        .flatten()
+                        .map_err(into_failure);
+                    let flushed = outgoing.select2(expired).then(move |result| match result {
+                        Ok(Either::A(_)) => Ok(()),
+                        Ok(Either::B(_)) => {
+                            debug!("Dropped unsent messages for peer={}", address);
+                            Ok(())
+                        }
+                        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+                    });
+                    Either::B(flushed)
+                }
+            })
+            .map_err(move |e| {
+                if let Some(&WriteTimeout(timeout)) = e.downcast_ref() {
+                    let _ = stalled_tx.send(timeout);
+                    return;
+                }
                 error!("Connection terminated: {}: {}", e, e.find_root_cause());
             });
 
@@ -324,25 +1946,80 @@ impl NetworkHandler {
         Ok(())
     }
 
-    fn configure_socket(
-        socket: TcpStream,
+    /// Exchanges protocol versions with the peer. If the versions are incompatible,
+    /// the connection is refused and `PeerDisconnected` is emitted.
+    fn check_protocol_version(
+        socket: Framed<T::Stream, MessagesCodec>,
+        address: SocketAddr,
         network_config: NetworkConfiguration,
-    ) -> Result<TcpStream, failure::Error> {
-        socket.set_nodelay(network_config.tcp_nodelay)?;
-        let duration = network_config.tcp_keep_alive.map(Duration::from_millis);
-        socket.set_keepalive(duration)?;
-        Ok(socket)
+        network_tx: EventsSender,
+    ) -> impl Future<Item = Framed<T::Stream, MessagesCodec>, Error = failure::Error> {
+        handshake::exchange_versions(socket, &network_config).or_else(move |e| {
+            if e.downcast_ref::<IncompatibleVersion>().is_none() {
+                return Either::A(future::err(e));
+            }
+            warn!("Refused connection with peer={}: {}", address, e);
+            let disconnected = network_tx
+                .send(NetworkEvent::PeerDisconnected(address))
+                .map_err(into_failure)
+                .and_then(move |_| Err(e));
+            Either::B(disconnected)
+        })
     }
 
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
     fn handle_connection(
-        connection: Connection,
+        connection: Connection<T::Stream>,
         message: Connect,
-        network_tx: &mpsc::Sender<NetworkEvent>,
+        network_tx: &EventsSender,
+        network_config: NetworkConfiguration,
+        rate_limiter: &RateLimiter,
+        message_filter: &MessageFilter,
+        metrics: Arc<NetworkMetrics>,
+        shutdown: ShutdownSignal,
     ) -> impl Future<Item = (), Error = failure::Error> {
         trace!("Established connection with peer={}", connection.address);
         let handle = connection.handle.clone();
-        Self::send_peer_connected_event(&connection.address, message, &network_tx)
-            .and_then(move |network_tx| Self::process_messages(&handle, connection, network_tx))
+        let rate_limiter = rate_limiter.for_peer(*message.pub_key());
+        let allowed = message_filter.allowed(message.pub_key());
+        // The peer has already been announced if the connection replaces another one.
+        let peer_connected = if connection.ticket.announce {
+            let event = Self::send_peer_connected_event(&connection.address, message, &network_tx);
+            Either::A(event)
+        } else {
+            trace!("Replaced connection with peer={}", connection.address);
+            Either::B(future::ok(network_tx.clone()))
+        };
+        peer_connected.and_then(move |network_tx| {
+            Self::process_messages(
+                &handle,
+                connection,
+                network_tx,
+                network_config,
+                rate_limiter,
+                allowed,
+                metrics,
+                shutdown,
+            )
+        })
+    }
+
+    /// Checks that the peer owns the key announced in its `Connect` message, unless
+    /// the authentication is disabled in the configuration.
+    fn authenticate_peer(
+        socket: Framed<T::Stream, MessagesCodec>,
+        message: Connect,
+        network_config: NetworkConfiguration,
+        signing_key: &SecretKey,
+    ) -> impl Future<Item = (Framed<T::Stream, MessagesCodec>, Connect), Error = failure::Error>
+    {
+        if !network_config.authenticate_peers {
+            return Either::A(future::ok((socket, message)));
+        }
+        let peer = *message.pub_key();
+        let authenticated =
+            handshake::authenticate(socket, peer, signing_key).map(move |socket| (socket, message));
+        Either::B(authenticated)
     }
 
     fn parse_connect_msg(raw: Option<RawMessage>) -> Result<Connect, failure::Error> {
@@ -360,23 +2037,62 @@ impl NetworkHandler {
     pub fn request_handler(
         self,
         receiver: mpsc::Receiver<NetworkRequest>,
-        cancel_handler: unsync::oneshot::Sender<()>,
     ) -> impl Future<Item = (), Error = failure::Error> {
-        let mut cancel_sender = Some(cancel_handler);
         let handle = self.handle.clone();
 
         let handler = receiver.for_each(move |request| {
             let fut = match request {
                 NetworkRequest::SendMessage(address, message) => {
-                    to_box(self.handle_send_message(&address, message))
+                    to_box(self.handle_send_message(&address, message, None))
+                }
+                NetworkRequest::SendMessageWithAck(address, message, confirmation) => {
+                    to_box(self.handle_send_message(&address, message, Some(confirmation)))
                 }
                 NetworkRequest::DisconnectWithPeer(peer) => to_box(self.disconnect_with_peer(peer)),
-                NetworkRequest::Shutdown => to_box(
-                    cancel_sender
-                        .take()
-                        .ok_or_else(|| format_err!("shutdown twice"))
-                        .into_future(),
-                ),
+                NetworkRequest::DisconnectPeer(peer, ban) => {
+                    to_box(self.disconnect_peer(peer, ban))
+                }
+                NetworkRequest::Pause => {
+                    self.registry.read_pause.pause();
+                    to_box(future::ok(()))
+                }
+                NetworkRequest::Resume => {
+                    self.registry.read_pause.resume();
+                    to_box(future::ok(()))
+                }
+                NetworkRequest::Broadcast(message, excluded) => {
+                    to_box(self.broadcast(&message, &excluded))
+                }
+                NetworkRequest::Shutdown => to_box(if self.shutdown.trigger() {
+                    future::ok(())
+                } else {
+                    future::err(format_err!("shutdown twice"))
+                }),
+                NetworkRequest::ConnectionsActivity(response_tx) => {
+                    // The requester may have gone away already.
+                    let _ = response_tx.send(self.registry.activity());
+                    to_box(future::ok(()))
+                }
+                NetworkRequest::PeerTraffic(response_tx) => {
+                    let _ = response_tx.send(self.registry.traffic());
+                    to_box(future::ok(()))
+                }
+                NetworkRequest::PeerInfo(response_tx) => {
+                    let _ = response_tx.send(self.registry.peer_info());
+                    to_box(future::ok(()))
+                }
+                NetworkRequest::ConnectionStats(response_tx) => {
+                    let _ = response_tx.send(self.connection_stats());
+                    to_box(future::ok(()))
+                }
+                NetworkRequest::ReconcilePeers(peers) => {
+                    self.reconcile_peers(peers);
+                    to_box(future::ok(()))
+                }
+                NetworkRequest::UpdateConfig(network_config) => {
+                    self.update_config(network_config);
+                    to_box(future::ok(()))
+                }
             }.map_err(log_error);
 
             handle.spawn(fut);
@@ -390,41 +2106,95 @@ impl NetworkHandler {
         &self,
         address: &SocketAddr,
         message: RawMessage,
+        confirmation: Option<ResponseSender<SendResult>>,
     ) -> impl Future<Item = (), Error = failure::Error> {
         let pool = self.pool.clone();
 
         if pool.contains(&address) {
-            to_box(pool.send_message(&address, &message))
-        } else if self.can_create_connections() {
-            to_box(self.create_new_connection(&address, message))
+            match pool.send_message(&address, &message) {
+                Ok(result) => {
+                    confirm_send(confirmation, result);
+                    to_box(future::ok(()))
+                }
+                Err(()) => {
+                    confirm_send(confirmation, SendResult::Dropped);
+                    to_box(self.disconnect_with_peer(*address))
+                }
+            }
+        } else if self
+            .handshake_params
+            .connect_list
+            .find_key_by_address(address)
+            .is_none()
+        {
+            warn!("Attempt to send message to unknown peer={}", address);
+            pool.failures
+                .publish(address, &message, SendFailureReason::PeerUnknown);
+            confirm_send(confirmation, SendResult::PeerUnknown);
+            to_box(future::ok(()))
+        } else if !self.is_banned_address(address) && self.can_create_connections() {
+            to_box(self.create_new_connection(&address, message, confirmation))
         } else {
+            pool.failures
+                .publish(address, &message, SendFailureReason::ConnectFailed);
+            confirm_send(confirmation, SendResult::Dropped);
             to_box(self.send_unable_connect_event(&address))
         }
     }
 
+    fn broadcast(
+        &self,
+        message: &RawMessage,
+        excluded: &HashSet<PublicKey>,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        let mut disconnects = Vec::new();
+        for (peer, address) in self.registry.connected_peers() {
+            if excluded.contains(&peer) {
+                continue;
+            }
+            if self.pool.send_message(&address, message).is_err() {
+                disconnects.push(self.disconnect_with_peer(address));
+            }
+        }
+        future::join_all(disconnects).map(drop)
+    }
+
     fn create_new_connection(
         &self,
         address: &SocketAddr,
         message: RawMessage,
+        confirmation: Option<ResponseSender<SendResult>>,
     ) -> impl Future<Item = (), Error = failure::Error> {
-        let pool = self.pool.clone();
         let address = *address;
-        let connect = self.handshake_params.connect.clone();
-        self.connect(address, &self.handshake_params)
-            .and_then(move |_| {
-                if &message == connect.raw() {
-                    Either::A(future::ok(()))
-                } else {
-                    Either::B(pool.send_message(&address, &message))
+        let connecting = self.connect(address, &self.handshake_params);
+        // The queue of the connection is created at once, so the message is queued before
+        // the ones sent while the connection is being established.
+        let queued = if &message == self.handshake_params.connect.raw() {
+            // The `Connect` message is sent during the handshake.
+            Ok(SendResult::Queued)
+        } else {
+            self.pool.send_message(&address, &message)
+        };
+        let failures = self.pool.failures.clone();
+        connecting.then(move |connected| {
+            let result = match (&connected, queued) {
+                (&Ok(()), Ok(result)) => result,
+                (&Err(_), Ok(SendResult::Queued)) => {
+                    failures.publish(&address, &message, SendFailureReason::ConnectFailed);
+                    SendResult::Dropped
                 }
-            })
+                _ => SendResult::Dropped,
+            };
+            confirm_send(confirmation, result);
+            connected
+        })
     }
 
     fn send_peer_connected_event(
         address: &SocketAddr,
         message: Connect,
-        network_tx: &mpsc::Sender<NetworkEvent>,
-    ) -> impl Future<Item = mpsc::Sender<NetworkEvent>, Error = failure::Error> {
+        network_tx: &EventsSender,
+    ) -> impl Future<Item = EventsSender, Error = failure::Error> {
         let peer_connected = NetworkEvent::PeerConnected(*address, message);
         network_tx
             .clone()
@@ -432,8 +2202,29 @@ impl NetworkHandler {
             .map_err(into_failure)
     }
 
+    fn update_config(&self, network_config: NetworkConfiguration) {
+        self.rate_limiter.update(&network_config);
+        self.pool.update(&network_config);
+        self.network_config.set(network_config);
+        info!("Network configuration has been updated");
+    }
+
+    fn connection_stats(&self) -> ConnectionStats {
+        let network_config = self.network_config.get();
+        let (incoming, outgoing) = self.registry.counts();
+        ConnectionStats {
+            incoming,
+            outgoing,
+            pending_outgoing: self.pending_connects.get(),
+            max_incoming_connections: network_config.max_incoming_connections,
+            max_outgoing_connections: network_config.max_outgoing_connections,
+        }
+    }
+
     fn can_create_connections(&self) -> bool {
-        self.pool.len() <= self.network_config.max_outgoing_connections
+        let network_config = self.network_config.get();
+        self.pool.len() <= network_config.max_outgoing_connections
+            && self.pending_connects.get() < network_config.max_concurrent_connects
     }
 
     fn disconnect_with_peer(
@@ -441,6 +2232,7 @@ impl NetworkHandler {
         peer: SocketAddr,
     ) -> impl Future<Item = (), Error = failure::Error> {
         self.pool.remove(&peer);
+        self.registry.close(&peer);
         self.network_tx
             .clone()
             .send(NetworkEvent::PeerDisconnected(peer))
@@ -448,6 +2240,66 @@ impl NetworkHandler {
             .map(drop)
     }
 
+    fn disconnect_peer(
+        &self,
+        peer: PublicKey,
+        ban: Option<Duration>,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        if let Some(duration) = ban {
+            self.banned.ban(peer, duration);
+        }
+        match self.registry.address_of(&peer) {
+            Some(address) => Either::A(self.disconnect_with_peer(address)),
+            None => Either::B(future::ok(())),
+        }
+    }
+
+    // Reading from the closed connections stops at once, while the messages queued
+    // for them are flushed within `half_close_timeout`.
+    fn reconcile_peers(&self, peers: HashSet<PublicKey>) {
+        let previous = mem::replace(&mut *self.peer_set.borrow_mut(), peers.clone());
+        for removed in previous.difference(&peers) {
+            let disconnected = self.disconnect_peer(*removed, None).map_err(log_error);
+            self.handle.spawn(disconnected);
+        }
+
+        let connect_list = &self.handshake_params.connect_list;
+        for added in peers.difference(&previous) {
+            if self.registry.address_of(added).is_some() || self.banned.is_banned(added) {
+                continue;
+            }
+            let address = match connect_list.find_address_by_key(added) {
+                Some(address) => address,
+                None => {
+                    warn!(
+                        "Cannot connect to peer={:?} missing in the ConnectList",
+                        added
+                    );
+                    continue;
+                }
+            };
+            if self.pool.contains(&address) {
+                // The connection is being established already.
+                continue;
+            }
+            if !self.can_create_connections() {
+                warn!("Cannot connect to peer={}, connections limit reached", address);
+                continue;
+            }
+            let connected = self
+                .connect(address, &self.handshake_params)
+                .map_err(log_error);
+            self.handle.spawn(connected);
+        }
+    }
+
+    fn is_banned_address(&self, address: &SocketAddr) -> bool {
+        self.handshake_params
+            .connect_list
+            .find_key_by_address(address)
+            .map_or(false, |peer| self.banned.is_banned(&peer))
+    }
+
     fn send_unable_connect_event(
         &self,
         peer: &SocketAddr,
@@ -461,10 +2313,10 @@ impl NetworkHandler {
     }
 
     fn build_handshake_initiator(
-        stream: TcpStream,
+        stream: T::Stream,
         peer: &SocketAddr,
         handshake_params: &HandshakeParams,
-    ) -> impl Future<Item = (Framed<TcpStream, MessagesCodec>, RawMessage), Error = failure::Error>
+    ) -> impl Future<Item = (Framed<T::Stream, MessagesCodec>, RawMessage), Error = failure::Error>
     {
         let connect_list = &handshake_params.connect_list.clone();
         if let Some(remote_public_key) = connect_list.find_key_by_address(&peer) {
@@ -481,29 +2333,164 @@ impl NetworkHandler {
     }
 }
 
+/// Merges the events of two network parts, e.g., running over different transports, into
+/// a single stream which can be passed to the aggregator in place of `network_rx`.
+/// The streams are polled in turns, so that neither of them is starved, and the merged
+/// stream completes once both of them are exhausted.
+pub fn merge_network<A, B>(a: A, b: B) -> impl Stream<Item = NetworkEvent, Error = A::Error>
+where
+    A: Stream<Item = NetworkEvent>,
+    B: Stream<Item = NetworkEvent, Error = A::Error>,
+{
+    a.select(b)
+}
+
+/// Grants the credits back to the peer once enough of its messages are handed over
+/// to the handler, see `ReceiveWindow::message_consumed`.
+fn grant_back(
+    consumed: Option<(Rc<RefCell<ReceiveWindow>>, unsync::mpsc::UnboundedSender<Frame>)>,
+) {
+    if let Some((window, control_tx)) = consumed {
+        if let Some(credit) = window.borrow_mut().message_consumed() {
+            let _ = control_tx.unbounded_send(Frame::Credit(credit));
+        }
+    }
+}
+
+fn confirm_send(confirmation: Option<ResponseSender<SendResult>>, result: SendResult) {
+    if let Some(confirmation) = confirmation {
+        // The requester may have gone away already.
+        let _ = confirmation.send(result);
+    }
+}
+
+/// Returns delays between reconnection attempts: each delay is `tcp_connect_retry_multiplier`
+/// times longer than the previous one, but not longer than `tcp_connect_retry_max_timeout`.
+fn connect_retry_delays(config: &NetworkConfiguration) -> impl Iterator<Item = Duration> {
+    let multiplier = config.tcp_connect_retry_multiplier;
+    let max_delay = config.tcp_connect_retry_max_timeout;
+    let mut delay = config.tcp_connect_retry_timeout.min(max_delay);
+
+    (0..config.tcp_connect_max_retries).map(move |_| {
+        let current = delay;
+        delay = (delay as f64 * multiplier).min(max_delay as f64) as Milliseconds;
+        Duration::from_millis(current)
+    })
+}
+
 impl NetworkPart {
+    /// Creates the network part listening on a single address.
+    pub fn new(
+        our_connect_message: Connect,
+        listen_address: SocketAddr,
+        network_config: NetworkConfiguration,
+        max_message_len: u32,
+        network_requests: (mpsc::Sender<NetworkRequest>, mpsc::Receiver<NetworkRequest>),
+        network_tx: mpsc::Sender<NetworkEvent>,
+    ) -> Self {
+        NetworkPart {
+            our_connect_message,
+            listen_addresses: vec![listen_address],
+            network_config,
+            max_message_len,
+            network_requests,
+            network_tx,
+            events_depth: QueueDepth::default(),
+            metrics: Arc::default(),
+            transport: TcpTransport,
+            message_filter: MessageFilter::default(),
+            endpoints: HashMap::new(),
+            resolver: Arc::new(SystemResolver),
+            send_failures: None,
+            frame_codec: Arc::new(BinaryFrameCodec),
+        }
+    }
+}
+
+impl<T: Transport> NetworkPart<T> {
+    /// Makes the network part accept and establish connections via the given transport.
+    pub fn with_transport<U: Transport>(self, transport: U) -> NetworkPart<U> {
+        NetworkPart {
+            our_connect_message: self.our_connect_message,
+            listen_addresses: self.listen_addresses,
+            network_config: self.network_config,
+            max_message_len: self.max_message_len,
+            network_requests: self.network_requests,
+            network_tx: self.network_tx,
+            events_depth: self.events_depth,
+            metrics: self.metrics,
+            transport,
+            message_filter: self.message_filter,
+            endpoints: self.endpoints,
+            resolver: self.resolver,
+            send_failures: self.send_failures,
+            frame_codec: self.frame_codec,
+        }
+    }
+
+    /// Restricts the types of the messages accepted from the peers.
+    pub fn with_message_filter(mut self, message_filter: MessageFilter) -> Self {
+        self.message_filter = message_filter;
+        self
+    }
+
+    /// Returns the stream of the failures to send messages to the peers, which complements
+    /// `SendResult` and `NetworkMetrics` for the tooling observing the node. The stream
+    /// buffers up to `capacity` failures; the failures occurring while it is full are
+    /// dropped. Only the stream returned last receives the failures.
+    pub fn subscribe_send_failures(&mut self, capacity: usize) -> mpsc::Receiver<SendFailure> {
+        let (tx, rx) = mpsc::channel(capacity);
+        self.send_failures = Some(tx);
+        rx
+    }
+
+    /// Makes the network part resolve the endpoints of the peers with the given resolver.
+    pub fn with_resolver<R: Resolver>(mut self, resolver: R) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Makes the network part exchange the frames with the peers in the given format,
+    /// e.g., `JsonFrameCodec` for debugging.
+    pub fn with_frame_codec<C: FrameCodec>(mut self, frame_codec: C) -> Self {
+        self.frame_codec = Arc::new(frame_codec);
+        self
+    }
+
     pub fn run(
         self,
         handle: &Handle,
         handshake_params: &HandshakeParams,
     ) -> impl Future<Item = (), Error = failure::Error> {
-        let listen_address = self.listen_address;
-        // `cancel_sender` is converted to future when we receive
-        // `NetworkRequest::Shutdown` causing its being completed with error.
-        // After that completes `cancel_handler` and event loop stopped.
-        let (cancel_sender, cancel_handler) = unsync::oneshot::channel::<()>();
-
+        let mut handshake_params = handshake_params.clone();
+        handshake_params.frame_codec = self.frame_codec;
+        let mut pool = ConnectionPool::new(&self.network_config, Arc::clone(&self.metrics));
+        pool.failures = SendFailures::new(self.send_failures);
         let handler = NetworkHandler::new(
+            self.transport,
             handle.clone(),
-            listen_address,
-            ConnectionPool::new(),
+            pool,
             self.network_config,
-            self.network_tx.clone(),
-            handshake_params.clone(),
+            CountingSender::new(self.network_tx.clone(), self.events_depth),
+            handshake_params,
+            self.metrics,
+            self.message_filter,
+            self.endpoints,
+            self.resolver,
         );
 
-        let listener = handler.clone().listener();
-        let request_handler = handler.request_handler(self.network_requests.1, cancel_sender);
+        // An accept loop per address; all of them share the events channel.
+        let listeners = self
+            .listen_addresses
+            .into_iter()
+            .map(|address| handler.clone().listener(address))
+            .collect::<Vec<_>>();
+        let listener = future::join_all(listeners);
+        // The shutdown signal drops its sender once we receive `NetworkRequest::Shutdown`
+        // or the network events receiver is gone, causing `cancel_handler` being completed
+        // with error. After that the event loop is stopped.
+        let cancel_handler = handler.shutdown.listen();
+        let request_handler = handler.request_handler(self.network_requests.1);
 
         let cancel_handler = cancel_handler.or_else(|e| {
             trace!("Requests handler closed: {}", e);
@@ -518,3 +2505,650 @@ impl NetworkPart {
             .map(drop)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::gen_keypair;
+    use events::{
+        codec::test::create_encrypted_codecs, resolver::ResolveFuture, tests::raw_message,
+        transport::{MemoryStream, MemoryTransport},
+    };
+    use messages::MessageBuffer;
+
+    #[test]
+    fn cloned_requests_share_response_sender() {
+        let (response_tx, response_rx) = oneshot::channel();
+        let request = NetworkRequest::PeerInfo(response_tx.into());
+        match (request.clone(), request) {
+            (NetworkRequest::PeerInfo(first), NetworkRequest::PeerInfo(second)) => {
+                assert!(first.send(Vec::new()).is_ok());
+                assert!(second.send(Vec::new()).is_err());
+            }
+            _ => unreachable!(),
+        }
+        assert!(response_rx.wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn connect_retry_delays_grow_exponentially() {
+        let config = NetworkConfiguration {
+            tcp_connect_retry_timeout: 100,
+            tcp_connect_max_retries: 6,
+            tcp_connect_retry_multiplier: 2.0,
+            tcp_connect_retry_max_timeout: 1_000,
+            ..NetworkConfiguration::default()
+        };
+
+        let delays: Vec<_> = connect_retry_delays(&config)
+            .map(|delay| delay.as_secs() * 1_000 + u64::from(delay.subsec_millis()))
+            .collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1_000, 1_000]);
+    }
+
+    #[test]
+    fn incoming_slots_evict_unknown_peers_first() {
+        let slots = IncomingSlots::default();
+        let known = slots.acquire(2).unwrap();
+        known.establish();
+        let mut unknown = slots.acquire(2).unwrap();
+
+        // The unknown connection makes room for the new one.
+        let newcomer = slots.acquire(2).unwrap();
+        assert_eq!(unknown.evicted().wait(), Ok(()));
+        drop(unknown);
+
+        // Once all the slots are taken by known peers, new connections are rejected.
+        newcomer.establish();
+        assert!(slots.acquire(2).is_none());
+        drop(known);
+        assert!(slots.acquire(2).is_some());
+        drop(newcomer);
+    }
+
+    #[test]
+    fn keep_alive_pings_idle_connection() {
+        let config = NetworkConfiguration {
+            keep_alive_interval: Some(1_000),
+            keep_alive_timeout: 500,
+            ..NetworkConfiguration::default()
+        };
+        let mut keep_alive = KeepAlive::new(&config).unwrap();
+        assert_eq!(keep_alive.period(), Duration::from_millis(500));
+        assert!(!keep_alive.tick().unwrap());
+
+        keep_alive.last_received -= Duration::from_millis(1_000);
+        assert!(keep_alive.tick().unwrap());
+        // The ping is sent only once.
+        assert!(!keep_alive.tick().unwrap());
+
+        keep_alive.frame_received(&Frame::Pong);
+        assert!(keep_alive.ping_sent.is_none());
+        assert!(!keep_alive.tick().unwrap());
+    }
+
+    #[test]
+    fn keep_alive_ping_timeout() {
+        let config = NetworkConfiguration {
+            keep_alive_interval: Some(1_000),
+            keep_alive_timeout: 500,
+            ..NetworkConfiguration::default()
+        };
+        let mut keep_alive = KeepAlive::new(&config).unwrap();
+        keep_alive.last_received -= Duration::from_millis(1_000);
+        assert!(keep_alive.tick().unwrap());
+
+        // Messages other than `Pong` do not answer the ping.
+        keep_alive.frame_received(&Frame::Ping);
+        *keep_alive.ping_sent.as_mut().unwrap() -= Duration::from_millis(500);
+        assert!(keep_alive.tick().is_err());
+    }
+
+    #[test]
+    fn merged_network_streams_are_interleaved() {
+        fn disconnects(ports: &[u16]) -> impl Stream<Item = NetworkEvent, Error = ()> {
+            let events: Vec<_> = ports
+                .iter()
+                .map(|&port| NetworkEvent::PeerDisconnected(([127, 0, 0, 1], port).into()))
+                .collect();
+            stream::iter_ok(events)
+        }
+
+        let merged = merge_network(disconnects(&[1, 2, 3]), disconnects(&[4, 5]));
+        let ports: Vec<_> = merged
+            .map(|event| match event {
+                NetworkEvent::PeerDisconnected(address) => address.port(),
+                other => panic!("Unexpected event {:?}", other),
+            })
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(ports, vec![1, 4, 2, 5, 3]);
+    }
+
+    #[test]
+    fn keep_alive_disabled() {
+        let config = NetworkConfiguration {
+            keep_alive_interval: None,
+            ..NetworkConfiguration::default()
+        };
+        assert!(KeepAlive::new(&config).is_none());
+    }
+
+    #[test]
+    fn dial_aborts_after_timeout() {
+        use tokio_core::reactor::Core;
+
+        let config = NetworkConfiguration {
+            connect_timeout: 200,
+            ..NetworkConfiguration::default()
+        };
+        // Packets to this address are dropped, so the connection is never established.
+        let address = "10.255.255.1:19773".parse().unwrap();
+        let metrics = Arc::new(NetworkMetrics::new());
+
+        let mut core = Core::new().unwrap();
+        let start = Instant::now();
+        let dial = NetworkHandler::dial(
+            &TcpTransport,
+            address,
+            &config,
+            &core.handle(),
+            Arc::clone(&metrics),
+        );
+        assert_eq!(core.run(dial).err(), Some(ConnectError::Timeout));
+        assert!(start.elapsed() < Duration::from_millis(1_000));
+        assert_eq!(metrics.failed_dials(), 1);
+        assert_eq!(metrics.connect_errors(ConnectError::Timeout), 1);
+    }
+
+    #[test]
+    fn refused_dial_is_categorized() {
+        use tokio_core::reactor::Core;
+
+        // Nothing listens on this address.
+        let address = "127.0.0.1:19840".parse().unwrap();
+        let metrics = Arc::new(NetworkMetrics::new());
+
+        let mut core = Core::new().unwrap();
+        let dial = NetworkHandler::dial(
+            &TcpTransport,
+            address,
+            &NetworkConfiguration::default(),
+            &core.handle(),
+            Arc::clone(&metrics),
+        );
+        assert_eq!(core.run(dial).err(), Some(ConnectError::Refused));
+        assert_eq!(metrics.connect_errors(ConnectError::Refused), 1);
+        assert_eq!(metrics.connect_errors(ConnectError::Timeout), 0);
+    }
+
+    #[test]
+    fn connect_errors_are_categorized() {
+        let error = |kind| io::Error::new(kind, "test");
+        let categories = vec![
+            (io::ErrorKind::ConnectionRefused, ConnectError::Refused),
+            (io::ErrorKind::ConnectionReset, ConnectError::Reset),
+            (io::ErrorKind::UnexpectedEof, ConnectError::Reset),
+            (io::ErrorKind::TimedOut, ConnectError::Timeout),
+            (io::ErrorKind::AddrNotAvailable, ConnectError::Other),
+        ];
+        for (kind, category) in categories {
+            assert_eq!(ConnectError::from_io(&error(kind)), category);
+        }
+
+        let reset = failure::Error::from(error(io::ErrorKind::ConnectionReset));
+        assert_eq!(ConnectError::from_handshake(&reset), ConnectError::Reset);
+        let rejected = format_err!("First message from a remote peer is not Connect");
+        let rejected = ConnectError::from_handshake(&rejected);
+        assert_eq!(rejected, ConnectError::HandshakeRejected);
+        assert!(!rejected.is_retriable());
+        assert!(ConnectError::Refused.is_retriable());
+    }
+
+    #[derive(Debug)]
+    struct StubResolver(Vec<SocketAddr>);
+
+    #[derive(Debug)]
+    struct FailingResolver;
+
+    impl Resolver for FailingResolver {
+        fn resolve(&self, _endpoint: &str) -> ResolveFuture {
+            let error = io::Error::new(io::ErrorKind::Other, "failed to lookup address");
+            Box::new(future::err(error))
+        }
+    }
+
+    impl Resolver for StubResolver {
+        fn resolve(&self, endpoint: &str) -> ResolveFuture {
+            assert_eq!(endpoint, "peer.example.com:6333");
+            Box::new(future::ok(self.0.clone()))
+        }
+    }
+
+    #[test]
+    fn dial_tries_resolved_addresses_in_order() {
+        use tokio_core::reactor::Core;
+
+        let config = NetworkConfiguration::default();
+        let unreachable = "127.0.0.1:19831".parse().unwrap();
+        let address = "127.0.0.1:19832".parse().unwrap();
+        let transport = MemoryTransport::new();
+        let listener = transport.listen(&address).unwrap();
+        let resolver = StubResolver(vec![unreachable, address]);
+        let metrics = Arc::new(NetworkMetrics::new());
+
+        let mut core = Core::new().unwrap();
+        let dial = NetworkHandler::dial_endpoint(
+            &transport,
+            &resolver,
+            "peer.example.com:6333",
+            &config,
+            &core.handle(),
+            Arc::clone(&metrics),
+        );
+        assert!(core.run(dial).is_ok());
+        let accepted = core.run(listener.into_future().map(|(accepted, _)| accepted));
+        assert!(accepted.map_err(|(e, _)| e).unwrap().is_some());
+        // The first of the resolved addresses is not listened on.
+        assert_eq!(metrics.failed_dials(), 1);
+    }
+
+    #[test]
+    fn unresolved_endpoint_is_categorized() {
+        use tokio_core::reactor::Core;
+
+        let metrics = Arc::new(NetworkMetrics::new());
+        let mut core = Core::new().unwrap();
+        let dial = NetworkHandler::dial_endpoint(
+            &MemoryTransport::new(),
+            &FailingResolver,
+            "peer.example.com:6333",
+            &NetworkConfiguration::default(),
+            &core.handle(),
+            Arc::clone(&metrics),
+        );
+        assert_eq!(core.run(dial).err(), Some(ConnectError::Resolve));
+        assert_eq!(metrics.connect_errors(ConnectError::Resolve), 1);
+    }
+
+    #[test]
+    fn malformed_frames_are_reported() {
+        use tokio_core::reactor::Core;
+
+        let config = NetworkConfiguration {
+            decode_error_policy: DecodeErrorPolicy::Report,
+            keep_alive_interval: None,
+            ..NetworkConfiguration::default()
+        };
+        let address = "127.0.0.1:19774".parse().unwrap();
+        let (peer, _) = gen_keypair();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let (local, remote) = MemoryStream::pair();
+        let (responder, initiator) = create_encrypted_codecs();
+        let registry = PeerRegistry::new(gen_keypair().0);
+        let ticket = registry
+            .register(peer, address, Direction::Incoming)
+            .unwrap();
+        let pool = ConnectionPool::new(&config, Arc::default());
+        let receiver_rx = pool.add_address(&address);
+        let socket = Framed::new(local, responder);
+        let connection = Connection::new(handle.clone(), address, socket, receiver_rx, ticket);
+        let (network_tx, network_rx) = mpsc::channel(8);
+        NetworkHandler::<MemoryTransport>::process_messages(
+            &handle,
+            connection,
+            CountingSender::new(network_tx, QueueDepth::default()),
+            config,
+            RateLimiter::new(&config).for_peer(peer),
+            None,
+            Arc::default(),
+            ShutdownSignal::default(),
+        ).unwrap();
+
+        // The frame with a wrong length in the message header is followed by a valid one.
+        let garbage = vec![0_u8, 0, 0, 0, 0, 0, 11, 0, 0, 0];
+        let garbage = RawMessage::new(MessageBuffer::from_vec(garbage));
+        let message = raw_message(11, 1000);
+        let send = Framed::new(remote, initiator)
+            .send(Frame::Message(garbage))
+            .and_then(|sink| sink.send(Frame::Message(message.clone())));
+        let _remote = core.run(send).unwrap();
+
+        let events = core.run(network_rx.take(2).collect()).unwrap();
+        match events[0] {
+            NetworkEvent::DecodeError {
+                peer: ref from,
+                error: DecodeError::MalformedFrame(_),
+            } if *from == address => {}
+            ref other => panic!("Unexpected event: {:?}", other),
+        }
+        match events[1] {
+            NetworkEvent::MessageReceived(_, ref key, ref received)
+                if *key == peer && *received == message => {}
+            ref other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn disallowed_messages_are_dropped() {
+        use tokio_core::reactor::Core;
+
+        let config = NetworkConfiguration {
+            keep_alive_interval: None,
+            ..NetworkConfiguration::default()
+        };
+        let address = "127.0.0.1:19829".parse().unwrap();
+        let (peer, _) = gen_keypair();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let (local, remote) = MemoryStream::pair();
+        let (responder, initiator) = create_encrypted_codecs();
+        let registry = PeerRegistry::new(gen_keypair().0);
+        let ticket = registry
+            .register(peer, address, Direction::Incoming)
+            .unwrap();
+        let pool = ConnectionPool::new(&config, Arc::default());
+        let receiver_rx = pool.add_address(&address);
+        let socket = Framed::new(local, responder);
+        let connection = Connection::new(handle.clone(), address, socket, receiver_rx, ticket);
+        let (network_tx, network_rx) = mpsc::channel(8);
+        let metrics = Arc::new(NetworkMetrics::new());
+        NetworkHandler::<MemoryTransport>::process_messages(
+            &handle,
+            connection,
+            CountingSender::new(network_tx, QueueDepth::default()),
+            config,
+            RateLimiter::new(&config).for_peer(peer),
+            Some(AllowedMessages::new(vec![(0, 11)])),
+            Arc::clone(&metrics),
+            ShutdownSignal::default(),
+        ).unwrap();
+
+        // The message of a disallowed type is followed by an allowed one.
+        let disallowed = raw_message(12, 1000);
+        let allowed = raw_message(11, 1000);
+        let send = Framed::new(remote, initiator)
+            .send(Frame::Message(disallowed))
+            .and_then(|sink| sink.send(Frame::Message(allowed.clone())));
+        let _remote = core.run(send).unwrap();
+
+        let events = core.run(network_rx.take(1).collect()).unwrap();
+        match events[0] {
+            NetworkEvent::MessageReceived(_, ref key, ref received)
+                if *key == peer && *received == allowed => {}
+            ref other => panic!("Unexpected event: {:?}", other),
+        }
+        assert_eq!(metrics.filtered_messages(), 1);
+    }
+
+    #[test]
+    fn queued_messages_are_flushed_after_peer_fin() {
+        use tokio_core::reactor::Core;
+        use tokio_io::AsyncWrite;
+
+        let config = NetworkConfiguration {
+            keep_alive_interval: None,
+            ..NetworkConfiguration::default()
+        };
+        let address = "127.0.0.1:19775".parse().unwrap();
+        let (peer, _) = gen_keypair();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let (local, mut remote) = MemoryStream::pair();
+        let (responder, initiator) = create_encrypted_codecs();
+        let registry = PeerRegistry::new(gen_keypair().0);
+        let ticket = registry
+            .register(peer, address, Direction::Incoming)
+            .unwrap();
+        let pool = ConnectionPool::new(&config, Arc::default());
+        let receiver_rx = pool.add_address(&address);
+        let messages: Vec<_> = (0..3).map(|i| raw_message(11, 1000 + i)).collect();
+        for message in &messages {
+            assert_eq!(pool.send_message(&address, message), Ok(SendResult::Queued));
+        }
+        // The peer closes its writing half while the messages are still queued.
+        remote.shutdown().unwrap();
+
+        let socket = Framed::new(local, responder);
+        let connection = Connection::new(handle.clone(), address, socket, receiver_rx, ticket);
+        let (network_tx, _network_rx) = mpsc::channel(8);
+        NetworkHandler::<MemoryTransport>::process_messages(
+            &handle,
+            connection,
+            CountingSender::new(network_tx, QueueDepth::default()),
+            config,
+            RateLimiter::new(&config).for_peer(peer),
+            None,
+            Arc::default(),
+            ShutdownSignal::default(),
+        ).unwrap();
+
+        // The connection is closed as soon as the queued messages are sent.
+        let received = Framed::new(remote, initiator).collect();
+        let expired = Timeout::new(Duration::from_millis(1_000), &handle).unwrap();
+        let received = match core.run(received.select2(expired)) {
+            Ok(Either::A((frames, _))) => frames,
+            _ => panic!("connection has not been closed"),
+        };
+        let received: Vec<_> = received
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::Message(message) => message,
+                other => panic!("Unexpected frame: {:?}", other),
+            })
+            .collect();
+        assert_eq!(received, messages);
+    }
+
+    /// Sink which never accepts anything.
+    struct StuckSink;
+
+    impl Sink for StuckSink {
+        type SinkItem = Frame;
+        type SinkError = failure::Error;
+
+        fn start_send(&mut self, item: Frame) -> StartSend<Frame, failure::Error> {
+            Ok(AsyncSink::NotReady(item))
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), failure::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    #[test]
+    fn stuck_write_times_out() {
+        use tokio_core::reactor::Core;
+
+        let mut core = Core::new().unwrap();
+        let timeout = Duration::from_millis(200);
+        let sink = WriteDeadline::new(StuckSink, Some(timeout), core.handle());
+
+        let start = Instant::now();
+        let error = core.run(sink.send(Frame::Ping)).err().unwrap();
+        assert!(start.elapsed() >= timeout);
+        assert!(start.elapsed() < Duration::from_millis(1_000));
+        assert!(error.downcast_ref::<WriteTimeout>().is_some());
+    }
+
+    fn ordered_keys() -> (PublicKey, PublicKey) {
+        let (first, _) = gen_keypair();
+        let (second, _) = gen_keypair();
+        if first < second {
+            (first, second)
+        } else {
+            (second, first)
+        }
+    }
+
+    #[test]
+    fn simultaneous_dials_keep_canonical_connection() {
+        let address = "127.0.0.1:19771".parse().unwrap();
+        let (lower, higher) = ordered_keys();
+
+        // The node with the lower key keeps its outgoing connection.
+        let registry = PeerRegistry::new(lower);
+        let mut incoming = registry
+            .register(higher, address, Direction::Incoming)
+            .unwrap();
+        assert!(incoming.announce);
+        let outgoing = registry
+            .register(higher, address, Direction::Outgoing)
+            .unwrap();
+        assert!(!outgoing.announce);
+        assert!(incoming.close_rx.take().unwrap().wait().is_ok());
+        drop(incoming);
+        assert_eq!(registry.peers.borrow().len(), 1);
+        drop(outgoing);
+        assert!(registry.peers.borrow().is_empty());
+
+        // The node with the higher key keeps its incoming connection.
+        let registry = PeerRegistry::new(higher);
+        let incoming = registry
+            .register(lower, address, Direction::Incoming)
+            .unwrap();
+        assert!(incoming.announce);
+        assert!(
+            registry
+                .register(lower, address, Direction::Outgoing)
+                .is_none()
+        );
+        assert_eq!(registry.peers.borrow().len(), 1);
+    }
+
+    #[test]
+    fn reconnection_replaces_stale_connection() {
+        let address = "127.0.0.1:19772".parse().unwrap();
+        let (lower, higher) = ordered_keys();
+
+        let registry = PeerRegistry::new(lower);
+        let mut stale = registry
+            .register(higher, address, Direction::Incoming)
+            .unwrap();
+        let mut fresh = registry
+            .register(higher, address, Direction::Incoming)
+            .unwrap();
+        assert!(fresh.announce);
+        assert!(stale.close_rx.take().unwrap().wait().is_ok());
+
+        registry.close(&address);
+        assert!(fresh.close_rx.take().unwrap().wait().is_ok());
+        assert!(registry.peers.borrow().is_empty());
+    }
+
+    fn overflowing_pool(overflow: OutgoingQueueOverflow) -> (ConnectionPool, Vec<RawMessage>) {
+        let config = NetworkConfiguration {
+            max_outgoing_queue_len: 2,
+            outgoing_queue_overflow: overflow,
+            ..NetworkConfiguration::default()
+        };
+        let pool = ConnectionPool::new(&config, Arc::default());
+        let messages = (0..3).map(|i| raw_message(i, 100)).collect();
+        (pool, messages)
+    }
+
+    #[test]
+    fn outgoing_queue_drop_oldest() {
+        let (pool, messages) = overflowing_pool(OutgoingQueueOverflow::DropOldest);
+        let address = "127.0.0.1:19720".parse().unwrap();
+        // The receiver is never polled, as if the socket of the peer is stalled.
+        let receiver = pool.add_address(&address);
+
+        for message in &messages {
+            assert_eq!(pool.send_message(&address, message), Ok(SendResult::Queued));
+        }
+        assert_eq!(pool.metrics.outgoing_overflows(), 1);
+
+        pool.remove(&address);
+        let queued = receiver.collect().wait().unwrap();
+        assert_eq!(queued, messages[1..].to_vec());
+    }
+
+    #[test]
+    fn send_failures_are_published() {
+        let (mut pool, messages) = overflowing_pool(OutgoingQueueOverflow::DropOldest);
+        let (tx, rx) = mpsc::channel(4);
+        pool.failures = SendFailures::new(Some(tx));
+        let address = "127.0.0.1:19835".parse().unwrap();
+        let _receiver = pool.add_address(&address);
+
+        for message in &messages {
+            assert_eq!(pool.send_message(&address, message), Ok(SendResult::Queued));
+        }
+        drop(pool);
+        let failures = rx.collect().wait().unwrap();
+        assert_eq!(
+            failures,
+            vec![SendFailure {
+                peer: address,
+                service_id: messages[0].service_id(),
+                message_type: messages[0].message_type(),
+                reason: SendFailureReason::QueueOverflow,
+            }]
+        );
+    }
+
+    #[test]
+    fn outgoing_queue_disconnect() {
+        let (pool, messages) = overflowing_pool(OutgoingQueueOverflow::Disconnect);
+        let address = "127.0.0.1:19721".parse().unwrap();
+        let receiver = pool.add_address(&address);
+
+        assert_eq!(pool.send_message(&address, &messages[0]), Ok(SendResult::Queued));
+        assert_eq!(pool.send_message(&address, &messages[1]), Ok(SendResult::Queued));
+        assert_eq!(pool.send_message(&address, &messages[2]), Err(()));
+        assert_eq!(pool.metrics.outgoing_overflows(), 1);
+
+        pool.remove(&address);
+        let queued = receiver.collect().wait().unwrap();
+        assert_eq!(queued, messages[..2].to_vec());
+    }
+
+    #[test]
+    fn messages_order_is_kept_through_reconnect() {
+        let pool = ConnectionPool::new(&NetworkConfiguration::default(), Arc::default());
+        let address = "127.0.0.1:19833".parse().unwrap();
+        let first = raw_message(0, 100);
+        let second = raw_message(1, 100);
+
+        let receiver = pool.add_address(&address);
+        assert_eq!(pool.send_message(&address, &first), Ok(SendResult::Queued));
+        // The connection is lost before the first message is written.
+        drop(receiver);
+        assert!(!pool.contains(&address));
+
+        let receiver = pool.add_address(&address);
+        assert_eq!(pool.send_message(&address, &second), Ok(SendResult::Queued));
+        pool.remove(&address);
+        let queued = receiver.collect().wait().unwrap();
+        assert_eq!(queued, vec![first, second]);
+    }
+
+    #[test]
+    fn messages_are_not_kept_after_reconnect_buffer_timeout() {
+        let config = NetworkConfiguration {
+            reconnect_buffer_timeout: 0,
+            ..NetworkConfiguration::default()
+        };
+        let pool = ConnectionPool::new(&config, Arc::default());
+        let address = "127.0.0.1:19834".parse().unwrap();
+        let first = raw_message(0, 100);
+        let second = raw_message(1, 100);
+
+        let receiver = pool.add_address(&address);
+        pool.send_message(&address, &first).unwrap();
+        drop(receiver);
+        ::std::thread::sleep(Duration::from_millis(10));
+
+        let receiver = pool.add_address(&address);
+        pool.send_message(&address, &second).unwrap();
+        pool.remove(&address);
+        let queued = receiver.collect().wait().unwrap();
+        assert_eq!(queued, vec![second]);
+    }
+}
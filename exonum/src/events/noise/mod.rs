@@ -20,7 +20,7 @@ use failure;
 pub use self::wrappers::sodium_wrapper::{
     handshake::{HandshakeParams, NoiseHandshake},
     wrapper::{
-        NoiseWrapper, HANDSHAKE_HEADER_LENGTH, MAX_HANDSHAKE_MESSAGE_LENGTH,
+        encrypted_msg_len, NoiseWrapper, HANDSHAKE_HEADER_LENGTH, MAX_HANDSHAKE_MESSAGE_LENGTH,
         MIN_HANDSHAKE_MESSAGE_LENGTH,
     },
 };
@@ -32,7 +32,7 @@ use tokio_io::{
     io::{read_exact, write_all}, AsyncRead, AsyncWrite,
 };
 
-use events::{codec::MessagesCodec, error::into_failure};
+use events::{codec::MessagesCodec, error::into_failure, tls};
 use messages::RawMessage;
 
 pub mod error;
@@ -59,17 +59,43 @@ impl HandshakeRawMessage {
     pub fn read<S: AsyncRead + 'static>(
         sock: S,
     ) -> impl Future<Item = (S, Self), Error = failure::Error> {
+        Self::read_checked(sock, |_| Ok(()))
+    }
+
+    /// Reads the first handshake message of the initiator, failing if the initiator starts
+    /// a TLS handshake instead, i.e., if TLS is enabled for the initiator only.
+    pub fn read_initial<S: AsyncRead + 'static>(
+        sock: S,
+    ) -> impl Future<Item = (S, Self), Error = failure::Error> {
+        Self::read_checked(sock, |header| {
+            if tls::is_tls_record(header) {
+                bail!("Peer started a TLS handshake, but TLS is disabled for this node");
+            }
+            Ok(())
+        })
+    }
+
+    fn read_checked<S, F>(
+        sock: S,
+        check_header: F,
+    ) -> impl Future<Item = (S, Self), Error = failure::Error>
+    where
+        S: AsyncRead + 'static,
+        F: FnOnce(&[u8]) -> Result<(), failure::Error>,
+    {
         let buf = vec![0_u8; HANDSHAKE_HEADER_LENGTH];
         // First `HANDSHAKE_HEADER_LENGTH` bytes of handshake message is the payload length
         // in little-endian, remaining bytes is the handshake payload. Therefore, we need to read
         // `HANDSHAKE_HEADER_LENGTH` bytes as a little-endian integer and than we need to read
         // remaining payload.
         read_exact(sock, buf)
+            .map_err(into_failure)
             .and_then(|(stream, msg)| {
+                check_header(&msg)?;
                 let len = LittleEndian::read_uint(&msg, HANDSHAKE_HEADER_LENGTH);
-                read_exact(stream, vec![0_u8; len as usize])
+                Ok(read_exact(stream, vec![0_u8; len as usize]).map_err(into_failure))
             })
-            .map_err(into_failure)
+            .flatten()
             .and_then(|(stream, msg)| Ok((stream, HandshakeRawMessage(msg))))
     }
 
@@ -17,14 +17,15 @@ use futures::future::{done, Future};
 use tokio_codec::{Decoder, Framed};
 use tokio_io::{AsyncRead, AsyncWrite};
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 use super::wrapper::NoiseWrapper;
 use crypto::{
     x25519::{self, into_x25519_keypair, into_x25519_public_key}, PublicKey, SecretKey,
 };
 use events::{
-    codec::MessagesCodec, noise::{Handshake, HandshakeRawMessage, HandshakeResult},
+    codec::{checksums_from_handshake, BinaryFrameCodec, CompressionKind, FrameCodec, MessagesCodec},
+    noise::{Handshake, HandshakeRawMessage, HandshakeResult},
 };
 use messages::Connect;
 use messages::RawMessage;
@@ -39,6 +40,15 @@ pub struct HandshakeParams {
     pub remote_key: Option<x25519::PublicKey>,
     pub connect_list: SharedConnectList,
     pub connect: Connect,
+    /// Compression announced to the peer during the handshake.
+    pub compression: CompressionKind,
+    /// Whether the support of frame checksums is announced to the peer during the handshake.
+    pub frame_checksums: bool,
+    /// Format of the frames exchanged after the handshake.
+    pub frame_codec: Arc<dyn FrameCodec>,
+    /// Secret key signing the challenge of the peer, which proves that we own
+    /// the key announced in `connect`.
+    pub signing_key: SecretKey,
     max_message_len: u32,
 }
 
@@ -50,6 +60,7 @@ impl HandshakeParams {
         connect: Connect,
         max_message_len: u32,
     ) -> Self {
+        let signing_key = secret_key.clone();
         let (public_key, secret_key) = into_x25519_keypair(public_key, secret_key).unwrap();
 
         HandshakeParams {
@@ -59,6 +70,10 @@ impl HandshakeParams {
             remote_key: None,
             connect,
             connect_list,
+            compression: CompressionKind::None,
+            frame_checksums: false,
+            frame_codec: Arc::new(BinaryFrameCodec),
+            signing_key,
         }
     }
 
@@ -74,6 +89,11 @@ pub struct NoiseHandshake {
     max_message_len: u32,
     connect_list: SharedConnectList,
     connect: Connect,
+    compression: CompressionKind,
+    remote_compression: CompressionKind,
+    frame_checksums: bool,
+    remote_frame_checksums: bool,
+    frame_codec: Arc<dyn FrameCodec>,
 }
 
 impl NoiseHandshake {
@@ -85,6 +105,11 @@ impl NoiseHandshake {
             max_message_len: params.max_message_len,
             connect_list: params.connect_list.clone(),
             connect: params.connect.clone(),
+            compression: params.compression,
+            remote_compression: CompressionKind::None,
+            frame_checksums: params.frame_checksums,
+            remote_frame_checksums: false,
+            frame_codec: Arc::clone(&params.frame_codec),
         }
     }
 
@@ -96,14 +121,40 @@ impl NoiseHandshake {
             max_message_len: params.max_message_len,
             connect_list: params.connect_list.clone(),
             connect: params.connect.clone(),
+            compression: params.compression,
+            remote_compression: CompressionKind::None,
+            frame_checksums: params.frame_checksums,
+            remote_frame_checksums: false,
+            frame_codec: Arc::clone(&params.frame_codec),
         }
     }
 
     pub fn read_handshake_msg<S: AsyncRead + 'static>(
-        mut self,
+        self,
+        stream: S,
+    ) -> impl Future<Item = (S, Self, Vec<u8>), Error = failure::Error> {
+        let raw = HandshakeRawMessage::read(stream);
+        self.read_noise_msg(raw)
+    }
+
+    /// Reads the first handshake message of the initiator, see
+    /// `HandshakeRawMessage::read_initial`.
+    pub fn read_initial_handshake_msg<S: AsyncRead + 'static>(
+        self,
         stream: S,
     ) -> impl Future<Item = (S, Self, Vec<u8>), Error = failure::Error> {
-        HandshakeRawMessage::read(stream).and_then(move |(stream, msg)| {
+        let raw = HandshakeRawMessage::read_initial(stream);
+        self.read_noise_msg(raw)
+    }
+
+    fn read_noise_msg<S, F>(
+        mut self,
+        raw: F,
+    ) -> impl Future<Item = (S, Self, Vec<u8>), Error = failure::Error>
+    where
+        F: Future<Item = (S, HandshakeRawMessage), Error = failure::Error>,
+    {
+        raw.and_then(move |(stream, msg)| {
             let message = self.noise.read_handshake_msg(&msg.0)?;
             Ok((stream, self, message))
         })
@@ -140,7 +191,11 @@ impl NoiseHandshake {
         }
 
         let noise = self.noise.into_transport_mode()?;
-        let framed = MessagesCodec::new(self.max_message_len, noise).framed(stream);
+        let framed = MessagesCodec::new(self.max_message_len, noise)
+            .with_compression(self.compression, self.remote_compression)
+            .with_checksums(self.frame_checksums, self.remote_frame_checksums)
+            .with_frame_codec(self.frame_codec)
+            .framed(stream);
         Ok((framed, RawMessage::from_vec(message)))
     }
 
@@ -160,8 +215,12 @@ impl Handshake for NoiseHandshake {
     {
         let peer_address = self.peer_address;
         let connect = self.connect.clone();
-        let framed = self.read_handshake_msg(stream)
-            .and_then(|(stream, handshake, _)| {
+        let framed = self.read_initial_handshake_msg(stream)
+            .and_then(|(stream, mut handshake, payload)| {
+                // The first message of the initiator carries the compression and
+                // the frame checksums it supports.
+                handshake.remote_compression = CompressionKind::from_handshake(&payload);
+                handshake.remote_frame_checksums = checksums_from_handshake(&payload);
                 handshake.write_handshake_msg(stream, &connect.into_bytes())
             })
             .and_then(|(stream, handshake)| handshake.read_handshake_msg(stream))
@@ -179,7 +238,8 @@ impl Handshake for NoiseHandshake {
     {
         let peer_address = self.peer_address;
         let connect = self.connect.clone();
-        let framed = self.write_handshake_msg(stream, &[])
+        let features = [self.compression.as_byte(), u8::from(self.frame_checksums)];
+        let framed = self.write_handshake_msg(stream, &features)
             .and_then(|(stream, handshake)| handshake.read_handshake_msg(stream))
             .and_then(|(stream, handshake, message)| {
                 (
@@ -105,7 +105,7 @@ impl NoiseWrapper {
         buf: &mut BytesMut,
     ) -> Result<BytesMut, failure::Error> {
         debug_assert!(len + HEADER_LENGTH <= buf.len());
-        let data = buf.split_to(len + HEADER_LENGTH).to_vec();
+        let data = buf.split_to(len + HEADER_LENGTH);
         let data = &data[HEADER_LENGTH..];
 
         let len = decrypted_msg_len(data.len());
@@ -177,7 +177,7 @@ fn decrypted_msg_len(raw_message_len: usize) -> usize {
 
 // In case of encryption we need to add `TAG_LENGTH` multiplied by messages count to
 // calculate actual message length.
-fn encrypted_msg_len(raw_message_len: usize) -> usize {
+pub fn encrypted_msg_len(raw_message_len: usize) -> usize {
     let tag_count = div_ceil(raw_message_len, MAX_MESSAGE_LENGTH - TAG_LENGTH);
     raw_message_len + TAG_LENGTH * tag_count
 }
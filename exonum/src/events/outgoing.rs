@@ -0,0 +1,249 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded queue of the messages waiting to be written to the peer's socket.
+//!
+//! Unlike `mpsc` channels, the queue never blocks the sender: when it is full,
+//! the oldest message is evicted. The queue is not thread-safe and is intended to be
+//! used within the network event loop only.
+//!
+//! Consensus votes and proposals are written before the other messages, e.g., bulk
+//! block and transactions responses, see `Priority`. Messages of the same priority
+//! are written in the order they have been queued.
+
+use futures::{
+    task::{self, Task}, Async, Poll, Stream,
+};
+
+use std::{cell::RefCell, collections::VecDeque, mem, rc::Rc, time::Instant};
+
+use messages::{
+    RawMessage, CONSENSUS, PRECOMMIT_MESSAGE_ID, PREVOTE_MESSAGE_ID, PROPOSE_MESSAGE_ID,
+};
+
+/// Lane of the queue a message is put into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Messages on which the progress of consensus depends.
+    High,
+    Normal,
+}
+
+impl Priority {
+    /// Classifies the message by its type.
+    pub fn of(message: &RawMessage) -> Self {
+        if message.service_id() != CONSENSUS {
+            return Priority::Normal;
+        }
+        match message.message_type() {
+            PROPOSE_MESSAGE_ID | PREVOTE_MESSAGE_ID | PRECOMMIT_MESSAGE_ID => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    high: VecDeque<RawMessage>,
+    normal: VecDeque<RawMessage>,
+    // Total number of messages in both lanes.
+    capacity: usize,
+    sender_dropped: bool,
+    // Moment the receiving half has been dropped.
+    receiver_dropped: Option<Instant>,
+    task: Option<Task>,
+}
+
+impl Inner {
+    fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+}
+
+/// Sending half of the queue.
+#[derive(Debug)]
+pub struct OutgoingSender {
+    inner: Rc<RefCell<Inner>>,
+}
+
+/// Receiving half of the queue, which is a stream of the queued messages.
+#[derive(Debug)]
+pub struct OutgoingReceiver {
+    inner: Rc<RefCell<Inner>>,
+}
+
+/// Creates a queue able to hold up to `capacity` messages.
+pub fn queue(capacity: usize) -> (OutgoingSender, OutgoingReceiver) {
+    let inner = Rc::new(RefCell::new(Inner {
+        high: VecDeque::new(),
+        normal: VecDeque::new(),
+        capacity: capacity.max(1),
+        sender_dropped: false,
+        receiver_dropped: None,
+        task: None,
+    }));
+    let sender = OutgoingSender {
+        inner: Rc::clone(&inner),
+    };
+    (sender, OutgoingReceiver { inner })
+}
+
+impl OutgoingSender {
+    /// Enqueues the message. If the queue is full, the oldest normal-priority message is
+    /// evicted and returned; if there are none, the oldest high-priority message is.
+    pub fn push(&self, message: RawMessage) -> Option<RawMessage> {
+        let mut inner = self.inner.borrow_mut();
+        let evicted = if inner.len() >= inner.capacity {
+            inner.normal.pop_front().or_else(|| inner.high.pop_front())
+        } else {
+            None
+        };
+        match Priority::of(&message) {
+            Priority::High => inner.high.push_back(message),
+            Priority::Normal => inner.normal.push_back(message),
+        }
+        if let Some(task) = inner.task.take() {
+            task.notify();
+        }
+        evicted
+    }
+
+    /// Returns `true` if the next pushed message will evict the oldest one.
+    pub fn is_full(&self) -> bool {
+        let inner = self.inner.borrow();
+        inner.len() >= inner.capacity
+    }
+
+    /// Returns `true` if the receiving half has been dropped, i.e., the connection is closed.
+    pub fn is_closed(&self) -> bool {
+        self.inner.borrow().receiver_dropped.is_some()
+    }
+
+    /// Returns the moment the receiving half has been dropped, if it has.
+    pub fn closed_at(&self) -> Option<Instant> {
+        self.inner.borrow().receiver_dropped
+    }
+
+    /// Takes the queued messages out of the queue, in the order they would be received.
+    pub fn take_queued(&self) -> Vec<RawMessage> {
+        let mut inner = self.inner.borrow_mut();
+        let high = mem::replace(&mut inner.high, VecDeque::new());
+        let normal = mem::replace(&mut inner.normal, VecDeque::new());
+        high.into_iter().chain(normal).collect()
+    }
+}
+
+impl Drop for OutgoingSender {
+    fn drop(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.sender_dropped = true;
+        if let Some(task) = inner.task.take() {
+            task.notify();
+        }
+    }
+}
+
+impl Stream for OutgoingReceiver {
+    type Item = RawMessage;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<RawMessage>, ()> {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(message) = inner.high.pop_front().or_else(|| inner.normal.pop_front()) {
+            return Ok(Async::Ready(Some(message)));
+        }
+        if inner.sender_dropped {
+            return Ok(Async::Ready(None));
+        }
+        inner.task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for OutgoingReceiver {
+    fn drop(&mut self) {
+        self.inner.borrow_mut().receiver_dropped = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+
+    use super::{queue, Priority};
+    use events::tests::raw_message;
+    use messages::{BLOCK_RESPONSE_MESSAGE_ID, PRECOMMIT_MESSAGE_ID, PROPOSE_MESSAGE_ID};
+
+    #[test]
+    fn evict_oldest_messages() {
+        let (sender, receiver) = queue(2);
+        let messages: Vec<_> = (0..3)
+            .map(|i| raw_message(BLOCK_RESPONSE_MESSAGE_ID + i, 10))
+            .collect();
+
+        assert_eq!(sender.push(messages[0].clone()), None);
+        assert!(!sender.is_full());
+        assert_eq!(sender.push(messages[1].clone()), None);
+        assert!(sender.is_full());
+        assert_eq!(sender.push(messages[2].clone()), Some(messages[0].clone()));
+
+        drop(sender);
+        let received = receiver.collect().wait().unwrap();
+        assert_eq!(received, messages[1..].to_vec());
+    }
+
+    #[test]
+    fn consensus_messages_jump_ahead() {
+        let (sender, receiver) = queue(4);
+        let bulk = raw_message(BLOCK_RESPONSE_MESSAGE_ID, 1_000);
+        let precommit = raw_message(PRECOMMIT_MESSAGE_ID, 10);
+        assert_eq!(Priority::of(&bulk), Priority::Normal);
+        assert_eq!(Priority::of(&precommit), Priority::High);
+
+        sender.push(bulk.clone());
+        sender.push(precommit.clone());
+        drop(sender);
+        let received = receiver.collect().wait().unwrap();
+        assert_eq!(received, vec![precommit, bulk]);
+    }
+
+    #[test]
+    fn evict_normal_messages_first() {
+        let (sender, receiver) = queue(2);
+        let propose = raw_message(PROPOSE_MESSAGE_ID, 10);
+        let bulk = raw_message(BLOCK_RESPONSE_MESSAGE_ID, 10);
+        let precommit = raw_message(PRECOMMIT_MESSAGE_ID, 10);
+
+        sender.push(propose.clone());
+        sender.push(bulk.clone());
+        assert_eq!(sender.push(precommit.clone()), Some(bulk));
+        drop(sender);
+        let received = receiver.collect().wait().unwrap();
+        assert_eq!(received, vec![propose, precommit]);
+    }
+
+    #[test]
+    fn closed_receiver() {
+        let (sender, receiver) = queue(2);
+        let message = raw_message(BLOCK_RESPONSE_MESSAGE_ID, 10);
+        sender.push(message.clone());
+        assert!(!sender.is_closed());
+        assert!(sender.closed_at().is_none());
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert!(sender.closed_at().is_some());
+        assert_eq!(sender.take_queued(), vec![message]);
+        assert!(sender.take_queued().is_empty());
+    }
+}
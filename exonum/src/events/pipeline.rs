@@ -0,0 +1,214 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Composition of event handlers, which adds cross-cutting behavior, e.g., auditing,
+//! to a handler without modifying it.
+//!
+//! `ChainedHandler` passes each event through an `EventMiddleware` and then, unless
+//! the middleware has consumed the event, to the next handler. Middlewares may be chained
+//! with each other as well, so `ChainedHandler::new(audit, ChainedHandler::new(filter, node))`
+//! is a valid handler.
+
+use std::time::Instant;
+
+use events::{error::HandlerError, Event, EventHandler};
+
+/// Stage of a pipeline which sees each event before the next handler.
+pub trait EventMiddleware {
+    /// Inspects the event and returns it back to pass it on, or `None` to consume it.
+    fn handle_event(&mut self, event: Event) -> Option<Event>;
+}
+
+/// Pair of handlers, the first of which sees each event before the second one.
+#[derive(Debug)]
+pub struct ChainedHandler<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> ChainedHandler<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        ChainedHandler { first, second }
+    }
+
+    /// Returns the first stage of the chain.
+    pub fn first(&self) -> &A {
+        &self.first
+    }
+
+    /// Returns the second stage of the chain.
+    pub fn second(&self) -> &B {
+        &self.second
+    }
+}
+
+impl<A: EventMiddleware, B: EventMiddleware> EventMiddleware for ChainedHandler<A, B> {
+    fn handle_event(&mut self, event: Event) -> Option<Event> {
+        let event = self.first.handle_event(event)?;
+        self.second.handle_event(event)
+    }
+}
+
+// Flushes and ticks are passed through the middleware as well, and then routed
+// by the second handler itself.
+impl<A: EventMiddleware, B: EventHandler> EventHandler for ChainedHandler<A, B> {
+    fn handle_event(&mut self, event: Event) {
+        if let Some(event) = self.first.handle_event(event) {
+            self.second.handle_event(event);
+        }
+    }
+
+    fn try_handle_event(&mut self, event: Event) -> Result<(), HandlerError> {
+        match self.first.handle_event(event) {
+            Some(event) => self.second.try_handle_event(event),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_events(&mut self, events: Vec<Event>) {
+        let events = self.pass_batch(events);
+        if !events.is_empty() {
+            self.second.handle_events(events);
+        }
+    }
+
+    fn handle_flush(&mut self) {
+        self.second.handle_flush();
+    }
+
+    fn handle_tick(&mut self, scheduled_at: Instant) {
+        self.second.handle_tick(scheduled_at);
+    }
+
+    fn try_handle_events(&mut self, events: Vec<Event>) -> Result<(), HandlerError> {
+        let events = self.pass_batch(events);
+        if events.is_empty() {
+            return Ok(());
+        }
+        self.second.try_handle_events(events)
+    }
+
+    fn handle_shutdown(&mut self) {
+        self.second.handle_shutdown();
+    }
+}
+
+impl<A: EventMiddleware, B> ChainedHandler<A, B> {
+    fn pass_batch(&mut self, events: Vec<Event>) -> Vec<Event> {
+        let first = &mut self.first;
+        events
+            .into_iter()
+            .filter_map(|event| first.handle_event(event))
+            .collect()
+    }
+}
+
+/// Middleware consuming the events which do not satisfy the predicate.
+#[derive(Debug)]
+pub struct Filter<F> {
+    predicate: F,
+}
+
+impl<F: FnMut(&Event) -> bool> Filter<F> {
+    pub fn new(predicate: F) -> Self {
+        Filter { predicate }
+    }
+}
+
+impl<F: FnMut(&Event) -> bool> EventMiddleware for Filter<F> {
+    fn handle_event(&mut self, event: Event) -> Option<Event> {
+        if (self.predicate)(&event) {
+            Some(event)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use events::{InternalEvent, NetworkEvent};
+    use node::NodeTimeout;
+
+    #[derive(Debug, Default)]
+    struct LoggingMiddleware {
+        log: Vec<String>,
+    }
+
+    impl EventMiddleware for LoggingMiddleware {
+        fn handle_event(&mut self, event: Event) -> Option<Event> {
+            self.log.push(event.summary());
+            Some(event)
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingHandler {
+        events: usize,
+        flushes: usize,
+    }
+
+    impl EventHandler for CountingHandler {
+        fn handle_event(&mut self, _: Event) {
+            self.events += 1;
+        }
+
+        fn handle_flush(&mut self) {
+            self.flushes += 1;
+        }
+    }
+
+    fn events() -> Vec<Event> {
+        let peer: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        vec![
+            Event::Network(NetworkEvent::PeerDisconnected(peer)),
+            Event::Internal(InternalEvent::Timeout(NodeTimeout::PeerExchange)),
+            Event::Internal(InternalEvent::Flush),
+        ]
+    }
+
+    #[test]
+    fn chained_handlers_see_each_event() {
+        let mut handler =
+            ChainedHandler::new(LoggingMiddleware::default(), CountingHandler::default());
+        for event in events() {
+            handler.try_handle_event(event).unwrap();
+        }
+        handler.try_handle_events(events()).unwrap();
+
+        assert_eq!(handler.first().log.len(), 6);
+        assert_eq!(handler.second().events, 4);
+        assert_eq!(handler.second().flushes, 2);
+    }
+
+    #[test]
+    fn filter_consumes_events() {
+        let is_network = Filter::new(|event: &Event| match *event {
+            Event::Network(_) => true,
+            _ => false,
+        });
+        let middleware = ChainedHandler::new(is_network, LoggingMiddleware::default());
+        let mut handler = ChainedHandler::new(middleware, CountingHandler::default());
+        for event in events() {
+            handler.try_handle_event(event).unwrap();
+        }
+
+        assert_eq!(handler.first().second().log.len(), 1);
+        assert_eq!(handler.second().events, 1);
+        assert_eq!(handler.second().flushes, 0);
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limiting the rate of messages received from peers.
+//!
+//! Each peer has a token bucket refilled with the allowed rate and holding up to one second
+//! worth of tokens. Buckets are keyed by the public key of the peer, so that reconnecting
+//! does not reset the limit. The limits may be changed at runtime, see `RateLimiter::update`.
+
+use std::{
+    cell::{Cell, RefCell}, collections::HashMap, rc::Rc, time::{Duration, Instant},
+};
+
+use crypto::PublicKey;
+use events::{error::RateLimitExceeded, network::NetworkConfiguration};
+use helpers::Milliseconds;
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated_at: Instant,
+    throttled_since: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            updated_at: now,
+            throttled_since: None,
+        }
+    }
+
+    /// Changes the allowed rate keeping the debt of the bucket, if any.
+    fn set_rate(&mut self, rate: u32) {
+        self.rate = f64::from(rate);
+        self.tokens = self.tokens.min(self.rate);
+    }
+
+    /// Takes a token for a message and returns the time to wait until the token is available.
+    fn acquire(&mut self, now: Instant) -> Duration {
+        if now > self.updated_at {
+            let elapsed = now - self.updated_at;
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+            self.updated_at = now;
+        }
+
+        // The bucket goes into debt, which is paid off by waiting.
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            self.throttled_since = None;
+            Duration::default()
+        } else {
+            self.throttled_since.get_or_insert(now);
+            Duration::from_micros((-self.tokens / self.rate * 1e6) as u64)
+        }
+    }
+
+    /// Returns the time the peer has been continuously sending above the limit.
+    fn throttled_for(&self, now: Instant) -> Duration {
+        self.throttled_since
+            .map_or_else(Duration::default, |since| now - since)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RateLimits {
+    rate: Option<u32>,
+    max_throttle_duration: Duration,
+}
+
+/// Rate limiter shared by all the connections of the node.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limits: Rc<Cell<RateLimits>>,
+    buckets: Rc<RefCell<HashMap<PublicKey, TokenBucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(network_config: &NetworkConfiguration) -> Self {
+        RateLimiter::with_rate(
+            network_config.max_incoming_rate,
+            network_config.max_throttle_duration,
+        )
+    }
+
+    fn with_rate(rate: Option<u32>, max_throttle_duration: Milliseconds) -> Self {
+        let limits = RateLimits {
+            rate,
+            max_throttle_duration: Duration::from_millis(max_throttle_duration),
+        };
+        RateLimiter {
+            limits: Rc::new(Cell::new(limits)),
+            buckets: Rc::default(),
+        }
+    }
+
+    /// Applies the limits from the configuration to all the connections, including
+    /// the established ones.
+    pub fn update(&self, network_config: &NetworkConfiguration) {
+        let rate = network_config.max_incoming_rate;
+        self.limits.set(RateLimits {
+            rate,
+            max_throttle_duration: Duration::from_millis(network_config.max_throttle_duration),
+        });
+        let mut buckets = self.buckets.borrow_mut();
+        match rate {
+            Some(rate) => {
+                for bucket in buckets.values_mut() {
+                    bucket.set_rate(rate);
+                }
+            }
+            None => buckets.clear(),
+        }
+    }
+
+    /// Returns the limiter for the connection with the given peer.
+    pub fn for_peer(&self, public_key: PublicKey) -> PeerRateLimiter {
+        PeerRateLimiter {
+            limiter: self.clone(),
+            public_key,
+        }
+    }
+}
+
+/// Rate limiter of a single connection.
+#[derive(Debug, Clone)]
+pub struct PeerRateLimiter {
+    limiter: RateLimiter,
+    public_key: PublicKey,
+}
+
+impl PeerRateLimiter {
+    /// Accounts a message received from the peer. Returns the time the reading from the peer
+    /// should be paused for, or an error if the peer has been exceeding the limit for too long.
+    pub fn throttle(&self, now: Instant) -> Result<Duration, RateLimitExceeded> {
+        let limits = self.limiter.limits.get();
+        let rate = match limits.rate {
+            Some(rate) => rate,
+            None => return Ok(Duration::default()),
+        };
+
+        let mut buckets = self.limiter.buckets.borrow_mut();
+        let bucket = buckets
+            .entry(self.public_key)
+            .or_insert_with(|| TokenBucket::new(rate, now));
+        let delay = bucket.acquire(now);
+        if bucket.throttled_for(now) > limits.max_throttle_duration {
+            return Err(RateLimitExceeded(limits.max_throttle_duration));
+        }
+        Ok(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::gen_keypair;
+
+    #[test]
+    fn pace_burst() {
+        let limiter = RateLimiter::with_rate(Some(10), 10_000).for_peer(gen_keypair().0);
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert_eq!(limiter.throttle(now), Ok(Duration::default()));
+        }
+        assert_eq!(limiter.throttle(now), Ok(Duration::from_millis(100)));
+        assert_eq!(limiter.throttle(now), Ok(Duration::from_millis(200)));
+
+        // After the pause the tokens are refilled.
+        let later = now + Duration::from_millis(1_200);
+        assert_eq!(limiter.throttle(later), Ok(Duration::default()));
+    }
+
+    #[test]
+    fn disconnect_persistent_abuser() {
+        let limiter = RateLimiter::with_rate(Some(10), 1_000).for_peer(gen_keypair().0);
+        let start = Instant::now();
+        let mut now = start;
+
+        for _ in 0..10 {
+            limiter.throttle(now).unwrap();
+        }
+        // The peer keeps sending above the limit, so each message has to wait.
+        let error = (0..100)
+            .filter_map(|_| match limiter.throttle(now) {
+                Ok(delay) => {
+                    assert!(delay > Duration::default());
+                    now += delay;
+                    None
+                }
+                Err(e) => Some(e),
+            })
+            .next()
+            .expect("Peer is not disconnected");
+
+        assert_eq!(error, RateLimitExceeded(Duration::from_millis(1_000)));
+        assert!(now - start > Duration::from_millis(1_000));
+    }
+
+    #[test]
+    fn limits_are_shared_by_connections() {
+        let limiter = RateLimiter::with_rate(Some(1), 10_000);
+        let public_key = gen_keypair().0;
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.for_peer(public_key).throttle(now),
+            Ok(Duration::default())
+        );
+        assert!(limiter.for_peer(public_key).throttle(now).unwrap() > Duration::default());
+        assert_eq!(
+            limiter.for_peer(gen_keypair().0).throttle(now),
+            Ok(Duration::default())
+        );
+    }
+
+    #[test]
+    fn update_limits() {
+        let limiter = RateLimiter::with_rate(Some(2), 10_000);
+        let peer = limiter.for_peer(gen_keypair().0);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert_eq!(peer.throttle(now), Ok(Duration::default()));
+        }
+        assert_eq!(peer.throttle(now), Ok(Duration::from_millis(500)));
+
+        // The established connections are throttled with the new rate.
+        let mut network_config = NetworkConfiguration::default();
+        network_config.max_incoming_rate = Some(10);
+        limiter.update(&network_config);
+        let later = now + Duration::from_secs(1);
+        for _ in 0..9 {
+            assert_eq!(peer.throttle(later), Ok(Duration::default()));
+        }
+        assert_eq!(peer.throttle(later), Ok(Duration::from_millis(100)));
+
+        network_config.max_incoming_rate = None;
+        limiter.update(&network_config);
+        for _ in 0..100 {
+            assert_eq!(peer.throttle(later), Ok(Duration::default()));
+        }
+    }
+
+    #[test]
+    fn unlimited_rate() {
+        let limiter = RateLimiter::with_rate(None, 10_000).for_peer(gen_keypair().0);
+        let now = Instant::now();
+        for _ in 0..1_000 {
+            assert_eq!(limiter.throttle(now), Ok(Duration::default()));
+        }
+    }
+}
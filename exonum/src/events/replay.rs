@@ -0,0 +1,365 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording of the events dispatched by `HandlerPart` and their replay into a handler
+//! without the network, e.g., to reproduce a consensus bug deterministically.
+//!
+//! The log is a sequence of records, one per event, in the order of dispatching. A record
+//! starts with a byte tag of the event followed by its fields; integers are little-endian,
+//! addresses are length-prefixed strings and messages are length-prefixed raw bytes.
+//! Network events carrying messages and connections, timeouts, `JumpToRound`
+//! and `Shutdown` are recorded; the other events, e.g., flushes, ticks and api messages,
+//! depend on the environment of the node and are skipped.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure;
+use futures::future::{self, Either, Future, Loop};
+
+use std::{
+    fmt, fs::File, io::{self, BufReader, BufWriter, Read, Write}, net::SocketAddr, path::Path,
+};
+
+use crypto::{Hash, PublicKey, HASH_SIZE, PUBLIC_KEY_LENGTH};
+use events::{error::HandlerError, AsyncEventHandler, Event, InternalEvent, NetworkEvent};
+use helpers::{Height, Round};
+use messages::{Connect, Message, RawMessage};
+use node::{state::RequestData, NodeTimeout};
+
+const MESSAGE_RECEIVED: u8 = 1;
+const PEER_CONNECTED: u8 = 2;
+const PEER_DISCONNECTED: u8 = 3;
+const UNABLE_CONNECT_TO_PEER: u8 = 4;
+const TIMEOUT: u8 = 16;
+const JUMP_TO_ROUND: u8 = 17;
+const SHUTDOWN: u8 = 18;
+
+/// Writer of the event log, see `HandlerPart::recorder`.
+pub struct EventRecorder {
+    writer: Box<dyn Write + Send>,
+}
+
+impl EventRecorder {
+    /// Creates a recorder appending the events to the given writer. The writer is flushed
+    /// after each record, so a buffered one does not lose the events preceding a crash.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        EventRecorder {
+            writer: Box::new(writer),
+        }
+    }
+
+    /// Creates a recorder writing the events to the file at the given path, which is
+    /// truncated if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        File::create(path).map(|file| Self::new(BufWriter::new(file)))
+    }
+
+    /// Writes the event to the log. Returns `false` if the event is not recorded
+    /// because of its kind.
+    pub fn record(&mut self, event: &Event) -> io::Result<bool> {
+        let mut record = Vec::new();
+        if !encode_event(&mut record, event)? {
+            return Ok(false);
+        }
+        self.writer.write_all(&record)?;
+        self.writer.flush()?;
+        Ok(true)
+    }
+}
+
+impl fmt::Debug for EventRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EventRecorder").finish()
+    }
+}
+
+/// Iterator over the events of a log written by `EventRecorder`.
+#[derive(Debug)]
+pub struct EventLog<R> {
+    reader: R,
+    failed: bool,
+}
+
+impl EventLog<BufReader<File>> {
+    /// Opens the log at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        File::open(path).map(|file| Self::new(BufReader::new(file)))
+    }
+}
+
+impl<R: Read> EventLog<R> {
+    pub fn new(reader: R) -> Self {
+        EventLog {
+            reader,
+            failed: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for EventLog<R> {
+    type Item = Result<Event, failure::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let tag = match self.reader.read_u8() {
+            Ok(tag) => tag,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(e.into()));
+            }
+        };
+        let event = decode_event(&mut self.reader, tag);
+        // The records following a malformed one cannot be located.
+        self.failed = event.is_err();
+        Some(event)
+    }
+}
+
+/// Replays the events of a log into the handler in the recorded order.
+#[derive(Debug)]
+pub struct ReplayHandlerPart<H, R> {
+    pub handler: H,
+    pub events: EventLog<R>,
+}
+
+impl<H: AsyncEventHandler, R: Read> ReplayHandlerPart<H, R> {
+    pub fn new(handler: H, events: EventLog<R>) -> Self {
+        ReplayHandlerPart { handler, events }
+    }
+
+    /// Dispatches the events one by one, waiting for each of them to be handled
+    /// before the next one. The returned future yields the handler once the log is over,
+    /// and fails if the log is malformed or the handler reports an unrecoverable error.
+    pub fn run(self) -> impl Future<Item = H, Error = HandlerError> {
+        future::loop_fn(
+            (self.handler, self.events),
+            |(mut handler, mut events)| match events.next() {
+                None => {
+                    handler.handle_shutdown();
+                    Either::A(future::ok(Loop::Break(handler)))
+                }
+                Some(Err(e)) => Either::A(future::err(HandlerError::new(format!(
+                    "Malformed event log: {}",
+                    e
+                )))),
+                Some(Ok(event)) => {
+                    let handled = handler.handle_event(event);
+                    Either::B(handled.map(move |()| Loop::Continue((handler, events))))
+                }
+            },
+        )
+    }
+}
+
+fn encode_event(buf: &mut Vec<u8>, event: &Event) -> io::Result<bool> {
+    match *event {
+        Event::Network(NetworkEvent::MessageReceived(address, ref key, ref raw)) => {
+            buf.write_u8(MESSAGE_RECEIVED)?;
+            write_address(buf, address)?;
+            buf.write_all(key.as_ref())?;
+            write_bytes(buf, raw.as_ref())?;
+        }
+        Event::Network(NetworkEvent::PeerConnected(address, ref connect)) => {
+            buf.write_u8(PEER_CONNECTED)?;
+            write_address(buf, address)?;
+            write_bytes(buf, connect.raw().as_ref())?;
+        }
+        Event::Network(NetworkEvent::PeerDisconnected(address)) => {
+            buf.write_u8(PEER_DISCONNECTED)?;
+            write_address(buf, address)?;
+        }
+        Event::Network(NetworkEvent::UnableConnectToPeer(address)) => {
+            buf.write_u8(UNABLE_CONNECT_TO_PEER)?;
+            write_address(buf, address)?;
+        }
+        Event::Internal(InternalEvent::Timeout(ref timeout)) => {
+            buf.write_u8(TIMEOUT)?;
+            write_timeout(buf, timeout)?;
+        }
+        Event::Internal(InternalEvent::JumpToRound(height, round)) => {
+            buf.write_u8(JUMP_TO_ROUND)?;
+            buf.write_u64::<LittleEndian>(height.0)?;
+            buf.write_u32::<LittleEndian>(round.0)?;
+        }
+        Event::Internal(InternalEvent::Shutdown) => buf.write_u8(SHUTDOWN)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn decode_event<R: Read>(reader: &mut R, tag: u8) -> Result<Event, failure::Error> {
+    let event = match tag {
+        MESSAGE_RECEIVED => {
+            let address = read_address(reader)?;
+            let key = read_public_key(reader)?;
+            let raw = RawMessage::from_vec(read_bytes(reader)?);
+            NetworkEvent::MessageReceived(address, key, raw).into()
+        }
+        PEER_CONNECTED => {
+            let address = read_address(reader)?;
+            let connect = Connect::from_raw(RawMessage::from_vec(read_bytes(reader)?))?;
+            NetworkEvent::PeerConnected(address, connect).into()
+        }
+        PEER_DISCONNECTED => NetworkEvent::PeerDisconnected(read_address(reader)?).into(),
+        UNABLE_CONNECT_TO_PEER => NetworkEvent::UnableConnectToPeer(read_address(reader)?).into(),
+        TIMEOUT => read_timeout(reader)?.into(),
+        JUMP_TO_ROUND => {
+            let height = Height(reader.read_u64::<LittleEndian>()?);
+            let round = Round(reader.read_u32::<LittleEndian>()?);
+            InternalEvent::JumpToRound(height, round).into()
+        }
+        SHUTDOWN => InternalEvent::Shutdown.into(),
+        _ => bail!("Unknown event tag {}", tag),
+    };
+    Ok(event)
+}
+
+fn write_timeout(buf: &mut Vec<u8>, timeout: &NodeTimeout) -> io::Result<()> {
+    match *timeout {
+        NodeTimeout::Status(height) => {
+            buf.write_u8(0)?;
+            buf.write_u64::<LittleEndian>(height.0)
+        }
+        NodeTimeout::Round(height, round) => {
+            buf.write_u8(1)?;
+            buf.write_u64::<LittleEndian>(height.0)?;
+            buf.write_u32::<LittleEndian>(round.0)
+        }
+        NodeTimeout::Request(ref data, ref key) => {
+            buf.write_u8(2)?;
+            write_request_data(buf, data)?;
+            match *key {
+                Some(ref key) => {
+                    buf.write_u8(1)?;
+                    buf.write_all(key.as_ref())
+                }
+                None => buf.write_u8(0),
+            }
+        }
+        NodeTimeout::Propose(height, round) => {
+            buf.write_u8(3)?;
+            buf.write_u64::<LittleEndian>(height.0)?;
+            buf.write_u32::<LittleEndian>(round.0)
+        }
+        NodeTimeout::UpdateApiState => buf.write_u8(4),
+        NodeTimeout::PeerExchange => buf.write_u8(5),
+    }
+}
+
+fn read_timeout<R: Read>(reader: &mut R) -> Result<NodeTimeout, failure::Error> {
+    let timeout = match reader.read_u8()? {
+        0 => NodeTimeout::Status(Height(reader.read_u64::<LittleEndian>()?)),
+        1 => {
+            let height = Height(reader.read_u64::<LittleEndian>()?);
+            NodeTimeout::Round(height, Round(reader.read_u32::<LittleEndian>()?))
+        }
+        2 => {
+            let data = read_request_data(reader)?;
+            let key = match reader.read_u8()? {
+                0 => None,
+                1 => Some(read_public_key(reader)?),
+                other => bail!("Invalid option tag {}", other),
+            };
+            NodeTimeout::Request(data, key)
+        }
+        3 => {
+            let height = Height(reader.read_u64::<LittleEndian>()?);
+            NodeTimeout::Propose(height, Round(reader.read_u32::<LittleEndian>()?))
+        }
+        4 => NodeTimeout::UpdateApiState,
+        5 => NodeTimeout::PeerExchange,
+        other => bail!("Unknown timeout tag {}", other),
+    };
+    Ok(timeout)
+}
+
+fn write_request_data(buf: &mut Vec<u8>, data: &RequestData) -> io::Result<()> {
+    match *data {
+        RequestData::Propose(ref hash) => {
+            buf.write_u8(0)?;
+            buf.write_all(hash.as_ref())
+        }
+        RequestData::ProposeTransactions(ref hash) => {
+            buf.write_u8(1)?;
+            buf.write_all(hash.as_ref())
+        }
+        RequestData::BlockTransactions => buf.write_u8(2),
+        RequestData::Prevotes(round, ref hash) => {
+            buf.write_u8(3)?;
+            buf.write_u32::<LittleEndian>(round.0)?;
+            buf.write_all(hash.as_ref())
+        }
+        RequestData::Block(height) => {
+            buf.write_u8(4)?;
+            buf.write_u64::<LittleEndian>(height.0)
+        }
+    }
+}
+
+fn read_request_data<R: Read>(reader: &mut R) -> Result<RequestData, failure::Error> {
+    let data = match reader.read_u8()? {
+        0 => RequestData::Propose(read_hash(reader)?),
+        1 => RequestData::ProposeTransactions(read_hash(reader)?),
+        2 => RequestData::BlockTransactions,
+        3 => {
+            let round = Round(reader.read_u32::<LittleEndian>()?);
+            RequestData::Prevotes(round, read_hash(reader)?)
+        }
+        4 => RequestData::Block(Height(reader.read_u64::<LittleEndian>()?)),
+        other => bail!("Unknown request data tag {}", other),
+    };
+    Ok(data)
+}
+
+fn write_address(buf: &mut Vec<u8>, address: SocketAddr) -> io::Result<()> {
+    write_bytes(buf, address.to_string().as_bytes())
+}
+
+fn read_address<R: Read>(reader: &mut R) -> Result<SocketAddr, failure::Error> {
+    let address = String::from_utf8(read_bytes(reader)?)?;
+    Ok(address.parse()?)
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    buf.write_u32::<LittleEndian>(bytes.len() as u32)?;
+    buf.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32::<LittleEndian>()? as usize;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn read_public_key<R: Read>(reader: &mut R) -> Result<PublicKey, failure::Error> {
+    let bytes = read_array(reader, PUBLIC_KEY_LENGTH)?;
+    PublicKey::from_slice(&bytes).ok_or_else(|| format_err!("Invalid public key"))
+}
+
+fn read_hash<R: Read>(reader: &mut R) -> Result<Hash, failure::Error> {
+    let bytes = read_array(reader, HASH_SIZE)?;
+    Hash::from_slice(&bytes).ok_or_else(|| format_err!("Invalid hash"))
+}
+
+fn read_array<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
@@ -0,0 +1,108 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resolution of the endpoints of the peers, see `NetworkPart::endpoints`.
+//!
+//! An endpoint is a `host:port` string, where the host is a domain name or an IP address;
+//! IPv6 addresses are enclosed in brackets and may carry a zone index, e.g.,
+//! `[fe80::1%eth0]:6333`. Endpoints are resolved each time the peer is dialed, so that
+//! the peers with dynamic IPs stay reachable.
+
+use futures::{sync::oneshot, Future};
+
+use std::{
+    fmt, io, net::{SocketAddr, ToSocketAddrs}, thread,
+};
+
+/// Future resolving into the addresses of an endpoint, in the order they should be dialed.
+pub type ResolveFuture = Box<dyn Future<Item = Vec<SocketAddr>, Error = io::Error>>;
+
+/// Means to resolve the endpoints of the peers into addresses.
+pub trait Resolver: fmt::Debug + Send + Sync + 'static {
+    /// Resolves the `host:port` endpoint.
+    fn resolve(&self, endpoint: &str) -> ResolveFuture;
+}
+
+/// Resolver using the facilities of the system, e.g., DNS. As they are blocking, each
+/// endpoint is resolved on a separate thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, endpoint: &str) -> ResolveFuture {
+        let endpoint = endpoint.to_owned();
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            // The dial may have been aborted already.
+            let _ = tx.send(resolve_endpoint(&endpoint));
+        });
+        let resolved = rx
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Resolver thread has panicked"))
+            .and_then(|result| result);
+        Box::new(resolved)
+    }
+}
+
+fn resolve_endpoint(endpoint: &str) -> io::Result<Vec<SocketAddr>> {
+    let (host, port) = split_endpoint(endpoint)?;
+    Ok((host, port).to_socket_addrs()?.collect())
+}
+
+/// Splits the endpoint into the host and the port, stripping the brackets of IPv6 hosts.
+fn split_endpoint(endpoint: &str) -> io::Result<(&str, u16)> {
+    let invalid = || {
+        let message = format!("Invalid endpoint {}, expected host:port", endpoint);
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    };
+    let colon = endpoint.rfind(':').ok_or_else(invalid)?;
+    let port = endpoint[colon + 1..].parse().map_err(|_| invalid())?;
+    let host = &endpoint[..colon];
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else {
+        host
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+
+    use super::{split_endpoint, Resolver, SystemResolver};
+
+    #[test]
+    fn endpoints_are_split() {
+        assert_eq!(
+            split_endpoint("node.example.com:6333").unwrap(),
+            ("node.example.com", 6333)
+        );
+        assert_eq!(split_endpoint("[::1]:6333").unwrap(), ("::1", 6333));
+        assert_eq!(
+            split_endpoint("[fe80::1%eth0]:6333").unwrap(),
+            ("fe80::1%eth0", 6333)
+        );
+        assert!(split_endpoint("node.example.com").is_err());
+        assert!(split_endpoint(":6333").is_err());
+    }
+
+    #[test]
+    fn ip_endpoints_are_resolved() {
+        let resolved = SystemResolver.resolve("[::1]:6333").wait().unwrap();
+        assert_eq!(resolved, vec!["[::1]:6333".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracing spans around the handling of events.
+//!
+//! A span is opened for every dispatched event (or batch of events) and closed once its
+//! handling is completed. Spans are annotated with the kind of the event, its `Event::summary`
+//! and, for timeouts, with the height and round; the handling duration is recorded
+//! in microseconds.
+//! Without the `tracing` feature spans are no-ops.
+
+#[cfg(feature = "tracing")]
+pub use self::enabled::EventSpan;
+#[cfg(not(feature = "tracing"))]
+pub use self::disabled::EventSpan;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use tracing::{field, span, Level, Span};
+
+    use std::time::Instant;
+
+    use events::Event;
+
+    /// Span covering the handling of an event.
+    #[derive(Debug)]
+    pub struct EventSpan {
+        span: Span,
+        started_at: Instant,
+    }
+
+    impl EventSpan {
+        /// Opens the span for the event.
+        pub fn new(event: &Event) -> Self {
+            let span = span!(
+                Level::TRACE,
+                "event",
+                kind = event.kind(),
+                summary = event.summary().as_str(),
+                height = field::Empty,
+                round = field::Empty,
+                duration_us = field::Empty
+            );
+            let (height, round) = event.position();
+            if let Some(height) = height {
+                span.record("height", &height.0);
+            }
+            if let Some(round) = round {
+                span.record("round", &round.0);
+            }
+            Self::with_span(span)
+        }
+
+        /// Opens the span for the batch of events.
+        pub fn batch(events: &[Event]) -> Self {
+            let span = span!(
+                Level::TRACE,
+                "events",
+                len = events.len(),
+                duration_us = field::Empty
+            );
+            Self::with_span(span)
+        }
+
+        fn with_span(span: Span) -> Self {
+            EventSpan {
+                span,
+                started_at: Instant::now(),
+            }
+        }
+
+        /// Invokes the closure within the span.
+        pub fn in_scope<F: FnOnce() -> T, T>(&self, f: F) -> T {
+            self.span.in_scope(f)
+        }
+
+        /// Records the handling duration and closes the span.
+        pub fn finish(self) {
+            let elapsed = self.started_at.elapsed();
+            let micros = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+            self.span.record("duration_us", &micros);
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use events::Event;
+
+    /// No-op span used if the `tracing` feature is disabled.
+    #[derive(Debug)]
+    pub struct EventSpan;
+
+    impl EventSpan {
+        pub fn new(_: &Event) -> Self {
+            EventSpan
+        }
+
+        pub fn batch(_: &[Event]) -> Self {
+            EventSpan
+        }
+
+        pub fn in_scope<F: FnOnce() -> T, T>(&self, f: F) -> T {
+            f()
+        }
+
+        pub fn finish(self) {}
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use futures::{sync::mpsc, Future};
+    use tracing::{
+        self, field::{Field, Visit}, span::{Attributes, Id, Record}, subscriber, Metadata,
+        Subscriber,
+    };
+
+    use std::{
+        fmt::Debug, net::SocketAddr, sync::{Arc, Mutex},
+    };
+
+    use events::{Event, EventHandler, HandlerPart, InternalEvent, NetworkEvent};
+    use helpers::{Height, Round};
+    use node::{ExternalMessage, NodeTimeout};
+
+    #[derive(Debug, Default)]
+    struct SpanFields {
+        kind: Option<String>,
+        summary: Option<String>,
+        height: Option<u64>,
+        round: Option<u64>,
+        duration_us: Option<u64>,
+    }
+
+    impl Visit for SpanFields {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            match field.name() {
+                "height" => self.height = Some(value),
+                "round" => self.round = Some(value),
+                "duration_us" => self.duration_us = Some(value),
+                _ => {}
+            }
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            match field.name() {
+                "kind" => self.kind = Some(value.to_owned()),
+                "summary" => self.summary = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
+    }
+
+    /// Subscriber collecting the fields of all the spans.
+    #[derive(Debug, Clone, Default)]
+    struct SpansCollector {
+        spans: Arc<Mutex<Vec<SpanFields>>>,
+    }
+
+    impl Subscriber for SpansCollector {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attributes: &Attributes) -> Id {
+            let mut fields = SpanFields::default();
+            attributes.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut spans[span.into_u64() as usize - 1]);
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &tracing::Event) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    struct NoopHandler;
+
+    impl EventHandler for NoopHandler {
+        fn handle_event(&mut self, _: Event) {}
+    }
+
+    #[test]
+    fn span_per_event() {
+        let peer: SocketAddr = "127.0.0.1:19710".parse().unwrap();
+        let (mut internal_tx, internal_rx) = mpsc::channel(4);
+        let (mut network_tx, network_rx) = mpsc::channel(4);
+        let (mut api_tx, api_rx) = mpsc::channel(4);
+        internal_tx
+            .try_send(InternalEvent::Timeout(NodeTimeout::Round(Height(5), Round(2))))
+            .unwrap();
+        network_tx
+            .try_send(NetworkEvent::PeerDisconnected(peer))
+            .unwrap();
+        api_tx.try_send(ExternalMessage::Rebroadcast).unwrap();
+        drop((internal_tx, network_tx, api_tx));
+
+        let collector = SpansCollector::default();
+        let spans = Arc::clone(&collector.spans);
+        subscriber::with_default(collector, || {
+            let handler_part = HandlerPart::new(NoopHandler, internal_rx, network_rx, api_rx);
+            handler_part.run().wait().unwrap();
+        });
+
+        let spans = spans.lock().unwrap();
+        let kinds: Vec<_> = spans.iter().filter_map(|span| span.kind.clone()).collect();
+        assert_eq!(kinds, vec!["timeout", "network", "api"]);
+        assert_eq!(spans[0].height, Some(5));
+        assert_eq!(spans[0].round, Some(2));
+        assert_eq!(
+            spans[1].summary.as_ref().map(String::as_str),
+            Some("network PeerDisconnected peer=127.0.0.1:19710")
+        );
+        assert!(spans[1].height.is_none());
+        assert!(spans.iter().all(|span| span.duration_us.is_some()));
+    }
+
+    #[test]
+    fn span_per_internal_event() {
+        let (mut internal_tx, internal_rx) = mpsc::channel(4);
+        let (_, network_rx) = mpsc::channel(1);
+        let (_, api_rx) = mpsc::channel(1);
+        internal_tx
+            .try_send(InternalEvent::JumpToRound(Height(7), Round(3)))
+            .unwrap();
+        internal_tx.try_send(InternalEvent::Flush).unwrap();
+        drop(internal_tx);
+
+        let collector = SpansCollector::default();
+        let spans = Arc::clone(&collector.spans);
+        subscriber::with_default(collector, || {
+            let handler_part = HandlerPart::new(NoopHandler, internal_rx, network_rx, api_rx);
+            handler_part.run().wait().unwrap();
+        });
+
+        let spans = spans.lock().unwrap();
+        let summaries: Vec<_> = spans
+            .iter()
+            .filter_map(|span| span.summary.clone())
+            .collect();
+        assert_eq!(
+            summaries,
+            vec!["internal JumpToRound height=7 round=3", "internal Flush"]
+        );
+        let kinds: Vec<_> = spans.iter().filter_map(|span| span.kind.clone()).collect();
+        assert_eq!(kinds, vec!["internal", "internal"]);
+        assert_eq!(spans[0].height, Some(7));
+        assert_eq!(spans[0].round, Some(3));
+        assert!(spans[1].height.is_none());
+    }
+}
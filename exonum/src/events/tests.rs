@@ -12,23 +12,42 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use futures::{sync::mpsc, Future, Sink, Stream};
+use futures::{
+    future, future::Either, stream, sync::{mpsc, oneshot}, task, Async, Future, Poll, Sink,
+    Stream,
+};
 use tokio::util::FutureExt;
-use tokio_core::reactor::Core;
+use tempdir::TempDir;
+use tokio_core::reactor::{Core, Handle, Timeout};
 
 use std::{
-    net::SocketAddr, thread, time::{self, Duration, SystemTime},
+    cell::{Cell, RefCell}, collections::{BinaryHeap, HashMap, HashSet, VecDeque}, fmt::Debug,
+    net::SocketAddr,
+    path::Path, rc::Rc, sync::Arc, thread, time::{self, Duration, Instant, SystemTime},
 };
 
 use blockchain::ConsensusConfig;
-use crypto::{gen_keypair, gen_keypair_from_seed, PublicKey, SecretKey, Seed, SEED_LENGTH};
+use crypto::{
+    gen_keypair, gen_keypair_from_seed, hash, PublicKey, SecretKey, Seed, SEED_LENGTH,
+};
 use events::{
-    error::log_error, network::{NetworkConfiguration, NetworkPart}, noise::HandshakeParams,
-    NetworkEvent, NetworkRequest,
+    codec::PROTOCOL_VERSION, error::{log_error, HandlerError},
+    network::{NetworkConfiguration, NetworkPart}, noise::HandshakeParams,
+    transport::MemoryTransport, AggregatorStatus, ConnectionActivity, ConnectionStats,
+    CountingSender, QueueDepth, TcpTransport, TlsConfig, TlsTransport,
+    ApiPart, AsyncEventHandler, Coalesce, Coalesced, EarliestFirst, Event, EventHandler, EventLog,
+    EventMask, EventRecorder, EventsAggregator, EventsMetrics,
+    Direction, HandlerFuture, HandlerPart, InternalEvent, InternalPart, InternalRequest,
+    NetworkEvent, NetworkMetrics, NetworkRequest, NodeParts, PeerInfo, PeerTraffic,
+    ReplayHandlerPart, SchedulePolicy, SendResult, SourceClosure, SourceClosurePolicy,
+    TimeoutRequest,
+};
+use helpers::{user_agent, Height, Round};
+use messages::{Connect, Message, MessageWriter, RawMessage, BLOCK_REQUEST_MESSAGE_ID};
+use node::{
+    state::{RequestData, SharedConnectList}, ConnectInfo, ConnectList, EventsPoolCapacity,
+    ExternalMessage, NodeChannel, NodeTimeout,
 };
-use helpers::user_agent;
-use messages::{Connect, Message, MessageWriter, RawMessage};
-use node::{state::SharedConnectList, ConnectInfo, ConnectList, EventsPoolCapacity, NodeChannel};
 
 #[derive(Debug)]
 pub struct TestHandler {
@@ -53,10 +72,12 @@ impl TestHandler {
     }
 
     pub fn wait_for_event(&mut self) -> Result<NetworkEvent, ()> {
+        self.wait_for_event_within(Duration::from_secs(30))
+    }
+
+    pub fn wait_for_event_within(&mut self, timeout: Duration) -> Result<NetworkEvent, ()> {
         let rx = self.network_events_rx.by_ref();
-        let future = rx.into_future()
-            .timeout(Duration::from_secs(30))
-            .map_err(drop);
+        let future = rx.into_future().timeout(timeout).map_err(drop);
 
         let mut core = Core::new().unwrap();
         let (event, _) = core.run(future)?;
@@ -71,6 +92,30 @@ impl TestHandler {
             .unwrap();
     }
 
+    pub fn disconnect_peer(&self, key: PublicKey, ban: Option<Duration>) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::DisconnectPeer(key, ban))
+            .wait()
+            .unwrap();
+    }
+
+    pub fn request(&self, request: NetworkRequest) {
+        self.network_requests_tx
+            .clone()
+            .send(request)
+            .wait()
+            .unwrap();
+    }
+
+    pub fn broadcast(&self, raw: RawMessage, excluded: HashSet<PublicKey>) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::Broadcast(raw, excluded))
+            .wait()
+            .unwrap();
+    }
+
     pub fn connect_with(&self, addr: SocketAddr, connect: Connect) {
         self.network_requests_tx
             .clone()
@@ -87,6 +132,16 @@ impl TestHandler {
             .unwrap();
     }
 
+    pub fn send_confirmed(&self, addr: SocketAddr, raw: RawMessage) -> SendResult {
+        let (confirmation_tx, confirmation_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::SendMessageWithAck(addr, raw, confirmation_tx.into()))
+            .wait()
+            .unwrap();
+        confirmation_rx.wait().unwrap()
+    }
+
     pub fn wait_for_connect(&mut self) -> Connect {
         match self.wait_for_event() {
             Ok(NetworkEvent::PeerConnected(_addr, connect)) => connect,
@@ -105,12 +160,52 @@ impl TestHandler {
 
     pub fn wait_for_message(&mut self) -> RawMessage {
         match self.wait_for_event() {
-            Ok(NetworkEvent::MessageReceived(_addr, msg)) => msg,
+            Ok(NetworkEvent::MessageReceived(_addr, _key, msg)) => msg,
             Ok(other) => panic!("Unexpected message received, {:?}", other),
             Err(e) => panic!("An error during wait for message occurred, {:?}", e),
         }
     }
 
+    pub fn connections_activity(&self) -> HashMap<SocketAddr, ConnectionActivity> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::ConnectionsActivity(response_tx.into()))
+            .wait()
+            .unwrap();
+        response_rx.wait().unwrap()
+    }
+
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::PeerInfo(response_tx.into()))
+            .wait()
+            .unwrap();
+        response_rx.wait().unwrap()
+    }
+
+    pub fn connection_stats(&self) -> ConnectionStats {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::ConnectionStats(response_tx.into()))
+            .wait()
+            .unwrap();
+        response_rx.wait().unwrap()
+    }
+
+    pub fn peer_traffic(&self) -> HashMap<PublicKey, PeerTraffic> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::PeerTraffic(response_tx.into()))
+            .wait()
+            .unwrap();
+        response_rx.wait().unwrap()
+    }
+
     pub fn shutdown(&mut self) {
         self.network_requests_tx
             .clone()
@@ -132,25 +227,55 @@ impl Drop for TestHandler {
 #[derive(Debug)]
 pub struct TestEvents {
     pub listen_address: SocketAddr,
+    /// Addresses the node listens on in addition to `listen_address`.
+    pub additional_listen_addresses: Vec<SocketAddr>,
     pub network_config: NetworkConfiguration,
     pub events_config: EventsPoolCapacity,
+    /// Transport to use instead of TCP.
+    pub memory_transport: Option<MemoryTransport>,
+    /// TLS configuration of the connections over TCP.
+    pub tls: Option<TlsConfig>,
+    pub network_metrics: Arc<NetworkMetrics>,
 }
 
 impl TestEvents {
     pub fn with_addr(listen_address: SocketAddr) -> TestEvents {
         TestEvents {
             listen_address,
+            additional_listen_addresses: Vec::new(),
             network_config: NetworkConfiguration::default(),
             events_config: EventsPoolCapacity::default(),
+            memory_transport: None,
+            tls: None,
+            network_metrics: Arc::default(),
         }
     }
 
     pub fn spawn(self, handshake_params: &HandshakeParams, connect: Connect) -> TestHandler {
+        let memory_transport = self.memory_transport.clone();
+        let tls_transport = self
+            .tls
+            .as_ref()
+            .map(|tls| TlsTransport::new(TcpTransport, tls).unwrap());
         let (mut handler_part, network_part) = self.into_reactor(connect);
         let handshake_params = handshake_params.clone();
         let handle = thread::spawn(move || {
             let mut core = Core::new().unwrap();
-            let fut = network_part.run(&core.handle(), &handshake_params);
+            let fut = match (memory_transport, tls_transport) {
+                (Some(transport), _) => Either::A(
+                    network_part
+                        .with_transport(transport)
+                        .run(&core.handle(), &handshake_params),
+                ),
+                (None, Some(transport)) => Either::B(Either::A(
+                    network_part
+                        .with_transport(transport)
+                        .run(&core.handle(), &handshake_params),
+                )),
+                (None, None) => Either::B(Either::B(
+                    network_part.run(&core.handle(), &handshake_params),
+                )),
+            };
             core.run(fut).map_err(log_error).unwrap();
         });
         handler_part.handle = Some(handle);
@@ -163,14 +288,18 @@ impl TestEvents {
         let (network_tx, network_rx) = channel.network_events;
         let network_requests_tx = channel.network_requests.0.clone();
 
-        let network_part = NetworkPart {
-            our_connect_message: connect,
-            listen_address: self.listen_address,
+        let mut network_part = NetworkPart::new(
+            connect,
+            self.listen_address,
             network_config,
-            max_message_len: ConsensusConfig::DEFAULT_MAX_MESSAGE_LEN,
-            network_requests: channel.network_requests,
-            network_tx: network_tx.clone(),
-        };
+            ConsensusConfig::DEFAULT_MAX_MESSAGE_LEN,
+            channel.network_requests,
+            network_tx.clone(),
+        );
+        network_part
+            .listen_addresses
+            .extend(self.additional_listen_addresses);
+        network_part.metrics = self.network_metrics;
 
         let handler_part = TestHandler::new(self.listen_address, network_requests_tx, network_rx);
         (handler_part, network_part)
@@ -197,6 +326,180 @@ pub fn raw_message(id: u16, len: usize) -> RawMessage {
     RawMessage::new(writer.sign(&gen_keypair().1))
 }
 
+/// Synchronously collects the events which the aggregator (or any other stream of events)
+/// yields without waiting, i.e., until it is not ready or exhausted. The aggregator is
+/// polled within a task, so it can be pumped again once more events are queued.
+pub fn pump<S>(aggregator: &mut S) -> Vec<Event>
+where
+    S: Stream<Item = Event>,
+    S::Error: Debug,
+{
+    future::lazy(|| {
+        let mut events = Vec::new();
+        loop {
+            match aggregator.poll() {
+                Ok(Async::Ready(Some(event))) => events.push(event),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(e) => panic!("Event sources failed: {:?}", e),
+            }
+        }
+        Ok::<_, ()>(events)
+    }).wait()
+        .unwrap()
+}
+
+/// Numbers of the events queued in the receivers of the `HandlerPart`, counted by their kinds
+/// like in `EventsMetrics`: timeouts are counted apart from the other internal events.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DrainCounts {
+    pub network: usize,
+    pub timeout: usize,
+    pub api: usize,
+    pub internal: usize,
+}
+
+impl DrainCounts {
+    /// Takes the events queued in the receivers without waiting for more, so that the events
+    /// produced by e.g. the `InternalPart` can be asserted without running the handler.
+    pub fn drain(
+        internal_rx: &mut mpsc::Receiver<InternalEvent>,
+        network_rx: &mut mpsc::Receiver<NetworkEvent>,
+        api_rx: &mut mpsc::Receiver<ExternalMessage>,
+    ) -> Self {
+        future::lazy(move || -> Result<_, ()> {
+            let mut counts = DrainCounts::default();
+            while let Async::Ready(Some(event)) = internal_rx.poll()? {
+                counts.record(&Event::Internal(event));
+            }
+            while let Async::Ready(Some(event)) = network_rx.poll()? {
+                counts.record(&Event::Network(event));
+            }
+            while let Async::Ready(Some(event)) = api_rx.poll()? {
+                counts.record(&Event::Api(event));
+            }
+            Ok(counts)
+        }).wait()
+            .unwrap()
+    }
+
+    fn record(&mut self, event: &Event) {
+        let counter = match *event {
+            Event::Network(_) => &mut self.network,
+            Event::Api(_) => &mut self.api,
+            Event::Internal(InternalEvent::Timeout(_)) => &mut self.timeout,
+            Event::Internal(_) => &mut self.internal,
+        };
+        *counter += 1;
+    }
+}
+
+/// Readiness of a scripted event source at a single poll, see `ScriptedSource`.
+#[derive(Debug, Clone)]
+pub enum Step<T> {
+    Ready(T),
+    NotReady,
+}
+
+/// Creates the script from the pattern in which `R` stands for a ready event created
+/// with `event`, and `.` for a poll at which the source is not ready.
+pub fn script<T, F: Fn() -> T>(pattern: &str, event: F) -> Vec<Step<T>> {
+    pattern
+        .chars()
+        .map(|step| match step {
+            'R' => Step::Ready(event()),
+            '.' => Step::NotReady,
+            other => panic!("Unknown step {:?}", other),
+        })
+        .collect()
+}
+
+/// Event source each poll of which takes the next step of the script; once the script
+/// is over, the source completes.
+#[derive(Debug)]
+pub struct ScriptedSource<T> {
+    steps: VecDeque<Step<T>>,
+}
+
+impl<T> ScriptedSource<T> {
+    pub fn new(steps: Vec<Step<T>>) -> Self {
+        ScriptedSource {
+            steps: steps.into(),
+        }
+    }
+}
+
+impl<T> Stream for ScriptedSource<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        match self.steps.pop_front() {
+            Some(Step::Ready(item)) => Ok(Async::Ready(Some(item))),
+            Some(Step::NotReady) => {
+                // The next step is taken at the next poll.
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+type ScriptedAggregator = EventsAggregator<
+    ScriptedSource<InternalEvent>,
+    ScriptedSource<NetworkEvent>,
+    ScriptedSource<ExternalMessage>,
+>;
+
+/// Aggregator over scripted sources, which are polled within a single task, so that
+/// the order of the dispatched events depends only on the scripts and the scheduling
+/// policy.
+#[derive(Debug)]
+pub struct DeterministicAggregator {
+    aggregator: ScriptedAggregator,
+}
+
+impl DeterministicAggregator {
+    pub fn new(
+        internal: Vec<Step<InternalEvent>>,
+        network: Vec<Step<NetworkEvent>>,
+        api: Vec<Step<ExternalMessage>>,
+    ) -> Self {
+        DeterministicAggregator {
+            aggregator: EventsAggregator::new(
+                ScriptedSource::new(internal),
+                ScriptedSource::new(network),
+                ScriptedSource::new(api),
+            ),
+        }
+    }
+
+    pub fn with_policy(self, policy: SchedulePolicy) -> Self {
+        DeterministicAggregator {
+            aggregator: self.aggregator.with_policy(policy),
+        }
+    }
+
+    /// Polls the aggregator until it completes. Returns the kinds of the yielded events,
+    /// with `idle` standing for the polls at which none of the sources has been ready.
+    pub fn dispatch_order(mut self) -> Vec<&'static str> {
+        let aggregator = &mut self.aggregator;
+        future::lazy(move || {
+            let mut order = Vec::new();
+            for _ in 0..1_000 {
+                match aggregator.poll() {
+                    Ok(Async::Ready(Some(event))) => order.push(event.kind()),
+                    Ok(Async::Ready(None)) => return Ok::<_, ()>(order),
+                    Ok(Async::NotReady) => order.push("idle"),
+                    Err(()) => unreachable!("Scripted sources never fail"),
+                }
+            }
+            panic!("Aggregator has not completed in 1000 polls");
+        }).wait()
+            .unwrap()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ConnectionParams {
     pub connect: Connect,
@@ -301,69 +604,88 @@ fn test_network_handshake() {
 }
 
 #[test]
-fn test_network_big_message() {
-    let first = "127.0.0.1:17200".parse().unwrap();
-    let second = "127.0.0.1:17201".parse().unwrap();
-
-    let m1 = raw_message(15, 100000);
-    let m2 = raw_message(16, 400);
+fn test_network_handshake_over_memory_transport() {
+    let first = "127.0.0.1:19792".parse().unwrap();
+    let second = "127.0.0.1:19793".parse().unwrap();
 
     let mut connect_list = ConnectList::default();
-
     let mut t1 = ConnectionParams::from_address(first);
     connect_list.add(t1.connect_info);
-
     let mut t2 = ConnectionParams::from_address(second);
     connect_list.add(t2.connect_info);
-
     let connect_list = SharedConnectList::from_connect_list(connect_list);
 
-    let e1 = TestEvents::with_addr(first);
-    let e2 = TestEvents::with_addr(second);
+    // Both nodes share the transport, so no sockets are bound.
+    let transport = MemoryTransport::new();
+    let mut e1 = TestEvents::with_addr(first);
+    e1.memory_transport = Some(transport.clone());
+    let mut e2 = TestEvents::with_addr(second);
+    e2.memory_transport = Some(transport);
 
     let mut e1 = t1.spawn(e1, connect_list.clone());
     let mut e2 = t2.spawn(e2, connect_list);
 
     e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
 
-    e2.wait_for_connect();
-    e1.wait_for_connect();
+    let msg = raw_message(11, 1000);
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
 
-    e1.send_to(second, m1.clone());
-    assert_eq!(e2.wait_for_message(), m1);
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+}
 
-    e1.send_to(second, m2.clone());
-    assert_eq!(e2.wait_for_message(), m2);
+fn test_tls_config(node: &str) -> TlsConfig {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/tls");
+    TlsConfig {
+        cert: dir.join(format!("{}.pem", node)),
+        key: dir.join(format!("{}.key", node)),
+        ca: dir.join("ca.pem"),
+        server_name: "exonum-node".to_owned(),
+    }
+}
 
-    e1.send_to(second, m1.clone());
-    assert_eq!(e2.wait_for_message(), m1);
+#[test]
+fn test_network_handshake_over_tls() {
+    let first = "127.0.0.1:19820".parse().unwrap();
+    let second = "127.0.0.1:19821".parse().unwrap();
 
-    e2.send_to(first, m2.clone());
-    assert_eq!(e1.wait_for_message(), m2);
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
 
-    e2.send_to(first, m1.clone());
-    assert_eq!(e1.wait_for_message(), m1);
+    // Certificates of the nodes are different, but issued by the same CA.
+    let mut e1 = TestEvents::with_addr(first);
+    e1.tls = Some(test_tls_config("node1"));
+    let mut e2 = TestEvents::with_addr(second);
+    e2.tls = Some(test_tls_config("node2"));
 
-    e2.send_to(first, m2.clone());
-    assert_eq!(e1.wait_for_message(), m2);
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+
+    let msg = raw_message(11, 1000);
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+    e2.send_to(first, msg.clone());
+    assert_eq!(e1.wait_for_message(), msg);
 
     e1.disconnect_with(second);
     assert_eq!(e1.wait_for_disconnect(), second);
-
-    e2.disconnect_with(first);
-    assert_eq!(e2.wait_for_disconnect(), first);
 }
 
 #[test]
-fn test_network_max_message_len() {
-    let first = "127.0.0.1:17202".parse().unwrap();
-    let second = "127.0.0.1:17303".parse().unwrap();
-
-    let max_message_length = ConsensusConfig::DEFAULT_MAX_MESSAGE_LEN as usize;
-    let max_payload_length =
-        max_message_length - ::messages::HEADER_LENGTH - ::crypto::SIGNATURE_LENGTH;
-    let acceptable_message = raw_message(15, max_payload_length);
-    let too_big_message = raw_message(16, max_payload_length + 1000);
+fn test_network_tls_mismatch() {
+    let first = "127.0.0.1:19822".parse().unwrap();
+    let second = "127.0.0.1:19823".parse().unwrap();
 
     let mut connect_list = ConnectList::default();
     let mut t1 = ConnectionParams::from_address(first);
@@ -372,150 +694,2700 @@ fn test_network_max_message_len() {
     connect_list.add(t2.connect_info);
     let connect_list = SharedConnectList::from_connect_list(connect_list);
 
-    let e1 = TestEvents::with_addr(first);
+    let mut e1 = TestEvents::with_addr(first);
+    e1.tls = Some(test_tls_config("node1"));
     let e2 = TestEvents::with_addr(second);
 
     let mut e1 = t1.spawn(e1, connect_list.clone());
     let mut e2 = t2.spawn(e2, connect_list);
 
+    // Neither side accepts the other one, whoever dials.
     e1.connect_with(second, t1.connect.clone());
-
-    e2.wait_for_connect();
-    e1.wait_for_connect();
-
-    e1.send_to(second, acceptable_message.clone());
-    assert_eq!(e2.wait_for_message(), acceptable_message);
-
-    e2.send_to(first, too_big_message.clone());
-    assert!(e1.wait_for_event().is_err());
+    e2.connect_with(first, t2.connect.clone());
+    let timeout = Duration::from_secs(2);
+    for event in &[e1.wait_for_event_within(timeout), e2.wait_for_event_within(timeout)] {
+        if let Ok(NetworkEvent::PeerConnected(..)) = *event {
+            panic!("Peer connected without TLS: {:?}", event);
+        }
+    }
 }
 
 #[test]
-fn test_network_reconnect() {
-    let first = "127.0.0.1:19100".parse().unwrap();
-    let second = "127.0.0.1:19101".parse().unwrap();
-
-    let msg = raw_message(11, 1000);
+fn test_network_connections_activity() {
+    let first = "127.0.0.1:19794".parse().unwrap();
+    let second = "127.0.0.1:19795".parse().unwrap();
 
     let mut connect_list = ConnectList::default();
     let mut t1 = ConnectionParams::from_address(first);
     connect_list.add(t1.connect_info);
-
     let mut t2 = ConnectionParams::from_address(second);
     connect_list.add(t2.connect_info);
     let connect_list = SharedConnectList::from_connect_list(connect_list);
 
-    let e1 = TestEvents::with_addr(first);
-    let e2 = TestEvents::with_addr(second);
-
+    // Pings would keep the timestamps advancing.
+    let mut e1 = TestEvents::with_addr(first);
+    e1.network_config.keep_alive_interval = None;
+    let mut e2 = TestEvents::with_addr(second);
+    e2.network_config.keep_alive_interval = None;
     let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
 
-    // First connect attempt.
-    let mut e2 = t2.spawn(e2, connect_list.clone());
-
-    // Handle first attempt.
     e1.connect_with(second, t1.connect.clone());
-    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
-    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+    e2.wait_for_connect();
+    e1.wait_for_connect();
 
+    let msg = raw_message(11, 1000);
     e1.send_to(second, msg.clone());
     assert_eq!(e2.wait_for_message(), msg);
 
-    e1.disconnect_with(second);
-    drop(e2);
-    assert_eq!(e1.wait_for_disconnect(), second);
+    let sent = e1.connections_activity()[&second];
+    assert!(sent.last_sent.is_some());
+    let received = e2.connections_activity()[&first];
+    assert!(received.last_received.is_some());
 
-    // Handle second attempt.
-    let e2 = TestEvents::with_addr(second);
-    let mut e2 = t2.spawn(e2, connect_list);
+    // Without traffic the timestamps stay the same.
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(e1.connections_activity()[&second], sent);
+    assert_eq!(e2.connections_activity()[&first], received);
+}
+
+#[test]
+fn test_network_peer_info() {
+    let first = "127.0.0.1:19824".parse().unwrap();
+    let second = "127.0.0.1:19825".parse().unwrap();
+    let third = "127.0.0.1:19826".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let mut t3 = ConnectionParams::from_address(third);
+    connect_list.add(t3.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
 
+    let mut e1 = t1.spawn(TestEvents::with_addr(first), connect_list.clone());
+    let mut e2 = t2.spawn(TestEvents::with_addr(second), connect_list.clone());
+    let mut e3 = t3.spawn(TestEvents::with_addr(third), connect_list);
+    assert!(e1.peer_info().is_empty());
+
+    // The first node dials the second one and is dialed by the third one.
     e1.connect_with(second, t1.connect.clone());
-    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
-    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+    e2.wait_for_connect();
+    e1.wait_for_connect();
+    e3.connect_with(first, t3.connect.clone());
+    e1.wait_for_connect();
+    e3.wait_for_connect();
 
+    let msg = raw_message(11, 100);
     e1.send_to(second, msg.clone());
     assert_eq!(e2.wait_for_message(), msg);
+    e1.send_to(third, msg.clone());
+    assert_eq!(e3.wait_for_message(), msg);
+
+    let mut peers = e1.peer_info();
+    peers.sort_by_key(|peer| peer.address);
+    match peers[..] {
+        [PeerInfo {
+            public_key: second_key,
+            address: second_address,
+            direction: Direction::Outgoing,
+            last_activity: Some(_),
+        }, PeerInfo {
+            public_key: third_key,
+            address: third_address,
+            direction: Direction::Incoming,
+            last_activity: Some(_),
+        }] => {
+            assert_eq!((second_key, second_address), (t2.public_key, second));
+            assert_eq!((third_key, third_address), (t3.public_key, third));
+        }
+        ref other => panic!("Unexpected peers: {:?}", other),
+    }
 
     e1.disconnect_with(second);
     assert_eq!(e1.wait_for_disconnect(), second);
+    let peers = e1.peer_info();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].public_key, t3.public_key);
 }
 
 #[test]
-fn test_network_multiple_connect() {
-    let main = "127.0.0.1:19600".parse().unwrap();
-
-    let nodes = [
-        "127.0.0.1:19601".parse().unwrap(),
-        "127.0.0.1:19602".parse().unwrap(),
-        "127.0.0.1:19603".parse().unwrap(),
-    ];
+fn test_network_connection_stats() {
+    let first = "127.0.0.1:19836".parse().unwrap();
+    let second = "127.0.0.1:19837".parse().unwrap();
+    let third = "127.0.0.1:19838".parse().unwrap();
+    let fourth = "127.0.0.1:19839".parse().unwrap();
 
     let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let mut t3 = ConnectionParams::from_address(third);
+    connect_list.add(t3.connect_info);
+    let mut t4 = ConnectionParams::from_address(fourth);
+    connect_list.add(t4.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
 
-    let mut connection_params: Vec<_> = nodes
-        .iter()
-        .cloned()
-        .map(|addr| ConnectionParams::from_address(addr))
-        .collect();
+    let mut e1 = t1.spawn(TestEvents::with_addr(first), connect_list.clone());
+    let mut e2 = t2.spawn(TestEvents::with_addr(second), connect_list.clone());
+    let mut e3 = t3.spawn(TestEvents::with_addr(third), connect_list.clone());
+    let mut e4 = t4.spawn(TestEvents::with_addr(fourth), connect_list);
+
+    let limits = NetworkConfiguration::default();
+    let expected = ConnectionStats {
+        incoming: 0,
+        outgoing: 0,
+        pending_outgoing: 0,
+        max_incoming_connections: limits.max_incoming_connections,
+        max_outgoing_connections: limits.max_outgoing_connections,
+    };
+    assert_eq!(e1.connection_stats(), expected);
+
+    // The first node dials the second one and is dialed by the third and the fourth ones.
+    e1.connect_with(second, t1.connect.clone());
+    e2.wait_for_connect();
+    e1.wait_for_connect();
+    e3.connect_with(first, t3.connect.clone());
+    e1.wait_for_connect();
+    e3.wait_for_connect();
+    e4.connect_with(first, t4.connect.clone());
+    e1.wait_for_connect();
+    e4.wait_for_connect();
 
-    for params in connection_params.iter().cloned() {
-        connect_list.add(params.connect_info);
-    }
+    assert_eq!(
+        e1.connection_stats(),
+        ConnectionStats {
+            incoming: 2,
+            outgoing: 1,
+            ..expected
+        }
+    );
+    assert_eq!(
+        e3.connection_stats(),
+        ConnectionStats {
+            outgoing: 1,
+            ..expected
+        }
+    );
 
-    let mut t1 = ConnectionParams::from_address(main);
-    let events = TestEvents::with_addr(t1.address);
+    e1.disconnect_with(third);
+    assert_eq!(e1.wait_for_disconnect(), third);
+    assert_eq!(
+        e1.connection_stats(),
+        ConnectionStats {
+            incoming: 1,
+            outgoing: 1,
+            ..expected
+        }
+    );
+}
 
+#[test]
+fn test_network_multiple_listen_addresses() {
+    let first = "127.0.0.1:19760".parse().unwrap();
+    let second = "127.0.0.1:19761".parse().unwrap();
+    let third = "127.0.0.1:19762".parse().unwrap();
+    let fourth = "127.0.0.1:19763".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(third);
+    connect_list.add(t2.connect_info);
+    let mut t3 = ConnectionParams::from_address(fourth);
+    connect_list.add(t3.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let mut e1 = TestEvents::with_addr(first);
+    e1.additional_listen_addresses.push(second);
+    let e2 = TestEvents::with_addr(third);
+    let e3 = TestEvents::with_addr(fourth);
+
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list.clone());
+    let mut e3 = t3.spawn(e3, connect_list);
+
+    // Peers connect to different addresses of the first node.
+    e2.connect_with(first, t2.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    e3.connect_with(second, t3.connect.clone());
+    assert_eq!(e3.wait_for_connect(), t1.connect.clone());
+
+    let mut connects = vec![e1.wait_for_connect(), e1.wait_for_connect()];
+    connects.sort_by_key(|connect| connect.addr());
+    assert_eq!(connects, vec![t2.connect.clone(), t3.connect.clone()]);
+}
+
+#[test]
+fn test_network_message_sender() {
+    let first = "127.0.0.1:19790".parse().unwrap();
+    let second = "127.0.0.1:19791".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let e2 = TestEvents::with_addr(second);
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+    e2.wait_for_connect();
+    e1.wait_for_connect();
+
+    let message = raw_message(12, 100);
+    e1.send_to(second, message.clone());
+    match e2.wait_for_event() {
+        Ok(NetworkEvent::MessageReceived(address, key, received)) => {
+            assert_eq!(address, first);
+            assert_eq!(&key, t1.connect.pub_key());
+            assert_eq!(received, message);
+        }
+        other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_big_message() {
+    let first = "127.0.0.1:17200".parse().unwrap();
+    let second = "127.0.0.1:17201".parse().unwrap();
+
+    let m1 = raw_message(15, 100000);
+    let m2 = raw_message(16, 400);
+
+    let mut connect_list = ConnectList::default();
+
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let e2 = TestEvents::with_addr(second);
+
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+
+    e2.wait_for_connect();
+    e1.wait_for_connect();
+
+    e1.send_to(second, m1.clone());
+    assert_eq!(e2.wait_for_message(), m1);
+
+    e1.send_to(second, m2.clone());
+    assert_eq!(e2.wait_for_message(), m2);
+
+    e1.send_to(second, m1.clone());
+    assert_eq!(e2.wait_for_message(), m1);
+
+    e2.send_to(first, m2.clone());
+    assert_eq!(e1.wait_for_message(), m2);
+
+    e2.send_to(first, m1.clone());
+    assert_eq!(e1.wait_for_message(), m1);
+
+    e2.send_to(first, m2.clone());
+    assert_eq!(e1.wait_for_message(), m2);
+
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+
+    e2.disconnect_with(first);
+    assert_eq!(e2.wait_for_disconnect(), first);
+}
+
+#[test]
+fn test_network_max_message_len() {
+    let first = "127.0.0.1:17202".parse().unwrap();
+    let second = "127.0.0.1:17303".parse().unwrap();
+
+    let max_message_length = ConsensusConfig::DEFAULT_MAX_MESSAGE_LEN as usize;
+    let max_payload_length =
+        max_message_length - ::messages::HEADER_LENGTH - ::crypto::SIGNATURE_LENGTH;
+    let acceptable_message = raw_message(15, max_payload_length);
+    let too_big_message = raw_message(16, max_payload_length + 1000);
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let e2 = TestEvents::with_addr(second);
+
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+
+    e2.wait_for_connect();
+    e1.wait_for_connect();
+
+    e1.send_to(second, acceptable_message.clone());
+    assert_eq!(e2.wait_for_message(), acceptable_message);
+
+    e2.send_to(first, too_big_message.clone());
+    assert!(e1.wait_for_event().is_err());
+}
+
+#[test]
+fn test_network_reconnect() {
+    let first = "127.0.0.1:19100".parse().unwrap();
+    let second = "127.0.0.1:19101".parse().unwrap();
+
+    let msg = raw_message(11, 1000);
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let e2 = TestEvents::with_addr(second);
+
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+
+    // First connect attempt.
+    let mut e2 = t2.spawn(e2, connect_list.clone());
+
+    // Handle first attempt.
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+
+    e1.disconnect_with(second);
+    drop(e2);
+    assert_eq!(e1.wait_for_disconnect(), second);
+
+    // Handle second attempt.
+    let e2 = TestEvents::with_addr(second);
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+}
+
+#[test]
+fn test_network_reconnect_with_backoff() {
+    let first = "127.0.0.1:19110".parse().unwrap();
+    let second = "127.0.0.1:19111".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let mut e1 = TestEvents::with_addr(first);
+    e1.network_config.tcp_connect_retry_timeout = 100;
+    e1.network_config.tcp_connect_retry_multiplier = 2.0;
+    e1.network_config.tcp_connect_retry_max_timeout = 400;
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+
+    // The second peer does not listen yet, so the first connection attempts fail.
+    e1.connect_with(second, t1.connect.clone());
+    thread::sleep(Duration::from_millis(500));
+
+    let e2 = TestEvents::with_addr(second);
+    let mut e2 = t2.spawn(e2, connect_list);
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+}
+
+#[test]
+fn test_network_reconnects_lost_connection() {
+    let first = "127.0.0.1:19841".parse().unwrap();
+    let second = "127.0.0.1:19842".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let transport = MemoryTransport::new();
+    let mut e1 = TestEvents::with_addr(first);
+    e1.memory_transport = Some(transport.clone());
+    e1.network_config.tcp_connect_retry_timeout = 100;
+    e1.network_config.tcp_connect_retry_multiplier = 2.0;
+    e1.network_config.tcp_connect_retry_max_timeout = 400;
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = TestEvents::with_addr(second);
+    e2.memory_transport = Some(transport.clone());
+    let mut e2 = t2.spawn(e2, connect_list.clone());
+
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+
+    // The connection drops as the second peer goes away, and the transport refuses
+    // the reconnection attempts until the peer is back.
+    drop(e2);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    thread::sleep(Duration::from_millis(500));
+
+    let mut e2 = TestEvents::with_addr(second);
+    e2.memory_transport = Some(transport);
+    let mut e2 = t2.spawn(e2, connect_list);
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+
+    let msg = raw_message(11, 1000);
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+}
+
+#[test]
+fn test_network_incompatible_protocol_version() {
+    let first = "127.0.0.1:19730".parse().unwrap();
+    let second = "127.0.0.1:19731".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let mut e2 = TestEvents::with_addr(second);
+    e2.network_config.min_protocol_version = PROTOCOL_VERSION + 1;
+    e2.network_config.max_protocol_version = PROTOCOL_VERSION + 1;
+
+    let e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    // The second peer refuses the first one instead of reporting it as connected.
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_disconnect(), first);
+}
+
+#[test]
+fn test_network_rate_limit() {
+    let first = "127.0.0.1:19740".parse().unwrap();
+    let second = "127.0.0.1:19741".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let mut e2 = TestEvents::with_addr(second);
+    e2.network_config.max_incoming_rate = Some(20);
+
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+    e2.wait_for_connect();
+    e1.wait_for_connect();
+
+    // The first 20 messages are delivered at once, the rest is paced at 20 messages per second.
+    let messages: Vec<_> = (0..50).map(|i| raw_message(i, 100)).collect();
+    let start = Instant::now();
+    for message in &messages {
+        e1.send_to(second, message.clone());
+    }
+    for message in &messages {
+        assert_eq!(&e2.wait_for_message(), message);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(1_400));
+}
+
+#[test]
+fn test_network_rate_limit_updated_at_runtime() {
+    let first = "127.0.0.1:19843".parse().unwrap();
+    let second = "127.0.0.1:19844".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let mut e2 = TestEvents::with_addr(second);
+    e2.network_config.max_incoming_rate = Some(1_000);
+    let mut network_config = e2.network_config;
+
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+    e2.wait_for_connect();
+    e1.wait_for_connect();
+
+    // The limit of the established connection is lowered; the requests are handled in order,
+    // so the new limit is applied once the activity of the connections is returned.
+    network_config.max_incoming_rate = Some(20);
+    e2.request(NetworkRequest::UpdateConfig(network_config));
+    assert!(e2.connections_activity().contains_key(&first));
+
+    // The first 20 messages are delivered at once, the rest is paced at 20 messages per second.
+    let messages: Vec<_> = (0..50).map(|i| raw_message(i, 100)).collect();
+    let start = Instant::now();
+    for message in &messages {
+        e1.send_to(second, message.clone());
+    }
+    for message in &messages {
+        assert_eq!(&e2.wait_for_message(), message);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(1_400));
+
+    // The peer is paced rather than disconnected.
+    assert!(e2.wait_for_event_within(Duration::from_millis(500)).is_err());
+    assert!(e1.wait_for_event_within(Duration::from_millis(500)).is_err());
+    assert!(e2.connections_activity().contains_key(&first));
+}
+
+#[test]
+fn test_network_backpressure() {
+    let first = "127.0.0.1:19750".parse().unwrap();
+    let second = "127.0.0.1:19751".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let mut e2 = TestEvents::with_addr(second);
+    e2.events_config.network_events_capacity = 1;
+
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+    e1.wait_for_connect();
+
+    // The handler of the second node does not read events for a while, so reading from
+    // the socket is paused; no messages are lost once the handler catches up.
+    let messages: Vec<_> = (0..20).map(|i| raw_message(i, 100)).collect();
+    for message in &messages {
+        e1.send_to(second, message.clone());
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    e2.wait_for_connect();
+    for message in &messages {
+        assert_eq!(&e2.wait_for_message(), message);
+    }
+}
+
+#[test]
+fn test_network_multiple_connect() {
+    let main = "127.0.0.1:19600".parse().unwrap();
+
+    let nodes = [
+        "127.0.0.1:19601".parse().unwrap(),
+        "127.0.0.1:19602".parse().unwrap(),
+        "127.0.0.1:19603".parse().unwrap(),
+    ];
+
+    let mut connect_list = ConnectList::default();
+
+    let mut connection_params: Vec<_> = nodes
+        .iter()
+        .cloned()
+        .map(|addr| ConnectionParams::from_address(addr))
+        .collect();
+
+    for params in connection_params.iter().cloned() {
+        connect_list.add(params.connect_info);
+    }
+
+    let mut t1 = ConnectionParams::from_address(main);
+    let events = TestEvents::with_addr(t1.address);
+
+    connect_list.add(t1.connect_info);
+
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+    let mut node = t1.spawn(events, connect_list.clone());
+
+    let connectors: Vec<_> = connection_params
+        .iter_mut()
+        .map(|params| {
+            let events = TestEvents::with_addr(params.address);
+            params.spawn(events, connect_list.clone())
+        })
+        .collect();
+
+    connectors[0].connect_with(main, connection_params[0].connect.clone());
+    assert_eq!(
+        node.wait_for_connect(),
+        connection_params[0].connect.clone()
+    );
+    connectors[1].connect_with(main, connection_params[1].connect.clone());
+    assert_eq!(
+        node.wait_for_connect(),
+        connection_params[1].connect.clone()
+    );
+    connectors[2].connect_with(main, connection_params[2].connect.clone());
+    assert_eq!(
+        node.wait_for_connect(),
+        connection_params[2].connect.clone()
+    );
+}
+
+#[test]
+fn test_send_first_not_connect() {
+    let main = "127.0.0.1:19500".parse().unwrap();
+    let other = "127.0.0.1:19501".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(main);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(other);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let node = TestEvents::with_addr(main);
+    let other_node = TestEvents::with_addr(other);
+
+    let mut node = t1.spawn(node, connect_list.clone());
+    let other_node = t2.spawn(other_node, connect_list.clone());
+
+    let message = raw_message(11, 1000);
+    other_node.send_to(main, message.clone()); // should connect before send message
+
+    assert_eq!(node.wait_for_connect(), t2.connect);
+    assert_eq!(node.wait_for_message(), message);
+}
+
+#[test]
+fn test_pump_drains_preloaded_sources() {
+    let peer: SocketAddr = "127.0.0.1:19716".parse().unwrap();
+
+    let internal = stream::iter_ok::<_, ()>(vec![InternalEvent::Flush]);
+    let network = stream::iter_ok::<_, ()>(vec![NetworkEvent::PeerDisconnected(peer)]);
+    let api = stream::iter_ok::<_, ()>(vec![ExternalMessage::Rebroadcast]);
+    let mut aggregator = EventsAggregator::new(internal, network, api);
+
+    let events = pump(&mut aggregator);
+    assert_eq!(events.len(), 3);
+    // The exhausted aggregator is pumped cleanly.
+    assert!(pump(&mut aggregator).is_empty());
+}
+
+#[test]
+fn test_pump_stops_when_not_ready() {
+    let (mut internal_tx, internal_rx) = mpsc::channel(4);
+    let (_network_tx, network_rx) = mpsc::channel::<NetworkEvent>(4);
+    let (_api_tx, api_rx) = mpsc::channel::<ExternalMessage>(4);
+    let mut aggregator = EventsAggregator::new(internal_rx, network_rx, api_rx);
+    assert!(pump(&mut aggregator).is_empty());
+
+    for round in 1..3 {
+        let event = InternalEvent::JumpToRound(Height(1), Round(round));
+        internal_tx.try_send(event).unwrap();
+    }
+    let rounds: Vec<_> = pump(&mut aggregator)
+        .into_iter()
+        .map(|event| match event {
+            Event::Internal(InternalEvent::JumpToRound(_, round)) => round,
+            other => panic!("Unexpected event: {:?}", other),
+        })
+        .collect();
+    assert_eq!(rounds, vec![Round(1), Round(2)]);
+    assert!(pump(&mut aggregator).is_empty());
+}
+
+#[test]
+fn test_drain_counts_preloaded_receivers() {
+    let peer: SocketAddr = "127.0.0.1:19717".parse().unwrap();
+    let (mut internal_tx, mut internal_rx) = mpsc::channel(8);
+    let (mut network_tx, mut network_rx) = mpsc::channel(8);
+    let (mut api_tx, mut api_rx) = mpsc::channel(8);
+
+    for timeout in vec![NodeTimeout::PeerExchange, NodeTimeout::UpdateApiState] {
+        internal_tx.try_send(InternalEvent::Timeout(timeout)).unwrap();
+    }
+    internal_tx.try_send(InternalEvent::Flush).unwrap();
+    for _ in 0..3 {
+        network_tx
+            .try_send(NetworkEvent::PeerDisconnected(peer))
+            .unwrap();
+    }
+    api_tx.try_send(ExternalMessage::Rebroadcast).unwrap();
+
+    let counts = DrainCounts::drain(&mut internal_rx, &mut network_rx, &mut api_rx);
+    assert_eq!(
+        counts,
+        DrainCounts {
+            network: 3,
+            timeout: 2,
+            api: 1,
+            internal: 1,
+        }
+    );
+    // The receivers are drained without waiting for the senders which are still alive.
+    let counts = DrainCounts::drain(&mut internal_rx, &mut network_rx, &mut api_rx);
+    assert_eq!(counts, DrainCounts::default());
+}
+
+#[test]
+fn test_events_aggregator_round_robin() {
+    let peer: SocketAddr = "127.0.0.1:19700".parse().unwrap();
+
+    let internal = stream::iter_ok::<_, ()>(vec![
+        InternalEvent::JumpToRound(Height(1), Round(1)),
+        InternalEvent::JumpToRound(Height(1), Round(2)),
+    ]);
+    let network = stream::iter_ok::<_, ()>(vec![
+        NetworkEvent::PeerDisconnected(peer),
+        NetworkEvent::UnableConnectToPeer(peer),
+    ]);
+    let api = stream::iter_ok::<_, ()>(vec![
+        ExternalMessage::Rebroadcast,
+        ExternalMessage::Enable(true),
+    ]);
+
+    let events = EventsAggregator::new(internal, network, api)
+        .collect()
+        .wait()
+        .unwrap();
+
+    assert_eq!(events.len(), 6);
+    for (i, event) in events.iter().enumerate() {
+        match (i % 3, event) {
+            (0, Event::Internal(_)) | (1, Event::Network(_)) | (2, Event::Api(_)) => {}
+            (_, other) => panic!("Unexpected event at position {}: {:?}", i, other),
+        }
+    }
+}
+
+#[test]
+fn test_events_aggregator_weighted_schedule() {
+    let peer: SocketAddr = "127.0.0.1:19714".parse().unwrap();
+
+    let timeouts = (0..100).map(|_| InternalEvent::Timeout(NodeTimeout::PeerExchange));
+    let internal = stream::iter_ok::<_, ()>(timeouts.collect::<Vec<_>>());
+    let flood = (0..1_000).map(|_| NetworkEvent::PeerDisconnected(peer));
+    let network = stream::iter_ok::<_, ()>(flood.collect::<Vec<_>>());
+    let api = stream::iter_ok::<_, ()>(Vec::<ExternalMessage>::new());
+
+    // Network events get three slots for each timeout.
+    let events = EventsAggregator::new(internal, network, api)
+        .with_policy(SchedulePolicy::weighted(vec![1, 3, 1]))
+        .take(100)
+        .collect()
+        .wait()
+        .unwrap();
+
+    let timeouts = events
+        .iter()
+        .filter(|event| match **event {
+            Event::Internal(InternalEvent::Timeout(_)) => true,
+            _ => false,
+        })
+        .count();
+    assert_eq!(timeouts, 25);
+}
+
+#[test]
+fn test_events_aggregator_shuts_down_on_fatal_closure() {
+    let peer: SocketAddr = "127.0.0.1:19827".parse().unwrap();
+    let sources = || {
+        let internal = stream::iter_ok::<_, ()>(vec![
+            InternalEvent::JumpToRound(Height(1), Round(1)),
+            InternalEvent::JumpToRound(Height(1), Round(2)),
+        ]);
+        let network = stream::repeat::<_, ()>(peer).map(NetworkEvent::PeerDisconnected);
+        let api = stream::iter_ok::<_, ()>(Vec::<ExternalMessage>::new());
+        (internal, network, api)
+    };
+
+    // By default, the remaining sources keep being polled.
+    let (internal, network, api) = sources();
+    let events = EventsAggregator::new(internal, network, api)
+        .take(10)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(events.len(), 10);
+
+    // The closure of the timeouts source shuts the aggregator down.
+    let (internal, network, api) = sources();
+    let policy = SourceClosurePolicy::default()
+        .on_closed(0, SourceClosure::Shutdown(Duration::from_secs(0)))
+        .on_closed(2, SourceClosure::Ignore);
+    let events = EventsAggregator::new(internal, network, api)
+        .with_closure_policy(policy)
+        .take(10)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(events.len(), 4);
+    for (i, event) in events.iter().enumerate() {
+        match (i % 2, event) {
+            (0, Event::Internal(InternalEvent::JumpToRound(..)))
+            | (1, Event::Network(NetworkEvent::PeerDisconnected(_))) => {}
+            (_, other) => panic!("Unexpected event at position {}: {:?}", i, other),
+        }
+    }
+}
+
+#[test]
+fn test_events_aggregator_closure_grace_period() {
+    let peer: SocketAddr = "127.0.0.1:19828".parse().unwrap();
+    let (network_tx, network_rx) = mpsc::channel(4);
+    let internal = stream::iter_ok::<_, ()>(Vec::<InternalEvent>::new());
+    let api = stream::iter_ok::<_, ()>(Vec::<ExternalMessage>::new());
+
+    let grace_period = Duration::from_millis(100);
+    let policy = SourceClosurePolicy::default().on_closed(0, SourceClosure::Shutdown(grace_period));
+    let aggregator = EventsAggregator::new(internal, network_rx, api).with_closure_policy(policy);
+    network_tx
+        .clone()
+        .send(NetworkEvent::PeerDisconnected(peer))
+        .wait()
+        .unwrap();
+
+    let mut core = Core::new().unwrap();
+    let start = Instant::now();
+    let guard = Timeout::new(Duration::from_secs(5), &core.handle()).unwrap();
+    let events = match core.run(aggregator.collect().select2(guard)) {
+        Ok(Either::A((events, _))) => events,
+        _ => panic!("Aggregator has not been shut down"),
+    };
+    assert!(start.elapsed() >= grace_period);
+    // The events of the remaining sources are delivered during the grace period.
+    match events[..] {
+        [Event::Network(NetworkEvent::PeerDisconnected(address))] if address == peer => {}
+        ref other => panic!("Unexpected events: {:?}", other),
+    }
+    drop(network_tx);
+}
+
+#[test]
+fn test_events_aggregator_isolates_source_errors() {
+    let peer: SocketAddr = "127.0.0.1:19717".parse().unwrap();
+    let sources = || {
+        let internal = stream::iter_ok::<_, String>(vec![
+            InternalEvent::JumpToRound(Height(1), Round(1)),
+            InternalEvent::JumpToRound(Height(1), Round(2)),
+        ]);
+        let network = stream::iter_result(vec![
+            Err("connection reset".to_owned()),
+            Ok(NetworkEvent::PeerDisconnected(peer)),
+        ]);
+        let api = stream::iter_ok::<_, String>(vec![ExternalMessage::Rebroadcast]);
+        (internal, network, api)
+    };
+
+    // By default, the error of a single source fails the aggregator.
+    let (internal, network, api) = sources();
+    let result = EventsAggregator::new(internal, network, api)
+        .collect()
+        .wait();
+    assert_eq!(result.unwrap_err(), "connection reset");
+
+    let (internal, network, api) = sources();
+    let events = EventsAggregator::new(internal, network, api)
+        .isolate_errors(1)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(events.len(), 5);
+    match events[1] {
+        Event::Network(NetworkEvent::StreamError {
+            source: 1,
+            ref error,
+        }) if error.contains("connection reset") => {}
+        ref other => panic!("Unexpected event: {:?}", other),
+    }
+    // The failed source keeps being polled.
+    match events[4] {
+        Event::Network(NetworkEvent::PeerDisconnected(address)) if address == peer => {}
+        ref other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+events_aggregator! {
+    /// Aggregator with an additional source of timeouts.
+    pub struct FiveSourcesAggregator {
+        internal: S1 = 0,
+        network: S2 = 1,
+        api: S3 = 2,
+        timeouts: S4 = 3,
+        extra: S5 = 4,
+    }
+}
+
+#[test]
+fn test_events_aggregator_five_sources() {
+    let peer: SocketAddr = "127.0.0.1:19706".parse().unwrap();
+
+    let internal = stream::iter_ok::<_, ()>(vec![
+        InternalEvent::JumpToRound(Height(1), Round(1)),
+        InternalEvent::JumpToRound(Height(1), Round(2)),
+    ]);
+    let network = stream::iter_ok::<_, ()>(vec![NetworkEvent::PeerDisconnected(peer)]);
+    let api = stream::iter_ok::<_, ()>(vec![ExternalMessage::Rebroadcast]);
+    let timeouts = stream::iter_ok::<_, ()>(vec![
+        NodeTimeout::PeerExchange,
+        NodeTimeout::UpdateApiState,
+    ]);
+    let extra = stream::iter_ok::<_, ()>(vec![InternalEvent::Shutdown]);
+
+    let events = FiveSourcesAggregator::new(internal, network, api, timeouts, extra)
+        .collect()
+        .wait()
+        .unwrap();
+
+    // Sources are polled in turn until the last one initiates the shutdown. Then the queued
+    // events of the other sources are dropped.
+    assert_eq!(events.len(), 4);
+    for (i, event) in events.iter().enumerate() {
+        match (i, event) {
+            (0, Event::Internal(InternalEvent::JumpToRound(..)))
+            | (1, Event::Network(_))
+            | (2, Event::Api(_))
+            | (3, Event::Internal(InternalEvent::Timeout(NodeTimeout::PeerExchange))) => {}
+            (_, other) => panic!("Unexpected event at position {}: {:?}", i, other),
+        }
+    }
+}
+
+#[test]
+fn test_events_aggregator_prioritizes_admin_messages() {
+    let peer: SocketAddr = "127.0.0.1:19707".parse().unwrap();
+
+    let (mut internal_tx, internal_rx) = mpsc::channel(64);
+    let (mut network_tx, network_rx) = mpsc::channel(64);
+    let (api_tx, api_rx) = mpsc::channel(4);
+    for round in 1..51 {
+        internal_tx
+            .try_send(InternalEvent::JumpToRound(Height(1), Round(round)))
+            .unwrap();
+        network_tx
+            .try_send(NetworkEvent::PeerDisconnected(peer))
+            .unwrap();
+    }
+
+    let mut events = EventsAggregator::new(internal_rx, network_rx, api_rx).wait();
+    for _ in 0..5 {
+        match events.next() {
+            Some(Ok(Event::Internal(_))) | Some(Ok(Event::Network(_))) => {}
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    // The admin message is yielded ahead of the backlog of the other events.
+    let _api_tx = api_tx.send(ExternalMessage::Enable(false)).wait().unwrap();
+    match events.next() {
+        Some(Ok(Event::Api(ExternalMessage::Enable(false)))) => {}
+        other => panic!("Unexpected event: {:?}", other),
+    }
+    match events.next() {
+        Some(Ok(Event::Network(_))) => {}
+        other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[test]
+fn test_events_aggregator_retry_propose() {
+    let propose_hash = hash(&[1, 2, 3]);
+    let internal = stream::iter_ok::<_, ()>(vec![InternalEvent::RetryPropose(
+        Height(3),
+        Round(2),
+        propose_hash,
+    )]);
+    let network = stream::empty::<NetworkEvent, ()>();
+    let api = stream::empty::<ExternalMessage, ()>();
+
+    let events = EventsAggregator::new(internal, network, api)
+        .collect()
+        .wait()
+        .unwrap();
+
+    assert_eq!(events.len(), 1);
+    match events[0] {
+        Event::Internal(InternalEvent::RetryPropose(height, round, hash)) => {
+            assert_eq!((height, round, hash), (Height(3), Round(2), propose_hash));
+        }
+        ref other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[test]
+fn test_events_aggregator_survives_closed_api() {
+    let peer: SocketAddr = "127.0.0.1:19701".parse().unwrap();
+
+    let (internal_tx, internal_rx) = mpsc::channel(4);
+    let (network_tx, network_rx) = mpsc::channel(4);
+    let (api_tx, api_rx) = mpsc::channel::<ExternalMessage>(4);
+    // Emulate the shutdown of the HTTP frontend.
+    drop(api_tx);
+
+    let mut events = EventsAggregator::new(internal_rx, network_rx, api_rx).wait();
+
+    let internal_tx = internal_tx
+        .send(InternalEvent::Timeout(NodeTimeout::PeerExchange))
+        .wait()
+        .unwrap();
+    match events.next() {
+        Some(Ok(Event::Internal(InternalEvent::Timeout(NodeTimeout::PeerExchange)))) => {}
+        other => panic!("Unexpected event: {:?}", other),
+    }
+
+    let network_tx = network_tx
+        .send(NetworkEvent::PeerDisconnected(peer))
+        .wait()
+        .unwrap();
+    match events.next() {
+        Some(Ok(Event::Network(NetworkEvent::PeerDisconnected(addr)))) => assert_eq!(addr, peer),
+        other => panic!("Unexpected event: {:?}", other),
+    }
+
+    let internal_tx = internal_tx
+        .send(InternalEvent::Timeout(NodeTimeout::UpdateApiState))
+        .wait()
+        .unwrap();
+    match events.next() {
+        Some(Ok(Event::Internal(InternalEvent::Timeout(NodeTimeout::UpdateApiState)))) => {}
+        other => panic!("Unexpected event: {:?}", other),
+    }
+
+    // The aggregator completes only after all sources are closed.
+    drop(internal_tx);
+    drop(network_tx);
+    assert!(events.next().is_none());
+}
+
+#[test]
+fn test_events_aggregator_keeps_peeked_event() {
+    let peers: Vec<SocketAddr> = vec![
+        "127.0.0.1:8000".parse().unwrap(),
+        "127.0.0.1:8001".parse().unwrap(),
+    ];
+    let (mut tx, rx) = mpsc::channel(4);
+    for &peer in &peers {
+        tx.try_send(NetworkEvent::PeerDisconnected(peer)).unwrap();
+    }
+    drop(tx);
+    let mut aggregator = EventsAggregator::from_network(rx);
+
+    // The future driving the aggregator peeks an event and loses the `select`.
+    {
+        let peeking = future::poll_fn(|| {
+            aggregator.peek()?;
+            Ok(Async::<()>::NotReady)
+        });
+        match peeking.select2(future::ok::<(), ()>(())).wait() {
+            Ok(Either::B(_)) => {}
+            _ => panic!("Peeking future has completed"),
+        }
+    }
+
+    let events = aggregator.collect().wait().unwrap();
+    let disconnected: Vec<_> = events
+        .into_iter()
+        .map(|event| match event {
+            Event::Network(NetworkEvent::PeerDisconnected(peer)) => peer,
+            other => panic!("Unexpected event: {:?}", other),
+        })
+        .collect();
+    assert_eq!(disconnected, peers);
+}
+
+fn jump_to_round() -> InternalEvent {
+    InternalEvent::JumpToRound(Height(1), Round(1))
+}
+
+fn peer_disconnected() -> NetworkEvent {
+    NetworkEvent::PeerDisconnected("127.0.0.1:8000".parse().unwrap())
+}
+
+#[test]
+fn test_deterministic_aggregator_round_robin() {
+    // A source which is not ready loses its turn.
+    let order = DeterministicAggregator::new(
+        script("R.R", jump_to_round),
+        script("RRR", peer_disconnected),
+        vec![],
+    ).dispatch_order();
+    assert_eq!(
+        order,
+        vec!["internal", "network", "network", "internal", "network"]
+    );
+
+    // The aggregator is idle only if none of the sources is ready.
+    let order = DeterministicAggregator::new(
+        script(".R", jump_to_round),
+        script(".R", peer_disconnected),
+        vec![],
+    ).with_policy(SchedulePolicy::round_robin())
+        .dispatch_order();
+    assert_eq!(order, vec!["idle", "internal", "network"]);
+}
+
+#[test]
+fn test_deterministic_aggregator_weighted_schedule() {
+    let order = DeterministicAggregator::new(
+        script("RRR", jump_to_round),
+        script("RRRRR", peer_disconnected),
+        vec![],
+    ).with_policy(SchedulePolicy::weighted(vec![1, 3, 1]))
+        .dispatch_order();
+    assert_eq!(
+        order,
+        vec![
+            "internal", "network", "network", "network", "internal", "network", "network",
+            "internal",
+        ]
+    );
+
+    // A source which is not ready forfeits the rest of its credit.
+    let order = DeterministicAggregator::new(
+        script("RR", jump_to_round),
+        script("R.RR", peer_disconnected),
+        vec![],
+    ).with_policy(SchedulePolicy::weighted(vec![1, 3, 1]))
+        .dispatch_order();
+    assert_eq!(
+        order,
+        vec!["internal", "network", "internal", "network", "network"]
+    );
+}
+
+#[test]
+fn test_deterministic_aggregator_prioritized_source() {
+    // An ordinary api message is peeked, but waits for the turn of its source.
+    let order = DeterministicAggregator::new(
+        script("RR", jump_to_round),
+        script("RR", peer_disconnected),
+        script("R", || ExternalMessage::Rebroadcast),
+    ).dispatch_order();
+    assert_eq!(
+        order,
+        vec!["internal", "network", "api", "internal", "network"]
+    );
+
+    // An admin message is yielded as soon as it is ready.
+    let order = DeterministicAggregator::new(
+        script("RRR", jump_to_round),
+        script("RRR", peer_disconnected),
+        script(".R", || ExternalMessage::Enable(false)),
+    ).dispatch_order();
+    assert_eq!(
+        order,
+        vec![
+            "internal", "api", "network", "internal", "network", "internal", "network",
+        ]
+    );
+}
+
+// Queues the items into the only open source of the aggregator and checks that they
+// are yielded in order, and that the aggregator completes only once the source is closed.
+fn check_single_source<A, T, F>(mut aggregator: A, mut tx: mpsc::Sender<T>, items: F)
+where
+    A: Stream<Item = Event, Error = ()>,
+    T: Into<Event>,
+    F: Fn() -> Vec<T>,
+{
+    assert!(pump(&mut aggregator).is_empty());
+    for item in items() {
+        tx.try_send(item).unwrap();
+    }
+    let expected: Vec<_> = items()
+        .into_iter()
+        .map(|item| Into::<Event>::into(item).summary())
+        .collect();
+    let yielded: Vec<_> = pump(&mut aggregator).iter().map(Event::summary).collect();
+    assert_eq!(yielded, expected);
+
+    let mut poll = move || future::lazy(|| aggregator.poll()).wait().unwrap();
+    assert!(poll().is_not_ready());
+    drop(tx);
+    match poll() {
+        Async::Ready(None) => {}
+        other => panic!("Aggregator has not completed: {:?}", other),
+    }
+}
+
+#[test]
+fn test_events_aggregator_polls_each_source() {
+    let peer: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+
+    let (tx, rx) = mpsc::channel(4);
+    let aggregator = EventsAggregator::new(
+        rx,
+        stream::empty::<NetworkEvent, _>(),
+        stream::empty::<ExternalMessage, _>(),
+    );
+    check_single_source(aggregator, tx, || {
+        vec![
+            InternalEvent::Timeout(NodeTimeout::PeerExchange),
+            InternalEvent::JumpToRound(Height(1), Round(2)),
+        ]
+    });
+
+    let (tx, rx) = mpsc::channel(4);
+    let aggregator = EventsAggregator::new(
+        stream::empty::<InternalEvent, _>(),
+        rx,
+        stream::empty::<ExternalMessage, _>(),
+    );
+    check_single_source(aggregator, tx, || {
+        vec![
+            NetworkEvent::PeerDisconnected(peer),
+            NetworkEvent::UnableConnectToPeer(peer),
+        ]
+    });
+
+    let (tx, rx) = mpsc::channel(4);
+    let aggregator = EventsAggregator::new(
+        stream::empty::<InternalEvent, _>(),
+        stream::empty::<NetworkEvent, _>(),
+        rx,
+    );
+    check_single_source(aggregator, tx, || {
+        vec![ExternalMessage::Rebroadcast, ExternalMessage::Enable(false)]
+    });
+}
+
+#[derive(Debug, Default)]
+struct RecordingHandler {
+    events: Rc<RefCell<Vec<Event>>>,
+    stopped: Rc<Cell<bool>>,
+}
+
+impl EventHandler for RecordingHandler {
+    fn handle_event(&mut self, event: Event) {
+        self.events.borrow_mut().push(event);
+    }
+
+    fn handle_shutdown(&mut self) {
+        self.stopped.set(true);
+    }
+}
+
+#[test]
+fn test_handler_part_shutdown() {
+    let peer: SocketAddr = "127.0.0.1:19702".parse().unwrap();
+
+    let (internal_tx, internal_rx) = mpsc::channel(4);
+    let (network_tx, network_rx) = mpsc::channel(4);
+    let (_api_tx, api_rx) = mpsc::channel(4);
+
+    let handler = RecordingHandler::default();
+    let events = Rc::clone(&handler.events);
+    let stopped = Rc::clone(&handler.stopped);
+    let handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+
+    let internal_tx = internal_tx.send(InternalEvent::Shutdown).wait().unwrap();
+    let _internal_tx = internal_tx
+        .send(InternalEvent::Timeout(NodeTimeout::PeerExchange))
+        .wait()
+        .unwrap();
+    let _network_tx = network_tx
+        .send(NetworkEvent::PeerDisconnected(peer))
+        .wait()
+        .unwrap();
+
+    // The future resolves even though all senders are still alive.
+    handler_part.run().wait().unwrap();
+
+    assert!(stopped.get());
+    // The queued timeout is handled, the network event is not.
+    let events = events.borrow();
+    assert_eq!(events.len(), 1);
+    match events[0] {
+        Event::Internal(InternalEvent::Timeout(NodeTimeout::PeerExchange)) => {}
+        ref other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[test]
+fn test_node_parts_join() {
+    let listen_address: SocketAddr = "127.0.0.1:19830".parse().unwrap();
+    let (public_key, secret_key) = gen_keypair();
+    let connect = connect_message(listen_address, &public_key, &secret_key);
+    let handshake_params = HandshakeParams::new(
+        public_key,
+        secret_key,
+        SharedConnectList::default(),
+        connect.clone(),
+        ConsensusConfig::DEFAULT_MAX_MESSAGE_LEN,
+    );
+
+    let channel = NodeChannel::new(&EventsPoolCapacity::default());
+    let (network_tx, network_rx) = channel.network_events;
+    let (internal_tx, internal_rx) = channel.internal_events;
+    let (internal_requests_tx, internal_requests_rx) = channel.internal_requests;
+    let network_part = NetworkPart::new(
+        connect,
+        listen_address,
+        NetworkConfiguration::default(),
+        ConsensusConfig::DEFAULT_MAX_MESSAGE_LEN,
+        channel.network_requests,
+        network_tx,
+    );
+    let handler = RecordingHandler::default();
+    let stopped = Rc::clone(&handler.stopped);
+    let parts = NodeParts {
+        handler_part: HandlerPart::new(handler, internal_rx, network_rx, channel.api_requests.1),
+        network_part,
+        internal_part: InternalPart::new(internal_tx, internal_requests_rx),
+        api_part: None,
+    };
+
+    // The shutdown is requested as by the node handler, which then drops the sender.
+    internal_requests_tx
+        .send(InternalRequest::Shutdown)
+        .wait()
+        .unwrap();
+
+    let mut core = Core::new().unwrap();
+    let joined = parts
+        .join(&core.handle(), &handshake_params, core.handle())
+        .timeout(Duration::from_secs(5));
+    core.run(joined).unwrap();
+
+    assert!(stopped.get());
+    // The listener is closed along with the network part.
+    assert!(::std::net::TcpStream::connect(listen_address).is_err());
+}
+
+#[test]
+fn test_handler_part_records_and_replays_events() {
+    let peer: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+    let (public_key, secret_key) = gen_keypair();
+    let dir = TempDir::new("exonum_events_replay").unwrap();
+    let path = dir.path().join("events.log");
+
+    let (internal_tx, internal_rx) = mpsc::channel(8);
+    let (network_tx, network_rx) = mpsc::channel(8);
+    let (_api_tx, api_rx) = mpsc::channel(1);
+
+    let handler = RecordingHandler::default();
+    let dispatched = Rc::clone(&handler.events);
+    let mut handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+    handler_part.recorder = Some(EventRecorder::create(&path).unwrap());
+
+    let network_events = vec![
+        NetworkEvent::PeerConnected(peer, connect_message(peer, &public_key, &secret_key)),
+        NetworkEvent::MessageReceived(peer, public_key, raw_message(BLOCK_REQUEST_MESSAGE_ID, 100)),
+        NetworkEvent::PeerDisconnected(peer),
+        NetworkEvent::UnableConnectToPeer(peer),
+    ];
+    let internal_events = vec![
+        InternalEvent::Timeout(NodeTimeout::Round(Height(3), Round(2))),
+        InternalEvent::Timeout(NodeTimeout::Request(
+            RequestData::Prevotes(Round(1), hash(&[1])),
+            Some(public_key),
+        )),
+        // Flushes are not recorded, but they do not reach `handle_event` either.
+        InternalEvent::Flush,
+        InternalEvent::JumpToRound(Height(3), Round(4)),
+        InternalEvent::Timeout(NodeTimeout::Request(RequestData::Block(Height(5)), None)),
+    ];
+    network_tx
+        .send_all(stream::iter_ok(network_events))
+        .wait()
+        .unwrap();
+    internal_tx
+        .send_all(stream::iter_ok(internal_events))
+        .wait()
+        .unwrap();
+    handler_part.run().wait().unwrap();
+    assert_eq!(dispatched.borrow().len(), 8);
+
+    let replay =
+        ReplayHandlerPart::new(RecordingHandler::default(), EventLog::open(&path).unwrap());
+    let handler = replay.run().wait().unwrap();
+    assert!(handler.stopped.get());
+    assert_eq!(
+        format!("{:?}", handler.events.borrow()),
+        format!("{:?}", dispatched.borrow())
+    );
+}
+
+#[test]
+fn test_handler_part_event_mask() {
+    let peer: SocketAddr = "127.0.0.1:19764".parse().unwrap();
+
+    let (mut internal_tx, internal_rx) = mpsc::channel(8);
+    let (mut network_tx, network_rx) = mpsc::channel(8);
+    let (_api_tx, api_rx) = mpsc::channel(1);
+    network_tx
+        .try_send(NetworkEvent::PeerDisconnected(peer))
+        .unwrap();
+    internal_tx
+        .try_send(InternalEvent::Timeout(NodeTimeout::PeerExchange))
+        .unwrap();
+    internal_tx.try_send(InternalEvent::Flush).unwrap();
+    internal_tx
+        .try_send(InternalEvent::Timeout(NodeTimeout::UpdateApiState))
+        .unwrap();
+    internal_tx.try_send(InternalEvent::Shutdown).unwrap();
+
+    let handler = RecordingHandler::default();
+    let events = Rc::clone(&handler.events);
+    let mut handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+    handler_part.event_mask = EventMask::TIMEOUT;
+    handler_part.run().wait().unwrap();
+
+    let timeouts: Vec<_> = events
+        .borrow()
+        .iter()
+        .map(|event| match *event {
+            Event::Internal(InternalEvent::Timeout(ref timeout)) => timeout.clone(),
+            ref other => panic!("Unexpected event: {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        timeouts,
+        vec![NodeTimeout::PeerExchange, NodeTimeout::UpdateApiState]
+    );
+}
+
+type EventSenders = (
+    mpsc::Sender<InternalEvent>,
+    mpsc::Sender<NetworkEvent>,
+    mpsc::Sender<ExternalMessage>,
+);
+
+fn queue_events_after_shutdown(
+    peer: SocketAddr,
+) -> (EventSenders, HandlerPart<RecordingHandler>, Rc<RefCell<Vec<Event>>>) {
+    let (mut internal_tx, internal_rx) = mpsc::channel(8);
+    let (mut network_tx, network_rx) = mpsc::channel(8);
+    let (mut api_tx, api_rx) = mpsc::channel(8);
+
+    internal_tx.try_send(InternalEvent::Shutdown).unwrap();
+    for _ in 0..3 {
+        internal_tx
+            .try_send(InternalEvent::Timeout(NodeTimeout::PeerExchange))
+            .unwrap();
+        network_tx
+            .try_send(NetworkEvent::PeerDisconnected(peer))
+            .unwrap();
+        api_tx.try_send(ExternalMessage::Rebroadcast).unwrap();
+    }
+
+    let handler = RecordingHandler::default();
+    let events = Rc::clone(&handler.events);
+    let handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+    ((internal_tx, network_tx, api_tx), handler_part, events)
+}
+
+#[test]
+fn test_handler_part_drains_queued_events() {
+    let peer: SocketAddr = "127.0.0.1:19708".parse().unwrap();
+    let (_senders, handler_part, events) = queue_events_after_shutdown(peer);
+
+    // The future resolves even though all senders are still alive.
+    handler_part
+        .run_until_drained(Duration::from_secs(10))
+        .wait()
+        .unwrap();
+
+    let events = events.borrow();
+    assert_eq!(events.len(), 9);
+    let network_events = events
+        .iter()
+        .filter(|event| match **event {
+            Event::Network(_) => true,
+            _ => false,
+        })
+        .count();
+    assert_eq!(network_events, 3);
+}
+
+#[test]
+fn test_handler_part_drain_deadline() {
+    let peer: SocketAddr = "127.0.0.1:19709".parse().unwrap();
+    let (_senders, handler_part, events) = queue_events_after_shutdown(peer);
+
+    handler_part
+        .run_until_drained(Duration::from_secs(0))
+        .wait()
+        .unwrap();
+    assert!(events.borrow().is_empty());
+}
+
+#[test]
+fn test_handler_part_coalesces_rounds() {
+    let (mut internal_tx, internal_rx) = mpsc::channel(8);
+    let (_network_tx, network_rx) = mpsc::channel(1);
+    let (_api_tx, api_rx) = mpsc::channel(1);
+    for &(height, round) in &[(5, 1), (5, 2), (5, 3), (6, 1)] {
+        internal_tx
+            .try_send(InternalEvent::JumpToRound(Height(height), Round(round)))
+            .unwrap();
+    }
+    internal_tx.try_send(InternalEvent::Shutdown).unwrap();
+
+    let handler = RecordingHandler::default();
+    let events = Rc::clone(&handler.events);
+    let handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+    handler_part.run().wait().unwrap();
+
+    let rounds: Vec<_> = events
+        .borrow()
+        .iter()
+        .map(|event| match *event {
+            Event::Internal(InternalEvent::JumpToRound(height, round)) => (height, round),
+            ref other => panic!("Unexpected event: {:?}", other),
+        })
+        .collect();
+    assert_eq!(rounds, vec![(Height(5), Round(3)), (Height(6), Round(1))]);
+}
+
+// Reports of a job, of which only the latest progress matters.
+#[derive(Debug, PartialEq)]
+enum JobReport {
+    Progress(u8),
+    Failed,
+}
+
+impl Coalesce for JobReport {
+    fn coalesce(&mut self, next: Self) -> Option<Self> {
+        if let JobReport::Progress(ref mut progress) = *self {
+            if let JobReport::Progress(next_progress) = next {
+                *progress = next_progress;
+                return None;
+            }
+        }
+        Some(next)
+    }
+}
+
+#[test]
+fn test_coalesced_applies_merge_rule() {
+    let reports = vec![
+        JobReport::Progress(10),
+        JobReport::Progress(20),
+        JobReport::Failed,
+        JobReport::Failed,
+        JobReport::Progress(30),
+        JobReport::Progress(40),
+        JobReport::Progress(50),
+    ];
+    let coalesced = Coalesced::new(stream::iter_ok::<_, ()>(reports))
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(
+        coalesced,
+        vec![
+            JobReport::Progress(20),
+            JobReport::Failed,
+            JobReport::Failed,
+            JobReport::Progress(50),
+        ]
+    );
+}
+
+#[test]
+fn test_handler_part_event_sender() {
+    let peer: SocketAddr = "127.0.0.1:19711".parse().unwrap();
+    let handler = RecordingHandler::default();
+    let events = Rc::clone(&handler.events);
+    let (handler_part, sender) = HandlerPart::with_sender(handler, &EventsPoolCapacity::default());
+
+    sender.send_timeout(NodeTimeout::PeerExchange).unwrap();
+    sender
+        .send_network(NetworkEvent::PeerDisconnected(peer))
+        .unwrap();
+    sender.send_api(ExternalMessage::Rebroadcast).unwrap();
+    sender
+        .send_internal(InternalEvent::JumpToRound(Height(1), Round(2)))
+        .unwrap();
+    drop(sender);
+    handler_part.run().wait().unwrap();
+
+    let events = events.borrow();
+    assert_eq!(events.len(), 4);
+    match (&events[0], &events[1], &events[2], &events[3]) {
+        (
+            Event::Internal(InternalEvent::Timeout(NodeTimeout::PeerExchange)),
+            Event::Network(NetworkEvent::PeerDisconnected(_)),
+            Event::Api(ExternalMessage::Rebroadcast),
+            Event::Internal(InternalEvent::JumpToRound(..)),
+        ) => {}
+        other => panic!("Unexpected events: {:?}", other),
+    }
+}
+
+#[test]
+fn test_event_sender_try_send_api() {
+    let capacity = EventsPoolCapacity {
+        api_requests_capacity: 2,
+        ..EventsPoolCapacity::default()
+    };
+    let (handler_part, mut sender) =
+        HandlerPart::with_sender(RecordingHandler::default(), &capacity);
+    let mut other_sender = sender.clone();
+
+    // Besides the capacity of the channel, each sender has a guaranteed slot.
+    for _ in 0..3 {
+        sender.try_send_api(ExternalMessage::Rebroadcast).unwrap();
+    }
+    let error = sender
+        .try_send_api(ExternalMessage::Enable(false))
+        .unwrap_err();
+    assert!(error.is_full());
+    match error.into_inner() {
+        ExternalMessage::Enable(false) => {}
+        other => panic!("Unexpected message: {:?}", other),
+    }
+    assert_eq!(handler_part.queue_depths.api.get(), 3);
+
+    drop(handler_part);
+    let error = other_sender
+        .try_send_api(ExternalMessage::Rebroadcast)
+        .unwrap_err();
+    assert!(error.is_disconnected());
+}
+
+#[test]
+fn test_handler_part_skips_expired_api_messages() {
+    let handler = RecordingHandler::default();
+    let events = Rc::clone(&handler.events);
+    let (handler_part, sender) = HandlerPart::with_sender(handler, &EventsPoolCapacity::default());
+    let metrics = Arc::clone(&handler_part.metrics);
+
+    let now = Instant::now();
+    sender
+        .send_api(ExternalMessage::Rebroadcast.expiring(now))
+        .unwrap();
+    sender
+        .send_api(ExternalMessage::Enable(false).expiring(now + Duration::from_secs(60)))
+        .unwrap();
+    sender.send_api(ExternalMessage::Enable(true)).unwrap();
+    drop(sender);
+    handler_part.run().wait().unwrap();
+
+    // The message which has not expired is unwrapped.
+    let events = events.borrow();
+    assert_eq!(events.len(), 2);
+    match (&events[0], &events[1]) {
+        (
+            Event::Api(ExternalMessage::Enable(false)),
+            Event::Api(ExternalMessage::Enable(true)),
+        ) => {}
+        other => panic!("Unexpected events: {:?}", other),
+    }
+    assert_eq!(metrics.expired_api_events(), 1);
+    assert_eq!(metrics.api_events(), 2);
+}
+
+#[test]
+fn test_handler_part_queue_depths() {
+    const EVENTS: usize = 5;
+
+    let peer: SocketAddr = "127.0.0.1:19712".parse().unwrap();
+    let handler = RecordingHandler::default();
+    let events = Rc::clone(&handler.events);
+    let (handler_part, sender) = HandlerPart::with_sender(handler, &EventsPoolCapacity::default());
+    let depths = handler_part.queue_depths.clone();
+
+    for _ in 0..EVENTS {
+        sender.send_timeout(NodeTimeout::PeerExchange).unwrap();
+        sender
+            .send_network(NetworkEvent::PeerDisconnected(peer))
+            .unwrap();
+        sender.send_api(ExternalMessage::Rebroadcast).unwrap();
+    }
+    assert_eq!(depths.internal.get(), EVENTS);
+    assert_eq!(depths.network.get(), EVENTS);
+    assert_eq!(depths.api.get(), EVENTS);
+
+    drop(sender);
+    handler_part.run().wait().unwrap();
+    assert_eq!(events.borrow().len(), EVENTS * 3);
+    assert_eq!(depths.internal.get(), 0);
+    assert_eq!(depths.network.get(), 0);
+    assert_eq!(depths.api.get(), 0);
+}
+
+#[test]
+fn test_api_part_counts_forwarded_messages() {
+    const MESSAGES: usize = 3;
+
+    let (api_tx, api_rx) = mpsc::channel(MESSAGES);
+    let (handler_tx, handler_rx) = mpsc::channel(MESSAGES);
+    let depth = QueueDepth::default();
+    let api_part = ApiPart {
+        api_rx,
+        api_tx: CountingSender::new(handler_tx, depth.clone()),
+    };
+
+    // The messages are sent through the plain channel, as by `ApiSender`.
+    for _ in 0..MESSAGES {
+        api_tx
+            .clone()
+            .send(ExternalMessage::Rebroadcast)
+            .wait()
+            .unwrap();
+    }
+    drop(api_tx);
+    api_part.run().wait().unwrap();
+    assert_eq!(depth.get(), MESSAGES);
+    assert_eq!(handler_rx.collect().wait().unwrap().len(), MESSAGES);
+}
+
+// Handles the exchange of peers slowly and the other events right away.
+#[derive(Debug, Default)]
+struct SlowPeerExchangeHandler;
+
+impl EventHandler for SlowPeerExchangeHandler {
+    fn handle_event(&mut self, event: Event) {
+        if let Event::Internal(InternalEvent::Timeout(NodeTimeout::PeerExchange)) = event {
+            thread::sleep(Duration::from_millis(30));
+        }
+    }
+}
+
+#[test]
+fn test_handler_part_handling_latency() {
+    let (handler_part, sender) =
+        HandlerPart::with_sender(SlowPeerExchangeHandler, &EventsPoolCapacity::default());
+    let metrics = Arc::clone(&handler_part.metrics);
+
+    // Every tenth event is slow.
+    for i in 0..20 {
+        let timeout = if i % 10 == 9 {
+            NodeTimeout::PeerExchange
+        } else {
+            NodeTimeout::UpdateApiState
+        };
+        sender.send_timeout(timeout).unwrap();
+    }
+    drop(sender);
+    handler_part.run().wait().unwrap();
+
+    let latency = metrics.handling_latency();
+    assert_eq!(latency.count(), 20);
+    let percentiles = latency.percentiles().unwrap();
+    assert!(
+        percentiles.p50 <= Duration::from_millis(1),
+        "{:?}",
+        percentiles
+    );
+    assert!(
+        percentiles.p95 >= Duration::from_millis(30),
+        "{:?}",
+        percentiles
+    );
+    assert!(percentiles.p99 >= percentiles.p95);
+    assert!(latency.total() < Duration::from_millis(30) * 3);
+}
+
+#[derive(Debug, Default)]
+struct BatchesHandler {
+    batches: Rc<RefCell<Vec<usize>>>,
+}
+
+impl EventHandler for BatchesHandler {
+    fn handle_event(&mut self, _: Event) {
+        self.batches.borrow_mut().push(1);
+    }
+
+    fn handle_events(&mut self, events: Vec<Event>) {
+        self.batches.borrow_mut().push(events.len());
+    }
+}
+
+#[test]
+fn test_handler_part_batches() {
+    let peer: SocketAddr = "127.0.0.1:19703".parse().unwrap();
+
+    let (_, internal_rx) = mpsc::channel(1);
+    let (_, api_rx) = mpsc::channel(1);
+    let (mut network_tx, network_rx) = mpsc::channel(8);
+    for _ in 0..5 {
+        network_tx
+            .try_send(NetworkEvent::PeerDisconnected(peer))
+            .unwrap();
+    }
+    drop(network_tx);
+
+    let handler = BatchesHandler::default();
+    let batches = Rc::clone(&handler.batches);
+    let mut handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+    handler_part.max_batch = 2;
+    handler_part.run().wait().unwrap();
+
+    assert_eq!(*batches.borrow(), vec![2, 2, 1]);
+}
+
+#[test]
+fn test_handler_part_aggregator_status() {
+    let peer: SocketAddr = "127.0.0.1:19712".parse().unwrap();
+
+    let (mut internal_tx, internal_rx) = mpsc::channel(4);
+    let (mut network_tx, network_rx) = mpsc::channel(4);
+    let (_api_tx, api_rx) = mpsc::channel(4);
+    internal_tx
+        .try_send(InternalEvent::Timeout(NodeTimeout::PeerExchange))
+        .unwrap();
+    network_tx
+        .try_send(NetworkEvent::PeerDisconnected(peer))
+        .unwrap();
+
+    let metrics = Arc::new(EventsMetrics::new());
+    let handler = BatchesHandler::default();
+    let handler_part = HandlerPart::with_metrics(
+        handler,
+        internal_rx,
+        network_rx,
+        api_rx,
+        Arc::clone(&metrics),
+    );
+    let mut event_loop = handler_part.run();
+    let mut event_loop = future::lazy(move || {
+        assert!(event_loop.poll().unwrap().is_not_ready());
+        Ok::<_, ()>(event_loop)
+    }).wait()
+        .unwrap();
+
+    let expected = AggregatorStatus {
+        timeout_ready: true,
+        network_ready: true,
+        ..AggregatorStatus::default()
+    };
+    assert_eq!(metrics.aggregator_status(), expected);
+
+    // No events have been received since the previous poll.
+    future::lazy(move || {
+        assert!(event_loop.poll().unwrap().is_not_ready());
+        Ok::<_, ()>(())
+    }).wait()
+        .unwrap();
+    assert_eq!(metrics.aggregator_status(), AggregatorStatus::default());
+}
+
+#[test]
+fn test_handler_part_idle_time() {
+    let peer: SocketAddr = "127.0.0.1:19715".parse().unwrap();
+
+    let (_internal_tx, internal_rx) = mpsc::channel(4);
+    let (mut network_tx, network_rx) = mpsc::channel(16);
+    let (_api_tx, api_rx) = mpsc::channel(4);
+    for _ in 0..10 {
+        network_tx
+            .try_send(NetworkEvent::PeerDisconnected(peer))
+            .unwrap();
+    }
+
+    let metrics = Arc::new(EventsMetrics::new());
+    let handler_part = HandlerPart::with_metrics(
+        BatchesHandler::default(),
+        internal_rx,
+        network_rx,
+        api_rx,
+        Arc::clone(&metrics),
+    );
+    let mut event_loop = handler_part.run();
+
+    // The busy loop dispatches the queued events without waiting.
+    let mut event_loop = future::lazy(move || {
+        assert!(event_loop.poll().unwrap().is_not_ready());
+        Ok::<_, ()>(event_loop)
+    }).wait()
+        .unwrap();
+    assert_eq!(metrics.idle_time(), Duration::from_millis(0));
+
+    // The quiet period is accounted once the next event arrives.
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(metrics.idle_time(), Duration::from_millis(0));
+    network_tx
+        .try_send(NetworkEvent::PeerDisconnected(peer))
+        .unwrap();
+    future::lazy(move || {
+        assert!(event_loop.poll().unwrap().is_not_ready());
+        Ok::<_, ()>(())
+    }).wait()
+        .unwrap();
+    assert!(metrics.idle_time() >= Duration::from_millis(100));
+}
+
+#[derive(Debug, Default)]
+struct FlushHandler {
+    events: Rc<Cell<usize>>,
+    flushes: Rc<Cell<usize>>,
+}
+
+impl EventHandler for FlushHandler {
+    fn handle_event(&mut self, _: Event) {
+        self.events.set(self.events.get() + 1);
+    }
+
+    fn handle_flush(&mut self) {
+        self.flushes.set(self.flushes.get() + 1);
+    }
+}
+
+#[test]
+fn test_handler_part_flush() {
+    let peer: SocketAddr = "127.0.0.1:19713".parse().unwrap();
+
+    let (mut internal_tx, internal_rx) = mpsc::channel(4);
+    let (mut network_tx, network_rx) = mpsc::channel(4);
+    let (_, api_rx) = mpsc::channel(1);
+    internal_tx.try_send(InternalEvent::Flush).unwrap();
+    network_tx
+        .try_send(NetworkEvent::PeerDisconnected(peer))
+        .unwrap();
+    drop((internal_tx, network_tx));
+
+    let handler = FlushHandler::default();
+    let events = Rc::clone(&handler.events);
+    let flushes = Rc::clone(&handler.flushes);
+    let handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+    handler_part.run().wait().unwrap();
+
+    assert_eq!(flushes.get(), 1);
+    assert_eq!(events.get(), 1);
+}
+
+#[derive(Debug, Default)]
+struct TickHandler {
+    ticks: Rc<Cell<usize>>,
+}
+
+impl EventHandler for TickHandler {
+    // Keeps the event loop busy, so that it would miss several ticks.
+    fn handle_event(&mut self, _: Event) {
+        thread::sleep(Duration::from_millis(300));
+    }
+
+    fn handle_tick(&mut self, _: Instant) {
+        self.ticks.set(self.ticks.get() + 1);
+    }
+}
+
+#[test]
+fn test_handler_part_ticks() {
+    let peer: SocketAddr = "127.0.0.1:19718".parse().unwrap();
+
+    let (_internal_tx, internal_rx) = mpsc::channel(1);
+    let (_api_tx, api_rx) = mpsc::channel(1);
+    let (mut network_tx, network_rx) = mpsc::channel(1);
+    network_tx
+        .try_send(NetworkEvent::PeerDisconnected(peer))
+        .unwrap();
+
+    let handler = TickHandler::default();
+    let ticks = Rc::clone(&handler.ticks);
+    let mut handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+    handler_part.tick_interval = Some(Duration::from_millis(10));
+
+    let mut core = Core::new().unwrap();
+    let stop = Timeout::new(Duration::from_millis(400), &core.handle()).unwrap();
+    match core.run(handler_part.run().select2(stop)) {
+        Ok(Either::B(_)) => {}
+        _ => panic!("Event loop has stopped unexpectedly"),
+    }
+
+    // About 30 ticks are missed while the event is being handled, but they are delivered
+    // as a single one, followed by the ticks of the remaining time.
+    assert!(ticks.get() > 0);
+    assert!(ticks.get() < 20, "Ticks have piled up: {}", ticks.get());
+}
+
+#[test]
+fn test_handler_part_ticks_end_with_sources() {
+    let (mut internal_tx, internal_rx) = mpsc::channel(1);
+    let (_, network_rx) = mpsc::channel(1);
+    let (_, api_rx) = mpsc::channel(1);
+    internal_tx
+        .try_send(InternalEvent::Timeout(NodeTimeout::UpdateApiState))
+        .unwrap();
+    drop(internal_tx);
+
+    let mut handler_part =
+        HandlerPart::new(TickHandler::default(), internal_rx, network_rx, api_rx);
+    handler_part.tick_interval = Some(Duration::from_millis(10));
+
+    let mut core = Core::new().unwrap();
+    let stop = Timeout::new(Duration::from_millis(2_000), &core.handle()).unwrap();
+    match core.run(handler_part.run().select2(stop)) {
+        Ok(Either::A(_)) => {}
+        _ => panic!("Event loop has not stopped after its sources were closed"),
+    }
+}
+
+#[test]
+fn test_handler_part_started_signal() {
+    let peer: SocketAddr = "127.0.0.1:19719".parse().unwrap();
+
+    let (internal_tx, internal_rx) = mpsc::channel(1);
+    let (api_tx, api_rx) = mpsc::channel(1);
+    let (network_tx, network_rx) = mpsc::channel(1);
+    let (started_tx, started_rx) = oneshot::channel();
+
+    let event_loop = thread::spawn(move || {
+        let handler = RecordingHandler::default();
+        let events = Rc::clone(&handler.events);
+        let handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+        handler_part
+            .run_with_started_signal(started_tx)
+            .wait()
+            .unwrap();
+        let events = events.borrow();
+        events.len()
+    });
+
+    started_rx.wait().unwrap();
+    network_tx
+        .send(NetworkEvent::PeerDisconnected(peer))
+        .wait()
+        .unwrap();
+    drop((internal_tx, api_tx));
+    assert_eq!(event_loop.join().unwrap(), 1);
+}
+
+#[derive(Debug)]
+struct DelayedHandler {
+    handle: Handle,
+    delays: Vec<u64>,
+    completed: Rc<RefCell<Vec<u64>>>,
+}
+
+impl AsyncEventHandler for DelayedHandler {
+    fn handle_event(&mut self, _: Event) -> HandlerFuture {
+        let delay = self.delays.remove(0);
+        let completed = Rc::clone(&self.completed);
+        let timeout = Timeout::new(Duration::from_millis(delay), &self.handle).unwrap();
+        Box::new(timeout.map_err(HandlerError::new).map(move |_| {
+            completed.borrow_mut().push(delay);
+        }))
+    }
+}
+
+#[test]
+fn test_handler_part_awaits_async_handler() {
+    let peer: SocketAddr = "127.0.0.1:19705".parse().unwrap();
+
+    let (_, internal_rx) = mpsc::channel(1);
+    let (_, api_rx) = mpsc::channel(1);
+    let (mut network_tx, network_rx) = mpsc::channel(8);
+    for _ in 0..3 {
+        network_tx
+            .try_send(NetworkEvent::PeerDisconnected(peer))
+            .unwrap();
+    }
+    drop(network_tx);
+
+    let mut core = Core::new().unwrap();
+    // The first event is handled the longest, but events are still completed in order.
+    let handler = DelayedHandler {
+        handle: core.handle(),
+        delays: vec![100, 50, 10],
+        completed: Rc::default(),
+    };
+    let completed = Rc::clone(&handler.completed);
+    let handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+    core.run(handler_part.run()).unwrap();
+
+    assert_eq!(*completed.borrow(), vec![100, 50, 10]);
+}
+
+#[derive(Debug, Default)]
+struct FailingHandler {
+    handled: Rc<Cell<usize>>,
+}
+
+impl EventHandler for FailingHandler {
+    fn handle_event(&mut self, event: Event) {
+        self.try_handle_event(event).unwrap();
+    }
+
+    fn try_handle_event(&mut self, event: Event) -> Result<(), HandlerError> {
+        self.handled.set(self.handled.get() + 1);
+        match event {
+            Event::Internal(InternalEvent::Timeout(NodeTimeout::Status(_))) => {
+                Err(HandlerError::new("Unexpected status timeout"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_handler_part_stops_on_error() {
+    let (mut internal_tx, internal_rx) = mpsc::channel(4);
+    let (_network_tx, network_rx) = mpsc::channel(1);
+    let (_api_tx, api_rx) = mpsc::channel(1);
+    for timeout in vec![
+        NodeTimeout::PeerExchange,
+        NodeTimeout::Status(Height(1)),
+        NodeTimeout::UpdateApiState,
+    ] {
+        internal_tx.try_send(InternalEvent::Timeout(timeout)).unwrap();
+    }
+
+    let handler = FailingHandler::default();
+    let handled = Rc::clone(&handler.handled);
+    let handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx);
+    // The future fails even though the senders are still alive and an event is pending.
+    let error = handler_part.run().wait().unwrap_err();
+    assert_eq!(error, HandlerError::new("Unexpected status timeout"));
+    assert_eq!(handled.get(), 2);
+}
+
+#[derive(Debug, Default)]
+struct PanickingHandler {
+    handled: Rc<Cell<usize>>,
+}
+
+impl EventHandler for PanickingHandler {
+    fn handle_event(&mut self, event: Event) {
+        if let Event::Internal(InternalEvent::Timeout(NodeTimeout::Status(_))) = event {
+            panic!("Unexpected status timeout");
+        }
+        self.handled.set(self.handled.get() + 1);
+    }
+}
+
+#[test]
+fn test_handler_part_recovers_from_panics() {
+    let (mut internal_tx, internal_rx) = mpsc::channel(4);
+    let (_, network_rx) = mpsc::channel(1);
+    let (_, api_rx) = mpsc::channel(1);
+    for timeout in vec![
+        NodeTimeout::PeerExchange,
+        NodeTimeout::Status(Height(1)),
+        NodeTimeout::UpdateApiState,
+    ] {
+        internal_tx.try_send(InternalEvent::Timeout(timeout)).unwrap();
+    }
+    drop(internal_tx);
+
+    let handler = PanickingHandler::default();
+    let handled = Rc::clone(&handler.handled);
+    let metrics = Arc::new(EventsMetrics::new());
+    let mut handler_part = HandlerPart::with_metrics(
+        handler,
+        internal_rx,
+        network_rx,
+        api_rx,
+        Arc::clone(&metrics),
+    );
+    handler_part.recover_panics = true;
+    handler_part.run().wait().unwrap();
+
+    // The events following the panicked one are handled.
+    assert_eq!(handled.get(), 2);
+    assert_eq!(metrics.handler_panics(), 1);
+}
+
+#[test]
+fn test_timeout_requests_order() {
+    let now = SystemTime::now();
+    let early = TimeoutRequest(now, NodeTimeout::PeerExchange);
+    let middle = TimeoutRequest(now + Duration::from_secs(1), NodeTimeout::Status(Height(1)));
+    let late = TimeoutRequest(now + Duration::from_secs(2), NodeTimeout::UpdateApiState);
+
+    // Natural order is ascending by time.
+    let mut requests = vec![late.clone(), early.clone(), middle.clone()];
+    requests.sort();
+    assert_eq!(requests, vec![early.clone(), middle.clone(), late.clone()]);
+
+    // The heap yields the earliest request first.
+    let mut heap: BinaryHeap<_> = vec![middle.clone(), late.clone(), early.clone()]
+        .into_iter()
+        .map(EarliestFirst)
+        .collect();
+    let popped: Vec<_> = (0..3).map(|_| heap.pop().unwrap().0).collect();
+    assert_eq!(popped, vec![early, middle, late]);
+}
+
+#[test]
+fn test_timeout_requests_tie_break() {
+    let now = SystemTime::now();
+    // Timeouts due at the same time in the order of firing.
+    let expected: Vec<_> = vec![
+        NodeTimeout::Round(Height(2), Round(1)),
+        NodeTimeout::Propose(Height(2), Round(1)),
+        NodeTimeout::Status(Height(2)),
+        NodeTimeout::Request(RequestData::Block(Height(2)), None),
+        NodeTimeout::UpdateApiState,
+        NodeTimeout::PeerExchange,
+    ].into_iter()
+        .map(|timeout| TimeoutRequest(now, timeout))
+        .collect();
+
+    let mut heap: BinaryHeap<_> = expected.iter().rev().cloned().map(EarliestFirst).collect();
+    // A round timeout due later fires after all of them.
+    let later = TimeoutRequest(
+        now + Duration::from_millis(1),
+        NodeTimeout::Round(Height(2), Round(2)),
+    );
+    heap.push(EarliestFirst(later.clone()));
+
+    let popped: Vec<_> = (0..7).map(|_| heap.pop().unwrap().0).collect();
+    assert_eq!(&popped[..6], &expected[..]);
+    assert_eq!(popped[6], later);
+}
+
+#[test]
+fn test_events_aggregator_routes_topology_events() {
+    let address: SocketAddr = "127.0.0.1:19704".parse().unwrap();
+    let (public_key, secret_key) = gen_keypair();
+    let connect = connect_message(address, &public_key, &secret_key);
+
+    let internal = stream::empty::<InternalEvent, ()>();
+    let network = stream::iter_ok::<_, ()>(vec![
+        NetworkEvent::PeerConnected(address, connect),
+        NetworkEvent::PeerDisconnected(address),
+    ]);
+    let api = stream::empty::<ExternalMessage, ()>();
+
+    let events = EventsAggregator::new(internal, network, api)
+        .collect()
+        .wait()
+        .unwrap();
+
+    assert_eq!(events.len(), 2);
+    match events[0] {
+        Event::Network(NetworkEvent::PeerConnected(addr, ref message)) => {
+            assert_eq!(addr, address);
+            assert_eq!(message.pub_key(), &public_key);
+        }
+        ref other => panic!("Unexpected event: {:?}", other),
+    }
+    match events[1] {
+        Event::Network(NetworkEvent::PeerDisconnected(addr)) => assert_eq!(addr, address),
+        ref other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[test]
+fn test_event_summary_omits_payload() {
+    let address: SocketAddr = "127.0.0.1:19705".parse().unwrap();
+    let (public_key, _) = gen_keypair();
+    let message = raw_message(BLOCK_REQUEST_MESSAGE_ID, 1000);
+    let payload = format!("{:?}", message.body());
+
+    let event = Event::Network(NetworkEvent::MessageReceived(address, public_key, message));
+    let summary = event.summary();
+    assert!(
+        summary.starts_with("network MessageReceived"),
+        "{}",
+        summary
+    );
+    assert!(summary.contains(&format!("type={}", BLOCK_REQUEST_MESSAGE_ID)));
+    assert!(summary.contains("from=127.0.0.1:19705"));
+    assert!(summary.contains(&format!("key={}", public_key)));
+    assert!(!summary.contains(&payload));
+    assert!(summary.len() < 200, "{}", summary);
+
+    let timeout: Event = NodeTimeout::Round(Height(5), Round(2)).into();
+    assert_eq!(timeout.summary(), "timeout Round height=5 round=2");
+    let shutdown: Event = ExternalMessage::Shutdown.into();
+    assert_eq!(shutdown.summary(), "api Shutdown");
+}
+
+// Accepts a single connection and forwards it to `target` after the delay.
+fn spawn_delaying_proxy(address: SocketAddr, target: SocketAddr, delay: Duration) {
+    use std::{
+        io, net::{TcpListener, TcpStream},
+    };
+
+    let listener = TcpListener::bind(address).unwrap();
+    thread::spawn(move || {
+        let (incoming, _) = listener.accept().unwrap();
+        thread::sleep(delay);
+        let outgoing = TcpStream::connect(target).unwrap();
+        let mut incoming_read = incoming.try_clone().unwrap();
+        let mut outgoing_write = outgoing.try_clone().unwrap();
+        thread::spawn(move || io::copy(&mut incoming_read, &mut outgoing_write));
+        let (mut outgoing_read, mut incoming_write) = (outgoing, incoming);
+        let _ = io::copy(&mut outgoing_read, &mut incoming_write);
+    });
+}
+
+#[test]
+fn test_network_connect_latency() {
+    let first = "127.0.0.1:19811".parse().unwrap();
+    let second = "127.0.0.1:19812".parse().unwrap();
+    let proxy = "127.0.0.1:19813".parse().unwrap();
+    let delay = Duration::from_millis(300);
+    spawn_delaying_proxy(proxy, second, delay);
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    // The first node dials the second one through the proxy.
+    connect_list.add(ConnectInfo {
+        address: proxy,
+        public_key: t2.public_key,
+    });
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let metrics = Arc::clone(&e1.network_metrics);
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let _e2 = t2.spawn(TestEvents::with_addr(second), connect_list);
+
+    e1.connect_with(proxy, t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+
+    let latency = metrics.connect_latency();
+    assert_eq!(latency.count(), 1);
+    assert!(latency.max() >= delay);
+    let faster: usize = latency
+        .buckets()
+        .iter()
+        .filter(|&&(bound, _)| bound.map_or(false, |bound| bound < delay))
+        .map(|&(_, count)| count)
+        .sum();
+    assert_eq!(faster, 0);
+}
+
+#[test]
+fn test_network_incoming_connections_limit() {
+    use std::{
+        io::{self, Read}, net::TcpStream,
+    };
+
+    let first = "127.0.0.1:19796".parse().unwrap();
+    let second = "127.0.0.1:19797".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
     connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let mut e2 = TestEvents::with_addr(second);
+    e2.network_config.max_incoming_connections = 2;
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    // Unknown peers which never complete the handshake take all the slots.
+    let mut unknown: Vec<_> = (0..2)
+        .map(|_| TcpStream::connect(second).unwrap())
+        .collect();
+    thread::sleep(Duration::from_millis(200));
+
+    // The oldest unknown connection is closed to make room for the known peer.
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+
+    let oldest = &mut unknown[0];
+    oldest
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert!(oldest.read(&mut [0; 1]).map(|len| len == 0).unwrap_or(true));
+
+    // The remaining unknown connection is kept until another one arrives.
+    let newest = &mut unknown[1];
+    newest
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let error = newest.read(&mut [0; 1]).unwrap_err();
+    assert!(error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn test_network_disconnect_peer_with_ban() {
+    let first = "127.0.0.1:19798".parse().unwrap();
+    let second = "127.0.0.1:19799".parse().unwrap();
 
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
     let connect_list = SharedConnectList::from_connect_list(connect_list);
-    let mut node = t1.spawn(events, connect_list.clone());
 
-    let connectors: Vec<_> = connection_params
+    let e1 = TestEvents::with_addr(first);
+    let e2 = TestEvents::with_addr(second);
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+
+    e1.disconnect_peer(t2.public_key, Some(Duration::from_secs(1)));
+    assert_eq!(e1.wait_for_disconnect(), second);
+    // The peer observes the closed connection.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !e2.connections_activity().is_empty() {
+        assert!(Instant::now() < deadline, "connection has not been closed");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // Reconnection is refused during the ban.
+    e1.connect_with(second, t1.connect.clone());
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::UnableConnectToPeer(address)) => assert_eq!(address, second),
+        other => panic!("Unexpected event: {:?}", other),
+    }
+
+    thread::sleep(Duration::from_secs(1));
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+}
+
+#[test]
+fn test_network_reconcile_peers() {
+    let addresses: Vec<SocketAddr> = (19765..19769)
+        .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+        .collect();
+
+    let mut connect_list = ConnectList::default();
+    let mut params: Vec<_> = addresses
+        .iter()
+        .map(|&address| ConnectionParams::from_address(address))
+        .collect();
+    for params in &params {
+        connect_list.add(params.connect_info);
+    }
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+    let mut nodes: Vec<_> = params
         .iter_mut()
-        .map(|params| {
-            let events = TestEvents::with_addr(params.address);
-            params.spawn(events, connect_list.clone())
+        .zip(&addresses)
+        .map(|(params, &address)| {
+            params.spawn(TestEvents::with_addr(address), connect_list.clone())
         })
         .collect();
+    let keys: Vec<_> = params.iter().map(|params| params.public_key).collect();
+
+    let reconcile = |node: &TestHandler, peers: &[usize]| {
+        let peers = peers.iter().map(|&i| keys[i]).collect();
+        node.request(NetworkRequest::ReconcilePeers(peers));
+    };
+    let connected = |node: &TestHandler| {
+        let mut connected: Vec<_> = node.connections_activity().keys().cloned().collect();
+        connected.sort();
+        connected
+    };
+
+    reconcile(&nodes[0], &[1, 2]);
+    for _ in 0..2 {
+        match nodes[0].wait_for_event() {
+            Ok(NetworkEvent::PeerConnected(..)) => {}
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+    assert_eq!(connected(&nodes[0]), vec![addresses[1], addresses[2]]);
 
-    connectors[0].connect_with(main, connection_params[0].connect.clone());
-    assert_eq!(
-        node.wait_for_connect(),
-        connection_params[0].connect.clone()
-    );
-    connectors[1].connect_with(main, connection_params[1].connect.clone());
-    assert_eq!(
-        node.wait_for_connect(),
-        connection_params[1].connect.clone()
-    );
-    connectors[2].connect_with(main, connection_params[2].connect.clone());
+    // The message queued before the reconciliation is delivered to the removed peer.
+    let message = raw_message(11, 1000);
+    nodes[0].send_to(addresses[1], message.clone());
+    reconcile(&nodes[0], &[2, 3]);
+    assert_eq!(nodes[1].wait_for_connect(), params[0].connect.clone());
+    assert_eq!(nodes[1].wait_for_message(), message);
+
+    let mut disconnected = None;
+    let mut connected_to = None;
+    for _ in 0..2 {
+        match nodes[0].wait_for_event() {
+            Ok(NetworkEvent::PeerDisconnected(address)) => disconnected = Some(address),
+            Ok(NetworkEvent::PeerConnected(address, _)) => connected_to = Some(address),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+    assert_eq!(disconnected, Some(addresses[1]));
+    assert_eq!(connected_to, Some(addresses[3]));
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while connected(&nodes[0]) != vec![addresses[2], addresses[3]] {
+        assert!(Instant::now() < deadline, "connections have not been reconciled");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_network_send_confirmation() {
+    let first = "127.0.0.1:19800".parse().unwrap();
+    let second = "127.0.0.1:19801".parse().unwrap();
+    let unknown = "127.0.0.1:19802".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let e2 = TestEvents::with_addr(second);
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    let message = raw_message(BLOCK_REQUEST_MESSAGE_ID, 100);
     assert_eq!(
-        node.wait_for_connect(),
-        connection_params[2].connect.clone()
+        e1.send_confirmed(unknown, message.clone()),
+        SendResult::PeerUnknown
     );
+
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+    assert_eq!(e1.send_confirmed(second, message.clone()), SendResult::Queued);
+    assert_eq!(e2.wait_for_message(), message);
 }
 
 #[test]
-fn test_send_first_not_connect() {
-    let main = "127.0.0.1:19500".parse().unwrap();
-    let other = "127.0.0.1:19501".parse().unwrap();
+fn test_network_broadcast() {
+    let addresses: Vec<SocketAddr> = (19803..19807)
+        .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+        .collect();
 
     let mut connect_list = ConnectList::default();
-    let mut t1 = ConnectionParams::from_address(main);
+    let mut params: Vec<_> = addresses
+        .iter()
+        .map(|&address| ConnectionParams::from_address(address))
+        .collect();
+    for t in &params {
+        connect_list.add(t.connect_info);
+    }
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let mut nodes: Vec<_> = params
+        .iter_mut()
+        .zip(&addresses)
+        .map(|(t, &address)| t.spawn(TestEvents::with_addr(address), connect_list.clone()))
+        .collect();
+
+    // The first node is connected to all the others.
+    let hub_connect = params[0].connect.clone();
+    for i in 1..nodes.len() {
+        nodes[0].connect_with(addresses[i], hub_connect.clone());
+        assert_eq!(nodes[i].wait_for_connect(), hub_connect);
+        assert_eq!(nodes[0].wait_for_connect(), params[i].connect);
+    }
+
+    let excluded = params[3].public_key;
+    let first = raw_message(11, 1000);
+    nodes[0].broadcast(first.clone(), vec![excluded].into_iter().collect());
+    let second = raw_message(11, 1001);
+    nodes[0].broadcast(second.clone(), HashSet::new());
+
+    for node in &mut nodes[1..3] {
+        assert_eq!(node.wait_for_message(), first);
+        assert_eq!(node.wait_for_message(), second);
+    }
+    // The excluded peer receives only the second message.
+    assert_eq!(nodes[3].wait_for_message(), second);
+}
+
+#[test]
+fn test_network_peer_traffic() {
+    let first = "127.0.0.1:19807".parse().unwrap();
+    let second = "127.0.0.1:19808".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
     connect_list.add(t1.connect_info);
-    let mut t2 = ConnectionParams::from_address(other);
+    let mut t2 = ConnectionParams::from_address(second);
     connect_list.add(t2.connect_info);
     let connect_list = SharedConnectList::from_connect_list(connect_list);
 
-    let node = TestEvents::with_addr(main);
-    let other_node = TestEvents::with_addr(other);
+    let e1 = TestEvents::with_addr(first);
+    let e2 = TestEvents::with_addr(second);
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
 
-    let mut node = t1.spawn(node, connect_list.clone());
-    let other_node = t2.spawn(other_node, connect_list.clone());
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
 
-    let message = raw_message(11, 1000);
-    other_node.send_to(main, message.clone()); // should connect before send message
+    let messages = [
+        raw_message(11, 1000),
+        raw_message(11, 500),
+        raw_message(11, 250),
+    ];
+    for message in &messages {
+        e1.send_to(second, message.clone());
+        assert_eq!(e2.wait_for_message(), *message);
+    }
+    let total_len: u64 = messages.iter().map(|message| message.len() as u64).sum();
+
+    // The `Connect` messages are exchanged during the handshake and are not accounted.
+    let sent = e1.peer_traffic()[&t2.public_key];
+    assert_eq!(sent.messages_sent, 3);
+    assert_eq!(sent.bytes_sent, total_len);
+    assert_eq!(sent.messages_received, 0);
+    let received = e2.peer_traffic()[&t1.public_key];
+    assert_eq!(received.messages_received, 3);
+    assert_eq!(received.bytes_received, total_len);
+    assert_eq!(received.bytes_sent, 0);
+}
 
-    assert_eq!(node.wait_for_connect(), t2.connect);
-    assert_eq!(node.wait_for_message(), message);
+#[test]
+fn test_network_pause_and_resume() {
+    let first = "127.0.0.1:19809".parse().unwrap();
+    let second = "127.0.0.1:19810".parse().unwrap();
+
+    let mut connect_list = ConnectList::default();
+    let mut t1 = ConnectionParams::from_address(first);
+    connect_list.add(t1.connect_info);
+    let mut t2 = ConnectionParams::from_address(second);
+    connect_list.add(t2.connect_info);
+    let connect_list = SharedConnectList::from_connect_list(connect_list);
+
+    let e1 = TestEvents::with_addr(first);
+    let e2 = TestEvents::with_addr(second);
+    let mut e1 = t1.spawn(e1, connect_list.clone());
+    let mut e2 = t2.spawn(e2, connect_list);
+
+    e1.connect_with(second, t1.connect.clone());
+    assert_eq!(e2.wait_for_connect(), t1.connect.clone());
+    assert_eq!(e1.wait_for_connect(), t2.connect.clone());
+
+    e2.request(NetworkRequest::Pause);
+    // Let the request be processed before the messages arrive.
+    thread::sleep(Duration::from_millis(100));
+    let messages = vec![raw_message(11, 1000), raw_message(11, 1001)];
+    for message in &messages {
+        e1.send_to(second, message.clone());
+    }
+    assert!(e2.wait_for_event_within(Duration::from_millis(500)).is_err());
+    // The connection is kept open.
+    assert!(!e2.connections_activity().is_empty());
+
+    e2.request(NetworkRequest::Resume);
+    for message in &messages {
+        assert_eq!(e2.wait_for_message(), *message);
+    }
 }
@@ -0,0 +1,198 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hierarchical timer wheel keeping a large number of timeouts at a coarse resolution.
+//!
+//! Time is divided into ticks of the given resolution counted from the origin of the wheel.
+//! The wheel consists of `LEVELS` levels of `SLOTS` slots each; a slot of the level `l`
+//! spans `SLOTS^l` ticks. An entry is put into the level at which its tick and the current
+//! one differ, and is moved to the lower levels as the wheel turns, so both insertion and
+//! expiration take constant amortized time. Entries fire at the first tick not earlier than
+//! their deadlines, i.e., they are never early and at most one tick late.
+
+use std::{
+    cmp, mem,
+    time::{Duration, SystemTime},
+};
+
+/// Number of bits of the tick selecting a slot within a level.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Debug)]
+pub struct TimerWheel<T> {
+    origin: SystemTime,
+    resolution: u64,
+    // Number of ticks elapsed since the origin.
+    current: u64,
+    levels: Vec<Vec<Vec<(u64, T)>>>,
+    // Entries too far in the future for the levels; they are placed once the top level
+    // turns over.
+    overflow: Vec<(u64, T)>,
+    // Entries which were due at the moment of insertion.
+    expired: Vec<T>,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    /// Creates an empty wheel whose ticks are counted from `origin`.
+    pub fn new(origin: SystemTime, resolution: Duration) -> Self {
+        let resolution = cmp::max(as_nanos(resolution), 1);
+        let levels = (0..LEVELS)
+            .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+            .collect();
+        TimerWheel {
+            origin,
+            resolution,
+            current: 0,
+            levels,
+            overflow: Vec::new(),
+            expired: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Returns `true` if the wheel has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the moment of the next tick of the wheel.
+    pub fn next_tick(&self) -> SystemTime {
+        let nanos = (self.current + 1) * self.resolution;
+        self.origin + Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32)
+    }
+
+    /// Adds the entry which should fire once `deadline` is reached.
+    pub fn insert(&mut self, deadline: SystemTime, entry: T) {
+        self.len += 1;
+        let elapsed = deadline
+            .duration_since(self.origin)
+            .map(as_nanos)
+            .unwrap_or(0);
+        // Rounding up, so that the entry never fires before its deadline.
+        let tick = (elapsed + self.resolution - 1) / self.resolution;
+        if tick <= self.current {
+            self.expired.push(entry);
+        } else {
+            self.place(tick, entry);
+        }
+    }
+
+    /// Turns the wheel up to the moment `now` and returns the entries due by then.
+    pub fn advance(&mut self, now: SystemTime) -> Vec<T> {
+        let elapsed = now.duration_since(self.origin).map(as_nanos).unwrap_or(0);
+        let target = elapsed / self.resolution;
+
+        let mut fired = mem::replace(&mut self.expired, Vec::new());
+        while self.current < target {
+            if self.len == fired.len() {
+                // No entries are left in the levels, so there is nothing to cascade.
+                self.current = target;
+                break;
+            }
+            self.current += 1;
+            self.cascade();
+            let slot = (self.current as usize) & (SLOTS - 1);
+            let due = mem::replace(&mut self.levels[0][slot], Vec::new());
+            fired.extend(due.into_iter().map(|(_, entry)| entry));
+        }
+        self.len -= fired.len();
+        fired
+    }
+
+    fn place(&mut self, tick: u64, entry: T) {
+        let differing = 64 - (tick ^ self.current).leading_zeros();
+        let level = (differing.saturating_sub(1) / SLOT_BITS) as usize;
+        if level >= LEVELS {
+            self.overflow.push((tick, entry));
+        } else {
+            let slot = ((tick >> (level as u32 * SLOT_BITS)) as usize) & (SLOTS - 1);
+            self.levels[level][slot].push((tick, entry));
+        }
+    }
+
+    // Moves the entries of the upper levels whose slots start at the current tick
+    // to the lower levels.
+    fn cascade(&mut self) {
+        let top_span = 1_u64 << (LEVELS as u32 * SLOT_BITS);
+        if self.current % top_span == 0 {
+            for (tick, entry) in mem::replace(&mut self.overflow, Vec::new()) {
+                self.place(tick, entry);
+            }
+        }
+        for level in (1..LEVELS).rev() {
+            let shift = level as u32 * SLOT_BITS;
+            if self.current & ((1 << shift) - 1) != 0 {
+                continue;
+            }
+            let slot = ((self.current >> shift) as usize) & (SLOTS - 1);
+            for (tick, entry) in mem::replace(&mut self.levels[level][slot], Vec::new()) {
+                self.place(tick, entry);
+            }
+        }
+    }
+}
+
+fn as_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * NANOS_PER_SEC + u64::from(duration.subsec_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use super::*;
+
+    #[test]
+    fn timer_wheel_fires_within_resolution() {
+        let origin = SystemTime::now();
+        let resolution = Duration::from_millis(10);
+        let mut wheel = TimerWheel::new(origin, resolution);
+        let mut rng = XorShiftRng::from_seed([7; 16]);
+
+        // Deadlines span all the levels of the wheel, including the ones in the past.
+        let mut deadlines: Vec<_> = (0..2_000)
+            .map(|_| Duration::from_millis(rng.gen_range(0, 3_000_000)))
+            .collect();
+        deadlines.push(Duration::from_millis(0));
+        deadlines.push(resolution);
+        wheel.advance(origin + resolution);
+        for (i, deadline) in deadlines.iter().enumerate() {
+            wheel.insert(origin + *deadline, i);
+        }
+        assert!(!wheel.is_empty());
+
+        let mut now = resolution;
+        let mut fired = 0;
+        while !wheel.is_empty() {
+            for i in wheel.advance(origin + now) {
+                let deadline = cmp::max(deadlines[i], resolution);
+                assert!(deadline <= now, "{:?} fired early at {:?}", deadline, now);
+                assert!(
+                    now - deadline < resolution * 2,
+                    "{:?} fired late at {:?}",
+                    deadline,
+                    now
+                );
+                fired += 1;
+            }
+            now += Duration::from_millis(rng.gen_range(1, 11));
+        }
+        assert_eq!(fired, deadlines.len());
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transport-level encryption of the connections with peers, see `TlsConfig`.
+//!
+//! `TlsTransport` secures the connections of another transport with TLS before the Noise
+//! handshake of the node, so both are performed for each connection. Peers authenticate
+//! each other mutually by the certificates issued by the CAs from the configuration.
+//!
+//! All the nodes of a network should either use TLS or not: a node with TLS enabled rejects
+//! the peers starting a plaintext handshake, and a node without TLS rejects the peers
+//! starting a TLS one, see `is_tls_record`.
+
+use failure;
+use futures::{Async, Future, Poll, Stream};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    client, rustls::{
+        internal::pemfile, AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey,
+        RootCertStore, ServerConfig,
+    },
+    server, webpki::{DNSName, DNSNameRef}, Accept, Connect, TlsAcceptor, TlsConnector,
+};
+
+use std::{
+    fmt, fs::File, io::{self, BufReader, Read, Write}, mem, net::SocketAddr, path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use events::{
+    network::NetworkConfiguration, transport::{DialFuture, ListenStream, TcpTransport, Transport},
+};
+
+/// Content type of the TLS record carrying a handshake message.
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+/// Major version of the TLS record; the same for all the versions of TLS.
+const TLS_MAJOR_VERSION: u8 = 0x03;
+
+/// Returns `true` if the first bytes received from the peer start a TLS record.
+///
+/// The length of the first message of the Noise handshake never starts with such bytes.
+pub fn is_tls_record(header: &[u8]) -> bool {
+    header.len() >= 2 && header[0] == TLS_HANDSHAKE_RECORD && header[1] == TLS_MAJOR_VERSION
+}
+
+/// TLS configuration of the node.
+///
+/// Unlike `NetworkConfiguration`, it cannot be changed while the node is running.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// Path to the PEM-encoded certificate chain of the node.
+    pub cert: PathBuf,
+    /// Path to the PEM-encoded PKCS#8 or RSA private key of the certificate.
+    pub key: PathBuf,
+    /// Path to the PEM-encoded certificates of the CAs issuing the certificates of the peers.
+    pub ca: PathBuf,
+    /// DNS name the certificates of the peers are issued for. Peers are dialed by their
+    /// IP addresses, so the name cannot be derived from the address.
+    pub server_name: String,
+}
+
+/// Transport securing the connections of the inner transport with TLS.
+#[derive(Clone)]
+pub struct TlsTransport<T = TcpTransport> {
+    inner: T,
+    server_config: Arc<ServerConfig>,
+    client_config: Arc<ClientConfig>,
+    server_name: DNSName,
+}
+
+impl<T> TlsTransport<T> {
+    /// Loads the certificates and the key from the configuration.
+    pub fn new(inner: T, config: &TlsConfig) -> Result<Self, failure::Error> {
+        let certs = load_certs(&config.cert)?;
+        let key = load_key(&config.key)?;
+        let mut roots = RootCertStore::empty();
+        for ca in load_certs(&config.ca)? {
+            roots.add(&ca).map_err(|e| {
+                format_err!("Invalid CA certificate in {}: {:?}", config.ca.display(), e)
+            })?;
+        }
+
+        let mut server_config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots.clone()));
+        server_config
+            .set_single_cert(certs.clone(), key.clone())
+            .map_err(|e| format_err!("Invalid certificate of the node: {}", e))?;
+        let mut client_config = ClientConfig::new();
+        client_config.root_store = roots;
+        client_config.set_single_client_cert(certs, key);
+
+        let server_name = DNSNameRef::try_from_ascii_str(&config.server_name)
+            .map_err(|_| format_err!("Invalid TLS server name: {:?}", config.server_name))?
+            .to_owned();
+        Ok(TlsTransport {
+            inner,
+            server_config: Arc::new(server_config),
+            client_config: Arc::new(client_config),
+            server_name,
+        })
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for TlsTransport<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsTransport")
+            .field("inner", &self.inner)
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl<T: Transport> Transport for TlsTransport<T> {
+    type Stream = TlsStream<T::Stream>;
+
+    fn listen(&self, address: &SocketAddr) -> io::Result<ListenStream<Self::Stream>> {
+        let server_config = Arc::clone(&self.server_config);
+        let incoming = self.inner.listen(address)?.map(move |(stream, address)| {
+            let state = State::Accepting(stream, Arc::clone(&server_config));
+            (TlsStream { state }, address)
+        });
+        Ok(Box::new(incoming))
+    }
+
+    fn dial(&self, address: &SocketAddr) -> DialFuture<Self::Stream> {
+        let client_config = Arc::clone(&self.client_config);
+        let server_name = self.server_name.clone();
+        let connection = self.inner.dial(address).map(move |stream| {
+            let state = State::Connecting(stream, client_config, server_name);
+            TlsStream { state }
+        });
+        Box::new(connection)
+    }
+
+    fn configure(
+        &self,
+        stream: &Self::Stream,
+        network_config: &NetworkConfiguration,
+    ) -> io::Result<()> {
+        // The connection is configured before any data is exchanged with the peer.
+        match stream.state {
+            State::Accepting(ref inner, _) | State::Connecting(ref inner, ..) => {
+                self.inner.configure(inner, network_config)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Connection secured with TLS. The TLS handshake is performed on the first read or write.
+pub struct TlsStream<S> {
+    state: State<S>,
+}
+
+enum State<S> {
+    Accepting(S, Arc<ServerConfig>),
+    Connecting(S, Arc<ClientConfig>, DNSName),
+    ServerHandshake(Accept<Prefixed<S>>),
+    ClientHandshake(Connect<Prefixed<S>>),
+    Server(server::TlsStream<Prefixed<S>>),
+    Client(client::TlsStream<Prefixed<S>>),
+    Failed,
+}
+
+impl<S: AsyncRead + AsyncWrite> TlsStream<S> {
+    /// Advances the TLS handshake, resolving once it is completed.
+    fn poll_handshake(&mut self) -> Poll<(), io::Error> {
+        loop {
+            self.state = match mem::replace(&mut self.state, State::Failed) {
+                State::Accepting(mut stream, config) => {
+                    // The first byte is checked by itself, so that the peers using plaintext
+                    // are rejected explicitly rather than by a garbled TLS handshake.
+                    let mut first = [0_u8; 1];
+                    match stream.read(&mut first) {
+                        Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                        Ok(_) if first[0] != TLS_HANDSHAKE_RECORD => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "peer does not use TLS, which is enabled for this node",
+                            ))
+                        }
+                        Ok(_) => {
+                            let stream = Prefixed::new(stream, Some(first[0]));
+                            State::ServerHandshake(TlsAcceptor::from(config).accept(stream))
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            self.state = State::Accepting(stream, config);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                State::Connecting(stream, config, server_name) => {
+                    let stream = Prefixed::new(stream, None);
+                    State::ClientHandshake(
+                        TlsConnector::from(config).connect(server_name.as_ref(), stream),
+                    )
+                }
+                State::ServerHandshake(mut handshake) => match handshake.poll()? {
+                    Async::Ready(stream) => State::Server(stream),
+                    Async::NotReady => {
+                        self.state = State::ServerHandshake(handshake);
+                        return Ok(Async::NotReady);
+                    }
+                },
+                State::ClientHandshake(mut handshake) => match handshake.poll()? {
+                    Async::Ready(stream) => State::Client(stream),
+                    Async::NotReady => {
+                        self.state = State::ClientHandshake(handshake);
+                        return Ok(Async::NotReady);
+                    }
+                },
+                state @ State::Server(_) | state @ State::Client(_) => {
+                    self.state = state;
+                    return Ok(Async::Ready(()));
+                }
+                State::Failed => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "TLS handshake failed",
+                    ))
+                }
+            };
+        }
+    }
+
+    fn established(&mut self) -> io::Result<&mut dyn ReadWrite> {
+        if self.poll_handshake()?.is_not_ready() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        match self.state {
+            State::Server(ref mut stream) => Ok(stream),
+            State::Client(ref mut stream) => Ok(stream),
+            _ => unreachable!("TLS handshake is completed"),
+        }
+    }
+}
+
+impl<S> fmt::Debug for TlsStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.state {
+            State::Accepting(..) | State::Connecting(..) => "Pending",
+            State::ServerHandshake(_) | State::ClientHandshake(_) => "Handshake",
+            State::Server(_) | State::Client(_) => "Established",
+            State::Failed => "Failed",
+        };
+        f.debug_struct("TlsStream").field("state", &state).finish()
+    }
+}
+
+trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
+
+impl<S: AsyncRead + AsyncWrite> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.established()?.read(buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.established()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.established()?.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.state {
+            State::Server(ref mut stream) => stream.shutdown(),
+            State::Client(ref mut stream) => stream.shutdown(),
+            // Nothing has been sent to the peer yet.
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
+
+/// Stream yielding the byte consumed while detecting TLS before the rest of the stream.
+struct Prefixed<S> {
+    stream: S,
+    prefix: Option<u8>,
+}
+
+impl<S> Prefixed<S> {
+    fn new(stream: S, prefix: Option<u8>) -> Self {
+        Prefixed { stream, prefix }
+    }
+}
+
+impl<S: Read> Read for Prefixed<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.prefix {
+            Some(byte) if !buf.is_empty() => {
+                buf[0] = byte;
+                self.prefix = None;
+                Ok(1)
+            }
+            _ => self.stream.read(buf),
+        }
+    }
+}
+
+impl<S: Write> Write for Prefixed<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Prefixed<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Prefixed<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, failure::Error> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format_err!("Unable to open {}: {}", path.display(), e))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, failure::Error> {
+    match pemfile::certs(&mut open(path)?) {
+        Ok(ref certs) if certs.is_empty() => bail!("No certificates in {}", path.display()),
+        Ok(certs) => Ok(certs),
+        Err(()) => bail!("Malformed certificates in {}", path.display()),
+    }
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, failure::Error> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut open(path)?);
+    let rsa = pemfile::rsa_private_keys(&mut open(path)?);
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .flat_map(|keys| keys)
+        .next()
+        .ok_or_else(|| format_err!("No private key in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_records_are_detected() {
+        assert!(is_tls_record(&[0x16, 0x03, 0x01]));
+        assert!(!is_tls_record(&[0x16]));
+        // Length of the first Noise message.
+        assert!(!is_tls_record(&[49, 0]));
+    }
+}
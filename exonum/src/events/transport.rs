@@ -0,0 +1,285 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transports used by the `NetworkPart` to accept and establish connections with peers.
+//!
+//! `TcpTransport` is used by the node; `MemoryTransport` connects nodes running within
+//! the same process without touching the network, which makes tests deterministic.
+
+use futures::{future, sync::mpsc, Async, Future, Poll, Stream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::{
+    cmp, collections::HashMap, io::{self, Read, Write}, net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex}, time::Duration,
+};
+
+use events::network::NetworkConfiguration;
+
+/// Stream of the accepted connections along with the addresses of the remote peers.
+pub type ListenStream<S> = Box<dyn Stream<Item = (S, SocketAddr), Error = io::Error>>;
+/// Future resolving into the established connection.
+pub type DialFuture<S> = Box<dyn Future<Item = S, Error = io::Error>>;
+
+/// Means to accept and establish byte streams with peers.
+pub trait Transport: Clone + 'static {
+    /// Connection with a peer.
+    type Stream: AsyncRead + AsyncWrite + 'static;
+
+    /// Starts accepting connections on the given address.
+    fn listen(&self, address: &SocketAddr) -> io::Result<ListenStream<Self::Stream>>;
+
+    /// Establishes a connection with the peer listening on the given address.
+    fn dial(&self, address: &SocketAddr) -> DialFuture<Self::Stream>;
+
+    /// Applies the connection options from the configuration to the established connection.
+    fn configure(
+        &self,
+        _stream: &Self::Stream,
+        _network_config: &NetworkConfiguration,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Transport over TCP.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+
+    fn listen(&self, address: &SocketAddr) -> io::Result<ListenStream<TcpStream>> {
+        let incoming = TcpListener::bind(address)?
+            .incoming()
+            .and_then(|socket| {
+                let address = socket.peer_addr()?;
+                Ok((socket, address))
+            });
+        Ok(Box::new(incoming))
+    }
+
+    fn dial(&self, address: &SocketAddr) -> DialFuture<TcpStream> {
+        Box::new(TcpStream::connect(address))
+    }
+
+    fn configure(
+        &self,
+        socket: &TcpStream,
+        network_config: &NetworkConfiguration,
+    ) -> io::Result<()> {
+        socket.set_nodelay(network_config.tcp_nodelay)?;
+        socket.set_keepalive(network_config.tcp_keep_alive.map(Duration::from_millis))?;
+        if let Some(size) = network_config.tcp_send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = network_config.tcp_recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+type Listeners = HashMap<SocketAddr, mpsc::UnboundedSender<(MemoryStream, SocketAddr)>>;
+
+/// Transport connecting the nodes which share it (or its clones) within the same process.
+///
+/// Nodes dial each other by their listen addresses, which are not bound in the system.
+/// Dialing nodes are reported to the listening side under `127.0.0.1` with a unique port.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTransport {
+    listeners: Arc<Mutex<Listeners>>,
+    next_port: Arc<Mutex<u16>>,
+}
+
+impl MemoryTransport {
+    /// Creates a transport without listeners.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn dialer_address(&self) -> SocketAddr {
+        let mut next_port = self.next_port.lock().unwrap();
+        // Ports below 1024 are skipped to resemble the ephemeral ports.
+        *next_port = cmp::max(next_port.wrapping_add(1), 1024);
+        SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), *next_port)
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Stream = MemoryStream;
+
+    fn listen(&self, address: &SocketAddr) -> io::Result<ListenStream<MemoryStream>> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.get(address).map_or(false, |tx| !tx.is_closed()) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (tx, rx) = mpsc::unbounded();
+        listeners.insert(*address, tx);
+        let incoming =
+            rx.map_err(|()| io::Error::new(io::ErrorKind::Other, "memory listener failed"));
+        Ok(Box::new(incoming))
+    }
+
+    fn dial(&self, address: &SocketAddr) -> DialFuture<MemoryStream> {
+        let (local, remote) = MemoryStream::pair();
+        let dialer_address = self.dialer_address();
+        let listeners = self.listeners.lock().unwrap();
+        let accepted = listeners
+            .get(address)
+            .map_or(false, |tx| tx.unbounded_send((remote, dialer_address)).is_ok());
+        if accepted {
+            Box::new(future::ok(local))
+        } else {
+            Box::new(future::err(io::ErrorKind::ConnectionRefused.into()))
+        }
+    }
+}
+
+/// One end of an in-memory duplex connection.
+#[derive(Debug)]
+pub struct MemoryStream {
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+    // Unread rest of the last received chunk.
+    buffer: Vec<u8>,
+    outgoing: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+impl MemoryStream {
+    /// Creates two connected ends; the bytes written into one end are read from the other.
+    pub fn pair() -> (Self, Self) {
+        let (first_tx, first_rx) = mpsc::unbounded();
+        let (second_tx, second_rx) = mpsc::unbounded();
+        let first = MemoryStream {
+            incoming: first_rx,
+            buffer: Vec::new(),
+            outgoing: Some(second_tx),
+        };
+        let second = MemoryStream {
+            incoming: second_rx,
+            buffer: Vec::new(),
+            outgoing: Some(first_tx),
+        };
+        (first, second)
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.buffer.is_empty() {
+            match self.incoming.poll() {
+                Ok(Async::Ready(Some(chunk))) => self.buffer = chunk,
+                // The other end has been closed.
+                Ok(Async::Ready(None)) | Err(()) => return Ok(0),
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+        let len = cmp::min(buf.len(), self.buffer.len());
+        buf[..len].copy_from_slice(&self.buffer[..len]);
+        self.buffer.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sent = self
+            .outgoing
+            .as_ref()
+            .map_or(false, |tx| tx.unbounded_send(buf.to_vec()).is_ok());
+        if sent {
+            Ok(buf.len())
+        } else {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for MemoryStream {}
+
+impl AsyncWrite for MemoryStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.outgoing = None;
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+    use tokio_io::io::{read_exact, write_all};
+
+    use super::*;
+
+    #[test]
+    fn tcp_transport_configures_socket() {
+        use std::net::TcpListener as StdTcpListener;
+
+        let config = NetworkConfiguration {
+            tcp_nodelay: true,
+            tcp_send_buffer: Some(64 * 1024),
+            tcp_recv_buffer: Some(128 * 1024),
+            ..NetworkConfiguration::default()
+        };
+        let listener = StdTcpListener::bind("127.0.0.1:19770").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut core = Core::new().unwrap();
+        let socket = core.run(TcpTransport.dial(&address)).unwrap();
+        TcpTransport.configure(&socket, &config).unwrap();
+
+        assert!(socket.nodelay().unwrap());
+        // The system may round the buffer sizes up, but never down.
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 128 * 1024);
+    }
+
+    #[test]
+    fn memory_transport_connects_listener() {
+        let transport = MemoryTransport::new();
+        let address = "127.0.0.1:19780".parse().unwrap();
+        let incoming = transport.listen(&address).unwrap();
+        assert!(transport.listen(&address).is_err());
+
+        let mut core = Core::new().unwrap();
+        let client = core.run(transport.dial(&address)).unwrap();
+        let (server, _) = match core.run(incoming.into_future()) {
+            Ok((Some(accepted), _)) => accepted,
+            _ => panic!("connection has not been accepted"),
+        };
+
+        let (_client, _) = core.run(write_all(client, b"ping".to_vec())).unwrap();
+        let (_server, buf) = core.run(read_exact(server, [0; 4])).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test]
+    fn memory_transport_refuses_unknown_address() {
+        let transport = MemoryTransport::new();
+        let address = "127.0.0.1:19781".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let error = core.run(transport.dial(&address)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        // A closed stream reads as the end of file.
+        let (mut first, second) = MemoryStream::pair();
+        drop(second);
+        let read = core.run(future::lazy(move || first.read(&mut [0; 4])));
+        assert_eq!(read.unwrap(), 0);
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracking of the depths of the event queues.
+//!
+//! The producers of the events send them through `CountingSender`, and the handler part
+//! receives them through `CountingReceiver`, which shares the depth with the sender,
+//! so that the depth is a gauge of the backlog of the source; see `QueueDepths`.
+//!
+//! Network events are received through `WatermarkStream`, which counts them as well.
+//! Once the depth reaches the high watermark, `NetworkEvent::HighWatermark` is delivered
+//! ahead of the queued events; `NetworkEvent::LowWatermark` follows once the queue is drained
+//! to the low one. Each crossing is reported once, so that the handler can shed load before
+//! the events are dropped or the peers are throttled.
+
+use futures::{
+    sync::mpsc::{self, SendError, TrySendError}, Async, Poll, Sink, StartSend, Stream,
+};
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering}, Arc,
+};
+
+use events::network::NetworkEvent;
+
+/// Thresholds of the network events queue depth.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Watermarks {
+    /// Depth at which `NetworkEvent::HighWatermark` is emitted.
+    pub high: usize,
+    /// Depth at which `NetworkEvent::LowWatermark` is emitted after the high watermark
+    /// has been reached. Should be less than `high`.
+    pub low: usize,
+}
+
+/// Number of events sent into a channel, but not received from it yet. Events sent
+/// by other senders than `CountingSender` are not counted.
+#[derive(Debug, Clone, Default)]
+pub struct QueueDepth(Arc<AtomicUsize>);
+
+/// Depths of the queues of the event sources of `HandlerPart`.
+#[derive(Debug, Clone, Default)]
+pub struct QueueDepths {
+    /// Internal events, including timeouts, sent by `InternalPart`.
+    pub internal: QueueDepth,
+    /// Network events sent by `NetworkPart`.
+    pub network: QueueDepth,
+    /// Api messages forwarded to the handler by `ApiPart`.
+    pub api: QueueDepth,
+}
+
+impl QueueDepth {
+    /// Returns the current depth.
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn pushed(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Saturates at zero, so that the events sent by other senders do not break the gauge.
+    fn popped(&self) {
+        let mut depth = self.0.load(Ordering::SeqCst);
+        while depth > 0 {
+            match self
+                .0
+                .compare_exchange(depth, depth - 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(actual) => depth = actual,
+            }
+        }
+    }
+}
+
+/// Sender which counts the sent events in the given depth.
+#[derive(Debug)]
+pub struct CountingSender<T> {
+    sender: mpsc::Sender<T>,
+    depth: QueueDepth,
+}
+
+impl<T> CountingSender<T> {
+    pub fn new(sender: mpsc::Sender<T>, depth: QueueDepth) -> Self {
+        CountingSender { sender, depth }
+    }
+
+    /// Sends the event if there is room in the channel.
+    pub fn try_send(&mut self, event: T) -> Result<(), TrySendError<T>> {
+        self.sender.try_send(event)?;
+        self.depth.pushed();
+        Ok(())
+    }
+}
+
+impl<T> Clone for CountingSender<T> {
+    fn clone(&self) -> Self {
+        CountingSender {
+            sender: self.sender.clone(),
+            depth: self.depth.clone(),
+        }
+    }
+}
+
+impl<T> Sink for CountingSender<T> {
+    type SinkItem = T;
+    type SinkError = SendError<T>;
+
+    fn start_send(&mut self, event: T) -> StartSend<T, SendError<T>> {
+        let sent = self.sender.start_send(event)?;
+        if sent.is_ready() {
+            self.depth.pushed();
+        }
+        Ok(sent)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), SendError<T>> {
+        self.sender.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), SendError<T>> {
+        self.sender.close()
+    }
+}
+
+/// Stream counting the received events in the given depth.
+#[derive(Debug)]
+pub struct CountingReceiver<S> {
+    stream: S,
+    depth: QueueDepth,
+}
+
+impl<S> CountingReceiver<S> {
+    pub fn new(stream: S, depth: QueueDepth) -> Self {
+        CountingReceiver { stream, depth }
+    }
+}
+
+impl<S: Stream> Stream for CountingReceiver<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let polled = self.stream.poll()?;
+        if let Async::Ready(Some(_)) = polled {
+            self.depth.popped();
+        }
+        Ok(polled)
+    }
+}
+
+/// Stream of the network events which reports the crossings of the watermarks by the depth
+/// of the queue. Passes the events through if the watermarks are not set.
+#[derive(Debug)]
+pub struct WatermarkStream<S> {
+    stream: S,
+    depth: QueueDepth,
+    watermarks: Option<Watermarks>,
+    // Whether the high watermark has been reached and the low one has not been yet.
+    above: bool,
+}
+
+impl<S> WatermarkStream<S> {
+    pub fn new(stream: S, depth: QueueDepth, watermarks: Option<Watermarks>) -> Self {
+        WatermarkStream {
+            stream,
+            depth,
+            watermarks,
+            above: false,
+        }
+    }
+
+    fn poll_crossing(&mut self) -> Option<NetworkEvent> {
+        let watermarks = self.watermarks?;
+        let depth = self.depth.get();
+        if !self.above && depth >= watermarks.high {
+            self.above = true;
+            Some(NetworkEvent::HighWatermark(depth))
+        } else if self.above && depth <= watermarks.low {
+            self.above = false;
+            Some(NetworkEvent::LowWatermark(depth))
+        } else {
+            None
+        }
+    }
+}
+
+impl<S: Stream<Item = NetworkEvent>> Stream for WatermarkStream<S> {
+    type Item = NetworkEvent;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<NetworkEvent>, S::Error> {
+        if let Some(crossing) = self.poll_crossing() {
+            return Ok(Async::Ready(Some(crossing)));
+        }
+        let polled = self.stream.poll()?;
+        if let Async::Ready(Some(_)) = polled {
+            self.depth.popped();
+        }
+        Ok(polled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn disconnected() -> NetworkEvent {
+        let address: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        NetworkEvent::PeerDisconnected(address)
+    }
+
+    fn crossing(event: NetworkEvent) -> Option<(bool, usize)> {
+        match event {
+            NetworkEvent::HighWatermark(depth) => Some((true, depth)),
+            NetworkEvent::LowWatermark(depth) => Some((false, depth)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn watermarks_are_reported_once_per_crossing() {
+        let (tx, rx) = mpsc::channel(16);
+        let depth = QueueDepth::default();
+        let mut tx = CountingSender::new(tx, depth.clone());
+        let watermarks = Watermarks { high: 3, low: 1 };
+        let mut events = WatermarkStream::new(rx, depth.clone(), Some(watermarks));
+        let mut next = move || (&mut events).wait().next().unwrap().unwrap();
+
+        for _ in 0..4 {
+            tx.try_send(disconnected()).unwrap();
+        }
+        assert_eq!(depth.get(), 4);
+        assert_eq!(crossing(next()), Some((true, 4)));
+        assert_eq!(crossing(next()), None);
+
+        // The depth is above the high watermark again, but it has not been crossed.
+        tx = tx.send(disconnected()).wait().unwrap();
+        assert_eq!(depth.get(), 4);
+        assert_eq!(crossing(next()), None);
+        assert_eq!(crossing(next()), None);
+        assert_eq!(crossing(next()), None);
+        assert_eq!(crossing(next()), Some((false, 1)));
+        assert_eq!(crossing(next()), None);
+        assert_eq!(depth.get(), 0);
+        drop(tx);
+    }
+}
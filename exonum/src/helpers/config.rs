@@ -127,11 +127,18 @@ impl ConfigManager {
     // Updates ConnectList on file system synchronously.
     // This method is public only for testing and should not be used explicitly.
     #[doc(hidden)]
-    pub fn update_connect_list<P>(connect_list: ConnectListConfig, path: &P) -> Result<(), Error>
+    pub fn update_connect_list<P>(
+        mut connect_list: ConnectListConfig,
+        path: &P,
+    ) -> Result<(), Error>
     where
         P: AsRef<Path>,
     {
         let mut current_config: NodeConfig = ConfigFile::load(path)?;
+        // Endpoints of the peers are not known to the node, so they are kept as configured.
+        for (key, endpoint) in current_config.connect_list.endpoints {
+            connect_list.endpoints.entry(key).or_insert(endpoint);
+        }
         current_config.connect_list = connect_list;
         ConfigFile::save(&current_config, path)?;
 
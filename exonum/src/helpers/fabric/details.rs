@@ -619,6 +619,7 @@ impl Command for Finalize {
                 listen_address: secret_config.listen_address,
                 external_address: secret_config.external_address,
                 network: Default::default(),
+                tls: None,
                 consensus_public_key: secret_config.consensus_public_key,
                 consensus_secret_key: secret_config.consensus_secret_key,
                 service_public_key: secret_config.service_public_key,
@@ -64,6 +64,7 @@ pub fn generate_testnet_config(count: u16, start_port: u16) -> Vec<NodeConfig> {
             listen_address: peers[idx],
             external_address: peers[idx],
             network: Default::default(),
+            tls: None,
             consensus_public_key: validator.0,
             consensus_secret_key: validator.1,
             service_public_key: service.0,
@@ -46,6 +46,7 @@ extern crate bytes;
 extern crate chrono;
 #[macro_use(crate_version, crate_authors)]
 extern crate clap;
+extern crate crc;
 extern crate env_logger;
 pub extern crate exonum_crypto as crypto;
 extern crate exonum_rocksdb as rocksdb;
@@ -57,6 +58,7 @@ extern crate futures;
 extern crate hex;
 #[macro_use]
 extern crate log;
+extern crate lz4;
 extern crate os_info;
 extern crate rand;
 extern crate rust_decimal;
@@ -72,8 +74,11 @@ extern crate tokio_core;
 extern crate tokio_executor;
 extern crate tokio_io;
 extern crate tokio_retry;
+extern crate tokio_rustls;
 extern crate tokio_threadpool;
 extern crate toml;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 extern crate uuid;
 extern crate vec_map;
 
@@ -15,6 +15,7 @@
 #![allow(unsafe_code)]
 
 use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
 
 use std::{convert, fmt::Debug, ops::Deref, sync};
 
@@ -43,6 +44,11 @@ impl RawMessage {
         RawMessage(sync::Arc::new(MessageBuffer::from_vec(vec)))
     }
 
+    /// Creates a new `RawMessage` instance sharing the given bytes without copying them.
+    pub fn from_bytes(bytes: Bytes) -> Self {
+        RawMessage(sync::Arc::new(MessageBuffer::from_bytes(bytes)))
+    }
+
     /// Returns hash of the `RawMessage`.
     pub fn hash(&self) -> Hash {
         hash(self.as_ref())
@@ -67,10 +73,12 @@ impl AsRef<[u8]> for RawMessage {
 // TODO: Payload_length as a first value into message header. (ECR-166)
 // TODO: Make sure that message length is enough when using mem::transmute. (ECR-166)
 
-/// A raw message represented by the bytes buffer.
+/// A raw message represented by the bytes buffer. The buffer may share memory with other
+/// `Bytes`, e.g., with the frame the message has been decoded from; use `to_vec` to get
+/// a copy of the message which does not keep the shared memory alive.
 #[derive(Debug, PartialEq)]
 pub struct MessageBuffer {
-    raw: Vec<u8>,
+    raw: Bytes,
 }
 
 impl MessageBuffer {
@@ -87,9 +95,34 @@ impl MessageBuffer {
     pub fn from_vec(raw: Vec<u8>) -> Self {
         // TODO: Check that size >= HEADER_LENGTH. (ECR-166)
         // TODO: Check that payload_length == raw.len(). (ECR-166)
+        Self { raw: raw.into() }
+    }
+
+    /// Creates `MessageBuffer` instance sharing the given bytes without copying them.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # extern crate bytes;
+    /// # extern crate exonum;
+    /// use bytes::Bytes;
+    /// use exonum::messages::MessageBuffer;
+    ///
+    /// # fn main() {
+    /// let frame = Bytes::from(vec![0, 1, 2, 3]);
+    /// let message_buffer = MessageBuffer::from_bytes(frame.slice_from(1));
+    /// assert_eq!(message_buffer.as_ref(), &[1, 2, 3]);
+    /// # }
+    /// ```
+    pub fn from_bytes(raw: Bytes) -> Self {
         Self { raw }
     }
 
+    /// Copies the message into a new vector.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.raw.to_vec()
+    }
+
     /// Returns the length of the message in bytes.
     ///
     /// # Example
@@ -236,7 +269,7 @@ impl MessageWriter {
         self.set_payload_length(payload_length);
         let signature = sign(&self.raw, secret_key);
         self.raw.extend_from_slice(signature.as_ref());
-        MessageBuffer { raw: self.raw }
+        MessageBuffer::from_vec(self.raw)
     }
 
     /// Appends the given signature to the message.
@@ -245,7 +278,7 @@ impl MessageWriter {
         self.set_payload_length(payload_length);
         self.raw.extend_from_slice(signature.as_ref());
         debug_assert_eq!(self.raw.len(), payload_length);
-        MessageBuffer { raw: self.raw }
+        MessageBuffer::from_vec(self.raw)
     }
 }
 
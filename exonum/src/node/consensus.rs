@@ -286,6 +286,22 @@ impl NodeHandler {
         }
     }
 
+    /// Handles the propose again if it is still actual and all of its transactions are known.
+    /// This function is called in the tick following `InternalRequest::RetryPropose`.
+    pub fn handle_retry_propose(&mut self, height: Height, round: Round, hash: Hash) {
+        if height != self.state.height() {
+            trace!("Ignoring retry of the propose {:?} at old height {}", hash, height);
+            return;
+        }
+        let is_full = self
+            .state
+            .propose(&hash)
+            .map_or(false, |state| !state.has_unknown_txs());
+        if is_full {
+            self.handle_full_propose(hash, round);
+        }
+    }
+
     /// Executes and commits block. This function is called when node has full block information.
     ///
     /// # Panics
@@ -518,6 +534,7 @@ impl NodeHandler {
             block_hash.to_hex(),
         );
 
+        self.cancel_timeouts_before(height);
         self.broadcast_status();
         self.add_status_timeout();
 
@@ -14,7 +14,10 @@
 
 use super::{ConnectListConfig, ExternalMessage, NodeHandler, NodeTimeout};
 use blockchain::Schema;
-use events::{error::LogError, Event, EventHandler, InternalEvent, InternalRequest, NetworkEvent};
+use events::{
+    error::LogError, Event, EventHandler, InternalEvent, InternalRequest, NetworkEvent,
+    NetworkRequest,
+};
 
 impl EventHandler for NodeHandler {
     fn handle_event(&mut self, event: Event) {
@@ -24,6 +27,14 @@ impl EventHandler for NodeHandler {
             Event::Internal(internal) => self.handle_internal_event(internal),
         }
     }
+
+    fn handle_shutdown(&mut self) {
+        info!(
+            "Consensus handler stopped at height {}, round {}",
+            self.state.height(),
+            self.state.round()
+        );
+    }
 }
 
 impl NodeHandler {
@@ -34,6 +45,12 @@ impl NodeHandler {
         match event {
             InternalEvent::Timeout(timeout) => self.handle_timeout(timeout),
             InternalEvent::JumpToRound(height, round) => self.handle_new_round(height, round),
+            InternalEvent::RetryPropose(height, round, hash) => {
+                self.handle_retry_propose(height, round, hash)
+            }
+            InternalEvent::Flush => self.handle_flush(),
+            // The node does not request periodic ticks.
+            InternalEvent::Tick(_) => {}
             InternalEvent::Shutdown => panic!("Shutdown should be processed in the event loop"),
             InternalEvent::TxVerified(tx) => {
                 // We don't care about result, because situation when transaction received twice
@@ -48,7 +65,19 @@ impl NodeHandler {
             NetworkEvent::PeerConnected(peer, connect) => self.handle_connected(&peer, connect),
             NetworkEvent::PeerDisconnected(peer) => self.handle_disconnected(peer),
             NetworkEvent::UnableConnectToPeer(peer) => self.handle_unable_to_connect(peer),
-            NetworkEvent::MessageReceived(_, raw) => self.handle_message(raw),
+            NetworkEvent::MessageReceived(_, _, raw) => self.handle_message(raw),
+            NetworkEvent::DecodeError { peer, error } => {
+                warn!("Received malformed frame from peer={}: {}", peer, error);
+            }
+            NetworkEvent::StreamError { source, error } => {
+                warn!("Event source {} has failed: {}", source, error);
+            }
+            NetworkEvent::HighWatermark(depth) => {
+                warn!("Network events queue has reached {} events", depth);
+            }
+            NetworkEvent::LowWatermark(depth) => {
+                info!("Network events queue has drained to {} events", depth);
+            }
         }
     }
 
@@ -88,6 +117,16 @@ impl NodeHandler {
             }
             ExternalMessage::Shutdown => self.execute_later(InternalRequest::Shutdown),
             ExternalMessage::Rebroadcast => self.handle_rebroadcast(),
+            ExternalMessage::ConnectionsActivity(response_tx) => {
+                let request = NetworkRequest::ConnectionsActivity(response_tx.into());
+                self.channel.network_requests.send(request).log_error();
+            }
+            ExternalMessage::PeerInfoRequest(response_tx) => {
+                let request = NetworkRequest::PeerInfo(response_tx.into());
+                self.channel.network_requests.send(request).log_error();
+            }
+            // The deadline is checked by `HandlerPart`.
+            ExternalMessage::Expiring { message, .. } => self.handle_api_event(*message),
         }
     }
 
@@ -25,15 +25,15 @@ pub use self::{
 pub mod state;
 
 use failure;
-use futures::{sync::mpsc, Future, Sink};
+use futures::{future::Either, sync::{mpsc, oneshot}, Future, Sink};
 use serde::de::{self, Deserialize, Deserializer};
 use tokio_core::reactor::Core;
 use tokio_threadpool::Builder as ThreadPoolBuilder;
 use toml::Value;
 
 use std::{
-    collections::{BTreeMap, HashSet}, fmt, net::{SocketAddr, ToSocketAddrs}, sync::Arc, thread,
-    time::{Duration, SystemTime},
+    collections::{BTreeMap, HashMap, HashSet}, fmt, net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf, sync::Arc, thread, time::{Duration, Instant, SystemTime},
 };
 
 use api::{
@@ -45,10 +45,14 @@ use blockchain::{
     ValidatorKeys,
 };
 use crypto::{self, CryptoHash, Hash, PublicKey, SecretKey};
+#[cfg(unix)]
+use events::control::ControlPart;
 use events::{
-    error::{into_failure, LogError}, noise::HandshakeParams, HandlerPart, InternalEvent,
-    InternalPart, InternalRequest, NetworkConfiguration, NetworkEvent, NetworkPart, NetworkRequest,
-    SyncSender, TimeoutRequest,
+    error::{into_failure, LogError}, noise::HandshakeParams, ApiPart, ConnectionActivity,
+    CountingSender, HandlerPart, InternalEvent, InternalEventsOverflow, InternalPart,
+    InternalRequest, NetworkConfiguration, NetworkEvent, NetworkPart, NetworkRequest, NodeParts,
+    PeerInfo, QueueDepths, SourceClosure, SourceClosurePolicy, SyncSender, TcpTransport,
+    TimeoutHandle, TimeoutRequest, TlsConfig, TlsTransport,
 };
 use helpers::{
     config::ConfigManager, fabric::{NodePrivateConfig, NodePublicConfig}, user_agent, Height,
@@ -77,10 +81,46 @@ pub enum ExternalMessage {
     Shutdown,
     /// Rebroadcast transactions from the pool.
     Rebroadcast,
+    /// Report the activity of the established connections indexed by the peer addresses.
+    ConnectionsActivity(oneshot::Sender<HashMap<SocketAddr, ConnectionActivity>>),
+    /// Report the connected peers.
+    PeerInfoRequest(oneshot::Sender<Vec<PeerInfo>>),
+    /// Message which is no longer needed after the deadline, e.g., because the client
+    /// requesting it has given up. `HandlerPart` skips the message if the deadline
+    /// has passed by the time the message is taken from the queue, and passes the inner
+    /// message to the handler otherwise.
+    Expiring {
+        message: Box<ExternalMessage>,
+        expires_at: Instant,
+    },
+}
+
+impl ExternalMessage {
+    /// Returns `true` for administrative messages, which are handled ahead of
+    /// the queued network events.
+    pub fn is_high_priority(&self) -> bool {
+        match *self {
+            ExternalMessage::PeerAdd(_)
+            | ExternalMessage::Enable(_)
+            | ExternalMessage::Shutdown
+            | ExternalMessage::ConnectionsActivity(_)
+            | ExternalMessage::PeerInfoRequest(_) => true,
+            ExternalMessage::Transaction(_) | ExternalMessage::Rebroadcast => false,
+            ExternalMessage::Expiring { ref message, .. } => message.is_high_priority(),
+        }
+    }
+
+    /// Wraps the message into `ExternalMessage::Expiring` with the given deadline.
+    pub fn expiring(self, expires_at: Instant) -> Self {
+        ExternalMessage::Expiring {
+            message: Box::new(self),
+            expires_at,
+        }
+    }
 }
 
 /// Node timeout types.
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum NodeTimeout {
     /// Status timeout with the current height.
     Status(Height),
@@ -96,6 +136,24 @@ pub enum NodeTimeout {
     PeerExchange,
 }
 
+impl NodeTimeout {
+    /// Returns the height and round of consensus the timeout refers to, if any. Timeouts
+    /// of the requests carry the height or round of the requested data, if it has one.
+    pub fn context(&self) -> (Option<Height>, Option<Round>) {
+        match *self {
+            NodeTimeout::Status(height) => (Some(height), None),
+            NodeTimeout::Round(height, round) | NodeTimeout::Propose(height, round) => {
+                (Some(height), Some(round))
+            }
+            NodeTimeout::Request(RequestData::Block(height), _) => (Some(height), None),
+            NodeTimeout::Request(RequestData::Prevotes(round, _), _) => (None, Some(round)),
+            NodeTimeout::Request(..) | NodeTimeout::UpdateApiState | NodeTimeout::PeerExchange => {
+                (None, None)
+            }
+        }
+    }
+}
+
 /// A helper trait that provides the node with information about the state of the system such
 /// as current time or listen address.
 pub trait SystemStateProvider: ::std::fmt::Debug + Send + 'static {
@@ -174,6 +232,9 @@ pub struct NodeApiConfig {
     ///
     /// [cors]: https://developer.mozilla.org/en-US/docs/Web/HTTP/CORS
     pub private_allow_origin: Option<AllowOrigin>,
+    /// Path of the UNIX domain socket accepting local control messages, e.g., to shut down
+    /// the node without exposing a TCP port, see `events::control`. Supported on UNIX only.
+    pub control_socket: Option<PathBuf>,
 }
 
 impl Default for NodeApiConfig {
@@ -184,6 +245,7 @@ impl Default for NodeApiConfig {
             private_api_address: None,
             public_allow_origin: None,
             private_allow_origin: None,
+            control_socket: None,
         }
     }
 }
@@ -199,6 +261,10 @@ pub struct EventsPoolCapacity {
     pub internal_events_capacity: usize,
     /// Maximum number of queued requests from api.
     pub api_requests_capacity: usize,
+    /// Action taken when an internal event is produced while `internal_events_capacity`
+    /// events are queued.
+    #[serde(default)]
+    pub internal_events_overflow: InternalEventsOverflow,
 }
 
 impl Default for EventsPoolCapacity {
@@ -208,6 +274,7 @@ impl Default for EventsPoolCapacity {
             network_events_capacity: 512,
             internal_events_capacity: 128,
             api_requests_capacity: 1024,
+            internal_events_overflow: InternalEventsOverflow::Block,
         }
     }
 }
@@ -243,6 +310,10 @@ pub struct NodeConfig {
     pub external_address: SocketAddr,
     /// Network configuration.
     pub network: NetworkConfiguration,
+    /// Optional TLS configuration of the connections with peers. It is kept apart from
+    /// `network`, since it cannot be updated while the node is running.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
     /// Consensus public key.
     pub consensus_public_key: PublicKey,
     /// Consensus secret key.
@@ -339,6 +410,11 @@ impl NodeRole {
 pub struct ConnectListConfig {
     /// Peers to which we can connect.
     pub peers: Vec<ConnectInfo>,
+    /// Endpoints (`host:port`) of the peers, by their public keys. Unlike the addresses
+    /// of the `peers`, which are resolved once the config is loaded, the endpoints are
+    /// resolved each time the peer is dialed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub endpoints: BTreeMap<PublicKey, String>,
 }
 
 impl ConnectListConfig {
@@ -352,7 +428,10 @@ impl ConnectListConfig {
             })
             .collect();
 
-        ConnectListConfig { peers }
+        ConnectListConfig {
+            peers,
+            endpoints: BTreeMap::new(),
+        }
     }
 
     /// Creates `ConnectListConfig` from validators keys and corresponding IP addresses.
@@ -366,13 +445,17 @@ impl ConnectListConfig {
             })
             .collect();
 
-        ConnectListConfig { peers }
+        ConnectListConfig {
+            peers,
+            endpoints: BTreeMap::new(),
+        }
     }
 
     /// Creates `ConnectListConfig` from `ConnectList`.
     pub fn from_connect_list(connect_list: &SharedConnectList) -> Self {
         ConnectListConfig {
             peers: connect_list.peers(),
+            endpoints: BTreeMap::new(),
         }
     }
 
@@ -380,6 +463,17 @@ impl ConnectListConfig {
     pub fn addresses(&self) -> Vec<SocketAddr> {
         self.peers.iter().map(|p| p.address).collect()
     }
+
+    /// Endpoints of the peers, by the peers addresses.
+    pub fn endpoints_by_address(&self) -> HashMap<SocketAddr, String> {
+        self.peers
+            .iter()
+            .filter_map(|p| {
+                let endpoint = self.endpoints.get(&p.public_key)?;
+                Some((p.address, endpoint.clone()))
+            })
+            .collect()
+    }
 }
 
 impl NodeHandler {
@@ -610,13 +704,63 @@ impl NodeHandler {
         self.send_to_addr(address, connect.raw());
     }
 
-    /// Add timeout request.
-    pub fn add_timeout(&mut self, timeout: NodeTimeout, time: SystemTime) {
+    /// Closes the connection with the peer having the given public key. If `ban` is given,
+    /// connections with the peer are refused for that long, e.g., after the peer has been
+    /// caught misbehaving.
+    pub fn disconnect_peer(&mut self, public_key: &PublicKey, ban: Option<Duration>) {
+        trace!("Disconnect peer with key: {:?}", public_key);
+        let request = NetworkRequest::DisconnectPeer(*public_key, ban);
+        self.channel.network_requests.send(request).log_error();
+    }
+
+    /// Add timeout request. The returned handle can be used to cancel the timeout.
+    pub fn add_timeout(&mut self, timeout: NodeTimeout, time: SystemTime) -> TimeoutHandle {
+        self.schedule_once(timeout, time)
+    }
+
+    /// Schedules the timeout firing once at the given time. The returned handle can be used
+    /// to cancel the timeout.
+    pub fn schedule_once(&mut self, timeout: NodeTimeout, time: SystemTime) -> TimeoutHandle {
         let request = TimeoutRequest(time, timeout);
+        let handle = request.handle();
         self.channel
             .internal_requests
             .send(request.into())
             .log_error();
+        handle
+    }
+
+    /// Schedules the timeout firing at the given time and then after each `interval`,
+    /// until it is cancelled with the returned handle.
+    pub fn schedule_repeating(
+        &mut self,
+        timeout: NodeTimeout,
+        first: SystemTime,
+        interval: Duration,
+    ) -> TimeoutHandle {
+        let request = TimeoutRequest(first, timeout);
+        let handle = request.handle();
+        self.channel
+            .internal_requests
+            .send(InternalRequest::RepeatingTimeout(request, interval))
+            .log_error();
+        handle
+    }
+
+    /// Cancels the timeout if it has not fired yet.
+    pub fn cancel_timeout(&mut self, handle: TimeoutHandle) {
+        self.channel
+            .internal_requests
+            .send(InternalRequest::CancelTimeout(handle))
+            .log_error();
+    }
+
+    /// Cancels the timeouts referring to the heights below the given one.
+    pub fn cancel_timeouts_before(&mut self, height: Height) {
+        self.channel
+            .internal_requests
+            .send(InternalRequest::CancelTimeoutsBefore(height))
+            .log_error();
     }
 
     /// Adds request timeout if it isn't already requested.
@@ -844,6 +988,16 @@ pub struct NodeChannel {
     pub network_events: (mpsc::Sender<NetworkEvent>, mpsc::Receiver<NetworkEvent>),
     /// Channel for internal events.
     pub internal_events: (mpsc::Sender<InternalEvent>, mpsc::Receiver<InternalEvent>),
+    /// Maximum number of queued internal events.
+    pub internal_events_capacity: usize,
+    /// Action taken when an internal event is produced while the internal events are
+    /// queued up to `internal_events_capacity`.
+    pub internal_events_overflow: InternalEventsOverflow,
+    /// Capacity of the channel through which `ApiPart` forwards the api requests
+    /// to the handler.
+    pub api_requests_capacity: usize,
+    /// Gauges of the numbers of the queued internal, network and api events.
+    pub queue_depths: QueueDepths,
 }
 
 /// Node that contains handler (`NodeHandler`) and `NodeApiConfig`.
@@ -851,10 +1005,12 @@ pub struct NodeChannel {
 pub struct Node {
     api_options: NodeApiConfig,
     network_config: NetworkConfiguration,
+    tls: Option<TlsConfig>,
     handler: NodeHandler,
     channel: NodeChannel,
     max_message_len: u32,
     thread_pool_size: Option<u8>,
+    peer_endpoints: HashMap<SocketAddr, String>,
 }
 
 impl NodeChannel {
@@ -866,6 +1022,10 @@ impl NodeChannel {
             api_requests: mpsc::channel(buffer_sizes.api_requests_capacity),
             network_events: mpsc::channel(buffer_sizes.network_events_capacity),
             internal_events: mpsc::channel(buffer_sizes.internal_events_capacity),
+            internal_events_capacity: buffer_sizes.internal_events_capacity,
+            internal_events_overflow: buffer_sizes.internal_events_overflow,
+            api_requests_capacity: buffer_sizes.api_requests_capacity,
+            queue_depths: QueueDepths::default(),
         }
     }
 
@@ -900,6 +1060,7 @@ impl Node {
         blockchain.initialize(node_cfg.genesis.clone()).unwrap();
 
         let peers = node_cfg.connect_list.addresses();
+        let peer_endpoints = node_cfg.connect_list.endpoints_by_address();
 
         let config = Configuration {
             listener: ListenerConfig {
@@ -934,8 +1095,10 @@ impl Node {
             handler,
             channel,
             network_config,
+            tls: node_cfg.tls,
             max_message_len: node_cfg.genesis.consensus.max_message_len,
             thread_pool_size: node_cfg.thread_pool_size,
+            peer_endpoints,
         }
     }
 
@@ -945,7 +1108,26 @@ impl Node {
         self.handler.initialize();
 
         let pool_size = self.thread_pool_size;
-        let (handler_part, network_part, internal_part) = self.into_reactor();
+        let control_socket = self.api_options.control_socket.clone();
+        #[cfg(unix)]
+        let control_part =
+            control_socket.map(|path| ControlPart::new(path, self.channel.api_requests.0.clone()));
+        #[cfg(not(unix))]
+        {
+            if control_socket.is_some() {
+                warn!("Control socket is supported on UNIX only, the setting is ignored");
+            }
+        }
+        let tls_transport = match self.tls {
+            Some(ref tls) => Some(TlsTransport::new(TcpTransport, tls)?),
+            None => None,
+        };
+        let NodeParts {
+            handler_part,
+            network_part,
+            internal_part,
+            api_part,
+        } = self.into_reactor();
         let handshake_params = handshake_params.clone();
 
         let network_thread = thread::spawn(move || {
@@ -959,17 +1141,34 @@ impl Node {
             let thread_pool = pool_builder.build();
             let executor = thread_pool.sender().clone();
 
+            #[cfg(unix)]
+            {
+                if let Some(control_part) = control_part {
+                    let server = control_part.run(&handle).map_err(into_failure)?;
+                    handle.spawn(server);
+                }
+            }
             core.handle().spawn(internal_part.run(handle, executor));
 
-            let network_handler = network_part.run(&core.handle(), &handshake_params);
+            let network_handler = match tls_transport {
+                Some(transport) => Either::A(
+                    network_part
+                        .with_transport(transport)
+                        .run(&core.handle(), &handshake_params),
+                ),
+                None => Either::B(network_part.run(&core.handle(), &handshake_params)),
+            };
             core.run(network_handler)
                 .map(drop)
                 .map_err(|e| format_err!("An error in the `Network` thread occurred: {}", e))
         });
 
         let mut core = Core::new().map_err(into_failure)?;
+        if let Some(api_part) = api_part {
+            core.handle().spawn(api_part.run());
+        }
         core.run(handler_part.run())
-            .map_err(|_| format_err!("An error in the `Handler` thread occurred"))?;
+            .map_err(|e| format_err!("An error in the `Handler` thread occurred: {}", e))?;
         network_thread.join().unwrap()
     }
 
@@ -1024,13 +1223,7 @@ impl Node {
         }.start()?;
 
         // Runs NodeHandler.
-        let handshake_params = HandshakeParams::new(
-            *self.state().consensus_public_key(),
-            self.state().consensus_secret_key().clone(),
-            self.state().connect_list().clone(),
-            self.state().our_connect_message().clone(),
-            self.max_message_len,
-        );
+        let handshake_params = self.handshake_params();
         self.run_handler(&handshake_params)?;
 
         // Stops actix web runtime.
@@ -1040,32 +1233,69 @@ impl Node {
         Ok(())
     }
 
-    fn into_reactor(self) -> (HandlerPart<NodeHandler>, NetworkPart, InternalPart) {
+    /// Returns the parameters of the handshakes with the peers.
+    pub fn handshake_params(&self) -> HandshakeParams {
+        let mut handshake_params = HandshakeParams::new(
+            *self.state().consensus_public_key(),
+            self.state().consensus_secret_key().clone(),
+            self.state().connect_list().clone(),
+            self.state().our_connect_message().clone(),
+            self.max_message_len,
+        );
+        handshake_params.compression = self.network_config.compression;
+        handshake_params.frame_checksums = self.network_config.frame_checksums;
+        handshake_params
+    }
+
+    /// Initializes the handler and splits the node into the parts, which may be run
+    /// on the reactor of the caller, see `NodeParts::join`. Unlike `run_handler`, TLS and
+    /// the control socket are not set up.
+    pub fn into_parts(mut self) -> NodeParts<NodeHandler> {
+        self.handler.initialize();
+        self.into_reactor()
+    }
+
+    fn into_reactor(self) -> NodeParts<NodeHandler> {
         let connect_message = self.state().our_connect_message().clone();
         let (network_tx, network_rx) = self.channel.network_events;
         let internal_requests_rx = self.channel.internal_requests.1;
-        let network_part = NetworkPart {
-            our_connect_message: connect_message,
-            listen_address: self.handler.system_state.listen_address(),
-            network_requests: self.channel.network_requests,
+        let queue_depths = self.channel.queue_depths;
+        let mut network_part = NetworkPart::new(
+            connect_message,
+            self.handler.system_state.listen_address(),
+            self.network_config,
+            self.max_message_len,
+            self.channel.network_requests,
             network_tx,
-            network_config: self.network_config,
-            max_message_len: self.max_message_len,
-        };
+        );
 
         let (internal_tx, internal_rx) = self.channel.internal_events;
-        let handler_part = HandlerPart {
-            handler: self.handler,
-            internal_rx,
-            network_rx,
+        let (api_tx, api_rx) = mpsc::channel(self.channel.api_requests_capacity);
+        let api_part = ApiPart {
             api_rx: self.channel.api_requests.1,
+            api_tx: CountingSender::new(api_tx, queue_depths.api.clone()),
         };
-
-        let internal_part = InternalPart {
-            internal_tx,
-            internal_requests_rx,
-        };
-        (handler_part, network_part, internal_part)
+        let mut handler_part = HandlerPart::new(self.handler, internal_rx, network_rx, api_rx);
+        handler_part.network_watermarks = self.network_config.events_watermarks;
+        // Consensus cannot proceed without timeouts, so the node is stopped if the internal
+        // events are gone, e.g., because the internal part has failed.
+        handler_part.closure_policy = SourceClosurePolicy::default()
+            .on_closed(0, SourceClosure::Shutdown(Duration::from_secs(1)));
+        network_part.events_depth = queue_depths.network.clone();
+        network_part.endpoints = self.peer_endpoints;
+
+        let mut internal_part = InternalPart::new(internal_tx, internal_requests_rx).with_overflow(
+            self.channel.internal_events_capacity,
+            self.channel.internal_events_overflow,
+        );
+        internal_part.events_depth = queue_depths.internal.clone();
+        handler_part.queue_depths = queue_depths;
+        NodeParts {
+            handler_part,
+            network_part,
+            internal_part,
+            api_part: Some(api_part),
+        }
     }
 
     /// Returns `Blockchain` instance.
@@ -1087,6 +1317,11 @@ impl Node {
     pub fn channel(&self) -> ApiSender {
         ApiSender::new(self.channel.api_requests.0.clone())
     }
+
+    /// Returns the gauges of the numbers of the events queued for the handler.
+    pub fn queue_depths(&self) -> QueueDepths {
+        self.channel.queue_depths.clone()
+    }
 }
 
 #[cfg(test)]
@@ -1117,6 +1352,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_timeout_context() {
+        let timeout = NodeTimeout::Round(Height(5), Round(2));
+        assert_eq!(timeout.context(), (Some(Height(5)), Some(Round(2))));
+        let timeout = NodeTimeout::Status(Height(3));
+        assert_eq!(timeout.context(), (Some(Height(3)), None));
+        let timeout = NodeTimeout::Request(RequestData::Prevotes(Round(4), Hash::zero()), None);
+        assert_eq!(timeout.context(), (None, Some(Round(4))));
+        assert_eq!(NodeTimeout::PeerExchange.context(), (None, None));
+    }
+
     #[test]
     fn test_duplicated_transaction() {
         let (p_key, s_key) = gen_keypair();
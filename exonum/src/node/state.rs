@@ -405,6 +405,12 @@ impl SharedConnectList {
         connect_list.find_key_by_address(address).cloned()
     }
 
+    /// Get address of the peer with the given public key.
+    pub fn find_address_by_key(&self, public_key: &PublicKey) -> Option<SocketAddr> {
+        let connect_list = self.connect_list.read().expect("ConnectList read lock");
+        connect_list.peers.get(public_key).cloned()
+    }
+
     /// Return `peers` from underlying `ConnectList`
     pub fn peers(&self) -> Vec<ConnectInfo> {
         self.connect_list
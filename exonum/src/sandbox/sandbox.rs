@@ -22,7 +22,7 @@ use futures::{self, sync::mpsc, Async, Future, Sink, Stream};
 use std::{
     self, cell::{Ref, RefCell, RefMut},
     collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque}, iter::FromIterator,
-    net::{IpAddr, Ipv4Addr, SocketAddr}, ops::{AddAssign, Deref}, sync::{Arc, Mutex},
+    mem, net::{IpAddr, Ipv4Addr, SocketAddr}, ops::{AddAssign, Deref}, sync::{Arc, Mutex},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
@@ -36,8 +36,8 @@ use blockchain::{
 };
 use crypto::{gen_keypair, gen_keypair_from_seed, Hash, PublicKey, SecretKey, Seed, SEED_LENGTH};
 use events::{
-    network::NetworkConfiguration, Event, EventHandler, InternalEvent, InternalRequest,
-    NetworkEvent, NetworkRequest, TimeoutRequest,
+    network::NetworkConfiguration, EarliestFirst, Event, EventHandler, InternalEvent,
+    InternalRequest, NetworkEvent, NetworkRequest, TimeoutRequest,
 };
 use helpers::{user_agent, Height, Milliseconds, Round, ValidatorId};
 use messages::{
@@ -48,7 +48,7 @@ use messages::{
 use node::ConnectInfo;
 use node::{
     ApiSender, Configuration, ConnectList, ConnectListConfig, ExternalMessage, ListenerConfig,
-    NodeHandler, NodeSender, ServiceConfig, State, SystemStateProvider,
+    NodeHandler, NodeSender, NodeTimeout, ServiceConfig, State, SystemStateProvider,
 };
 use storage::{MapProof, MemoryDB};
 
@@ -78,7 +78,10 @@ pub struct SandboxInner {
     pub handler: NodeHandler,
     pub sent: VecDeque<(SocketAddr, RawMessage)>,
     pub events: VecDeque<Event>,
-    pub timers: BinaryHeap<TimeoutRequest>,
+    pub timers: BinaryHeap<EarliestFirst>,
+    /// Repeating timers among the `timers`, with the requests they were scheduled with
+    /// and their intervals.
+    pub repeating_timers: BTreeMap<TimeoutRequest, (TimeoutRequest, Duration)>,
     pub network_requests_rx: mpsc::Receiver<NetworkRequest>,
     pub internal_requests_rx: mpsc::Receiver<InternalRequest>,
     pub api_requests_rx: mpsc::Receiver<ExternalMessage>,
@@ -101,8 +104,22 @@ impl SandboxInner {
         let network_getter = futures::lazy(|| -> Result<(), ()> {
             while let Async::Ready(Some(network)) = self.network_requests_rx.poll()? {
                 match network {
-                    NetworkRequest::SendMessage(peer, msg) => self.sent.push_back((peer, msg)),
-                    NetworkRequest::DisconnectWithPeer(_) | NetworkRequest::Shutdown => {}
+                    NetworkRequest::SendMessage(peer, msg)
+                    | NetworkRequest::SendMessageWithAck(peer, msg, _) => {
+                        self.sent.push_back((peer, msg))
+                    }
+                    NetworkRequest::DisconnectWithPeer(_)
+                    | NetworkRequest::DisconnectPeer(..)
+                    | NetworkRequest::Broadcast(..)
+                    | NetworkRequest::Pause
+                    | NetworkRequest::Resume
+                    | NetworkRequest::Shutdown
+                    | NetworkRequest::ConnectionsActivity(_)
+                    | NetworkRequest::PeerTraffic(_)
+                    | NetworkRequest::PeerInfo(_)
+                    | NetworkRequest::ConnectionStats(_)
+                    | NetworkRequest::ReconcilePeers(_)
+                    | NetworkRequest::UpdateConfig(_) => {}
                 }
             }
             Ok(())
@@ -114,9 +131,39 @@ impl SandboxInner {
         let internal_getter = futures::lazy(|| -> Result<(), ()> {
             while let Async::Ready(Some(internal)) = self.internal_requests_rx.poll()? {
                 match internal {
-                    InternalRequest::Timeout(t) => self.timers.push(t),
-                    InternalRequest::JumpToRound(height, round) => self.handler
-                        .handle_event(InternalEvent::JumpToRound(height, round).into()),
+                    InternalRequest::Timeout(t) => self.timers.push(EarliestFirst(t)),
+                    InternalRequest::RepeatingTimeout(t, interval) => {
+                        if interval == Duration::from_secs(0) {
+                            warn!("Ignoring repeating timeout {:?} with zero interval", t);
+                        } else if !self.is_repeating(&t) {
+                            self.repeating_timers
+                                .insert(t.clone(), (t.clone(), interval));
+                            self.timers.push(EarliestFirst(t));
+                        }
+                    }
+                    InternalRequest::CancelTimeout(handle) => {
+                        self.remove_timers(|timer| handle.matches(timer))
+                    }
+                    InternalRequest::CancelTimeoutsBefore(height) => {
+                        self.remove_timers(|&TimeoutRequest(_, ref timeout)| {
+                            timeout.context().0.map_or(false, |h| h < height)
+                        })
+                    }
+                    InternalRequest::JumpToRound(height, round) => {
+                        self.remove_timers(|&TimeoutRequest(_, ref timeout)| match *timeout {
+                            NodeTimeout::Round(h, r) => h == height && r < round,
+                            _ => false,
+                        });
+                        self.handler
+                            .handle_event(InternalEvent::JumpToRound(height, round).into())
+                    }
+                    InternalRequest::RetryPropose(height, round, hash) => {
+                        let event = InternalEvent::RetryPropose(height, round, hash);
+                        self.handler.handle_event(event.into())
+                    }
+                    InternalRequest::Flush => {
+                        self.handler.handle_event(InternalEvent::Flush.into())
+                    }
                     InternalRequest::Shutdown => unimplemented!(),
                     InternalRequest::VerifyTx(tx) => {
                         if tx.verify() {
@@ -130,6 +177,42 @@ impl SandboxInner {
         });
         internal_getter.wait().unwrap();
     }
+
+    fn remove_timers<F: Fn(&TimeoutRequest) -> bool>(&mut self, predicate: F) {
+        let timers = mem::replace(&mut self.timers, BinaryHeap::new());
+        let repeating_timers = &mut self.repeating_timers;
+        self.timers = timers
+            .into_iter()
+            .filter(|timer| {
+                // Repeating timers are identified by the requests they were scheduled with.
+                let removed = match repeating_timers.get(&timer.0) {
+                    Some(&(ref scheduled, _)) => predicate(scheduled),
+                    None => predicate(&timer.0),
+                };
+                if removed {
+                    repeating_timers.remove(&timer.0);
+                }
+                !removed
+            })
+            .collect();
+    }
+
+    fn is_repeating(&self, request: &TimeoutRequest) -> bool {
+        self.repeating_timers
+            .values()
+            .any(|&(ref scheduled, _)| scheduled == request)
+    }
+
+    /// Re-arms the timer if it is repeating, advancing its deadline by the interval.
+    fn rearm_timer(&mut self, timer: &TimeoutRequest) {
+        if let Some((scheduled, interval)) = self.repeating_timers.remove(timer) {
+            let next = TimeoutRequest(timer.0 + interval, timer.1.clone());
+            self.repeating_timers
+                .insert(next.clone(), (scheduled, interval));
+            self.timers.push(EarliestFirst(next));
+        }
+    }
+
     fn process_api_requests(&mut self) {
         let api_getter = futures::lazy(|| -> Result<(), ()> {
             while let Async::Ready(Some(api)) = self.api_requests_rx.poll()? {
@@ -433,7 +516,7 @@ impl Sandbox {
     pub fn recv<T: Message>(&self, msg: &T) {
         self.check_unexpected_message();
         let dummy_addr = SocketAddr::from(([127, 0, 0, 1], 12_039));
-        let event = NetworkEvent::MessageReceived(dummy_addr, msg.raw().clone());
+        let event = NetworkEvent::MessageReceived(dummy_addr, PublicKey::zero(), msg.raw().clone());
         self.inner.borrow_mut().handle_event(event);
     }
 
@@ -569,13 +652,14 @@ impl Sandbox {
         // handle timeouts if occurs
         loop {
             let timeout = {
-                let timers = &mut self.inner.borrow_mut().timers;
-                if let Some(TimeoutRequest(time, timeout)) = timers.pop() {
-                    if time > now {
-                        timers.push(TimeoutRequest(time, timeout));
+                let inner = &mut *self.inner.borrow_mut();
+                if let Some(EarliestFirst(timer)) = inner.timers.pop() {
+                    if timer.0 > now {
+                        inner.timers.push(EarliestFirst(timer));
                         break;
                     } else {
-                        timeout
+                        inner.rearm_timer(&timer);
+                        timer.1
                     }
                 } else {
                     break;
@@ -855,6 +939,7 @@ impl Sandbox {
             sent: VecDeque::new(),
             events: VecDeque::new(),
             timers: BinaryHeap::new(),
+            repeating_timers: BTreeMap::new(),
             internal_requests_rx: internal_channel.1,
             network_requests_rx: network_channel.1,
             api_requests_rx: api_channel.1,
@@ -1087,6 +1172,7 @@ fn sandbox_with_services_uninitialized(
         sent: VecDeque::new(),
         events: VecDeque::new(),
         timers: BinaryHeap::new(),
+        repeating_timers: BTreeMap::new(),
         network_requests_rx: network_channel.1,
         api_requests_rx: api_channel.1,
         internal_requests_rx: internal_channel.1,
@@ -1236,6 +1322,43 @@ mod tests {
         s.assert_state(Height(1), Round(2));
     }
 
+    #[test]
+    fn test_sandbox_repeating_timeout_is_rearmed() {
+        let s = timestamping_sandbox();
+        let timeout = NodeTimeout::Status(Height(100));
+        let interval = Duration::from_millis(100);
+        let first = s.time() + interval;
+        let handle = {
+            let mut inner = s.inner.borrow_mut();
+            let handle = inner
+                .handler
+                .schedule_repeating(timeout.clone(), first, interval);
+            inner.process_events();
+            handle
+        };
+        let deadlines = || -> Vec<SystemTime> {
+            let inner = s.inner.borrow();
+            inner
+                .timers
+                .iter()
+                .filter(|timer| (timer.0).1 == timeout)
+                .map(|timer| (timer.0).0)
+                .collect()
+        };
+        assert_eq!(deadlines(), vec![first]);
+
+        s.add_time(Duration::from_millis(250));
+        assert_eq!(deadlines(), vec![first + interval * 2]);
+
+        {
+            let mut inner = s.inner.borrow_mut();
+            inner.handler.cancel_timeout(handle);
+            inner.process_events();
+        }
+        assert!(deadlines().is_empty());
+        assert!(s.inner.borrow().repeating_timers.is_empty());
+    }
+
     #[test]
     #[should_panic(expected = "Expected to send the message")]
     fn test_sandbox_expected_to_send_but_nothing_happened() {
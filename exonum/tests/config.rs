@@ -16,18 +16,20 @@
 extern crate exonum;
 #[macro_use]
 extern crate pretty_assertions;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate toml;
 
 use exonum::{
     api::backends::actix::AllowOrigin, crypto::{PublicKey, PUBLIC_KEY_LENGTH},
-    encoding::serialize::FromHex,
+    encoding::serialize::FromHex, events::NetworkConfiguration,
     helpers::{
         config::{ConfigFile, ConfigManager}, fabric::NodeBuilder,
     },
-    node::{ConnectInfo, ConnectListConfig, NodeConfig},
+    node::{ConnectInfo, ConnectListConfig, EventsPoolCapacity, NodeConfig},
 };
+use serde::Serialize;
 use toml::Value;
 
 use std::{
@@ -88,11 +90,35 @@ fn compare_files(filename: &str, folder: &str) {
     destination.read_to_string(&mut destination_buffer).unwrap();
 
     assert!(len > 0);
-    let source_toml: toml::Value = toml::de::from_str(&source_buffer).unwrap();
+    let mut source_toml: toml::Value = toml::de::from_str(&source_buffer).unwrap();
+    fill_defaults(
+        &mut source_toml,
+        &["network"],
+        NetworkConfiguration::default(),
+    );
+    fill_defaults(
+        &mut source_toml,
+        &["mempool", "events_pool_capacity"],
+        EventsPoolCapacity::default(),
+    );
     let destination_toml: toml::Value = toml::de::from_str(&destination_buffer).unwrap();
     assert_eq!(source_toml, destination_toml);
 }
 
+/// Fills the keys missing in the table at `path` of the testdata with their default values,
+/// so that the testdata need not list the parameters added after it was written.
+fn fill_defaults<T: Serialize>(config: &mut Value, path: &[&str], defaults: T) {
+    let table = path.iter().fold(Some(config), |table, key| {
+        table.and_then(|table| table.get_mut(key))
+    });
+    if let Some(table) = table.and_then(Value::as_table_mut) {
+        let defaults = Value::try_from(defaults).unwrap();
+        for (key, value) in defaults.as_table().unwrap() {
+            table.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
+
 fn default_run_with_matches<I, T>(iter: I) -> bool
 where
     I: IntoIterator<Item = T>,
@@ -348,7 +374,10 @@ fn test_update_config() {
         public_key: PublicKey::new([1; PUBLIC_KEY_LENGTH]),
     };
 
-    let connect_list = ConnectListConfig { peers: vec![peer] };
+    let connect_list = ConnectListConfig {
+        peers: vec![peer],
+        endpoints: Default::default(),
+    };
 
     ConfigManager::update_connect_list(connect_list.clone(), &config_path)
         .expect("Unable to update connect list");
@@ -407,6 +436,7 @@ fn test_domain_name_peer() {
                 ).unwrap(),
             },
         ],
+        endpoints: Default::default(),
     };
 
     assert_eq!(config.peers, connect_list.peers);
@@ -431,7 +431,10 @@ impl TestKit {
                         ExternalMessage::PeerAdd(_)
                         | ExternalMessage::Enable(_)
                         | ExternalMessage::Rebroadcast
-                        | ExternalMessage::Shutdown => { /* Ignored */ }
+                        | ExternalMessage::Shutdown
+                        | ExternalMessage::ConnectionsActivity(_)
+                        | ExternalMessage::PeerInfoRequest(_)
+                        | ExternalMessage::Expiring { .. } => { /* Ignored */ }
                     }
                 }
                 blockchain.merge(fork.into_patch()).unwrap();